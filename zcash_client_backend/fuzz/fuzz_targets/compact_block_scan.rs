@@ -0,0 +1,41 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use protobuf::parse_from_bytes;
+use std::collections::HashMap;
+use std::panic;
+use zcash_client_backend::{
+    proto::compact_formats::CompactBlock, validation::validate_compact_block,
+    welding_rig::scan_block,
+};
+use zcash_primitives::{
+    merkle_tree::CommitmentTree,
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let block = match panic::catch_unwind(|| parse_from_bytes::<CompactBlock>(data)) {
+        Ok(Ok(block)) => block,
+        _ => return,
+    };
+
+    // scan_block is only given blocks that have passed validation, as the wallet
+    // validates blocks before caching or scanning them. Fuzz the validation, and stop
+    // here if it rejects the block.
+    if validate_compact_block(&block).is_err() {
+        return;
+    }
+
+    // A fixed set of keys, so that every run trial-decrypts with the same IVKs.
+    let extfvks: Vec<_> = (0..2u8)
+        .map(|i| ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[i])))
+        .collect();
+    let mut tree = CommitmentTree::new();
+    scan_block(
+        block,
+        &extfvks,
+        &[0, 0],
+        &HashMap::new(),
+        &mut tree,
+        &mut [],
+    );
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use protobuf::parse_from_bytes;
+use std::panic;
+use zcash_client_backend::proto::compact_formats::CompactBlock;
+
+fuzz_target!(|data: &[u8]| {
+    // Callers catch parser panics in the same way (see zcash_client_sqlite::scan).
+    let _ = panic::catch_unwind(|| parse_from_bytes::<CompactBlock>(data));
+});
@@ -0,0 +1,665 @@
+//! An experimental state machine for off-chain payment channels.
+//!
+//! A [`PaymentChannel`] tracks how a fixed total value is split between two parties, A
+//! and B. Every state is signed by the party that pays in it, so that the counterparty
+//! can later prove the agreed balances. Each party checks the states it receives with
+//! [`accept_update`] and stores the latest one, which [`close_channel`] then uses to
+//! reject older states. This module only manages channel state; it does not create or
+//! settle any on-chain transactions.
+//!
+//! States are never signed with a spend authorizing key directly. Each party instead
+//! signs with their key randomized by a value derived from the channel ID, in the same
+//! way that spends are signed with a randomized key. Both parties' randomized keys, and
+//! which of them signed, are part of every signed state.
+
+use blake2b_simd::Params;
+use pairing::bls12_381::Bls12;
+use rand_core::RngCore;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use zcash_primitives::{
+    jubjub::{fs::Fs, FixedGenerators, ToUniform},
+    redjubjub::{PrivateKey, PublicKey, Signature},
+    transaction::components::Amount,
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    JUBJUB,
+};
+
+/// Domain separator for signed channel states.
+const CHANNEL_STATE_TAG: &[u8; 16] = b"ZcashChannelStat";
+
+/// BLAKE2b personalization for deriving the key randomizer of a channel.
+const CHANNEL_KEY_PERSONALIZATION: &[u8; 16] = b"Zcash_ChannelKey";
+
+/// The length in bytes of an encoded [`PaymentChannel`].
+pub const CHANNEL_STATE_SIZE: usize = 32 + 32 + 32 + 1 + 8 + 8 + 8 + 64;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The channel state belongs to a different channel.
+    ChannelMismatch,
+    /// The payer's balance is smaller than the payment.
+    InsufficientBalance(Amount),
+    /// A balance or payment amount is negative, or the total value overflowed.
+    InvalidAmount,
+    /// The channel state was not signed by the expected key.
+    InvalidSignature,
+    /// The channel has reached the maximum number of rounds.
+    RoundOverflow,
+    /// The channel state is not newer than the last stored state.
+    StaleRound(u64),
+    /// The channel state increases the balance of the party that signed it.
+    UnauthorizedUpdate(Party),
+    /// A key does not belong to the expected party of the channel.
+    WrongKey(Party),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ChannelMismatch => write!(f, "Channel state belongs to a different channel"),
+            Error::InsufficientBalance(balance) => write!(
+                f,
+                "Insufficient channel balance (have {})",
+                i64::from(*balance)
+            ),
+            Error::InvalidAmount => write!(f, "Invalid channel amount"),
+            Error::InvalidSignature => write!(f, "Invalid channel state signature"),
+            Error::RoundOverflow => write!(f, "Channel round counter overflowed"),
+            Error::StaleRound(round) => write!(
+                f,
+                "Channel state for round {} is not newer than the stored state",
+                round
+            ),
+            Error::UnauthorizedUpdate(party) => write!(
+                f,
+                "Channel state signed by party {:?} increases their own balance",
+                party
+            ),
+            Error::WrongKey(party) => write!(f, "Key does not belong to party {:?}", party),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// One of the two parties to a [`PaymentChannel`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Party {
+    A,
+    B,
+}
+
+impl Party {
+    fn to_byte(self) -> u8 {
+        match self {
+            Party::A => 0,
+            Party::B => 1,
+        }
+    }
+}
+
+/// A signed state of a payment channel between two parties.
+#[derive(Clone, Copy)]
+pub struct PaymentChannel {
+    pub channel_id: [u8; 32],
+    /// The encoding of party A's [`channel_verification_key`] for this channel.
+    pub key_a: [u8; 32],
+    /// The encoding of party B's [`channel_verification_key`] for this channel.
+    pub key_b: [u8; 32],
+    /// The party that created and signed this state.
+    pub signer: Party,
+    pub balance_a: Amount,
+    pub balance_b: Amount,
+    /// The number of updates since the channel was opened.
+    pub round: u64,
+    /// The signature over this state by the party that created it.
+    pub last_update: [u8; 64],
+}
+
+impl fmt::Debug for PaymentChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PaymentChannel(channel_id = {}, signer = {:?}, balance_a = {:?}, balance_b = {:?}, round = {})",
+            hex::encode(&self.channel_id),
+            self.signer,
+            self.balance_a,
+            self.balance_b,
+            self.round
+        )
+    }
+}
+
+impl PartialEq for PaymentChannel {
+    fn eq(&self, other: &Self) -> bool {
+        self.channel_id == other.channel_id
+            && self.key_a == other.key_a
+            && self.key_b == other.key_b
+            && self.signer == other.signer
+            && self.balance_a == other.balance_a
+            && self.balance_b == other.balance_b
+            && self.round == other.round
+            && self.last_update[..] == other.last_update[..]
+    }
+}
+
+impl PaymentChannel {
+    /// Returns the message that is signed for this state.
+    fn state_message(&self) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(CHANNEL_STATE_TAG.len() + CHANNEL_STATE_SIZE - 64);
+        msg.extend_from_slice(CHANNEL_STATE_TAG);
+        msg.extend_from_slice(&self.channel_id);
+        msg.extend_from_slice(&self.key_a);
+        msg.extend_from_slice(&self.key_b);
+        msg.push(self.signer.to_byte());
+        msg.extend_from_slice(&self.balance_a.to_i64_le_bytes());
+        msg.extend_from_slice(&self.balance_b.to_i64_le_bytes());
+        msg.extend_from_slice(&self.round.to_le_bytes());
+        msg
+    }
+
+    /// Signs this state with the channel signing key derived from `extsk`, which must
+    /// belong to [`PaymentChannel::signer`].
+    fn sign<R: RngCore>(mut self, extsk: &ExtendedSpendingKey, rng: &mut R) -> Result<Self, Error> {
+        let sk = channel_signing_key(extsk, &self.channel_id);
+        let vk = PublicKey::from_private(&sk, FixedGenerators::SpendingKeyGenerator, &JUBJUB);
+        if encode_key(&vk) != *self.key(self.signer) {
+            return Err(Error::WrongKey(self.signer));
+        }
+
+        let sig = sk.sign(
+            &self.state_message(),
+            rng,
+            FixedGenerators::SpendingKeyGenerator,
+            &JUBJUB,
+        );
+        sig.write(&mut self.last_update[..])
+            .expect("Signatures are 64 bytes");
+        Ok(self)
+    }
+
+    /// Returns true if this state was signed with the key of [`PaymentChannel::signer`].
+    pub fn verify(&self) -> bool {
+        let signer = match PublicKey::<Bls12>::read(&self.key(self.signer)[..], &JUBJUB) {
+            Ok(signer) => signer,
+            Err(_) => return false,
+        };
+        match Signature::read(&self.last_update[..]) {
+            Ok(sig) => signer.verify(
+                &self.state_message(),
+                &sig,
+                FixedGenerators::SpendingKeyGenerator,
+                &JUBJUB,
+            ),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the encoded channel verification key of the given party.
+    pub fn key(&self, party: Party) -> &[u8; 32] {
+        match party {
+            Party::A => &self.key_a,
+            Party::B => &self.key_b,
+        }
+    }
+
+    /// Returns the balance of the given party.
+    pub fn balance(&self, party: Party) -> Amount {
+        match party {
+            Party::A => self.balance_a,
+            Party::B => self.balance_b,
+        }
+    }
+
+    /// Reads a channel state in the format written by [`PaymentChannel::write`].
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut channel_id = [0; 32];
+        reader.read_exact(&mut channel_id)?;
+        let mut read_key = || -> io::Result<[u8; 32]> {
+            let mut key = [0; 32];
+            reader.read_exact(&mut key)?;
+            PublicKey::<Bls12>::read(&key[..], &JUBJUB)?;
+            Ok(key)
+        };
+        let key_a = read_key()?;
+        let key_b = read_key()?;
+        let mut signer = [0; 1];
+        reader.read_exact(&mut signer)?;
+        let signer = match signer[0] {
+            0 => Party::A,
+            1 => Party::B,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid signer")),
+        };
+        let mut read_balance = || -> io::Result<Amount> {
+            let mut bytes = [0; 8];
+            reader.read_exact(&mut bytes)?;
+            Amount::from_nonnegative_i64_le_bytes(bytes)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid balance"))
+        };
+        let balance_a = read_balance()?;
+        let balance_b = read_balance()?;
+        let mut round = [0; 8];
+        reader.read_exact(&mut round)?;
+        let mut last_update = [0; 64];
+        reader.read_exact(&mut last_update)?;
+
+        Ok(PaymentChannel {
+            channel_id,
+            key_a,
+            key_b,
+            signer,
+            balance_a,
+            balance_b,
+            round: u64::from_le_bytes(round),
+            last_update,
+        })
+    }
+
+    /// Writes this channel state as [`CHANNEL_STATE_SIZE`] bytes.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.channel_id)?;
+        writer.write_all(&self.key_a)?;
+        writer.write_all(&self.key_b)?;
+        writer.write_all(&[self.signer.to_byte()])?;
+        writer.write_all(&self.balance_a.to_i64_le_bytes())?;
+        writer.write_all(&self.balance_b.to_i64_le_bytes())?;
+        writer.write_all(&self.round.to_le_bytes())?;
+        writer.write_all(&self.last_update)
+    }
+}
+
+/// Returns the randomizer that is applied to both parties' keys in the given channel.
+fn channel_randomizer(channel_id: &[u8; 32]) -> Fs {
+    let hash = Params::new()
+        .hash_length(64)
+        .personal(CHANNEL_KEY_PERSONALIZATION)
+        .hash(channel_id);
+    Fs::to_uniform(hash.as_bytes())
+}
+
+/// Returns the key that signs states of the given channel for the holder of `extsk`.
+fn channel_signing_key(extsk: &ExtendedSpendingKey, channel_id: &[u8; 32]) -> PrivateKey<Bls12> {
+    PrivateKey(extsk.expsk.ask).randomize(channel_randomizer(channel_id))
+}
+
+fn encode_key(key: &PublicKey<Bls12>) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    key.write(&mut bytes[..])
+        .expect("Jubjub points are 32 bytes");
+    bytes
+}
+
+/// Returns the key that verifies states of the given channel signed by the spending key
+/// for `extfvk`.
+///
+/// This is the spend validating key of `extfvk` randomized for the channel, so it
+/// differs between channels.
+pub fn channel_verification_key(
+    extfvk: &ExtendedFullViewingKey,
+    channel_id: &[u8; 32],
+) -> PublicKey<Bls12> {
+    PublicKey((&extfvk.fvk.vk.ak).into()).randomize(
+        channel_randomizer(channel_id),
+        FixedGenerators::SpendingKeyGenerator,
+        &JUBJUB,
+    )
+}
+
+/// Opens a channel with the given initial balances, signed by `extsk` as party A.
+///
+/// `counterparty` is party B's [`channel_verification_key`] for the channel. The
+/// `channel_id` should be unique to the pair of parties, for example a hash of the
+/// transaction that funds the channel.
+pub fn open_channel<R: RngCore>(
+    extsk: &ExtendedSpendingKey,
+    counterparty: &PublicKey<Bls12>,
+    channel_id: [u8; 32],
+    balance_a: Amount,
+    balance_b: Amount,
+    rng: &mut R,
+) -> Result<PaymentChannel, Error> {
+    if balance_a.is_negative() || balance_b.is_negative() {
+        return Err(Error::InvalidAmount);
+    }
+    balance_a
+        .checked_add(balance_b)
+        .ok_or(Error::InvalidAmount)?;
+
+    let key_a = PublicKey::from_private(
+        &channel_signing_key(extsk, &channel_id),
+        FixedGenerators::SpendingKeyGenerator,
+        &JUBJUB,
+    );
+    PaymentChannel {
+        channel_id,
+        key_a: encode_key(&key_a),
+        key_b: encode_key(counterparty),
+        signer: Party::A,
+        balance_a,
+        balance_b,
+        round: 0,
+        last_update: [0; 64],
+    }
+    .sign(extsk, rng)
+}
+
+/// Moves `amount` from `payer` to the other party, returning the next channel state
+/// signed by `extsk`, which must be the payer's spending key.
+pub fn update_channel<R: RngCore>(
+    channel: &PaymentChannel,
+    extsk: &ExtendedSpendingKey,
+    payer: Party,
+    amount: Amount,
+    rng: &mut R,
+) -> Result<PaymentChannel, Error> {
+    if amount.is_negative() {
+        return Err(Error::InvalidAmount);
+    }
+    let payer_balance = channel.balance(payer);
+    if i64::from(payer_balance) < i64::from(amount) {
+        return Err(Error::InsufficientBalance(payer_balance));
+    }
+    let round = channel.round.checked_add(1).ok_or(Error::RoundOverflow)?;
+
+    let mut next = PaymentChannel {
+        signer: payer,
+        round,
+        last_update: [0; 64],
+        ..*channel
+    };
+    let (from, to) = match payer {
+        Party::A => (&mut next.balance_a, &mut next.balance_b),
+        Party::B => (&mut next.balance_b, &mut next.balance_a),
+    };
+    *from = from.checked_sub(amount).ok_or(Error::InvalidAmount)?;
+    *to = to.checked_add(amount).ok_or(Error::InvalidAmount)?;
+
+    next.sign(extsk, rng)
+}
+
+/// Checks that `next` can replace `last`, the most recent state of the channel stored
+/// by this party.
+///
+/// `next` must be signed by the party whose balance it does not increase, which for a
+/// state created by [`update_channel`] is the payer, and must have a greater round than
+/// `last`. Each party must therefore store every state it accepts, so that it can
+/// reject older states and states that a party signed to pay itself.
+pub fn accept_update(last: &PaymentChannel, next: &PaymentChannel) -> Result<(), Error> {
+    if next.channel_id != last.channel_id {
+        return Err(Error::ChannelMismatch);
+    }
+    if next.key_a != last.key_a {
+        return Err(Error::WrongKey(Party::A));
+    }
+    if next.key_b != last.key_b {
+        return Err(Error::WrongKey(Party::B));
+    }
+    if !next.verify() {
+        return Err(Error::InvalidSignature);
+    }
+    if next.round <= last.round {
+        return Err(Error::StaleRound(next.round));
+    }
+
+    // The total value of the channel never changes.
+    let total =
+        |state: &PaymentChannel| i64::from(state.balance_a).checked_add(i64::from(state.balance_b));
+    if total(next).is_none() || total(next) != total(last) {
+        return Err(Error::InvalidAmount);
+    }
+    if i64::from(next.balance(next.signer)) > i64::from(last.balance(next.signer)) {
+        return Err(Error::UnauthorizedUpdate(next.signer));
+    }
+    Ok(())
+}
+
+/// Closes the channel with the state `closing`, returning the final balances of parties
+/// A and B.
+///
+/// `last_stored` is the most recent state of the channel stored by this party, between
+/// the parties with the channel verification keys `key_a` and `key_b`. The channel can
+/// be closed with `last_stored` itself, or with any state that [`accept_update`] would
+/// accept in its place.
+pub fn close_channel(
+    last_stored: &PaymentChannel,
+    closing: &PaymentChannel,
+    key_a: &PublicKey<Bls12>,
+    key_b: &PublicKey<Bls12>,
+) -> Result<(Amount, Amount), Error> {
+    if encode_key(key_a) != last_stored.key_a {
+        return Err(Error::WrongKey(Party::A));
+    }
+    if encode_key(key_b) != last_stored.key_b {
+        return Err(Error::WrongKey(Party::B));
+    }
+    if !last_stored.verify() {
+        return Err(Error::InvalidSignature);
+    }
+    if closing != last_stored {
+        accept_update(last_stored, closing)?;
+    }
+    Ok((closing.balance_a, closing.balance_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+    use zcash_primitives::{
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{
+        accept_update, channel_verification_key, close_channel, encode_key, open_channel,
+        update_channel, Error, Party, PaymentChannel, CHANNEL_STATE_SIZE,
+    };
+
+    #[test]
+    fn channel_updates_are_signed_by_payer() {
+        let mut rng = XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x3d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+        let channel_id = [7; 32];
+        let extsk_a = ExtendedSpendingKey::master(&[0]);
+        let extsk_b = ExtendedSpendingKey::master(&[1]);
+        let vk_a = channel_verification_key(&ExtendedFullViewingKey::from(&extsk_a), &channel_id);
+        let vk_b = channel_verification_key(&ExtendedFullViewingKey::from(&extsk_b), &channel_id);
+
+        let opened = open_channel(
+            &extsk_a,
+            &vk_b,
+            channel_id,
+            Amount::from_u64(10).unwrap(),
+            Amount::zero(),
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(opened.signer, Party::A);
+        assert!(opened.verify());
+
+        // A pays B, then B pays some of it back
+        let paid = update_channel(
+            &opened,
+            &extsk_a,
+            Party::A,
+            Amount::from_u64(6).unwrap(),
+            &mut rng,
+        )
+        .unwrap();
+        let refunded = update_channel(
+            &paid,
+            &extsk_b,
+            Party::B,
+            Amount::from_u64(2).unwrap(),
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(refunded.round, 2);
+        assert_eq!(refunded.signer, Party::B);
+        assert!(refunded.verify());
+        assert_eq!(
+            update_channel(
+                &refunded,
+                &extsk_b,
+                Party::B,
+                Amount::from_u64(5).unwrap(),
+                &mut rng
+            ),
+            Err(Error::InsufficientBalance(Amount::from_u64(4).unwrap()))
+        );
+
+        // Only the payer can sign a payment
+        assert_eq!(
+            update_channel(
+                &refunded,
+                &extsk_b,
+                Party::A,
+                Amount::from_u64(1).unwrap(),
+                &mut rng
+            ),
+            Err(Error::WrongKey(Party::A))
+        );
+
+        // The state round-trips through its encoding
+        let mut encoded = vec![];
+        refunded.write(&mut encoded).unwrap();
+        assert_eq!(encoded.len(), CHANNEL_STATE_SIZE);
+        let decoded = PaymentChannel::read(&encoded[..]).unwrap();
+        assert_eq!(decoded, refunded);
+
+        // Each party accepts the other's payments
+        assert_eq!(accept_update(&opened, &paid), Ok(()));
+        assert_eq!(accept_update(&paid, &decoded), Ok(()));
+
+        assert_eq!(
+            close_channel(&decoded, &decoded, &vk_b, &vk_a),
+            Err(Error::WrongKey(Party::A))
+        );
+        assert_eq!(
+            close_channel(&decoded, &decoded, &vk_a, &vk_b),
+            Ok((Amount::from_u64(6).unwrap(), Amount::from_u64(4).unwrap()))
+        );
+
+        // The channel can also be closed with a newer state than the one stored
+        assert_eq!(
+            close_channel(&paid, &decoded, &vk_a, &vk_b),
+            Ok((Amount::from_u64(6).unwrap(), Amount::from_u64(4).unwrap()))
+        );
+    }
+
+    #[test]
+    fn channel_states_bind_both_parties() {
+        let mut rng = XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x3d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+        let channel_id = [7; 32];
+        let extsk_a = ExtendedSpendingKey::master(&[0]);
+        let extsk_b = ExtendedSpendingKey::master(&[1]);
+        let extsk_c = ExtendedSpendingKey::master(&[2]);
+        let extfvk_a = ExtendedFullViewingKey::from(&extsk_a);
+        let vk_a = channel_verification_key(&extfvk_a, &channel_id);
+        let vk_b = channel_verification_key(&ExtendedFullViewingKey::from(&extsk_b), &channel_id);
+        let vk_c = channel_verification_key(&ExtendedFullViewingKey::from(&extsk_c), &channel_id);
+
+        let opened = open_channel(
+            &extsk_a,
+            &vk_b,
+            channel_id,
+            Amount::from_u64(10).unwrap(),
+            Amount::zero(),
+            &mut rng,
+        )
+        .unwrap();
+
+        // Keys are randomized for each channel, so the spend validating key is not used
+        let mut ak = [0; 32];
+        extfvk_a.fvk.vk.ak.write(&mut ak[..]).unwrap();
+        assert_ne!(opened.key_a, ak);
+        assert_ne!(
+            encode_key(&channel_verification_key(&extfvk_a, &[8; 32])),
+            encode_key(&vk_a)
+        );
+
+        // Replacing the counterparty invalidates the signature
+        let mut replaced = opened;
+        vk_c.write(&mut replaced.key_b[..]).unwrap();
+        assert!(!replaced.verify());
+        assert_eq!(
+            close_channel(&replaced, &replaced, &vk_a, &vk_c),
+            Err(Error::InvalidSignature)
+        );
+        assert_eq!(
+            close_channel(&opened, &replaced, &vk_a, &vk_b),
+            Err(Error::WrongKey(Party::B))
+        );
+
+        // So does claiming that the other party signed the state
+        let mut reattributed = opened;
+        reattributed.signer = Party::B;
+        assert!(!reattributed.verify());
+    }
+
+    #[test]
+    fn close_channel_rejects_self_payments_and_stale_states() {
+        let mut rng = XorShiftRng::from_seed([
+            0x59, 0x62, 0xbe, 0x3d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06,
+            0xbc, 0xe5,
+        ]);
+        let channel_id = [7; 32];
+        let extsk_a = ExtendedSpendingKey::master(&[0]);
+        let extsk_b = ExtendedSpendingKey::master(&[1]);
+        let vk_a = channel_verification_key(&ExtendedFullViewingKey::from(&extsk_a), &channel_id);
+        let vk_b = channel_verification_key(&ExtendedFullViewingKey::from(&extsk_b), &channel_id);
+
+        let opened = open_channel(
+            &extsk_a,
+            &vk_b,
+            channel_id,
+            Amount::from_u64(10).unwrap(),
+            Amount::zero(),
+            &mut rng,
+        )
+        .unwrap();
+        let paid = update_channel(
+            &opened,
+            &extsk_a,
+            Party::A,
+            Amount::from_u64(6).unwrap(),
+            &mut rng,
+        )
+        .unwrap();
+
+        // B signs a newer state that gives B the whole channel
+        let forged = PaymentChannel {
+            signer: Party::B,
+            balance_a: Amount::zero(),
+            balance_b: Amount::from_u64(10).unwrap(),
+            round: paid.round + 1,
+            last_update: [0; 64],
+            ..paid
+        }
+        .sign(&extsk_b, &mut rng)
+        .unwrap();
+        assert!(forged.verify());
+        assert_eq!(
+            accept_update(&paid, &forged),
+            Err(Error::UnauthorizedUpdate(Party::B))
+        );
+        assert_eq!(
+            close_channel(&paid, &forged, &vk_a, &vk_b),
+            Err(Error::UnauthorizedUpdate(Party::B))
+        );
+
+        // A replays the opening state, from before A paid B
+        assert_eq!(accept_update(&paid, &opened), Err(Error::StaleRound(0)));
+        assert_eq!(
+            close_channel(&paid, &opened, &vk_a, &vk_b),
+            Err(Error::StaleRound(0))
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Payment disclosures, which prove to a third party that a payment was made.
+//!
+//! A payment disclosure reveals the [`OutgoingViewingKey`] used to send a Sapling
+//! output, allowing anyone holding the disclosure and the transaction to recover the
+//! recipient, value, and memo of that output. Note that the revealed key can also be used
+//! to recover every other output that was sent with it.
+
+use ff::{PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::Bls12;
+use std::error;
+use std::fmt;
+use std::io;
+use zcash_primitives::{
+    keys::OutgoingViewingKey,
+    note_encryption::{try_sapling_output_recovery, Memo},
+    primitives::PaymentAddress,
+    transaction::{
+        components::{OutputDescription, Zatoshi},
+        Transaction, TxId,
+    },
+    zip32::ExtendedFullViewingKey,
+    JUBJUB,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The raw transaction could not be parsed.
+    InvalidTransaction(io::Error),
+    /// The disclosure does not match the corresponding output of the transaction.
+    Mismatch,
+    /// The transaction does not have an output with the given index.
+    NoSuchOutput(usize),
+    /// The output could not be recovered with the outgoing viewing key.
+    RecoveryFailed,
+    /// The raw transaction does not have the expected transaction ID.
+    TxIdMismatch(TxId),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidTransaction(e) => write!(f, "Invalid transaction: {}", e),
+            Error::Mismatch => write!(f, "Disclosure does not match the transaction output"),
+            Error::NoSuchOutput(index) => write!(f, "Transaction has no output {}", index),
+            Error::RecoveryFailed => write!(f, "Failed to recover the output with the OVK"),
+            Error::TxIdMismatch(txid) => write!(f, "Transaction has unexpected txid {}", txid),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::InvalidTransaction(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A proof that a specific Sapling output was sent with a particular
+/// [`OutgoingViewingKey`].
+#[derive(Clone)]
+pub struct PaymentDisclosure {
+    pub txid: TxId,
+    pub output_index: usize,
+    pub ovk: [u8; 32],
+    pub cv: [u8; 32],
+    pub cm: [u8; 32],
+    pub epk: [u8; 32],
+    pub c_out: [u8; 80],
+}
+
+/// The details of a payment, recovered from a [`PaymentDisclosure`].
+pub struct DisclosedPayment {
+    pub to: PaymentAddress<Bls12>,
+    pub value: Zatoshi,
+    pub memo: Memo,
+}
+
+/// Parses `raw_tx`, and checks that it has the given ID and an output at `output_index`.
+fn read_transaction(raw_tx: &[u8], txid: TxId, output_index: usize) -> Result<Transaction, Error> {
+    let tx = Transaction::read(raw_tx).map_err(Error::InvalidTransaction)?;
+    if tx.txid() != txid {
+        return Err(Error::TxIdMismatch(tx.txid()));
+    }
+    if output_index >= tx.shielded_outputs.len() {
+        return Err(Error::NoSuchOutput(output_index));
+    }
+
+    Ok(tx)
+}
+
+/// Recovers the payment made by `output` with the given [`OutgoingViewingKey`].
+fn recover_payment(
+    ovk: &OutgoingViewingKey,
+    output: &OutputDescription,
+) -> Result<DisclosedPayment, Error> {
+    let epk = output
+        .ephemeral_key
+        .as_prime_order(&JUBJUB)
+        .ok_or(Error::RecoveryFailed)?;
+
+    let (note, to, memo) = try_sapling_output_recovery(
+        ovk,
+        &output.cv,
+        &output.cmu,
+        &epk,
+        &output.enc_ciphertext,
+        &output.out_ciphertext,
+    )
+    .ok_or(Error::RecoveryFailed)?;
+
+    Ok(DisclosedPayment {
+        to,
+        value: Zatoshi::from(note.value),
+        memo,
+    })
+}
+
+/// Creates a [`PaymentDisclosure`] for the Sapling output at `output_index` in the given
+/// transaction, which must have been sent by `extfvk`.
+///
+/// Returns an error if `raw_tx` does not have the ID `txid`, or if the output cannot be
+/// recovered with the outgoing viewing key of `extfvk`.
+pub fn prove_outgoing_payment(
+    extfvk: &ExtendedFullViewingKey,
+    txid: TxId,
+    output_index: usize,
+    raw_tx: &[u8],
+) -> Result<PaymentDisclosure, Error> {
+    let tx = read_transaction(raw_tx, txid, output_index)?;
+    let output = &tx.shielded_outputs[output_index];
+    let ovk = extfvk.fvk.ovk;
+
+    // Only disclose outputs that were actually sent with this key.
+    recover_payment(&ovk, output)?;
+
+    let mut cv = [0; 32];
+    output.cv.write(&mut cv[..]).unwrap();
+    let mut cm = [0; 32];
+    output.cmu.into_repr().write_le(&mut cm[..]).unwrap();
+    let mut epk = [0; 32];
+    output.ephemeral_key.write(&mut epk[..]).unwrap();
+
+    Ok(PaymentDisclosure {
+        txid,
+        output_index,
+        ovk: ovk.0,
+        cv,
+        cm,
+        epk,
+        c_out: output.out_ciphertext,
+    })
+}
+
+/// Verifies a [`PaymentDisclosure`] against the transaction it refers to, and returns the
+/// details of the disclosed payment.
+pub fn verify_payment_disclosure(
+    disclosure: &PaymentDisclosure,
+    raw_tx: &[u8],
+) -> Result<DisclosedPayment, Error> {
+    let tx = read_transaction(raw_tx, disclosure.txid, disclosure.output_index)?;
+    let output = &tx.shielded_outputs[disclosure.output_index];
+
+    let mut cv = [0; 32];
+    output.cv.write(&mut cv[..]).unwrap();
+    let mut cm = [0; 32];
+    output.cmu.into_repr().write_le(&mut cm[..]).unwrap();
+    let mut epk = [0; 32];
+    output.ephemeral_key.write(&mut epk[..]).unwrap();
+    if cv != disclosure.cv
+        || cm != disclosure.cm
+        || epk != disclosure.epk
+        || output.out_ciphertext[..] != disclosure.c_out[..]
+    {
+        return Err(Error::Mismatch);
+    }
+
+    recover_payment(&OutgoingViewingKey(disclosure.ovk), output)
+}
+
+#[cfg(test)]
+mod tests {
+    use ff::Field;
+    use pairing::bls12_381::Bls12;
+    use rand_core::RngCore;
+    use rand_os::OsRng;
+    use zcash_primitives::{
+        jubjub::fs::Fs,
+        note_encryption::{Memo, SaplingNoteEncryption},
+        primitives::ValueCommitment,
+        redjubjub::Signature,
+        transaction::{
+            components::{OutputDescription, Zatoshi, GROTH_PROOF_SIZE},
+            TransactionData,
+        },
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+        JUBJUB,
+    };
+
+    use super::{prove_outgoing_payment, verify_payment_disclosure, Error};
+
+    #[test]
+    fn disclose_outgoing_payment() {
+        let mut rng = OsRng;
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+
+        // Create a transaction with a single output sent by extfvk
+        let value = rng.next_u32() as u64;
+        let note = to
+            .create_note(value, Fs::random(&mut rng), &JUBJUB)
+            .unwrap();
+        let cv = ValueCommitment::<Bls12> {
+            value,
+            randomness: Fs::random(&mut rng),
+        }
+        .cm(&JUBJUB)
+        .into();
+        let cmu = note.cm(&JUBJUB);
+        let memo = Memo::from_str("Thanks for the coffee").unwrap();
+        let encryptor =
+            SaplingNoteEncryption::new(extfvk.fvk.ovk, note, to.clone(), memo.clone(), &mut rng);
+
+        let mut mtx = TransactionData::new();
+        mtx.shielded_outputs.push(OutputDescription {
+            cv,
+            cmu,
+            ephemeral_key: encryptor.epk().clone().into(),
+            enc_ciphertext: encryptor.encrypt_note_plaintext(),
+            out_ciphertext: encryptor.encrypt_outgoing_plaintext(&cv, &cmu),
+            zkproof: [0; GROTH_PROOF_SIZE],
+        });
+        mtx.binding_sig = Some(Signature::read(&[0u8; 64][..]).unwrap());
+        let tx = mtx.freeze().unwrap();
+        let mut raw_tx = vec![];
+        tx.write(&mut raw_tx).unwrap();
+
+        // The sender can disclose the payment
+        let disclosure = prove_outgoing_payment(&extfvk, tx.txid(), 0, &raw_tx).unwrap();
+        let payment = verify_payment_disclosure(&disclosure, &raw_tx).unwrap();
+        assert_eq!(payment.to, to);
+        assert_eq!(payment.value, Zatoshi::from(value));
+        assert_eq!(payment.memo, memo);
+
+        // Other keys cannot disclose the payment
+        let extfvk2 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1]));
+        match prove_outgoing_payment(&extfvk2, tx.txid(), 0, &raw_tx) {
+            Err(Error::RecoveryFailed) => (),
+            _ => panic!("Should have failed"),
+        }
+
+        // Outputs must exist
+        match prove_outgoing_payment(&extfvk, tx.txid(), 1, &raw_tx) {
+            Err(Error::NoSuchOutput(1)) => (),
+            _ => panic!("Should have failed"),
+        }
+
+        // Tampered disclosures are rejected
+        let mut tampered = disclosure.clone();
+        tampered.c_out[0] ^= 1;
+        match verify_payment_disclosure(&tampered, &raw_tx) {
+            Err(Error::Mismatch) => (),
+            _ => panic!("Should have failed"),
+        }
+    }
+}
@@ -1,6 +1,11 @@
 //! Helper functions for managing light client key material.
 
-use zcash_primitives::zip32::{ChildIndex, ExtendedSpendingKey};
+use pairing::bls12_381::Bls12;
+use zcash_primitives::{
+    primitives::PaymentAddress,
+    zip32::{DiversifierIndex, ExtendedFullViewingKey, ExtendedSpendingKey},
+    JUBJUB,
+};
 
 /// Derives the ZIP 32 [`ExtendedSpendingKey`] for a given coin type and account from the
 /// given seed.
@@ -13,12 +18,140 @@ use zcash_primitives::zip32::{ChildIndex, ExtendedSpendingKey};
 /// let extsk = spending_key(&[0; 32][..], COIN_TYPE, 0);
 /// ```
 pub fn spending_key(seed: &[u8], coin_type: u32, account: u32) -> ExtendedSpendingKey {
-    ExtendedSpendingKey::from_path(
-        &ExtendedSpendingKey::master(&seed),
-        &[
-            ChildIndex::Hardened(32),
-            ChildIndex::Hardened(coin_type),
-            ChildIndex::Hardened(account),
-        ],
-    )
+    ExtendedSpendingKey::from_zip32_path(seed, coin_type, account)
+}
+
+/// Derives the Sapling payment address with the given diversifier index, for a given
+/// coin type and account, from the given seed.
+///
+/// Only some diversifier indices produce valid diversifiers. Returns `None` if
+/// `diversifier_index` does not, or if it is not a valid 88-bit ZIP 32 diversifier index.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{constants::testnet::COIN_TYPE, keys::derive_sapling_address};
+///
+/// let addr = derive_sapling_address(&[0; 32][..], COIN_TYPE, 0, 3);
+/// ```
+pub fn derive_sapling_address(
+    seed: &[u8],
+    coin_type: u32,
+    account: u32,
+    diversifier_index: u128,
+) -> Option<PaymentAddress<Bls12>> {
+    if diversifier_index >> 88 != 0 {
+        return None;
+    }
+    let mut j = DiversifierIndex::new();
+    j.0.copy_from_slice(&diversifier_index.to_le_bytes()[..11]);
+
+    let extfvk = ExtendedFullViewingKey::from(&spending_key(seed, coin_type, account));
+    match extfvk.address(j) {
+        // address() searches forward for a valid diversifier, so check that the
+        // requested index was used.
+        Ok((found, addr)) if found == j => Some(addr),
+        _ => None,
+    }
+}
+
+/// Returns `true` if the given payment address was derived from the given
+/// [`ExtendedFullViewingKey`], with any diversifier.
+///
+/// The address's `pk_d` is compared to the one derived from the key's incoming viewing
+/// key and the address's diversifier. This can be used to check that an address belongs
+/// to an account before scanning for it.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{
+///     constants::testnet::COIN_TYPE,
+///     keys::{spending_key, verify_address_ownership},
+/// };
+/// use zcash_primitives::zip32::ExtendedFullViewingKey;
+///
+/// let extfvk = ExtendedFullViewingKey::from(&spending_key(&[0; 32][..], COIN_TYPE, 0));
+/// let (_, addr) = extfvk.default_address().unwrap();
+/// assert!(verify_address_ownership(&extfvk, &addr));
+/// ```
+pub fn verify_address_ownership(
+    extfvk: &ExtendedFullViewingKey,
+    addr: &PaymentAddress<Bls12>,
+) -> bool {
+    extfvk
+        .fvk
+        .vk
+        .into_payment_address(addr.diversifier, &JUBJUB)
+        .map_or(false, |expected| expected.pk_d == addr.pk_d)
+}
+
+#[cfg(test)]
+mod tests {
+    use zcash_primitives::{
+        primitives::PaymentAddress,
+        zip32::{DiversifierIndex, ExtendedFullViewingKey},
+    };
+
+    use super::{derive_sapling_address, spending_key, verify_address_ownership};
+    use crate::constants::testnet::COIN_TYPE;
+
+    fn index_to_u128(j: DiversifierIndex) -> u128 {
+        let mut bytes = [0; 16];
+        bytes[..11].copy_from_slice(&j.0);
+        u128::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn derive_sapling_address_matches_zip32() {
+        let seed = [0; 32];
+        let extfvk = ExtendedFullViewingKey::from(&spending_key(&seed, COIN_TYPE, 1));
+
+        // Check the first few valid diversifier indices
+        let mut j = DiversifierIndex::new();
+        for _ in 0..5 {
+            let (found, addr) = extfvk.address(j).unwrap();
+
+            // Indices between j and the next valid one have no address
+            for invalid in index_to_u128(j)..index_to_u128(found) {
+                assert_eq!(derive_sapling_address(&seed, COIN_TYPE, 1, invalid), None);
+            }
+            assert_eq!(
+                derive_sapling_address(&seed, COIN_TYPE, 1, index_to_u128(found)),
+                Some(addr)
+            );
+
+            j = found;
+            j.increment().unwrap();
+        }
+
+        // Diversifier indices are 88 bits
+        assert_eq!(derive_sapling_address(&seed, COIN_TYPE, 1, 1 << 88), None);
+    }
+
+    #[test]
+    fn verify_address_ownership_checks_pk_d() {
+        let seed = [0; 32];
+        let extfvk = ExtendedFullViewingKey::from(&spending_key(&seed, COIN_TYPE, 0));
+        let other = ExtendedFullViewingKey::from(&spending_key(&seed, COIN_TYPE, 1));
+
+        // Every diversified address of the account is owned by it
+        let mut j = DiversifierIndex::new();
+        for _ in 0..3 {
+            let (found, addr) = extfvk.address(j).unwrap();
+            assert!(verify_address_ownership(&extfvk, &addr));
+            assert!(!verify_address_ownership(&other, &addr));
+            j = found;
+            j.increment().unwrap();
+        }
+
+        // The other account's pk_d with our diversifier is not ours
+        let (_, addr) = extfvk.default_address().unwrap();
+        let (_, other_addr) = other.default_address().unwrap();
+        let forged = PaymentAddress {
+            pk_d: other_addr.pk_d,
+            diversifier: addr.diversifier,
+        };
+        assert!(!verify_address_ownership(&extfvk, &forged));
+    }
 }
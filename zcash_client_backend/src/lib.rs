@@ -3,9 +3,18 @@
 //! `zcash_client_backend` contains Rust structs and traits for creating shielded Zcash
 //! light clients.
 
+pub mod channels;
 pub mod constants;
+pub mod disclosure;
 pub mod encoding;
 pub mod keys;
 pub mod proto;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod storage;
+#[cfg(any(test, feature = "test-dependencies"))]
+pub mod testing;
+pub mod transaction;
+pub mod validation;
 pub mod wallet;
 pub mod welding_rig;
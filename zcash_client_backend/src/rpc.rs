@@ -0,0 +1,421 @@
+//! A client for the JSON-RPC interface of a full `zcashd` node.
+//!
+//! This can be used in place of a `lightwalletd` server. Full blocks are fetched from the
+//! node and converted into [`CompactBlock`]s on the client side.
+
+use ff::{PrimeField, PrimeFieldRepr};
+use serde_json::{json, Value};
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use zcash_primitives::{
+    block::BlockHeader,
+    serialize::Vector,
+    transaction::{Transaction, TxId},
+};
+
+use crate::{
+    proto::compact_formats::{CompactBlock, CompactOutput, CompactSpend, CompactTx},
+    validation::COMPACT_NOTE_SIZE,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The node is running on a different network than the one expected.
+    ChainMismatch { expected: String, actual: String },
+    /// A TCP connection to the node could not be established.
+    Connect(io::Error),
+    /// The node's host name could not be resolved.
+    Dns(io::Error),
+    /// The node returned data that could not be decoded.
+    Hex(hex::FromHexError),
+    /// The HTTP request to the node failed.
+    Http(reqwest::Error),
+    /// The node returned a block that could not be parsed.
+    InvalidBlock(io::Error),
+    /// The node returned a response in an unexpected format.
+    InvalidResponse(&'static str),
+    /// The URL of the node could not be parsed.
+    InvalidUrl(reqwest::UrlError),
+    /// The node returned an error for the RPC call.
+    Rpc(Value),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ChainMismatch { expected, actual } => write!(
+                f,
+                "Node is on chain {}, but {} was expected",
+                actual, expected
+            ),
+            Error::Connect(e) => write!(f, "Could not connect to node: {}", e),
+            Error::Dns(e) => write!(f, "Could not resolve node address: {}", e),
+            Error::Hex(e) => write!(f, "Invalid hex in response: {}", e),
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::InvalidBlock(e) => write!(f, "Invalid block: {}", e),
+            Error::InvalidResponse(reason) => write!(f, "Invalid response: {}", reason),
+            Error::InvalidUrl(e) => write!(f, "Invalid node URL: {}", e),
+            Error::Rpc(e) => write!(f, "RPC error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Connect(e) => Some(e),
+            Error::Dns(e) => Some(e),
+            Error::Hex(e) => Some(e),
+            Error::Http(e) => Some(e),
+            Error::InvalidBlock(e) => Some(e),
+            Error::InvalidUrl(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<hex::FromHexError> for Error {
+    fn from(e: hex::FromHexError) -> Self {
+        Error::Hex(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+/// The result of a successful connectivity check against a node.
+#[derive(Debug)]
+pub struct ConnectivityReport {
+    /// The round-trip time of the `getblockchaininfo` call, in milliseconds.
+    pub latency_ms: u64,
+    /// The height of the node's best block.
+    pub server_height: u32,
+    /// The network the node is running on, e.g. `"main"` or `"test"`.
+    pub chain_name: String,
+}
+
+/// A client for a `zcashd` node's JSON-RPC interface.
+///
+/// HTTP connections are reused across calls.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::rpc::ZcashdClient;
+///
+/// let client = ZcashdClient::new("http://127.0.0.1:8232").with_basic_auth("user", "pass");
+/// let block = client.get_block(500_000);
+/// ```
+#[derive(Clone)]
+pub struct ZcashdClient {
+    client: reqwest::Client,
+    url: String,
+    auth: Option<(String, String)>,
+}
+
+impl ZcashdClient {
+    /// Creates a client for the node listening at the given URL.
+    pub fn new(url: &str) -> Self {
+        ZcashdClient {
+            client: reqwest::Client::new(),
+            url: url.to_owned(),
+            auth: None,
+        }
+    }
+
+    /// Configures the client to authenticate to the node with HTTP Basic authentication.
+    pub fn with_basic_auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some((user.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Calls an RPC method on the node, and returns its result.
+    fn call(&self, method: &str, params: Value) -> Result<Value, Error> {
+        self.call_with_timeout(method, params, None)
+    }
+
+    /// Calls an RPC method on the node, and returns its result.
+    ///
+    /// If `timeout` is set, it overrides the client's default timeout for the whole
+    /// request, including reading the response.
+    fn call_with_timeout(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Option<Duration>,
+    ) -> Result<Value, Error> {
+        let mut request = self.client.post(&self.url).json(&json!({
+            "jsonrpc": "1.0",
+            "id": "zcash_client_backend",
+            "method": method,
+            "params": params,
+        }));
+        if let Some((user, password)) = &self.auth {
+            request = request.basic_auth(user, Some(password));
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+
+        let mut response: Value = request.send()?.json()?;
+        match response.get("error") {
+            Some(Value::Null) | None => (),
+            Some(e) => return Err(Error::Rpc(e.clone())),
+        }
+        response
+            .get_mut("result")
+            .map(Value::take)
+            .ok_or(Error::InvalidResponse("Missing result"))
+    }
+
+    /// Checks that the node is reachable and running on the `expected_chain` network
+    /// (`"main"`, `"test"` or `"regtest"`), to help diagnose sync failures.
+    ///
+    /// The host name is resolved and a TCP connection established within `timeout`
+    /// before `getblockchaininfo` is called, so that DNS and connection failures are
+    /// reported separately from HTTP (including TLS) and RPC errors. The
+    /// `getblockchaininfo` call must also complete within `timeout`, so a node that
+    /// accepts connections but never responds is reported as an HTTP error.
+    pub fn check_connectivity(
+        &self,
+        expected_chain: &str,
+        timeout: Duration,
+    ) -> Result<ConnectivityReport, Error> {
+        let url = reqwest::Url::parse(&self.url).map_err(Error::InvalidUrl)?;
+        let host = url
+            .host_str()
+            .ok_or(Error::InvalidUrl(reqwest::UrlError::EmptyHost))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or(Error::InvalidUrl(reqwest::UrlError::InvalidPort))?;
+
+        let addrs: Vec<_> = (host, port)
+            .to_socket_addrs()
+            .map_err(Error::Dns)?
+            .collect();
+        let mut connected = Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Host name resolved to no addresses",
+        ));
+        for addr in &addrs {
+            connected = TcpStream::connect_timeout(addr, timeout);
+            if connected.is_ok() {
+                break;
+            }
+        }
+        connected.map_err(Error::Connect)?;
+
+        let start = Instant::now();
+        let info = self.call_with_timeout("getblockchaininfo", json!([]), Some(timeout))?;
+        let elapsed = start.elapsed();
+
+        let chain_name = info
+            .get("chain")
+            .and_then(Value::as_str)
+            .ok_or(Error::InvalidResponse("getblockchaininfo is missing chain"))?
+            .to_owned();
+        let server_height =
+            info.get("blocks")
+                .and_then(Value::as_u64)
+                .ok_or(Error::InvalidResponse(
+                    "getblockchaininfo is missing blocks",
+                ))? as u32;
+
+        if chain_name != expected_chain {
+            return Err(Error::ChainMismatch {
+                expected: expected_chain.to_owned(),
+                actual: chain_name,
+            });
+        }
+
+        Ok(ConnectivityReport {
+            latency_ms: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+            server_height,
+            chain_name,
+        })
+    }
+
+    /// Returns the height of the node's best block.
+    pub fn get_block_count(&self) -> Result<u32, Error> {
+        let count = self.call("getblockcount", json!([]))?;
+        count
+            .as_u64()
+            .map(|count| count as u32)
+            .ok_or(Error::InvalidResponse(
+                "getblockcount did not return a number",
+            ))
+    }
+
+    /// Fetches the block at the given height, and converts it into a [`CompactBlock`].
+    pub fn get_block(&self, height: u32) -> Result<CompactBlock, Error> {
+        let raw = self.call("getblock", json!([height.to_string(), 0]))?;
+        let raw = raw
+            .as_str()
+            .ok_or(Error::InvalidResponse("getblock did not return a string"))?;
+        compact_block_from_raw(height, &hex::decode(raw)?)
+    }
+
+    /// Broadcasts the given hex-encoded transaction to the network.
+    ///
+    /// Returns the ID of the transaction.
+    pub fn send_raw_transaction(&self, hex: &str) -> Result<TxId, Error> {
+        let txid = self.call("sendrawtransaction", json!([hex]))?;
+        let txid = txid.as_str().ok_or(Error::InvalidResponse(
+            "sendrawtransaction did not return a string",
+        ))?;
+
+        // Transaction IDs are displayed in reverse byte order.
+        let mut txid = hex::decode(txid)?;
+        if txid.len() != 32 {
+            return Err(Error::InvalidResponse("Transaction ID is not 32 bytes"));
+        }
+        txid.reverse();
+        let mut ret = [0; 32];
+        ret.copy_from_slice(&txid);
+        Ok(TxId(ret))
+    }
+}
+
+/// Converts a serialized full block at the given height into a [`CompactBlock`].
+///
+/// Transaction fees cannot be determined without the transactions' inputs, and are left
+/// unset.
+pub fn compact_block_from_raw(height: u32, mut raw: &[u8]) -> Result<CompactBlock, Error> {
+    let header = BlockHeader::read(&mut raw).map_err(Error::InvalidBlock)?;
+    let txs = Vector::read(&mut raw, |r| Transaction::read(r)).map_err(Error::InvalidBlock)?;
+    if !raw.is_empty() {
+        return Err(Error::InvalidBlock(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Trailing data after block",
+        )));
+    }
+
+    let mut cb = CompactBlock::new();
+    cb.set_height(u64::from(height));
+    cb.set_hash(header.hash().0.to_vec());
+    cb.set_prevHash(header.prev_block.0.to_vec());
+    cb.set_time(header.time);
+    let mut encoded_header = vec![];
+    header
+        .write(&mut encoded_header)
+        .map_err(Error::InvalidBlock)?;
+    cb.set_header(encoded_header);
+
+    for (index, tx) in txs.iter().enumerate() {
+        if tx.shielded_spends.is_empty() && tx.shielded_outputs.is_empty() {
+            continue;
+        }
+
+        let mut ctx = CompactTx::new();
+        ctx.set_index(index as u64);
+        ctx.set_hash(tx.txid().0.to_vec());
+        for spend in &tx.shielded_spends {
+            let mut cspend = CompactSpend::new();
+            cspend.set_nf(spend.nullifier.to_vec());
+            ctx.spends.push(cspend);
+        }
+        for output in &tx.shielded_outputs {
+            let mut cmu = vec![];
+            output
+                .cmu
+                .into_repr()
+                .write_le(&mut cmu)
+                .map_err(Error::InvalidBlock)?;
+            let mut epk = vec![];
+            output
+                .ephemeral_key
+                .write(&mut epk)
+                .map_err(Error::InvalidBlock)?;
+
+            let mut cout = CompactOutput::new();
+            cout.set_cmu(cmu);
+            cout.set_epk(epk);
+            cout.set_ciphertext(output.enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec());
+            ctx.outputs.push(cout);
+        }
+        cb.vtx.push(ctx);
+    }
+
+    Ok(cb)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use zcash_primitives::block::{BlockHash, BlockHeaderData};
+
+    use super::{compact_block_from_raw, Error, ZcashdClient};
+
+    #[test]
+    fn check_connectivity_failures() {
+        let timeout = Duration::from_secs(1);
+
+        match ZcashdClient::new("not a url").check_connectivity("main", timeout) {
+            Err(Error::InvalidUrl(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // Nothing listens on a port that was bound and then released
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}", port);
+        match ZcashdClient::new(&url).check_connectivity("main", timeout) {
+            Err(Error::Connect(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn check_connectivity_times_out_on_unresponsive_node() {
+        // Connections are queued by the OS, but the listener never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        match ZcashdClient::new(&url).check_connectivity("main", Duration::from_millis(200)) {
+            Err(Error::Http(ref e)) if e.is_timeout() => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn compact_block_from_raw_header() {
+        let header = BlockHeaderData {
+            version: 4,
+            prev_block: BlockHash([7; 32]),
+            merkle_root: [0; 32],
+            final_sapling_root: [0; 32],
+            time: 1_234_567,
+            bits: 0,
+            nonce: [0; 32],
+            solution: vec![],
+        }
+        .freeze()
+        .unwrap();
+
+        // A block with no transactions
+        let mut raw = vec![];
+        header.write(&mut raw).unwrap();
+        raw.push(0);
+
+        let cb = compact_block_from_raw(500_000, &raw).unwrap();
+        assert_eq!(cb.height, 500_000);
+        assert_eq!(cb.hash(), header.hash());
+        assert_eq!(cb.prev_hash(), BlockHash([7; 32]));
+        assert_eq!(cb.time, 1_234_567);
+        assert!(cb.vtx.is_empty());
+
+        // Trailing data is rejected
+        raw.push(0);
+        assert!(compact_block_from_raw(500_000, &raw).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! A storage-agnostic interface to a light client's block cache and wallet data.
+//!
+//! [`StorageBackend`] describes the operations that scanning needs from the databases
+//! holding the compact block cache and the wallet's state, so that
+//! [`scan_cached_blocks_with_backend`] can be used with any storage: SQLite (as provided
+//! by `zcash_client_sqlite`), a server-side database, or an in-memory map for testing.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use zcash_primitives::{
+    block::BlockHash,
+    merkle_tree::{CommitmentTree, IncrementalWitness},
+    sapling::Node,
+    transaction::TxId,
+    zip32::ExtendedFullViewingKey,
+    JUBJUB,
+};
+
+use crate::{
+    proto::compact_formats::CompactBlock,
+    validation::{validate_compact_block, ValidationError},
+    wallet::WalletShieldedOutput,
+    welding_rig::{scan_block_with_key_cache, WalletKeyCache},
+};
+
+/// The number of blocks for which witnesses are kept, to allow rolling back the chain.
+pub const WITNESS_RETENTION_BLOCKS: u32 = 100;
+
+/// Problems found by [`scan_cached_blocks_with_backend`], either with the cached blocks
+/// or with the witnesses of the notes stored as `NoteRef`.
+#[derive(Debug, PartialEq)]
+pub enum ScanError<NoteRef> {
+    /// A cached block was not at the expected height (expected, actual).
+    InvalidHeight(u32, u32),
+    /// A cached block at the given height is malformed.
+    InvalidCompactBlock(u32, ValidationError),
+    /// The witness of a newly-received note does not match the commitment tree after
+    /// scanning a block (output index, txid, height, witness root).
+    InvalidNewWitnessAnchor(usize, TxId, u32, Node),
+    /// The witness of a stored note does not match the commitment tree after scanning
+    /// the block at the given height.
+    InvalidWitnessAnchor(NoteRef, u32),
+}
+
+impl<NoteRef: fmt::Display> fmt::Display for ScanError<NoteRef> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::InvalidHeight(expected, actual) => write!(
+                f,
+                "Expected block at height {}, found height {}",
+                expected, actual
+            ),
+            ScanError::InvalidCompactBlock(height, e) => {
+                write!(f, "Invalid compact block at height {}: {}", height, e)
+            }
+            ScanError::InvalidNewWitnessAnchor(output, txid, height, anchor) => write!(
+                f,
+                "New witness for output {} in tx {} has incorrect anchor after scanning block {}: {:?}",
+                output, txid, height, anchor,
+            ),
+            ScanError::InvalidWitnessAnchor(note_ref, height) => write!(
+                f,
+                "Witness for note {} has incorrect anchor after scanning block {}",
+                note_ref, height
+            ),
+        }
+    }
+}
+
+impl<NoteRef: fmt::Debug + fmt::Display> error::Error for ScanError<NoteRef> {}
+
+/// Storage for a light client's compact block cache or wallet data.
+///
+/// The block cache only needs to implement [`StorageBackend::with_cached_blocks`]; the
+/// remaining methods are used with the wallet's data. Writes made while scanning a
+/// block are grouped with [`StorageBackend::transactionally`], so that an interrupted
+/// scan never leaves a partially-stored block behind.
+pub trait StorageBackend {
+    /// The error type of the backend, which must be able to represent the problems
+    /// found while scanning.
+    type Error: From<ScanError<Self::NoteRef>>;
+
+    /// A reference to a stored transaction.
+    type TxRef: Copy;
+
+    /// A reference to a stored received note.
+    type NoteRef: Copy;
+
+    /// Calls `with_block` for each cached block above `last_scanned`, or for every
+    /// cached block if `last_scanned` is `None`, in height order.
+    fn with_cached_blocks<F>(
+        &self,
+        last_scanned: Option<u32>,
+        with_block: F,
+    ) -> Result<(), Self::Error>
+    where
+        F: FnMut(CompactBlock) -> Result<(), Self::Error>;
+
+    /// Returns the height of the most recently scanned block. If no blocks have been
+    /// scanned, returns the height before the first block to scan, or `None` if
+    /// scanning may start at any height.
+    fn get_max_height(&self) -> Result<Option<u32>, Self::Error>;
+
+    /// Returns the keys of the tracked accounts, in account order, with the height of
+    /// each account's birthday (or zero if it is not known).
+    fn get_accounts(&self) -> Result<Vec<(ExtendedFullViewingKey, u32)>, Self::Error>;
+
+    /// Returns the Sapling commitment tree as of the end of the block at `height`, if
+    /// that block has been scanned.
+    fn get_commitment_tree(&self, height: u32)
+        -> Result<Option<CommitmentTree<Node>>, Self::Error>;
+
+    /// Returns the witnesses stored as of the block at `height` for the notes that may
+    /// still be spent.
+    fn get_witnesses(
+        &self,
+        height: u32,
+    ) -> Result<Vec<(Self::NoteRef, IncrementalWitness<Node>)>, Self::Error>;
+
+    /// Returns the nullifiers of the unspent received notes, mapped to their accounts.
+    fn get_nullifiers(&self) -> Result<HashMap<[u8; 32], usize>, Self::Error>;
+
+    /// Calls `f`, storing either all of its writes or, if it returns an error, none of
+    /// them.
+    fn transactionally<F, A>(&mut self, f: F) -> Result<A, Self::Error>
+    where
+        F: FnOnce(&mut Self) -> Result<A, Self::Error>;
+
+    /// Stores a scanned block with the commitment tree as of its end.
+    fn store_block(
+        &mut self,
+        height: u32,
+        hash: BlockHash,
+        time: u32,
+        tree: &CommitmentTree<Node>,
+    ) -> Result<(), Self::Error>;
+
+    /// Records that the transaction `txid` was mined at position `index` of the block at
+    /// `height`, storing it if it is not already known.
+    fn store_tx(
+        &mut self,
+        height: u32,
+        txid: TxId,
+        index: usize,
+    ) -> Result<Self::TxRef, Self::Error>;
+
+    /// Marks the note with nullifier `nf` as spent by the given transaction.
+    fn mark_spent(&mut self, tx_ref: Self::TxRef, nf: &[u8]) -> Result<(), Self::Error>;
+
+    /// Stores a note received by the given transaction, with its nullifier.
+    fn store_note(
+        &mut self,
+        tx_ref: Self::TxRef,
+        output: &WalletShieldedOutput,
+        nf: &[u8],
+    ) -> Result<Self::NoteRef, Self::Error>;
+
+    /// Stores the witness of a note as of the end of the block at `height`.
+    fn store_witness(
+        &mut self,
+        note_ref: Self::NoteRef,
+        height: u32,
+        witness: &IncrementalWitness<Node>,
+    ) -> Result<(), Self::Error>;
+
+    /// Stores the witnesses returned by [`StorageBackend::get_witnesses`] for the block
+    /// at `from_height` again for the block at `to_height`.
+    ///
+    /// This is used for blocks without Sapling outputs, which leave the witnesses
+    /// unchanged. Backends that can copy the witnesses without decoding them should
+    /// override it.
+    fn copy_witnesses(&mut self, from_height: u32, to_height: u32) -> Result<(), Self::Error> {
+        for (note_ref, witness) in self.get_witnesses(from_height)? {
+            self.store_witness(note_ref, to_height, &witness)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the witnesses stored for blocks below `height`.
+    fn prune_witnesses(&mut self, height: u32) -> Result<(), Self::Error>;
+
+    /// Marks the notes spent by unmined transactions that expired before `height` as
+    /// unspent again.
+    fn update_expired_notes(&mut self, height: u32) -> Result<(), Self::Error>;
+}
+
+/// Scans the blocks in `cache` above the last block scanned into `data` for transactions
+/// relevant to the accounts in `data`, and stores what is found.
+///
+/// Blocks are stored one at a time, each within [`StorageBackend::transactionally`], so
+/// if scanning fails part-way through, the blocks before the failing one remain stored.
+/// Cached blocks below the last scanned block are not checked against it, so the caller
+/// must handle chain reorgs.
+///
+/// The accounts to scan for are fetched once, when scanning starts. The witnesses of the
+/// stored notes are not fetched until the first block with Sapling outputs is scanned;
+/// until then, [`StorageBackend::copy_witnesses`] carries them forward to each block.
+///
+/// Returns [`ScanError::InvalidHeight`] if a block is missing from the cache.
+pub fn scan_cached_blocks_with_backend<B: StorageBackend>(
+    cache: &B,
+    data: &mut B,
+) -> Result<(), B::Error> {
+    let last_scanned = data.get_max_height()?;
+    let (extfvks, birthdays): (Vec<_>, Vec<_>) = data.get_accounts()?.into_iter().unzip();
+    let keys = WalletKeyCache::new(extfvks);
+
+    let mut tree = match last_scanned {
+        Some(height) => data
+            .get_commitment_tree(height)?
+            .unwrap_or_else(CommitmentTree::new),
+        None => CommitmentTree::new(),
+    };
+    let mut witnesses: Option<Vec<(B::NoteRef, IncrementalWitness<Node>)>> = None;
+    let mut nullifiers = data.get_nullifiers()?;
+
+    let mut last_height = last_scanned;
+    cache.with_cached_blocks(last_scanned, |block| {
+        let height = block.height as u32;
+        let prev_height = last_height;
+        if let Some(prev_height) = prev_height {
+            if height != prev_height + 1 {
+                return Err(ScanError::<B::NoteRef>::InvalidHeight(prev_height + 1, height).into());
+            }
+        }
+        last_height = Some(height);
+        log::trace!("Scanning block {}", height);
+
+        validate_compact_block(&block)
+            .map_err(|e| ScanError::<B::NoteRef>::InvalidCompactBlock(height, e))?;
+        let block_hash = block.hash();
+        let block_time = block.time;
+
+        // Blocks without Sapling outputs leave the witnesses unchanged, so they don't
+        // need to be fetched.
+        if witnesses.is_none() && block.vtx.iter().any(|tx| !tx.outputs.is_empty()) {
+            witnesses = Some(match prev_height {
+                Some(prev_height) => data.get_witnesses(prev_height)?,
+                None => vec![],
+            });
+        }
+
+        let txs = {
+            let mut witness_refs: Vec<_> = witnesses
+                .iter_mut()
+                .flat_map(|witnesses| witnesses.iter_mut())
+                .map(|(_, witness)| witness)
+                .collect();
+            scan_block_with_key_cache(
+                block,
+                &keys,
+                &birthdays,
+                &nullifiers,
+                &mut tree,
+                &mut witness_refs[..],
+            )
+        };
+
+        // Enforce that all roots match. This is slow, so only include in debug builds.
+        #[cfg(debug_assertions)]
+        {
+            let cur_root = tree.root();
+            for (note_ref, witness) in witnesses.iter().flat_map(|witnesses| witnesses.iter()) {
+                if witness.root() != cur_root {
+                    return Err(
+                        ScanError::<B::NoteRef>::InvalidWitnessAnchor(*note_ref, height).into(),
+                    );
+                }
+            }
+            for tx in &txs {
+                for output in tx.shielded_outputs.iter() {
+                    if output.witness.root() != cur_root {
+                        return Err(ScanError::<B::NoteRef>::InvalidNewWitnessAnchor(
+                            output.index,
+                            tx.txid,
+                            height,
+                            output.witness.root(),
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+
+        data.transactionally(|data| {
+            data.store_block(height, block_hash, block_time, &tree)?;
+
+            for tx in txs {
+                let tx_ref = data.store_tx(height, tx.txid, tx.index)?;
+
+                // Mark notes as spent, and stop looking for further spends of them.
+                for spend in &tx.shielded_spends {
+                    data.mark_spent(tx_ref, &spend.nf)?;
+                    nullifiers.remove(&spend.nf[..]);
+                }
+
+                for output in tx.shielded_outputs {
+                    log::debug!("Found note in tx {:?} output {}", tx.txid, output.index);
+                    let nf = output.note.nf(
+                        &keys.extfvks()[output.account].fvk.vk,
+                        output.witness.position() as u64,
+                        &JUBJUB,
+                    );
+                    let note_ref = data.store_note(tx_ref, &output, &nf)?;
+
+                    // Track the new note's witness and spends for the rest of the scan.
+                    witnesses
+                        .as_mut()
+                        .expect("witnesses are fetched for blocks with outputs")
+                        .push((note_ref, output.witness));
+                    let mut nf_key = [0; 32];
+                    nf_key.copy_from_slice(&nf);
+                    nullifiers.insert(nf_key, output.account);
+                }
+            }
+
+            match &witnesses {
+                Some(witnesses) => {
+                    for (note_ref, witness) in witnesses {
+                        data.store_witness(*note_ref, height, witness)?;
+                    }
+                }
+                None => {
+                    if let Some(prev_height) = prev_height {
+                        data.copy_witnesses(prev_height, height)?;
+                    }
+                }
+            }
+            data.prune_witnesses(height.saturating_sub(WITNESS_RETENTION_BLOCKS))?;
+            data.update_expired_notes(height)
+        })
+    })
+}
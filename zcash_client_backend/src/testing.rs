@@ -0,0 +1,364 @@
+//! Utilities for constructing [`CompactBlock`]s in tests.
+//!
+//! This module is only available with the `test-dependencies` feature enabled.
+
+use ff::{Field, PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::{Bls12, Fr};
+use rand_chacha::ChaCha20Rng;
+use rand_core::{CryptoRng, RngCore, SeedableRng};
+use rand_os::OsRng;
+use zcash_primitives::{
+    jubjub::{fs::Fs, FixedGenerators, JubjubParams, ToUniform},
+    keys::OutgoingViewingKey,
+    note_encryption::{Memo, SaplingNoteEncryption},
+    primitives::{Note, PaymentAddress},
+    transaction::components::Amount,
+    zip32::ExtendedFullViewingKey,
+    JUBJUB,
+};
+
+use crate::{
+    proto::compact_formats::{CompactBlock, CompactOutput, CompactSpend, CompactTx},
+    validation::COMPACT_NOTE_SIZE,
+};
+
+/// A builder for [`CompactBlock`]s with precise control over their contents.
+///
+/// Spends and outputs are added to a pending transaction, which is appended to the block
+/// when a random transaction is added or the block is built. Each transaction is given
+/// its position in the block as its index, and a random hash.
+pub struct CompactBlockBuilder<R: RngCore + CryptoRng = OsRng> {
+    block: CompactBlock,
+    tx: Option<CompactTx>,
+    rng: R,
+}
+
+impl CompactBlockBuilder<OsRng> {
+    /// Creates a builder for an empty block at the given height, with a random hash.
+    pub fn new(height: u32, prev_hash: [u8; 32]) -> Self {
+        CompactBlockBuilder::new_with_rng(height, prev_hash, OsRng)
+    }
+}
+
+impl<R: RngCore + CryptoRng> CompactBlockBuilder<R> {
+    /// Creates a builder for an empty block at the given height, with a hash drawn from
+    /// `rng`.
+    ///
+    /// The transaction hashes and note randomness are also drawn from `rng`, so a seeded
+    /// RNG produces the same block every time.
+    pub fn new_with_rng(height: u32, prev_hash: [u8; 32], mut rng: R) -> Self {
+        let mut block = CompactBlock::new();
+        block.set_height(u64::from(height));
+        block.hash.resize(32, 0);
+        rng.fill_bytes(&mut block.hash);
+        block.set_prevHash(prev_hash.to_vec());
+
+        CompactBlockBuilder {
+            block,
+            tx: None,
+            rng,
+        }
+    }
+
+    /// Sets the block's timestamp.
+    pub fn set_time(&mut self, timestamp: u32) -> &mut Self {
+        self.block.set_time(timestamp);
+        self
+    }
+
+    /// Returns the pending transaction, creating it if necessary.
+    fn pending_tx(&mut self) -> &mut CompactTx {
+        let rng = &mut self.rng;
+        self.tx.get_or_insert_with(|| {
+            let mut txid = vec![0; 32];
+            rng.fill_bytes(&mut txid);
+            let mut ctx = CompactTx::new();
+            ctx.set_hash(txid);
+            ctx
+        })
+    }
+
+    /// Appends the pending transaction, if any, to the block.
+    fn push_pending_tx(&mut self) {
+        if let Some(mut ctx) = self.tx.take() {
+            ctx.set_index(self.block.vtx.len() as u64);
+            self.block.vtx.push(ctx);
+        }
+    }
+
+    /// Adds a spend of the given nullifier to the pending transaction.
+    pub fn add_sapling_spend(&mut self, nf: &[u8]) -> &mut Self {
+        let mut cspend = CompactSpend::new();
+        cspend.set_nf(nf.to_vec());
+        self.pending_tx().spends.push(cspend);
+        self
+    }
+
+    /// Adds an output to the pending transaction, paying `value` to the default address
+    /// of `extfvk`.
+    ///
+    /// Returns the note that was created, from which its nullifier can be derived.
+    pub fn add_sapling_output(
+        &mut self,
+        extfvk: &ExtendedFullViewingKey,
+        value: Amount,
+        memo: Memo,
+    ) -> Note<Bls12> {
+        let to = extfvk.default_address().unwrap().1;
+        self.add_sapling_output_to(extfvk.fvk.ovk, to, value, memo)
+    }
+
+    /// Adds an output to the pending transaction, paying `value` to `to` and encrypted
+    /// to `ovk`.
+    ///
+    /// Returns the note that was created, from which its nullifier can be derived.
+    pub fn add_sapling_output_to(
+        &mut self,
+        ovk: OutgoingViewingKey,
+        to: PaymentAddress<Bls12>,
+        value: Amount,
+        memo: Memo,
+    ) -> Note<Bls12> {
+        let note = Note {
+            g_d: to.diversifier.g_d::<Bls12>(&JUBJUB).unwrap(),
+            pk_d: to.pk_d.clone(),
+            value: value.into(),
+            r: Fs::random(&mut self.rng),
+        };
+        let encryptor = SaplingNoteEncryption::new(ovk, note.clone(), to, memo, &mut self.rng);
+        let mut cmu = vec![];
+        note.cm(&JUBJUB).into_repr().write_le(&mut cmu).unwrap();
+        let mut epk = vec![];
+        encryptor.epk().write(&mut epk).unwrap();
+        let enc_ciphertext = encryptor.encrypt_note_plaintext();
+
+        let mut cout = CompactOutput::new();
+        cout.set_cmu(cmu);
+        cout.set_epk(epk);
+        cout.set_ciphertext(enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec());
+        self.pending_tx().outputs.push(cout);
+        note
+    }
+
+    /// Appends a transaction with a single random spend and a single random output,
+    /// neither of which is detectable by any wallet.
+    pub fn add_random_tx<T: RngCore>(&mut self, rng: &mut T) -> &mut Self {
+        self.push_pending_tx();
+
+        let fake_nf = {
+            let mut nf = vec![0; 32];
+            rng.fill_bytes(&mut nf);
+            nf
+        };
+        let fake_cmu = {
+            let fake_cmu = Fr::random(rng);
+            let mut bytes = vec![];
+            fake_cmu.into_repr().write_le(&mut bytes).unwrap();
+            bytes
+        };
+        let fake_epk = {
+            let mut buffer = vec![0; 64];
+            rng.fill_bytes(&mut buffer);
+            let fake_esk = Fs::to_uniform(&buffer[..]);
+            let fake_epk = JUBJUB
+                .generator(FixedGenerators::SpendingKeyGenerator)
+                .mul(fake_esk, &JUBJUB);
+            let mut bytes = vec![];
+            fake_epk.write(&mut bytes).unwrap();
+            bytes
+        };
+        let mut cspend = CompactSpend::new();
+        cspend.set_nf(fake_nf);
+        let mut cout = CompactOutput::new();
+        cout.set_cmu(fake_cmu);
+        cout.set_epk(fake_epk);
+        cout.set_ciphertext(vec![0; COMPACT_NOTE_SIZE]);
+        let mut ctx = CompactTx::new();
+        let mut txid = vec![0; 32];
+        rng.fill_bytes(&mut txid);
+        ctx.set_hash(txid);
+        ctx.spends.push(cspend);
+        ctx.outputs.push(cout);
+        ctx.set_index(self.block.vtx.len() as u64);
+        self.block.vtx.push(ctx);
+        self
+    }
+
+    /// Returns the constructed [`CompactBlock`].
+    pub fn build(mut self) -> CompactBlock {
+        self.push_pending_tx();
+        self.block
+    }
+}
+
+/// The number of transactions in each block created by a [`BlockGenerator`].
+const TXS_PER_BLOCK: usize = 4;
+
+/// A deterministic generator of chains of [`CompactBlock`]s, for property tests of
+/// scanning.
+///
+/// Two generators created with the same seed produce the same blocks, given the same
+/// arguments to [`BlockGenerator::generate`].
+pub struct BlockGenerator {
+    rng: ChaCha20Rng,
+    next_height: u32,
+    prev_hash: [u8; 32],
+    received: Vec<u64>,
+}
+
+impl BlockGenerator {
+    /// Creates a generator whose first block will be at `start_height`.
+    pub fn new(seed: [u8; 32], start_height: u32) -> Self {
+        BlockGenerator {
+            rng: ChaCha20Rng::from_seed(seed),
+            next_height: start_height,
+            prev_hash: [0; 32],
+            received: vec![],
+        }
+    }
+
+    /// Generates the next `count` blocks of the chain.
+    ///
+    /// Each block contains a fixed number of transactions. Each transaction pays a
+    /// random amount to a randomly-chosen account in `accounts` with probability
+    /// `owned_fraction`, and otherwise contains a random spend and output that are not
+    /// detectable by any wallet.
+    pub fn generate(
+        &mut self,
+        count: usize,
+        owned_fraction: f32,
+        accounts: &[ExtendedFullViewingKey],
+    ) -> Vec<CompactBlock> {
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut seed = [0; 32];
+            self.rng.fill_bytes(&mut seed);
+            let mut builder = CompactBlockBuilder::new_with_rng(
+                self.next_height,
+                self.prev_hash,
+                ChaCha20Rng::from_seed(seed),
+            );
+
+            for _ in 0..TXS_PER_BLOCK {
+                // Uniform in [0, 1)
+                let roll = (self.rng.next_u32() >> 8) as f32 / (1 << 24) as f32;
+                if !accounts.is_empty() && roll < owned_fraction {
+                    let account = self.rng.next_u32() as usize % accounts.len();
+                    let value = u64::from(self.rng.next_u32() % 1000) + 1;
+                    builder.add_sapling_output(
+                        &accounts[account],
+                        Amount::from_u64(value).unwrap(),
+                        Memo::default(),
+                    );
+                    if self.received.len() <= account {
+                        self.received.resize(account + 1, 0);
+                    }
+                    self.received[account] += value;
+                } else {
+                    builder.add_random_tx(&mut self.rng);
+                }
+            }
+
+            let block = builder.build();
+            self.prev_hash.copy_from_slice(&block.hash);
+            self.next_height += 1;
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Returns the total value paid to the account at index `account` in all blocks
+    /// generated so far.
+    pub fn received(&self, account: usize) -> Amount {
+        Amount::from_u64(self.received.get(account).cloned().unwrap_or(0)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_os::OsRng;
+    use std::collections::HashMap;
+    use zcash_primitives::{
+        merkle_tree::CommitmentTree,
+        note_encryption::Memo,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{BlockGenerator, CompactBlockBuilder};
+    use crate::{validation::validate_compact_block, welding_rig::scan_block};
+
+    #[test]
+    fn compact_block_builder_orders_transactions() {
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let value = Amount::from_u64(5).unwrap();
+
+        let mut builder = CompactBlockBuilder::new(10, [7; 32]);
+        builder.set_time(1_234_567);
+        builder.add_random_tx(&mut OsRng);
+        builder.add_sapling_spend(&[1; 32]);
+        builder.add_sapling_output(&extfvk, value, Memo::default());
+        builder.add_random_tx(&mut OsRng);
+        let cb = builder.build();
+
+        validate_compact_block(&cb).unwrap();
+        assert_eq!(cb.height, 10);
+        assert_eq!(cb.prevHash, vec![7; 32]);
+        assert_eq!(cb.time, 1_234_567);
+        assert_eq!(cb.vtx.len(), 3);
+        for (index, tx) in cb.vtx.iter().enumerate() {
+            assert_eq!(tx.index, index as u64);
+        }
+        assert_eq!(cb.vtx[1].spends.len(), 1);
+        assert_eq!(cb.vtx[1].outputs.len(), 1);
+
+        // Only the output paying extfvk is detected
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(cb, &[extfvk], &[], &HashMap::new(), &mut tree, &mut []);
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].index, 1);
+        assert_eq!(txs[0].shielded_outputs[0].note.value, 5);
+    }
+
+    #[test]
+    fn block_generator_is_deterministic() {
+        let accounts = [
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[])),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1])),
+        ];
+
+        let mut generator = BlockGenerator::new([7; 32], 1);
+        let blocks = generator.generate(20, 0.3, &accounts);
+        assert_eq!(
+            BlockGenerator::new([7; 32], 1).generate(20, 0.3, &accounts),
+            blocks
+        );
+        assert_ne!(
+            BlockGenerator::new([8; 32], 1).generate(20, 0.3, &accounts),
+            blocks
+        );
+
+        // The blocks form a chain, and scanning them finds everything paid to the accounts
+        let mut tree = CommitmentTree::new();
+        let mut received = [0; 2];
+        let mut prev_hash = vec![0; 32];
+        for (i, cb) in blocks.into_iter().enumerate() {
+            validate_compact_block(&cb).unwrap();
+            assert_eq!(cb.height, i as u64 + 1);
+            assert_eq!(cb.prevHash, prev_hash);
+            prev_hash = cb.hash.clone();
+            for tx in scan_block(cb, &accounts, &[], &HashMap::new(), &mut tree, &mut []) {
+                for output in tx.shielded_outputs {
+                    received[output.account] += output.note.value;
+                }
+            }
+        }
+        assert_eq!(tree.size(), 20 * 4);
+        for account in 0..2 {
+            assert_eq!(
+                generator.received(account),
+                Amount::from_u64(received[account]).unwrap()
+            );
+        }
+    }
+}
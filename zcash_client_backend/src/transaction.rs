@@ -0,0 +1,252 @@
+//! Decoding of raw transactions, for inspecting transactions while debugging.
+//!
+//! [`decode_raw_transaction`] parses a hex-encoded transaction, as passed to or returned
+//! by the `sendrawtransaction` and `getrawtransaction` RPC methods, and summarises its
+//! contents. Sapling outputs are decrypted with any viewing keys that are provided.
+
+use ff::{PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::Bls12;
+use std::error;
+use std::fmt;
+use std::io;
+use zcash_primitives::{
+    jubjub::fs::Fs,
+    note_encryption::{try_sapling_note_decryption, Memo},
+    primitives::PaymentAddress,
+    transaction::{
+        components::{Amount, OutputDescription, Zatoshi},
+        Transaction, TxId,
+    },
+    zip32::ExtendedFullViewingKey,
+    JUBJUB,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The input is not valid hex.
+    InvalidHex(hex::FromHexError),
+    /// The raw transaction could not be parsed.
+    InvalidTransaction(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidHex(e) => write!(f, "Invalid hex: {}", e),
+            Error::InvalidTransaction(e) => write!(f, "Invalid transaction: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::InvalidHex(e) => Some(e),
+            Error::InvalidTransaction(e) => Some(e),
+        }
+    }
+}
+
+/// A Sapling spend in a [`DecodedTransaction`].
+pub struct DecodedSpend {
+    pub nullifier: [u8; 32],
+    pub anchor: [u8; 32],
+}
+
+/// A Sapling output in a [`DecodedTransaction`].
+pub struct DecodedOutput {
+    pub cmu: [u8; 32],
+    /// The contents of the output, if it could be decrypted with one of the given keys.
+    pub decrypted: Option<DecryptedOutput>,
+}
+
+/// The contents of a Sapling output that was decrypted with an incoming viewing key.
+pub struct DecryptedOutput {
+    /// The index of the key that decrypted the output.
+    pub account: usize,
+    pub to: PaymentAddress<Bls12>,
+    pub value: Zatoshi,
+    pub memo: Memo,
+}
+
+/// A summary of a transaction, returned by [`decode_raw_transaction`].
+pub struct DecodedTransaction {
+    pub txid: TxId,
+    pub version: u32,
+    pub n_vin: usize,
+    pub n_vout: usize,
+    pub sapling_spends: Vec<DecodedSpend>,
+    pub sapling_outputs: Vec<DecodedOutput>,
+    pub value_balance: Amount,
+}
+
+/// Decrypts `output` with the first of `ivks` that can decrypt it.
+fn decrypt_output(ivks: &[Fs], output: &OutputDescription) -> Option<DecryptedOutput> {
+    let epk = output.ephemeral_key.as_prime_order(&JUBJUB)?;
+    ivks.iter().enumerate().find_map(|(account, ivk)| {
+        try_sapling_note_decryption(ivk, &epk, &output.cmu, &output.enc_ciphertext).map(
+            |(note, to, memo)| DecryptedOutput {
+                account,
+                to,
+                value: Zatoshi::from(note.value),
+                memo,
+            },
+        )
+    })
+}
+
+/// Parses the hex-encoded transaction `hex`, decrypting its Sapling outputs with the
+/// incoming viewing keys of `extfvks`.
+///
+/// Outputs that cannot be decrypted with any of the keys are still returned, with only
+/// their note commitments. Pass an empty slice to skip decryption.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::transaction::decode_raw_transaction;
+///
+/// match decode_raw_transaction("0400008085202f89", &[]) {
+///     Ok(tx) => println!("Transaction {} has version {}", tx.txid, tx.version),
+///     Err(e) => println!("Could not decode transaction: {}", e),
+/// }
+/// ```
+pub fn decode_raw_transaction(
+    hex: &str,
+    extfvks: &[ExtendedFullViewingKey],
+) -> Result<DecodedTransaction, Error> {
+    let raw_tx = hex::decode(hex.trim()).map_err(Error::InvalidHex)?;
+    let tx = Transaction::read(&raw_tx[..]).map_err(Error::InvalidTransaction)?;
+    let ivks: Vec<_> = extfvks.iter().map(|extfvk| extfvk.fvk.vk.ivk()).collect();
+
+    let sapling_spends = tx
+        .shielded_spends
+        .iter()
+        .map(|spend| {
+            let mut anchor = [0; 32];
+            spend.anchor.into_repr().write_le(&mut anchor[..]).unwrap();
+            DecodedSpend {
+                nullifier: spend.nullifier,
+                anchor,
+            }
+        })
+        .collect();
+
+    let sapling_outputs = tx
+        .shielded_outputs
+        .iter()
+        .map(|output| {
+            let mut cmu = [0; 32];
+            output.cmu.into_repr().write_le(&mut cmu[..]).unwrap();
+            DecodedOutput {
+                cmu,
+                decrypted: decrypt_output(&ivks, output),
+            }
+        })
+        .collect();
+
+    Ok(DecodedTransaction {
+        txid: tx.txid(),
+        version: tx.version,
+        n_vin: tx.vin.len(),
+        n_vout: tx.vout.len(),
+        sapling_spends,
+        sapling_outputs,
+        value_balance: tx.value_balance,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ff::Field;
+    use pairing::bls12_381::Bls12;
+    use rand_core::RngCore;
+    use rand_os::OsRng;
+    use zcash_primitives::{
+        jubjub::fs::Fs,
+        note_encryption::{Memo, SaplingNoteEncryption},
+        primitives::ValueCommitment,
+        redjubjub::Signature,
+        transaction::{
+            components::{Amount, OutputDescription, Zatoshi, GROTH_PROOF_SIZE},
+            TransactionData, SAPLING_TX_VERSION,
+        },
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+        JUBJUB,
+    };
+
+    use super::{decode_raw_transaction, Error};
+
+    #[test]
+    fn decode_transaction_with_sapling_output() {
+        let mut rng = OsRng;
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let other = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1]));
+        let to = extfvk.default_address().unwrap().1;
+
+        // Create a transaction with a single output paying extfvk
+        let value = rng.next_u32() as u64;
+        let note = to
+            .create_note(value, Fs::random(&mut rng), &JUBJUB)
+            .unwrap();
+        let cv = ValueCommitment::<Bls12> {
+            value,
+            randomness: Fs::random(&mut rng),
+        }
+        .cm(&JUBJUB)
+        .into();
+        let cmu = note.cm(&JUBJUB);
+        let memo = Memo::from_str("Thanks for the coffee").unwrap();
+        let encryptor =
+            SaplingNoteEncryption::new(other.fvk.ovk, note, to.clone(), memo.clone(), &mut rng);
+
+        let mut mtx = TransactionData::new();
+        mtx.shielded_outputs.push(OutputDescription {
+            cv,
+            cmu,
+            ephemeral_key: encryptor.epk().clone().into(),
+            enc_ciphertext: encryptor.encrypt_note_plaintext(),
+            out_ciphertext: encryptor.encrypt_outgoing_plaintext(&cv, &cmu),
+            zkproof: [0; GROTH_PROOF_SIZE],
+        });
+        mtx.value_balance = Amount::from_i64(-1000).unwrap();
+        mtx.binding_sig = Some(Signature::read(&[0u8; 64][..]).unwrap());
+        let tx = mtx.freeze().unwrap();
+        let mut raw_tx = vec![];
+        tx.write(&mut raw_tx).unwrap();
+        let hex = hex::encode(&raw_tx);
+
+        // The output is decrypted by the key it was sent to
+        let decoded = decode_raw_transaction(&hex, &[other.clone(), extfvk]).unwrap();
+        assert_eq!(decoded.txid, tx.txid());
+        assert_eq!(decoded.version, SAPLING_TX_VERSION);
+        assert_eq!(decoded.n_vin, 0);
+        assert_eq!(decoded.n_vout, 0);
+        assert!(decoded.sapling_spends.is_empty());
+        assert_eq!(decoded.sapling_outputs.len(), 1);
+        assert_eq!(decoded.value_balance, Amount::from_i64(-1000).unwrap());
+        let decrypted = decoded.sapling_outputs[0].decrypted.as_ref().unwrap();
+        assert_eq!(decrypted.account, 1);
+        assert_eq!(decrypted.to, to);
+        assert_eq!(decrypted.value, Zatoshi::from(value));
+        assert_eq!(decrypted.memo, memo);
+
+        // Without the key, only the note commitment is returned
+        let decoded = decode_raw_transaction(&hex, &[other]).unwrap();
+        assert!(decoded.sapling_outputs[0].decrypted.is_none());
+    }
+
+    #[test]
+    fn decode_invalid_transaction() {
+        match decode_raw_transaction("not hex", &[]) {
+            Err(Error::InvalidHex(_)) => (),
+            _ => panic!("Should have failed"),
+        }
+
+        match decode_raw_transaction("0400008085202f89", &[]) {
+            Err(Error::InvalidTransaction(_)) => (),
+            _ => panic!("Should have failed"),
+        }
+    }
+}
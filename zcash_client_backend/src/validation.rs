@@ -0,0 +1,230 @@
+//! Checks for the well-formedness of compact blocks.
+
+use std::error;
+use std::fmt;
+use zcash_primitives::block::{compute_block_header_hash, BlockHeader};
+
+use crate::proto::compact_formats::CompactBlock;
+
+/// The size of the prefix of a note ciphertext that is included in a
+/// `CompactOutput`.
+pub(crate) const COMPACT_NOTE_SIZE: usize = 52;
+
+/// The ways in which a [`CompactBlock`] can be malformed.
+///
+/// Transaction, spend, and output indices are positions within the compact block, not
+/// within the full block.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// The block has a height of zero.
+    ZeroHeight,
+    /// The block hash is not 32 bytes.
+    InvalidHashLength(usize),
+    /// A transaction's ID is not 32 bytes.
+    InvalidTxIdLength(usize),
+    /// An output's note commitment is not a valid field element.
+    InvalidCmu(usize, usize),
+    /// An output's ciphertext prefix is not 52 bytes.
+    InvalidCiphertextLength(usize, usize),
+    /// A spend's nullifier is not 32 bytes.
+    InvalidNullifierLength(usize, usize),
+    /// The block header could not be parsed.
+    InvalidHeader,
+    /// The block hash does not match the hash of the block header.
+    HashMismatch,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::ZeroHeight => write!(f, "Block has height zero"),
+            ValidationError::InvalidHashLength(len) => {
+                write!(f, "Block hash has length {}, expected 32", len)
+            }
+            ValidationError::InvalidTxIdLength(tx) => {
+                write!(f, "Invalid txid length for tx {}", tx)
+            }
+            ValidationError::InvalidCmu(tx, output) => {
+                write!(f, "Invalid cmu for output {} in tx {}", output, tx)
+            }
+            ValidationError::InvalidCiphertextLength(tx, output) => write!(
+                f,
+                "Invalid ciphertext length for output {} in tx {}",
+                output, tx
+            ),
+            ValidationError::InvalidNullifierLength(tx, spend) => write!(
+                f,
+                "Invalid nullifier length for spend {} in tx {}",
+                spend, tx
+            ),
+            ValidationError::InvalidHeader => write!(f, "Invalid block header"),
+            ValidationError::HashMismatch => {
+                write!(f, "Block hash does not match the block header")
+            }
+        }
+    }
+}
+
+impl error::Error for ValidationError {}
+
+/// Checks that the given [`CompactBlock`] is well-formed.
+///
+/// This should be called on blocks received from an untrusted source (such as a
+/// `lightwalletd` server) before they are stored or scanned, as malformed blocks can
+/// otherwise cause panics while scanning.
+///
+/// Ephemeral public keys are not checked, as the consensus rules allow outputs whose
+/// `epk` is not a prime-order group element. Such outputs cannot be decrypted, but their
+/// note commitments are still part of the commitment tree.
+pub fn validate_compact_block(block: &CompactBlock) -> Result<(), ValidationError> {
+    if block.height == 0 {
+        return Err(ValidationError::ZeroHeight);
+    }
+    if block.hash.len() != 32 {
+        return Err(ValidationError::InvalidHashLength(block.hash.len()));
+    }
+
+    for (tx_index, tx) in block.vtx.iter().enumerate() {
+        if tx.hash.len() != 32 {
+            return Err(ValidationError::InvalidTxIdLength(tx_index));
+        }
+
+        for (index, spend) in tx.spends.iter().enumerate() {
+            if spend.nf.len() != 32 {
+                return Err(ValidationError::InvalidNullifierLength(tx_index, index));
+            }
+        }
+
+        for (index, output) in tx.outputs.iter().enumerate() {
+            if output.cmu.len() != 32 || output.cmu().is_err() {
+                return Err(ValidationError::InvalidCmu(tx_index, index));
+            }
+            if output.ciphertext.len() != COMPACT_NOTE_SIZE {
+                return Err(ValidationError::InvalidCiphertextLength(tx_index, index));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the hash of the given [`CompactBlock`] is the hash of its header.
+///
+/// This detects blocks whose hash has been tampered with, for example in a cache
+/// database. Blocks without a header cannot be checked, and are accepted. The block
+/// should already have been checked with [`validate_compact_block`].
+pub fn validate_block_hash(block: &CompactBlock) -> Result<(), ValidationError> {
+    if block.header.is_empty() {
+        return Ok(());
+    }
+
+    let header =
+        BlockHeader::read(&block.header[..]).map_err(|_| ValidationError::InvalidHeader)?;
+    if compute_block_header_hash(&header).0[..] != block.hash[..] {
+        return Err(ValidationError::HashMismatch);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use zcash_primitives::block::{BlockHash, BlockHeaderData};
+
+    use super::{validate_block_hash, validate_compact_block, ValidationError, COMPACT_NOTE_SIZE};
+    use crate::proto::compact_formats::{CompactBlock, CompactOutput, CompactSpend, CompactTx};
+
+    #[test]
+    fn validate_compact_block_checks_fields() {
+        let mut cb = CompactBlock::new();
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::ZeroHeight)
+        );
+
+        cb.set_height(1);
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::InvalidHashLength(0))
+        );
+
+        cb.hash.resize(32, 0);
+        assert_eq!(validate_compact_block(&cb), Ok(()));
+
+        let mut ctx = CompactTx::new();
+        ctx.set_hash(vec![0; 31]);
+        cb.vtx.push(ctx);
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::InvalidTxIdLength(0))
+        );
+
+        cb.vtx[0].hash.push(0);
+        assert_eq!(validate_compact_block(&cb), Ok(()));
+
+        let mut cspend = CompactSpend::new();
+        cspend.set_nf(vec![0; 31]);
+        cb.vtx[0].spends.push(cspend);
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::InvalidNullifierLength(0, 0))
+        );
+
+        cb.vtx[0].spends[0].nf.push(0);
+        assert_eq!(validate_compact_block(&cb), Ok(()));
+
+        // The field modulus is not a valid cmu
+        let mut cout = CompactOutput::new();
+        cout.set_cmu(vec![0xff; 32]);
+        cb.vtx[0].outputs.push(cout);
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::InvalidCmu(0, 0))
+        );
+
+        cb.vtx[0].outputs[0].set_cmu(vec![0; 32]);
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::InvalidCiphertextLength(0, 0))
+        );
+
+        // Outputs with an invalid epk are accepted
+        cb.vtx[0].outputs[0].set_epk(vec![0xff; 32]);
+        cb.vtx[0].outputs[0].ciphertext.resize(COMPACT_NOTE_SIZE, 0);
+        assert_eq!(validate_compact_block(&cb), Ok(()));
+    }
+
+    #[test]
+    fn validate_block_hash_checks_header() {
+        let mut cb = CompactBlock::new();
+        cb.set_height(1);
+        cb.hash.resize(32, 0);
+
+        // Blocks without headers are not checked
+        assert_eq!(validate_block_hash(&cb), Ok(()));
+
+        let header = BlockHeaderData {
+            version: 4,
+            prev_block: BlockHash([1; 32]),
+            merkle_root: [2; 32],
+            final_sapling_root: [3; 32],
+            time: 1_234_567,
+            bits: 0x1d00_ffff,
+            nonce: [4; 32],
+            solution: vec![5; 1344],
+        }
+        .freeze()
+        .unwrap();
+        header.write(&mut cb.header).unwrap();
+        assert_eq!(validate_block_hash(&cb), Err(ValidationError::HashMismatch));
+
+        cb.set_hash(header.hash().0.to_vec());
+        assert_eq!(validate_block_hash(&cb), Ok(()));
+
+        cb.header.truncate(100);
+        assert_eq!(
+            validate_block_hash(&cb),
+            Err(ValidationError::InvalidHeader)
+        );
+    }
+}
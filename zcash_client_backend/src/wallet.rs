@@ -1,13 +1,19 @@
 //! Structs representing transaction data scanned from the block chain by a wallet or
 //! light client.
 
+use ff::{PrimeField, PrimeFieldRepr};
 use pairing::bls12_381::{Bls12, Fr};
+use std::collections::HashMap;
+use std::fmt;
 use zcash_primitives::{
     jubjub::{edwards, PrimeOrder},
     merkle_tree::IncrementalWitness,
     primitives::{Note, PaymentAddress},
     sapling::Node,
-    transaction::TxId,
+    transaction::{
+        components::{Amount, OutPoint, TxOut, Zatoshi},
+        TxId,
+    },
 };
 
 /// A subset of a [`Transaction`] relevant to wallets and light clients.
@@ -22,15 +28,112 @@ pub struct WalletTx {
     pub shielded_outputs: Vec<WalletShieldedOutput>,
 }
 
+impl WalletTx {
+    /// Returns the net change in the balance of `account` caused by this transaction:
+    /// the value of the notes it received, minus the value of the notes it spent. A
+    /// positive value is a net receive, and a negative value a net send.
+    ///
+    /// `spent_note_values` maps the nullifiers of the wallet's notes to their values.
+    /// Returns `None` if a note spent by `account` is missing from the map, or if the
+    /// result is out of range.
+    pub fn net_value_for_account(
+        &self,
+        account: usize,
+        spent_note_values: &HashMap<Vec<u8>, Amount>,
+    ) -> Option<Amount> {
+        let received = self
+            .shielded_outputs
+            .iter()
+            .filter(|output| output.account == account)
+            .map(|output| output.value().try_into_amount().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let spent = self
+            .shielded_spends
+            .iter()
+            .filter(|spend| spend.account == account)
+            .map(|spend| spent_note_values.get(&spend.nf).cloned())
+            .collect::<Option<Vec<_>>>()?;
+
+        let received: Amount = received.into_iter().sum::<Option<_>>()?;
+        let spent: Amount = spent.into_iter().sum::<Option<_>>()?;
+        received - spent
+    }
+}
+
+impl fmt::Debug for WalletTx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WalletTx")
+            .field("txid", &self.txid.to_string())
+            .field("index", &self.index)
+            .field("num_spends", &self.num_spends)
+            .field("num_outputs", &self.num_outputs)
+            .field("shielded_spends", &self.shielded_spends)
+            .field("shielded_outputs", &self.shielded_outputs)
+            .finish()
+    }
+}
+
+/// Formats the transaction on one line, followed by one line for each of its spends and
+/// outputs.
+impl fmt::Display for WalletTx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tx {} (index {}, {} spends, {} outputs)",
+            self.txid, self.index, self.num_spends, self.num_outputs
+        )?;
+        for spend in &self.shielded_spends {
+            write!(f, "\n  {}", spend)?;
+        }
+        for output in &self.shielded_outputs {
+            write!(f, "\n  {}", output)?;
+        }
+        Ok(())
+    }
+}
+
+/// The shielded pool that a nullifier belongs to.
+///
+/// Only Sapling is currently supported; variants for other pools (such as Orchard) will
+/// be added as wallets gain support for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NullifierType {
+    Sapling,
+}
+
 /// A subset of a [`SpendDescription`] relevant to wallets and light clients.
 ///
 /// [`SpendDescription`]: zcash_primitives::transaction::components::SpendDescription
 pub struct WalletShieldedSpend {
     pub index: usize,
     pub nf: Vec<u8>,
+    pub nf_type: NullifierType,
     pub account: usize,
 }
 
+impl fmt::Debug for WalletShieldedSpend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WalletShieldedSpend")
+            .field("index", &self.index)
+            .field("nf", &hex::encode(&self.nf))
+            .field("nf_type", &self.nf_type)
+            .field("account", &self.account)
+            .finish()
+    }
+}
+
+impl fmt::Display for WalletShieldedSpend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "spend {} from account {} (nf {})",
+            self.index,
+            self.account,
+            hex::encode(&self.nf)
+        )
+    }
+}
+
 /// A subset of an [`OutputDescription`] relevant to wallets and light clients.
 ///
 /// [`OutputDescription`]: zcash_primitives::transaction::components::OutputDescription
@@ -44,3 +147,163 @@ pub struct WalletShieldedOutput {
     pub is_change: bool,
     pub witness: IncrementalWitness<Node>,
 }
+
+impl WalletShieldedOutput {
+    /// Returns the value of the received note.
+    pub fn value(&self) -> Zatoshi {
+        Zatoshi::from(self.note.value)
+    }
+
+    fn cmu_hex(&self) -> String {
+        let mut cmu = vec![];
+        self.cmu
+            .into_repr()
+            .write_le(&mut cmu)
+            .expect("Should be able to write to a Vec");
+        hex::encode(cmu)
+    }
+
+    fn epk_hex(&self) -> String {
+        let mut epk = vec![];
+        self.epk
+            .write(&mut epk)
+            .expect("Should be able to write to a Vec");
+        hex::encode(epk)
+    }
+}
+
+impl fmt::Debug for WalletShieldedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WalletShieldedOutput")
+            .field("index", &self.index)
+            .field("cmu", &self.cmu_hex())
+            .field("epk", &self.epk_hex())
+            .field("account", &self.account)
+            .field("value", &self.note.value)
+            .field("to", &self.to.to_string())
+            .field("is_change", &self.is_change)
+            .field("position", &self.witness.position())
+            .finish()
+    }
+}
+
+impl fmt::Display for WalletShieldedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "output {} to account {}: {} zat{} (cmu {}, epk {})",
+            self.index,
+            self.account,
+            self.note.value,
+            if self.is_change { " change" } else { "" },
+            self.cmu_hex(),
+            self.epk_hex()
+        )
+    }
+}
+
+/// An unspent transparent output that a wallet can spend, such as a coinbase output.
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+}
+
+#[cfg(test)]
+mod tests {
+    use ff::Field;
+    use std::collections::HashMap;
+    use zcash_primitives::{
+        jubjub::fs::Fs,
+        merkle_tree::{CommitmentTree, Hashable, IncrementalWitness},
+        sapling::Node,
+        transaction::{components::Amount, TxId},
+        zip32::ExtendedSpendingKey,
+        JUBJUB,
+    };
+
+    use super::{NullifierType, WalletShieldedOutput, WalletShieldedSpend, WalletTx};
+
+    fn fake_output(index: usize, account: usize, value: u64) -> WalletShieldedOutput {
+        let to = ExtendedSpendingKey::master(&[])
+            .default_address()
+            .unwrap()
+            .1;
+        let note = to.create_note(value, Fs::zero(), &JUBJUB).unwrap();
+        let mut tree = CommitmentTree::new();
+        tree.append(Node::blank()).unwrap();
+        WalletShieldedOutput {
+            index,
+            cmu: note.cm(&JUBJUB),
+            epk: note.g_d.clone(),
+            account,
+            note,
+            to,
+            is_change: false,
+            witness: IncrementalWitness::from_tree(&tree),
+        }
+    }
+
+    fn fake_spend(index: usize, account: usize, nf: u8) -> WalletShieldedSpend {
+        WalletShieldedSpend {
+            index,
+            nf: vec![nf; 32],
+            nf_type: NullifierType::Sapling,
+            account,
+        }
+    }
+
+    #[test]
+    fn net_value_for_account() {
+        let tx = WalletTx {
+            txid: TxId([0; 32]),
+            index: 0,
+            num_spends: 2,
+            num_outputs: 3,
+            shielded_spends: vec![fake_spend(0, 0, 1), fake_spend(1, 1, 2)],
+            shielded_outputs: vec![
+                fake_output(0, 0, 3),
+                fake_output(1, 1, 10),
+                fake_output(2, 1, 4),
+            ],
+        };
+        let mut spent_note_values = HashMap::new();
+        spent_note_values.insert(vec![1; 32], Amount::from_u64(8).unwrap());
+        spent_note_values.insert(vec![2; 32], Amount::from_u64(5).unwrap());
+
+        // Account 0 spent 8 and received 3 in change
+        assert_eq!(
+            tx.net_value_for_account(0, &spent_note_values),
+            Some(Amount::from_i64(-5).unwrap())
+        );
+        // Account 1 spent 5 and received 14
+        assert_eq!(
+            tx.net_value_for_account(1, &spent_note_values),
+            Some(Amount::from_u64(9).unwrap())
+        );
+        // Account 2 was not involved
+        assert_eq!(
+            tx.net_value_for_account(2, &spent_note_values),
+            Some(Amount::zero())
+        );
+
+        // The values of spent notes must be known
+        spent_note_values.remove(&vec![1; 32]);
+        assert_eq!(tx.net_value_for_account(0, &spent_note_values), None);
+    }
+
+    #[test]
+    fn spend_formats_nullifier_as_hex() {
+        let spend = WalletShieldedSpend {
+            index: 2,
+            nf: vec![0xab; 4],
+            nf_type: NullifierType::Sapling,
+            account: 1,
+        };
+        assert_eq!(spend.to_string(), "spend 2 from account 1 (nf abababab)");
+        assert_eq!(
+            format!("{:?}", spend),
+            "WalletShieldedSpend { index: 2, nf: \"abababab\", nf_type: Sapling, account: 1 }"
+        );
+    }
+}
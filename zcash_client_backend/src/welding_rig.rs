@@ -1,8 +1,7 @@
 //! Tools for scanning a compact representation of the Zcash block chain.
 
 use ff::PrimeField;
-use std::collections::HashSet;
-use subtle::{ConditionallySelectable, ConstantTimeEq, CtOption};
+use std::collections::{HashMap, HashSet};
 use zcash_primitives::{
     jubjub::fs::Fs,
     merkle_tree::{CommitmentTree, IncrementalWitness},
@@ -13,18 +12,21 @@ use zcash_primitives::{
 };
 
 use crate::proto::compact_formats::{CompactBlock, CompactOutput};
-use crate::wallet::{WalletShieldedOutput, WalletShieldedSpend, WalletTx};
+use crate::validation::COMPACT_NOTE_SIZE;
+use crate::wallet::{NullifierType, WalletShieldedOutput, WalletShieldedSpend, WalletTx};
 
 /// Scans a [`CompactOutput`] with a set of [`ExtendedFullViewingKey`]s.
 ///
 /// Returns a [`WalletShieldedOutput`] and corresponding [`IncrementalWitness`] if this
-/// output belongs to any of the given [`ExtendedFullViewingKey`]s.
+/// output belongs to any of the given [`ExtendedFullViewingKey`]s. Trial decryption
+/// stops at the first account that can decrypt the output, so the output is attributed
+/// to the lowest-numbered such account, and the remaining keys are not tried.
 ///
 /// The given [`CommitmentTree`] and existing [`IncrementalWitness`]es are incremented
 /// with this output's commitment.
 fn scan_output(
     (index, output): (usize, CompactOutput),
-    ivks: &[Fs],
+    ivks: &[Option<Fs>],
     spent_from_accounts: &HashSet<usize>,
     tree: &mut CommitmentTree<Node>,
     existing_witnesses: &mut [&mut IncrementalWitness<Node>],
@@ -32,7 +34,6 @@ fn scan_output(
     new_witnesses: &mut [&mut IncrementalWitness<Node>],
 ) -> Option<WalletShieldedOutput> {
     let cmu = output.cmu().ok()?;
-    let epk = output.epk().ok()?;
     let ct = output.ciphertext;
 
     // Increment tree and witnesses
@@ -48,7 +49,23 @@ fn scan_output(
     }
     tree.append(node).unwrap();
 
+    // A truncated ciphertext can't be decrypted, but its commitment is still in the tree
+    if ct.len() != COMPACT_NOTE_SIZE {
+        return None;
+    }
+
+    // Nor can an output whose epk is not a prime-order point
+    let epk = match output.epk() {
+        Ok(epk) => epk,
+        Err(()) => return None,
+    };
+
     for (account, ivk) in ivks.iter().enumerate() {
+        let ivk = match ivk {
+            Some(ivk) => ivk,
+            // The account did not exist when this output was created.
+            None => continue,
+        };
         let (note, to) = match try_sapling_compact_note_decryption(ivk, &epk, &cmu, &ct) {
             Some(ret) => ret,
             None => continue,
@@ -81,45 +98,142 @@ fn scan_output(
 /// Returns a vector of [`WalletTx`]s belonging to any of the given
 /// [`ExtendedFullViewingKey`]s, and the corresponding new [`IncrementalWitness`]es.
 ///
+/// Outputs are appended to the commitment tree in the order they appear in the block,
+/// so the returned transactions, and the outputs within each transaction, are already
+/// ordered by their positions in the tree (as given by [`IncrementalWitness::position`]).
+///
+/// `birthdays[i]` is the height of the earliest block that may contain outputs for
+/// `extfvks[i]`. Outputs are not trial-decrypted with the keys of accounts whose
+/// birthdays are after this block. Accounts without a corresponding entry in `birthdays`
+/// are scanned in every block.
+///
+/// A transaction may have at most `u32::MAX` outputs, so that each output's index fits
+/// in a `u32`. The Sapling commitment tree holds at most 2^32 notes, so no valid
+/// transaction exceeds this.
+///
+/// `nullifiers` maps the nullifiers of the wallet's unspent notes to the accounts that
+/// received them. Spends of these notes are returned as [`WalletShieldedSpend`]s. If a
+/// block spends the same nullifier more than once, only the first spend is returned.
+///
 /// The given [`CommitmentTree`] and existing [`IncrementalWitness`]es are
 /// incremented appropriately.
+///
+/// # Panics
+///
+/// Panics if a transaction in the block has more than `u32::MAX` outputs.
 pub fn scan_block(
     block: CompactBlock,
     extfvks: &[ExtendedFullViewingKey],
-    nullifiers: &[(&[u8], usize)],
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
     tree: &mut CommitmentTree<Node>,
     existing_witnesses: &mut [&mut IncrementalWitness<Node>],
 ) -> Vec<WalletTx> {
-    let mut wtxs: Vec<WalletTx> = vec![];
     let ivks: Vec<_> = extfvks.iter().map(|extfvk| extfvk.fvk.vk.ivk()).collect();
+    scan_block_with_ivks(
+        block,
+        &ivks,
+        birthdays,
+        nullifiers,
+        tree,
+        existing_witnesses,
+    )
+}
 
+/// The [`ExtendedFullViewingKey`]s of a set of accounts, along with their incoming
+/// viewing keys.
+///
+/// Deriving an incoming viewing key requires a BLAKE2s hash and a scalar reduction, so a
+/// `WalletKeyCache` should be reused when scanning many blocks with the same accounts.
+pub struct WalletKeyCache {
+    extfvks: Vec<ExtendedFullViewingKey>,
+    ivks: Vec<Fs>,
+}
+
+impl WalletKeyCache {
+    /// Derives the incoming viewing keys for the given accounts.
+    pub fn new(extfvks: Vec<ExtendedFullViewingKey>) -> Self {
+        let ivks = extfvks.iter().map(|extfvk| extfvk.fvk.vk.ivk()).collect();
+        WalletKeyCache { extfvks, ivks }
+    }
+
+    /// Returns the [`ExtendedFullViewingKey`]s of the accounts, indexed by account.
+    pub fn extfvks(&self) -> &[ExtendedFullViewingKey] {
+        &self.extfvks
+    }
+}
+
+/// Scans a [`CompactBlock`] in the same way as [`scan_block`], using the incoming
+/// viewing keys in `keys` instead of deriving them from each account's
+/// [`ExtendedFullViewingKey`].
+pub fn scan_block_with_key_cache(
+    block: CompactBlock,
+    keys: &WalletKeyCache,
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
+    tree: &mut CommitmentTree<Node>,
+    existing_witnesses: &mut [&mut IncrementalWitness<Node>],
+) -> Vec<WalletTx> {
+    scan_block_with_ivks(
+        block,
+        &keys.ivks,
+        birthdays,
+        nullifiers,
+        tree,
+        existing_witnesses,
+    )
+}
+
+fn scan_block_with_ivks(
+    block: CompactBlock,
+    ivks: &[Fs],
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
+    tree: &mut CommitmentTree<Node>,
+    existing_witnesses: &mut [&mut IncrementalWitness<Node>],
+) -> Vec<WalletTx> {
+    let mut wtxs: Vec<WalletTx> = vec![];
+    let ivks: Vec<_> = ivks
+        .iter()
+        .enumerate()
+        .map(|(account, ivk)| match birthdays.get(account) {
+            Some(&birthday) if block.height < u64::from(birthday) => None,
+            _ => Some(*ivk),
+        })
+        .collect();
+
+    let mut spent_in_block = HashSet::new();
     for tx in block.vtx.into_iter() {
         let num_spends = tx.spends.len();
         let num_outputs = tx.outputs.len();
+        assert!(
+            num_outputs <= u32::max_value() as usize,
+            "a transaction may have at most u32::MAX outputs"
+        );
 
         // Check for spent notes
-        // The only step that is not constant-time is the filter() at the end.
+        // Looking up each nullifier in a map takes constant time in the number of
+        // tracked notes, but unlike a constant-time comparison against every tracked
+        // nullifier, the lookup's timing depends on whether the spend is ours.
         let shielded_spends: Vec<_> = tx
             .spends
             .into_iter()
             .enumerate()
-            .map(|(index, spend)| {
-                // Find the first tracked nullifier that matches this spend, and produce
-                // a WalletShieldedSpend if there is a match, in constant time.
-                nullifiers
-                    .iter()
-                    .map(|&(nf, account)| CtOption::new(account as u64, nf.ct_eq(&spend.nf[..])))
-                    .fold(CtOption::new(0, 0.into()), |first, next| {
-                        CtOption::conditional_select(&next, &first, first.is_some())
-                    })
-                    .map(|account| WalletShieldedSpend {
-                        index,
-                        nf: spend.nf,
-                        account: account as usize,
-                    })
+            .filter_map(|(index, spend)| {
+                let account = *nullifiers.get(&spend.nf[..])?;
+                // A valid chain never spends a nullifier twice, but a cache might. Only
+                // the first spend in the block is reported, so the note is never marked
+                // as spent by more than one transaction.
+                if !spent_in_block.insert(spend.nf.clone()) {
+                    return None;
+                }
+                Some(WalletShieldedSpend {
+                    index,
+                    nf: spend.nf,
+                    nf_type: NullifierType::Sapling,
+                    account,
+                })
             })
-            .filter(|spend| spend.is_some().into())
-            .map(|spend| spend.unwrap())
             .collect();
 
         // Collect the set of accounts that were spent from in this transaction
@@ -182,14 +296,54 @@ pub fn scan_block(
     wtxs
 }
 
+/// The result of scanning a block with [`scan_block_pure`].
+pub struct ScanBlockResult {
+    /// The commitment tree, incremented with every output in the block.
+    pub tree: CommitmentTree<Node>,
+    /// The given witnesses, incremented with every output in the block.
+    pub witnesses: Vec<IncrementalWitness<Node>>,
+    /// The transactions in the block that belong to the given keys.
+    pub wallet_txs: Vec<WalletTx>,
+}
+
+/// Scans a [`CompactBlock`] in the same way as [`scan_block`], but takes ownership of
+/// the [`CommitmentTree`] and existing [`IncrementalWitness`]es and returns their updated
+/// values instead of mutating them in place.
+pub fn scan_block_pure(
+    block: CompactBlock,
+    extfvks: &[ExtendedFullViewingKey],
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
+    mut tree: CommitmentTree<Node>,
+    mut witnesses: Vec<IncrementalWitness<Node>>,
+) -> ScanBlockResult {
+    let wallet_txs = {
+        let mut witness_refs: Vec<_> = witnesses.iter_mut().collect();
+        scan_block(
+            block,
+            extfvks,
+            birthdays,
+            nullifiers,
+            &mut tree,
+            &mut witness_refs,
+        )
+    };
+
+    ScanBlockResult {
+        tree,
+        witnesses,
+        wallet_txs,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use ff::{Field, PrimeField, PrimeFieldRepr};
     use pairing::bls12_381::{Bls12, Fr};
-    use rand_core::RngCore;
     use rand_os::OsRng;
+    use std::collections::HashMap;
     use zcash_primitives::{
-        jubjub::{fs::Fs, FixedGenerators, JubjubParams, ToUniform},
+        jubjub::{edwards, fs::Fs, Unknown},
         merkle_tree::CommitmentTree,
         note_encryption::{Memo, SaplingNoteEncryption},
         primitives::Note,
@@ -198,46 +352,11 @@ mod tests {
         JUBJUB,
     };
 
-    use super::scan_block;
-    use crate::proto::compact_formats::{CompactBlock, CompactOutput, CompactSpend, CompactTx};
-
-    fn random_compact_tx<R: RngCore>(rng: &mut R) -> CompactTx {
-        let fake_nf = {
-            let mut nf = vec![0; 32];
-            rng.fill_bytes(&mut nf);
-            nf
-        };
-        let fake_cmu = {
-            let fake_cmu = Fr::random(rng);
-            let mut bytes = vec![];
-            fake_cmu.into_repr().write_le(&mut bytes).unwrap();
-            bytes
-        };
-        let fake_epk = {
-            let mut buffer = vec![0; 64];
-            rng.fill_bytes(&mut buffer);
-            let fake_esk = Fs::to_uniform(&buffer[..]);
-            let fake_epk = JUBJUB
-                .generator(FixedGenerators::SpendingKeyGenerator)
-                .mul(fake_esk, &JUBJUB);
-            let mut bytes = vec![];
-            fake_epk.write(&mut bytes).unwrap();
-            bytes
-        };
-        let mut cspend = CompactSpend::new();
-        cspend.set_nf(fake_nf);
-        let mut cout = CompactOutput::new();
-        cout.set_cmu(fake_cmu);
-        cout.set_epk(fake_epk);
-        cout.set_ciphertext(vec![0; 52]);
-        let mut ctx = CompactTx::new();
-        let mut txid = vec![0; 32];
-        rng.fill_bytes(&mut txid);
-        ctx.set_hash(txid);
-        ctx.spends.push(cspend);
-        ctx.outputs.push(cout);
-        ctx
-    }
+    use super::{scan_block, scan_block_pure, scan_block_with_key_cache, WalletKeyCache};
+    use crate::proto::compact_formats::{CompactBlock, CompactOutput, CompactTx};
+    use crate::testing::CompactBlockBuilder;
+    use crate::validation::{validate_compact_block, ValidationError, COMPACT_NOTE_SIZE};
+    use crate::wallet::NullifierType;
 
     /// Create a fake CompactBlock at the given height, with a transaction containing a
     /// single spend of the given nullifier and a single output paying the given address.
@@ -249,63 +368,21 @@ mod tests {
         value: Amount,
         tx_after: bool,
     ) -> CompactBlock {
-        let to = extfvk.default_address().unwrap().1;
-
-        // Create a fake Note for the account
         let mut rng = OsRng;
-        let note = Note {
-            g_d: to.diversifier.g_d::<Bls12>(&JUBJUB).unwrap(),
-            pk_d: to.pk_d.clone(),
-            value: value.into(),
-            r: Fs::random(&mut rng),
-        };
-        let encryptor = SaplingNoteEncryption::new(
-            extfvk.fvk.ovk,
-            note.clone(),
-            to.clone(),
-            Memo::default(),
-            &mut rng,
-        );
-        let mut cmu = vec![];
-        note.cm(&JUBJUB).into_repr().write_le(&mut cmu).unwrap();
-        let mut epk = vec![];
-        encryptor.epk().write(&mut epk).unwrap();
-        let enc_ciphertext = encryptor.encrypt_note_plaintext();
-
-        // Create a fake CompactBlock containing the note
-        let mut cb = CompactBlock::new();
-        cb.set_height(height as u64);
+        let mut builder = CompactBlockBuilder::new(height as u32, [0; 32]);
 
         // Add a random Sapling tx before ours
-        {
-            let mut tx = random_compact_tx(&mut rng);
-            tx.index = cb.vtx.len() as u64;
-            cb.vtx.push(tx);
-        }
+        builder.add_random_tx(&mut rng);
 
-        let mut cspend = CompactSpend::new();
-        cspend.set_nf(nf.to_vec());
-        let mut cout = CompactOutput::new();
-        cout.set_cmu(cmu);
-        cout.set_epk(epk);
-        cout.set_ciphertext(enc_ciphertext[..52].to_vec());
-        let mut ctx = CompactTx::new();
-        let mut txid = vec![0; 32];
-        rng.fill_bytes(&mut txid);
-        ctx.set_hash(txid);
-        ctx.spends.push(cspend);
-        ctx.outputs.push(cout);
-        ctx.index = cb.vtx.len() as u64;
-        cb.vtx.push(ctx);
+        builder.add_sapling_spend(&nf);
+        builder.add_sapling_output(&extfvk, value, Memo::default());
 
         // Optionally add another random Sapling tx after ours
         if tx_after {
-            let mut tx = random_compact_tx(&mut rng);
-            tx.index = cb.vtx.len() as u64;
-            cb.vtx.push(tx);
+            builder.add_random_tx(&mut rng);
         }
 
-        cb
+        builder.build()
     }
 
     #[test]
@@ -323,7 +400,7 @@ mod tests {
         assert_eq!(cb.vtx.len(), 2);
 
         let mut tree = CommitmentTree::new();
-        let txs = scan_block(cb, &[extfvk], &[], &mut tree, &mut []);
+        let txs = scan_block(cb, &[extfvk], &[], &HashMap::new(), &mut tree, &mut []);
         assert_eq!(txs.len(), 1);
 
         let tx = &txs[0];
@@ -355,7 +432,7 @@ mod tests {
         assert_eq!(cb.vtx.len(), 3);
 
         let mut tree = CommitmentTree::new();
-        let txs = scan_block(cb, &[extfvk], &[], &mut tree, &mut []);
+        let txs = scan_block(cb, &[extfvk], &[], &HashMap::new(), &mut tree, &mut []);
         assert_eq!(txs.len(), 1);
 
         let tx = &txs[0];
@@ -370,6 +447,9 @@ mod tests {
 
         // Check that the witness root matches
         assert_eq!(tx.shielded_outputs[0].witness.root(), tree.root());
+
+        // Our output follows the output of the random tx before it in the tree
+        assert_eq!(tx.shielded_outputs[0].witness.position(), 1);
     }
 
     #[test]
@@ -383,7 +463,14 @@ mod tests {
         assert_eq!(cb.vtx.len(), 2);
 
         let mut tree = CommitmentTree::new();
-        let txs = scan_block(cb, &[], &[(&nf, account)], &mut tree, &mut []);
+        let txs = scan_block(
+            cb,
+            &[],
+            &[],
+            &[(nf, account)].iter().cloned().collect(),
+            &mut tree,
+            &mut [],
+        );
         assert_eq!(txs.len(), 1);
 
         let tx = &txs[0];
@@ -394,6 +481,395 @@ mod tests {
         assert_eq!(tx.shielded_outputs.len(), 0);
         assert_eq!(tx.shielded_spends[0].index, 0);
         assert_eq!(tx.shielded_spends[0].nf, nf);
+        assert_eq!(tx.shielded_spends[0].nf_type, NullifierType::Sapling);
         assert_eq!(tx.shielded_spends[0].account, account);
     }
+
+    #[test]
+    fn scan_block_with_double_nullifier() {
+        let nf = [7; 32];
+        let account = 12;
+
+        // Two transactions spending the same nullifier, which is invalid on-chain but
+        // could be found in a corrupted cache
+        let mut builder = CompactBlockBuilder::new(1, [0; 32]);
+        builder.add_sapling_spend(&nf);
+        builder.add_random_tx(&mut OsRng);
+        builder.add_sapling_spend(&[1; 32]);
+        builder.add_sapling_spend(&nf);
+        let cb = builder.build();
+        assert_eq!(cb.vtx.len(), 3);
+
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb,
+            &[],
+            &[],
+            &[(nf, account)].iter().cloned().collect(),
+            &mut tree,
+            &mut [],
+        );
+
+        // First occurrence wins: the spend is only reported in the first transaction
+        assert_eq!(txs.len(), 1);
+        let tx = &txs[0];
+        assert_eq!(tx.index, 0);
+        assert_eq!(tx.shielded_spends.len(), 1);
+        assert_eq!(tx.shielded_spends[0].index, 0);
+        assert_eq!(tx.shielded_spends[0].nf, nf);
+        assert_eq!(tx.shielded_spends[0].account, account);
+    }
+
+    #[test]
+    fn scan_block_with_key_cache_matches_scan_block() {
+        let extfvks = vec![
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[])),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1])),
+        ];
+        let keys = WalletKeyCache::new(extfvks.clone());
+        assert_eq!(keys.extfvks(), &extfvks[..]);
+
+        let cb = fake_compact_block(
+            1,
+            [0; 32],
+            extfvks[1].clone(),
+            Amount::from_u64(5).unwrap(),
+            true,
+        );
+
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb.clone(),
+            &extfvks,
+            &[],
+            &HashMap::new(),
+            &mut tree,
+            &mut [],
+        );
+        let mut cached_tree = CommitmentTree::new();
+        let cached_txs =
+            scan_block_with_key_cache(cb, &keys, &[], &HashMap::new(), &mut cached_tree, &mut []);
+
+        assert_eq!(cached_tree.root(), tree.root());
+        assert_eq!(cached_txs.len(), 1);
+        assert_eq!(cached_txs[0].txid, txs[0].txid);
+        assert_eq!(cached_txs[0].shielded_outputs[0].account, 1);
+        assert_eq!(
+            cached_txs[0].shielded_outputs[0].note.value,
+            txs[0].shielded_outputs[0].note.value
+        );
+    }
+
+    #[test]
+    fn scan_block_stops_at_first_matching_account() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+
+        let cb = fake_compact_block(
+            1,
+            [0; 32],
+            extfvk.clone(),
+            Amount::from_u64(5).unwrap(),
+            false,
+        );
+
+        // Both accounts can decrypt the output, but it is only detected once
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb,
+            &[extfvk.clone(), extfvk],
+            &[],
+            &HashMap::new(),
+            &mut tree,
+            &mut [],
+        );
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].shielded_outputs.len(), 1);
+        assert_eq!(txs[0].shielded_outputs[0].account, 0);
+    }
+
+    #[test]
+    fn scan_block_detects_change() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let extfvk2 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1]));
+        let nf = [7; 32];
+
+        let cb = fake_compact_block(1, nf, extfvk.clone(), Amount::from_u64(5).unwrap(), false);
+
+        // Account 0 spends a note and receives an output in the same tx
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb.clone(),
+            &[extfvk.clone(), extfvk2.clone()],
+            &[],
+            &[(nf, 0)].iter().cloned().collect(),
+            &mut tree,
+            &mut [],
+        );
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].shielded_spends[0].account, 0);
+        assert_eq!(txs[0].shielded_outputs[0].account, 0);
+        assert!(txs[0].shielded_outputs[0].is_change);
+
+        // Account 1 spends a note, and account 0 receives an output
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb,
+            &[extfvk, extfvk2],
+            &[],
+            &[(nf, 1)].iter().cloned().collect(),
+            &mut tree,
+            &mut [],
+        );
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].shielded_spends[0].account, 1);
+        assert_eq!(txs[0].shielded_outputs[0].account, 0);
+        assert!(!txs[0].shielded_outputs[0].is_change);
+    }
+
+    #[test]
+    fn scan_block_skips_accounts_before_birthday() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+
+        let cb = fake_compact_block(
+            1,
+            [0; 32],
+            extfvk.clone(),
+            Amount::from_u64(5).unwrap(),
+            false,
+        );
+
+        // The account was created after this block, so its output is not detected
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb.clone(),
+            &[extfvk.clone()],
+            &[2],
+            &HashMap::new(),
+            &mut tree,
+            &mut [],
+        );
+        assert!(txs.is_empty());
+        assert_eq!(tree.size(), 2);
+
+        // The output is detected from the account's birthday onwards
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(cb, &[extfvk], &[1], &HashMap::new(), &mut tree, &mut []);
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].shielded_outputs[0].account, 0);
+    }
+
+    #[test]
+    fn scan_block_pure_returns_updated_state() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+
+        let cb = fake_compact_block(
+            1,
+            [0; 32],
+            extfvk.clone(),
+            Amount::from_u64(5).unwrap(),
+            false,
+        );
+        let cb2 = fake_compact_block(
+            2,
+            [0; 32],
+            extfvk.clone(),
+            Amount::from_u64(7).unwrap(),
+            false,
+        );
+
+        let before = CommitmentTree::new();
+        let first = scan_block_pure(
+            cb,
+            &[extfvk.clone()],
+            &[],
+            &HashMap::new(),
+            before.clone(),
+            vec![],
+        );
+        assert_eq!(before.size(), 0);
+        assert_eq!(first.tree.size(), 2);
+        assert_eq!(first.wallet_txs.len(), 1);
+
+        // Carry the new witness into the next block
+        let witness = first.wallet_txs[0].shielded_outputs[0].witness.clone();
+        let second = scan_block_pure(
+            cb2,
+            &[extfvk],
+            &[],
+            &HashMap::new(),
+            first.tree,
+            vec![witness],
+        );
+        assert_eq!(second.tree.size(), 4);
+        assert_eq!(second.witnesses.len(), 1);
+        assert_eq!(second.witnesses[0].position(), 1);
+        assert_eq!(second.witnesses[0].root(), second.tree.root());
+        assert_eq!(
+            second.wallet_txs[0].shielded_outputs[0].witness.root(),
+            second.tree.root()
+        );
+    }
+
+    #[test]
+    fn scan_block_skips_malformed_outputs() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+
+        let cb = fake_compact_block(
+            1,
+            [0; 32],
+            extfvk.clone(),
+            Amount::from_u64(5).unwrap(),
+            false,
+        );
+
+        // Returns the size of the tree after scanning the block
+        let check = |cb: CompactBlock, validation: Result<(), ValidationError>| {
+            assert_eq!(validate_compact_block(&cb), validation);
+            let mut tree = CommitmentTree::new();
+            let txs = scan_block(
+                cb,
+                &[extfvk.clone()],
+                &[],
+                &HashMap::new(),
+                &mut tree,
+                &mut [],
+            );
+            assert!(txs.is_empty());
+            tree.size()
+        };
+        let outputs = cb.vtx.iter().map(|tx| tx.outputs.len()).sum::<usize>();
+
+        // The field modulus is not a valid cmu
+        let mut bad = cb.clone();
+        bad.vtx[1].outputs[0].set_cmu(vec![0xff; 32]);
+        check(bad, Err(ValidationError::InvalidCmu(1, 0)));
+
+        // An out-of-range y-coordinate is not a valid epk, but the block is valid and the
+        // output's commitment is still appended to the tree
+        let mut bad = cb.clone();
+        bad.vtx[1].outputs[0].set_epk(vec![0xff; 32]);
+        assert_eq!(check(bad, Ok(())), outputs);
+
+        // The point (0, -1) is on the curve, but has order 2
+        let mut y = Fr::one();
+        y.negate();
+        let mut low_order_epk = vec![];
+        edwards::Point::<Bls12, Unknown>::get_for_y(y, false, &JUBJUB)
+            .unwrap()
+            .write(&mut low_order_epk)
+            .unwrap();
+        let mut bad = cb.clone();
+        bad.vtx[1].outputs[0].set_epk(low_order_epk);
+        assert_eq!(check(bad, Ok(())), outputs);
+
+        // A truncated ciphertext cannot be decrypted
+        let mut bad = cb;
+        bad.vtx[1].outputs[0]
+            .ciphertext
+            .truncate(COMPACT_NOTE_SIZE - 1);
+        assert_eq!(
+            check(bad, Err(ValidationError::InvalidCiphertextLength(1, 0))),
+            outputs
+        );
+    }
+
+    #[test]
+    fn scan_block_handles_empty_blocks() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let nf = [7; 32];
+
+        // A block with no transactions
+        let cb = CompactBlockBuilder::new(1, [0; 32]).build();
+        assert!(cb.vtx.is_empty());
+        assert_eq!(validate_compact_block(&cb), Ok(()));
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(
+            cb,
+            &[extfvk.clone()],
+            &[],
+            &[(nf, 0)].iter().cloned().collect(),
+            &mut tree,
+            &mut [],
+        );
+        assert!(txs.is_empty());
+        assert_eq!(tree.size(), 0);
+
+        // A transaction with no outputs
+        let mut builder = CompactBlockBuilder::new(1, [0; 32]);
+        builder.add_sapling_spend(&nf);
+        let cb = builder.build();
+        assert!(cb.vtx[0].outputs.is_empty());
+        let txs = scan_block(
+            cb,
+            &[extfvk.clone()],
+            &[],
+            &[(nf, 0)].iter().cloned().collect(),
+            &mut tree,
+            &mut [],
+        );
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].num_outputs, 0);
+        assert_eq!(txs[0].shielded_spends.len(), 1);
+        assert_eq!(tree.size(), 0);
+
+        // A block at height zero is rejected by validation, but can still be scanned
+        let cb = fake_compact_block(
+            0,
+            [0; 32],
+            extfvk.clone(),
+            Amount::from_u64(5).unwrap(),
+            false,
+        );
+        assert_eq!(
+            validate_compact_block(&cb),
+            Err(ValidationError::ZeroHeight)
+        );
+        let txs = scan_block(cb, &[extfvk], &[], &HashMap::new(), &mut tree, &mut []);
+        assert_eq!(txs.len(), 1);
+    }
+
+    #[test]
+    fn scan_block_accepts_any_note_value() {
+        let mut rng = OsRng;
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let to = extfvk.default_address().unwrap().1;
+
+        // A note whose value is larger than any valid Amount
+        let note = Note {
+            g_d: to.diversifier.g_d::<Bls12>(&JUBJUB).unwrap(),
+            pk_d: to.pk_d.clone(),
+            value: u64::max_value(),
+            r: Fs::random(&mut rng),
+        };
+        let encryptor =
+            SaplingNoteEncryption::new(extfvk.fvk.ovk, note.clone(), to, Memo::default(), &mut rng);
+        let mut cmu = vec![];
+        note.cm(&JUBJUB).into_repr().write_le(&mut cmu).unwrap();
+        let mut epk = vec![];
+        encryptor.epk().write(&mut epk).unwrap();
+
+        let mut cout = CompactOutput::new();
+        cout.set_cmu(cmu);
+        cout.set_epk(epk);
+        cout.set_ciphertext(encryptor.encrypt_note_plaintext()[..COMPACT_NOTE_SIZE].to_vec());
+        let mut ctx = CompactTx::new();
+        ctx.set_hash(vec![0; 32]);
+        ctx.outputs.push(cout);
+        let mut cb = CompactBlockBuilder::new(1, [0; 32]).build();
+        cb.vtx.push(ctx);
+        assert_eq!(validate_compact_block(&cb), Ok(()));
+
+        // The value is returned unchanged, for the caller to range-check
+        let mut tree = CommitmentTree::new();
+        let txs = scan_block(cb, &[extfvk], &[], &HashMap::new(), &mut tree, &mut []);
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].shielded_outputs[0].note.value, u64::max_value());
+    }
 }
@@ -0,0 +1,79 @@
+//! Writes the data database fixture used by `test_cross_version_db_compat`.
+//!
+//! Run this when releasing a version of this crate that changes the data database
+//! schema, and commit the resulting `testdata/wallet_v<N>.db`, where `N` is the
+//! released schema version:
+//!
+//! ```text
+//! cargo run --example generate_testdata
+//! ```
+//!
+//! Existing fixtures are never overwritten, because they record what earlier
+//! releases of this crate wrote. `wallet_v0.db` has the original schema, from before
+//! the schema version was recorded, and each later fixture has the tables and indices
+//! that `init_data_database` created in the release with that `DATA_DB_VERSION`. The
+//! account is a testnet account, so the fixtures must be generated without the
+//! `mainnet` feature.
+
+use rusqlite::{Connection, NO_PARAMS};
+use std::fs;
+use std::path::Path;
+use zcash_client_sqlite::init::{init_accounts_table, init_data_database};
+use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+/// Two mined transactions: the first receives 50000 and 7000 zatoshis, and the
+/// second spends the 7000 note and receives 5000 zatoshis of change. Account 0
+/// therefore has a balance of 55000 zatoshis.
+const FIXTURE: &str = "
+    INSERT INTO blocks (height, hash, time, sapling_tree) VALUES
+        (280000, zeroblob(32), 1600000000, x'000000'),
+        (280001, zeroblob(32), 1600000075, x'000000');
+    INSERT INTO transactions (id_tx, txid, block, tx_index) VALUES
+        (1, x'1111111111111111111111111111111111111111111111111111111111111111', 280000, 0),
+        (2, x'2222222222222222222222222222222222222222222222222222222222222222', 280001, 0);
+    INSERT INTO received_notes (
+        id_note, tx, output_index, account, diversifier, value, rcm, nf, is_change, spent,
+        commitment_tree_position
+    ) VALUES
+        (1, 1, 0, 0, zeroblob(11), 50000, zeroblob(32),
+            x'0101010101010101010101010101010101010101010101010101010101010101', 0, NULL, 0),
+        (2, 1, 1, 0, zeroblob(11), 7000, zeroblob(32),
+            x'0202020202020202020202020202020202020202020202020202020202020202', 0, 2, 1),
+        (3, 2, 0, 0, zeroblob(11), 5000, zeroblob(32),
+            x'0303030303030303030303030303030303030303030303030303030303030303', 1, NULL, 2);
+";
+
+fn main() {
+    let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    fs::create_dir_all(&testdata).unwrap();
+
+    // Build the database under a temporary name, as its version is only known once
+    // the current schema has been created.
+    let db_data = testdata.join("wallet_new.db");
+    if db_data.exists() {
+        fs::remove_file(&db_data).unwrap();
+    }
+
+    init_data_database(&db_data).unwrap();
+    let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+    init_accounts_table(&db_data, &[extfvk]).unwrap();
+
+    let version: i32 = {
+        let data = Connection::open(&db_data).unwrap();
+        data.execute_batch(FIXTURE).unwrap();
+        data.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    };
+
+    let fixture = testdata.join(format!("wallet_v{}.db", version));
+    if fixture.exists() {
+        fs::remove_file(&db_data).unwrap();
+        eprintln!(
+            "{} already exists; bump DATA_DB_VERSION before generating a new fixture",
+            fixture.display()
+        );
+        std::process::exit(1);
+    }
+    fs::rename(&db_data, &fixture).unwrap();
+    println!("Wrote {}", fixture.display());
+}
@@ -18,6 +18,7 @@ use zcash_client_backend::constants::testnet::{
 };
 
 /// An address that funds can be sent to.
+#[derive(Clone)]
 pub enum RecipientAddress {
     Shielded(PaymentAddress<Bls12>),
     Transparent(TransparentAddress),
@@ -0,0 +1,1385 @@
+//! Functions for populating the cache database and managing its size.
+//!
+//! Blocks that have been scanned are no longer needed in the cache, except to re-scan
+//! them after a chain reorg. The pruning functions can be used to remove old blocks,
+//! keeping only as many as the wallet might need to roll back.
+
+use protobuf::{parse_from_bytes, Message};
+use rusqlite::{types::ToSql, Connection, OpenFlags, NO_PARAMS};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use zcash_client_backend::{
+    proto::compact_formats::CompactBlock,
+    validation::{validate_block_hash, validate_compact_block},
+};
+use zcash_primitives::block::BlockHash;
+
+#[cfg(feature = "rpc")]
+use std::{panic, sync::mpsc, thread};
+#[cfg(feature = "rpc")]
+use zcash_client_backend::rpc::ZcashdClient;
+
+use crate::{
+    error::{Error, ErrorKind},
+    init::CacheConfig,
+    query::open_wallet_readonly,
+    scan::ScanConfig,
+    SAPLING_ACTIVATION_HEIGHT,
+};
+
+#[cfg(feature = "rpc")]
+use crate::scan::scan_cached_blocks;
+
+/// The default number of blocks that [`download_blocks`] fetches before writing them to
+/// the cache database.
+pub const DEFAULT_DOWNLOAD_CHUNK_SIZE: u32 = 1000;
+
+/// The largest block that [`read_block_stream`] will read, matching the default limit
+/// applied when scanning.
+const MAX_STREAMED_BLOCK_SIZE: u64 = 2_000_000;
+
+/// The zstd compression level used for cached blocks.
+const CACHE_COMPRESSION_LEVEL: i32 = 3;
+
+/// Inserts the given blocks into the cache database, in a single transaction.
+///
+/// Blocks that are already in the cache are skipped. If the cache holds a different
+/// block at the height of a given block, the chain has been reorganized, so that block
+/// and all cached blocks above it are removed before the given block is inserted. The
+/// blocks should therefore be given in height order. Returns the number of blocks that
+/// were inserted.
+///
+/// Each block is checked with [`validate_compact_block`] and [`validate_block_hash`]
+/// first. If any block is malformed, an error with kind
+/// [`ErrorKind::InvalidCompactBlock`] is returned and nothing is inserted.
+pub fn insert_compact_blocks<P: AsRef<Path>>(
+    db_cache: P,
+    blocks: &[CompactBlock],
+) -> Result<u32, Error> {
+    let cache = Connection::open(db_cache)?;
+    insert_blocks(&cache, blocks)
+}
+
+fn insert_blocks(cache: &Connection, blocks: &[CompactBlock]) -> Result<u32, Error> {
+    for block in blocks {
+        validate_compact_block(block)
+            .and_then(|()| validate_block_hash(block))
+            .map_err(|e| Error(ErrorKind::InvalidCompactBlock(block.height as i32, e)))?;
+    }
+
+    let compress = is_compressed(cache)?;
+    let mut stmt_cached_block = cache.prepare(&format!(
+        "SELECT data, {} FROM compactblocks WHERE height = ?",
+        compressed_column(cache)?
+    ))?;
+    let mut stmt_truncate = cache.prepare("DELETE FROM compactblocks WHERE height >= ?")?;
+    let mut stmt_insert_block = cache.prepare(&format!(
+        "INSERT INTO compactblocks (height, data{}) VALUES (?, ?{})",
+        if compress { ", compressed" } else { "" },
+        if compress { ", ?" } else { "" },
+    ))?;
+
+    cache.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+    let mut inserted = 0;
+    for block in blocks {
+        let height = block.height as i64;
+
+        // A cached block with a different hash is from a chain that has since been
+        // reorganized away, as are the cached blocks above it. A cached block that
+        // cannot be read is replaced in the same way.
+        let cached_hash = match stmt_cached_block.query_row(&[height], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?))
+        }) {
+            Ok((data, compressed)) => Some(
+                decompress_block(
+                    height as i32,
+                    data,
+                    compressed,
+                    ScanConfig::default().max_block_size_bytes,
+                )
+                .ok()
+                .and_then(|data| parse_from_bytes::<CompactBlock>(&data).ok())
+                .map(|cached| cached.hash),
+            ),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+        match cached_hash {
+            Some(Some(ref hash)) if hash == &block.hash => continue,
+            Some(_) => {
+                stmt_truncate.execute(&[height])?;
+            }
+            None => (),
+        }
+
+        let data = block.write_to_bytes()?;
+        inserted += if compress {
+            let (data, compressed) = compress_block(data)?;
+            stmt_insert_block.execute(&[height.to_sql()?, data.to_sql()?, compressed.to_sql()?])?
+        } else {
+            stmt_insert_block.execute(&[height.to_sql()?, data.to_sql()?])?
+        } as u32;
+    }
+    cache.execute("COMMIT", NO_PARAMS)?;
+
+    Ok(inserted)
+}
+
+/// Returns whether the cache database has been set up to store compressed blocks, by
+/// [`init_cache_database_compressed`] or [`compress_cache`].
+///
+/// [`init_cache_database_compressed`]: crate::init::init_cache_database_compressed
+fn is_compressed(cache: &Connection) -> Result<bool, Error> {
+    Ok(cache
+        .prepare("SELECT name FROM pragma_table_info('compactblocks') WHERE name = 'compressed'")?
+        .exists(NO_PARAMS)?)
+}
+
+/// Adds the `compressed` column to the cache database, if it is not already present.
+///
+/// Blocks that are already cached are left uncompressed.
+pub(crate) fn enable_compression(cache: &Connection) -> Result<(), Error> {
+    if !is_compressed(cache)? {
+        cache.execute(
+            "ALTER TABLE compactblocks ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0",
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the SQL expression for whether a row of `compactblocks` is compressed, for
+/// use in queries that read cached blocks from any cache database.
+pub(crate) fn compressed_column(cache: &Connection) -> Result<&'static str, Error> {
+    Ok(if is_compressed(cache)? {
+        "compressed"
+    } else {
+        "0"
+    })
+}
+
+/// Compresses the serialized `data` of a block with zstd, returning the data to store
+/// and whether it was compressed.
+///
+/// Blocks with few transactions consist mostly of hashes, which do not compress, so the
+/// data is only compressed if that makes it smaller.
+fn compress_block(data: Vec<u8>) -> Result<(Vec<u8>, bool), Error> {
+    let compressed = zstd::block::compress(&data, CACHE_COMPRESSION_LEVEL)?;
+    Ok(if compressed.len() < data.len() {
+        (compressed, true)
+    } else {
+        (data, false)
+    })
+}
+
+/// Returns the serialized block at `height` stored as `data` in the cache database.
+///
+/// Blocks that are larger than `max_size` bytes once decompressed are rejected, without
+/// decompressing more than `max_size + 1` bytes.
+pub(crate) fn decompress_block(
+    height: i32,
+    data: Vec<u8>,
+    compressed: bool,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let data = if compressed {
+        let mut decompressed = vec![];
+        zstd::stream::Decoder::new(&data[..])
+            .and_then(|decoder| {
+                decoder
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut decompressed)
+            })
+            .map_err(|_| Error(ErrorKind::MalformedBlock(height)))?;
+        decompressed
+    } else {
+        data
+    };
+
+    if data.len() > max_size {
+        return Err(Error(ErrorKind::BlockTooLarge(height, max_size)));
+    }
+    Ok(data)
+}
+
+/// The space saved by [`compress_cache`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompressionStats {
+    /// The number of previously-uncompressed blocks that were checked.
+    pub blocks_checked: u64,
+    /// The number of blocks that were compressed. Blocks that would not be made smaller
+    /// are left uncompressed.
+    pub blocks_compressed: u64,
+    /// The total size in bytes of the checked blocks before compression.
+    pub bytes_before: u64,
+    /// The total size in bytes of the checked blocks after compression.
+    pub bytes_after: u64,
+}
+
+/// Compresses the blocks in the cache database with zstd, and sets up the cache to
+/// compress blocks as they are inserted.
+///
+/// Compressed blocks are decompressed transparently by the functions that read the
+/// cache, such as [`scan_cached_blocks`] and [`validate_combined_chain`]. Blocks that
+/// are inserted directly into the `compactblocks` table, without setting `compressed`,
+/// are stored and read uncompressed.
+///
+/// The cache database is vacuumed afterwards, so that the space saved is returned to the
+/// filesystem.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::compress_cache;
+///
+/// if let Ok(stats) = compress_cache("/path/to/cache.db") {
+///     println!("Saved {} bytes", stats.bytes_before - stats.bytes_after);
+/// }
+/// ```
+///
+/// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+/// [`validate_combined_chain`]: crate::chain::validate_combined_chain
+pub fn compress_cache<P: AsRef<Path>>(db_cache: P) -> Result<CompressionStats, Error> {
+    let cache = Connection::open(db_cache)?;
+    enable_compression(&cache)?;
+
+    let mut stats = CompressionStats::default();
+    cache.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+    {
+        let mut stmt_blocks =
+            cache.prepare("SELECT height, data FROM compactblocks WHERE compressed = 0")?;
+        let mut stmt_update_block =
+            cache.prepare("UPDATE compactblocks SET data = ?, compressed = 1 WHERE height = ?")?;
+
+        let rows = stmt_blocks.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        for row in rows {
+            let (height, data) = row?;
+            stats.blocks_checked += 1;
+            stats.bytes_before += data.len() as u64;
+
+            let (data, compressed) = compress_block(data)?;
+            stats.bytes_after += data.len() as u64;
+            if compressed {
+                stmt_update_block.execute(&[data.to_sql()?, height.to_sql()?])?;
+                stats.blocks_compressed += 1;
+            }
+        }
+    }
+    cache.execute("COMMIT", NO_PARAMS)?;
+    cache.execute("VACUUM", NO_PARAMS)?;
+
+    Ok(stats)
+}
+
+/// Writes the given blocks to `writer` as a stream of length-delimited protobuf messages.
+///
+/// Each block is prefixed with its encoded length as a varint. This can be used to save
+/// the contents of a cache database as a portable snapshot, which can be read with
+/// [`read_block_stream`]. Returns the number of blocks that were written.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::File;
+/// use zcash_client_sqlite::cache::write_block_stream;
+///
+/// if let Ok(file) = File::create("/path/to/blocks.bin") {
+///     let written = write_block_stream(vec![], file);
+/// }
+/// ```
+pub fn write_block_stream<I, W>(blocks: I, mut writer: W) -> Result<u64, Error>
+where
+    I: IntoIterator<Item = CompactBlock>,
+    W: Write,
+{
+    let mut written = 0;
+    for block in blocks {
+        block.write_length_delimited_to_writer(&mut writer)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Reads a stream of blocks written by [`write_block_stream`].
+///
+/// The returned iterator ends at the end of the stream, or after the first error. A
+/// stream that ends partway through a block is an error.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::File;
+/// use zcash_client_sqlite::cache::{insert_compact_blocks, read_block_stream};
+///
+/// if let Ok(file) = File::open("/path/to/blocks.bin") {
+///     let blocks: Result<Vec<_>, _> = read_block_stream(file).collect();
+///     if let Ok(blocks) = blocks {
+///         insert_compact_blocks("/path/to/cache.db", &blocks);
+///     }
+/// }
+/// ```
+pub fn read_block_stream<R: Read>(
+    mut reader: R,
+) -> impl Iterator<Item = Result<CompactBlock, Error>> {
+    let mut failed = false;
+    iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let next = read_streamed_block(&mut reader).transpose();
+        failed = match next {
+            Some(Err(_)) => true,
+            _ => false,
+        };
+        next
+    })
+}
+
+/// Reads the next length-delimited block from the stream, or `None` at the end of the
+/// stream.
+fn read_streamed_block<R: Read>(reader: &mut R) -> Result<Option<CompactBlock>, Error> {
+    // Read the varint length prefix.
+    let mut len = 0u64;
+    let mut i = 0;
+    loop {
+        let mut byte = [0];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && i == 0 => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        len |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        i += 1;
+        if i == 10 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid block length").into());
+        }
+    }
+
+    if len > MAX_STREAMED_BLOCK_SIZE {
+        return Err(
+            io::Error::new(io::ErrorKind::InvalidData, "Block in stream is too large").into(),
+        );
+    }
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some(parse_from_bytes(&data)?))
+}
+
+/// Fetches the blocks from `from_height` to `to_height` (inclusive) from a `zcashd` node,
+/// and inserts them into the cache database.
+///
+/// Blocks are written to the cache in chunks of [`DEFAULT_DOWNLOAD_CHUNK_SIZE`], so if
+/// an error occurs, the blocks in the chunks before it remain cached. Blocks that are
+/// already in the cache are skipped, so calling this again with the same range resumes
+/// the download. Returns the number of blocks that were inserted.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::rpc::ZcashdClient;
+/// use zcash_client_sqlite::cache::download_blocks;
+///
+/// let client = ZcashdClient::new("http://127.0.0.1:8232");
+/// let inserted = download_blocks(&client, "/path/to/cache.db", 500_000, 500_999);
+/// ```
+#[cfg(feature = "rpc")]
+pub fn download_blocks<P: AsRef<Path>>(
+    client: &ZcashdClient,
+    db_cache: P,
+    from_height: u32,
+    to_height: u32,
+) -> Result<u32, Error> {
+    download_blocks_with_chunk_size(
+        client,
+        db_cache,
+        from_height,
+        to_height,
+        DEFAULT_DOWNLOAD_CHUNK_SIZE,
+    )
+}
+
+/// Fetches the blocks from `from_height` to `to_height` (inclusive) from a `zcashd` node,
+/// and inserts them into the cache database in chunks of `chunk_size` blocks.
+///
+/// See [`download_blocks`].
+#[cfg(feature = "rpc")]
+pub fn download_blocks_with_chunk_size<P: AsRef<Path>>(
+    client: &ZcashdClient,
+    db_cache: P,
+    from_height: u32,
+    to_height: u32,
+    chunk_size: u32,
+) -> Result<u32, Error> {
+    if chunk_size == 0 {
+        return Err(Error(ErrorKind::InvalidChunkSize));
+    }
+
+    let cache = Connection::open(db_cache)?;
+    let mut inserted = 0;
+    let mut start = from_height;
+    while start <= to_height {
+        let end = start.saturating_add(chunk_size - 1).min(to_height);
+        let blocks = (start..=end)
+            .map(|height| client.get_block(height))
+            .collect::<Result<Vec<_>, _>>()?;
+        inserted += insert_blocks(&cache, &blocks)?;
+
+        start = match end.checked_add(1) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    Ok(inserted)
+}
+
+/// Downloads blocks from a `zcashd` node into the cache database from `from_height` up
+/// to the node's current chain tip, scanning each batch as soon as it is cached.
+///
+/// Blocks are downloaded in batches of [`DEFAULT_DOWNLOAD_CHUNK_SIZE`] on a separate
+/// thread, so that the next batch is downloaded while the current one is scanned. Notes
+/// therefore appear in the data database after their batch has been scanned, rather
+/// than after the whole range has been downloaded. `progress` is called with the height
+/// of the last scanned block after each batch.
+///
+/// `from_height` should be the height after the last block in the data database. If an
+/// error occurs, the batches before it remain cached and scanned, so calling this again
+/// resumes the sync.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::rpc::ZcashdClient;
+/// use zcash_client_sqlite::cache::sync_and_scan;
+///
+/// let client = ZcashdClient::new("http://127.0.0.1:8232");
+/// let result = sync_and_scan(&client, "/path/to/cache.db", "/path/to/data.db", 500_000, |h| {
+///     println!("Scanned up to {}", h)
+/// });
+/// ```
+#[cfg(feature = "rpc")]
+pub fn sync_and_scan<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(u32)>(
+    client: &ZcashdClient,
+    db_cache: P,
+    db_data: Q,
+    from_height: u32,
+    mut progress: F,
+) -> Result<(), Error> {
+    let to_height = client.get_block_count()?;
+    let cache = Connection::open(&db_cache)?;
+
+    // A rendezvous channel, so the downloader fetches at most one batch ahead of the
+    // scanner.
+    let (tx, rx) = mpsc::sync_channel(0);
+    let downloader = {
+        let client = client.clone();
+        thread::spawn(move || {
+            let mut start = from_height;
+            while start <= to_height {
+                let end = start
+                    .saturating_add(DEFAULT_DOWNLOAD_CHUNK_SIZE - 1)
+                    .min(to_height);
+                let batch = (start..=end)
+                    .map(|height| client.get_block(height))
+                    .collect::<Result<Vec<_>, _>>();
+                let failed = batch.is_err();
+
+                // Stop if the scanner has returned early, or after reporting an error.
+                if tx.send(batch).is_err() || failed {
+                    break;
+                }
+                start = match end.checked_add(1) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+        })
+    };
+
+    for batch in rx.iter() {
+        let blocks = batch?;
+        insert_blocks(&cache, &blocks)?;
+        scan_cached_blocks(&db_cache, &db_data)?;
+        if let Some(last) = blocks.last() {
+            progress(last.height as u32);
+        }
+    }
+
+    // The downloader has finished; if it panicked, the sync is incomplete.
+    if let Err(e) = downloader.join() {
+        panic::resume_unwind(e);
+    }
+    Ok(())
+}
+
+/// Deletes all cached blocks with heights below `below_height`.
+///
+/// Returns the number of blocks that were deleted.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::prune_cache;
+///
+/// let deleted = prune_cache("/path/to/cache.db", 500_000);
+/// ```
+pub fn prune_cache<P: AsRef<Path>>(db_cache: P, below_height: u32) -> Result<u64, Error> {
+    let cache = Connection::open(db_cache)?;
+    let deleted = cache.execute(
+        "DELETE FROM compactblocks WHERE height < ?",
+        &[below_height],
+    )?;
+    Ok(deleted as u64)
+}
+
+/// Deletes all cached blocks with heights below `MAX(height) - keep_last`.
+///
+/// Returns the number of blocks that were deleted.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::prune_cache_automatic;
+///
+/// let deleted = prune_cache_automatic("/path/to/cache.db", 100);
+/// ```
+pub fn prune_cache_automatic<P: AsRef<Path>>(db_cache: P, keep_last: u32) -> Result<u64, Error> {
+    let cache = Connection::open(&db_cache)?;
+    let max_height: Option<u32> =
+        cache.query_row("SELECT MAX(height) FROM compactblocks", NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+
+    match max_height {
+        Some(max_height) => prune_cache(db_cache, max_height.saturating_sub(keep_last)),
+        // The cache is empty.
+        None => Ok(0),
+    }
+}
+
+/// Returns the limits stored in the cache database by
+/// [`init_cache_database_with_limits`], if any.
+///
+/// [`init_cache_database_with_limits`]: crate::init::init_cache_database_with_limits
+fn read_cache_limits(cache: &Connection) -> Result<Option<CacheConfig>, Error> {
+    let has_limits = cache
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'cache_config'")?
+        .exists(NO_PARAMS)?;
+    if !has_limits {
+        return Ok(None);
+    }
+
+    match cache.query_row(
+        "SELECT max_size_bytes, max_blocks FROM cache_config WHERE id = 0",
+        NO_PARAMS,
+        |row| {
+            let max_size_bytes: Option<i64> = row.get(0)?;
+            Ok(CacheConfig {
+                max_size_bytes: max_size_bytes.map(|size| size as u64),
+                max_blocks: row.get(1)?,
+            })
+        },
+    ) {
+        Ok(limits) => Ok(Some(limits)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Deletes the oldest cached blocks until the cache is within the limits stored in it,
+/// without deleting any blocks at or above `scanned_height`.
+///
+/// Returns the number of blocks that were deleted.
+pub(crate) fn apply_cache_limits(cache: &Connection, scanned_height: u32) -> Result<u64, Error> {
+    let limits = match read_cache_limits(cache)? {
+        Some(limits) => limits,
+        None => return Ok(0),
+    };
+
+    // Find the oldest block that fits within the limits, counting back from the newest.
+    let mut stmt_sizes =
+        cache.prepare("SELECT height, LENGTH(data) FROM compactblocks ORDER BY height DESC")?;
+    let sizes = stmt_sizes.query_map(NO_PARAMS, |row| {
+        let height: u32 = row.get(0)?;
+        let size: i64 = row.get(1)?;
+        Ok((height, size as u64))
+    })?;
+    let mut keep_from = None;
+    let mut total_size = 0u64;
+    for (count, row) in sizes.enumerate() {
+        let (height, size) = row?;
+        total_size += size;
+        let too_many = limits
+            .max_blocks
+            .map_or(false, |max_blocks| count as u64 >= u64::from(max_blocks));
+        let too_large = limits
+            .max_size_bytes
+            .map_or(false, |max_size| total_size > max_size);
+        if too_many || too_large {
+            keep_from = Some(height + 1);
+            break;
+        }
+    }
+
+    match keep_from {
+        Some(keep_from) => {
+            let deleted = cache.execute(
+                "DELETE FROM compactblocks WHERE height < ?",
+                &[keep_from.min(scanned_height)],
+            )?;
+            Ok(deleted as u64)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Deletes the oldest cached blocks until the cache is within the limits set by
+/// [`init_cache_database_with_limits`].
+///
+/// Blocks at or above the last height scanned into the data database are never deleted.
+/// Returns the number of blocks that were deleted.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::enforce_cache_limits;
+///
+/// let deleted = enforce_cache_limits("/path/to/cache.db", "/path/to/data.db");
+/// ```
+///
+/// [`init_cache_database_with_limits`]: crate::init::init_cache_database_with_limits
+pub fn enforce_cache_limits<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+) -> Result<u64, Error> {
+    let cache = Connection::open(db_cache)?;
+    let data = open_wallet_readonly(db_data)?;
+    let scanned_height: Option<u32> =
+        data.query_row("SELECT MAX(height) FROM blocks", NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+
+    match scanned_height {
+        Some(scanned_height) => apply_cache_limits(&cache, scanned_height),
+        // Nothing has been scanned, so every cached block is still needed.
+        None => Ok(0),
+    }
+}
+
+/// How completely the wallet covers the chain, as reported by [`get_scan_completeness`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanCompleteness {
+    /// The number of heights from the wallet's starting height to the chain tip.
+    pub total_expected: u32,
+    /// The number of those heights present in the cache database.
+    pub cached: u32,
+    /// The number of those heights scanned into the data database.
+    pub scanned: u32,
+    /// The inclusive ranges of heights that are neither cached nor scanned, in order.
+    pub missing_from_cache: Vec<(u32, u32)>,
+}
+
+/// Compares the heights in the cache and data databases to the range of heights the
+/// wallet needs, ending at `chain_tip`.
+///
+/// The range starts at the lowest height in the data database, which is the checkpoint
+/// for wallets initialized with [`init_blocks_table`], or `SAPLING_ACTIVATION_HEIGHT`
+/// if nothing has been scanned. Blocks that have been scanned and then pruned from the
+/// cache are not missing, but any gap in [`ScanCompleteness::missing_from_cache`] must
+/// be downloaded before the wallet's balances are correct.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::get_scan_completeness;
+///
+/// if let Ok(completeness) =
+///     get_scan_completeness("/path/to/cache.db", "/path/to/data.db", 500_000)
+/// {
+///     for (from, to) in completeness.missing_from_cache {
+///         println!("Blocks {} to {} are missing", from, to);
+///     }
+/// }
+/// ```
+///
+/// [`init_blocks_table`]: crate::init::init_blocks_table
+pub fn get_scan_completeness<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    chain_tip: u32,
+) -> Result<ScanCompleteness, Error> {
+    let cache = Connection::open_with_flags(db_cache, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let data = open_wallet_readonly(db_data)?;
+
+    let start: u32 = data.query_row(
+        "SELECT COALESCE(MIN(height), ?) FROM blocks",
+        &[SAPLING_ACTIVATION_HEIGHT],
+        |row| row.get(0),
+    )?;
+    if chain_tip < start {
+        return Ok(ScanCompleteness {
+            total_expected: 0,
+            cached: 0,
+            scanned: 0,
+            missing_from_cache: vec![],
+        });
+    }
+
+    let fetch_heights = |conn: &Connection, table: &str| -> Result<BTreeSet<u32>, Error> {
+        let mut stmt_heights = conn.prepare(&format!(
+            "SELECT height FROM {} WHERE height >= ? AND height <= ?",
+            table
+        ))?;
+        let heights = stmt_heights.query_map(&[start, chain_tip], |row| row.get::<_, u32>(0))?;
+        Ok(heights.collect::<Result<_, _>>()?)
+    };
+    let cached = fetch_heights(&cache, "compactblocks")?;
+    let scanned = fetch_heights(&data, "blocks")?;
+
+    // Walk the union of the two sets, recording the gaps between consecutive heights.
+    let mut missing_from_cache = vec![];
+    let mut next = start;
+    for &height in cached.union(&scanned) {
+        if height > next {
+            missing_from_cache.push((next, height - 1));
+        }
+        next = height + 1;
+    }
+    if next <= chain_tip {
+        missing_from_cache.push((next, chain_tip));
+    }
+
+    Ok(ScanCompleteness {
+        total_expected: chain_tip - start + 1,
+        cached: cached.len() as u32,
+        scanned: scanned.len() as u32,
+        missing_from_cache,
+    })
+}
+
+/// The problems found in a cache database by [`verify_cache_integrity`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheIntegrityReport {
+    /// The number of blocks in the cache database.
+    pub blocks_checked: u32,
+    /// The heights of blocks that could not be parsed, or that are stored at a height
+    /// other than their own.
+    pub parse_errors: Vec<u32>,
+    /// The inclusive ranges of heights missing between the first and last cached blocks.
+    pub gap_errors: Vec<(u32, u32)>,
+    /// The heights of blocks without a 32-byte hash and parent hash, or whose parent hash
+    /// does not match the hash of the block cached below them.
+    pub hash_chain_errors: Vec<u32>,
+}
+
+impl CacheIntegrityReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.parse_errors.is_empty()
+            && self.gap_errors.is_empty()
+            && self.hash_chain_errors.is_empty()
+    }
+}
+
+/// Checks that every block in the cache database parses, and that the cached blocks
+/// form a single unbroken chain.
+///
+/// Unlike [`validate_combined_chain`], this does not compare the cache against the
+/// data database, and reports every problem found rather than stopping at the first.
+/// A block that fails to parse also breaks the hash chain check for the block above it.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::verify_cache_integrity;
+///
+/// if let Ok(report) = verify_cache_integrity("/path/to/cache.db") {
+///     for height in report.parse_errors {
+///         println!("Block {} is corrupt", height);
+///     }
+/// }
+/// ```
+///
+/// [`validate_combined_chain`]: crate::chain::validate_combined_chain
+pub fn verify_cache_integrity<P: AsRef<Path>>(db_cache: P) -> Result<CacheIntegrityReport, Error> {
+    let cache = Connection::open(db_cache)?;
+
+    let mut stmt_blocks = cache.prepare(&format!(
+        "SELECT height, data, {} FROM compactblocks ORDER BY height",
+        compressed_column(&cache)?
+    ))?;
+    let rows = stmt_blocks.query_map(NO_PARAMS, |row| {
+        Ok((
+            row.get::<_, u32>(0)?,
+            row.get::<_, Vec<u8>>(1)?,
+            row.get(2)?,
+        ))
+    })?;
+
+    let mut report = CacheIntegrityReport::default();
+    let mut prev: Option<(u32, Option<BlockHash>)> = None;
+    for row in rows {
+        let (height, data, compressed) = row?;
+        report.blocks_checked += 1;
+
+        if let Some((prev_height, _)) = prev {
+            if height > prev_height + 1 {
+                report.gap_errors.push((prev_height + 1, height - 1));
+            }
+        }
+
+        let block = match decompress_block(
+            height as i32,
+            data,
+            compressed,
+            ScanConfig::default().max_block_size_bytes,
+        )
+        .map(|data| parse_from_bytes::<CompactBlock>(&data))
+        {
+            Ok(Ok(block)) if block.height == u64::from(height) => block,
+            _ => {
+                report.parse_errors.push(height);
+                prev = Some((height, None));
+                continue;
+            }
+        };
+        let hashes = match block.header() {
+            Some(header) => Some((header.hash(), header.prev_block)),
+            None if block.hash.len() == 32 && block.prevHash.len() == 32 => {
+                Some((block.hash(), block.prev_hash()))
+            }
+            None => None,
+        };
+
+        match (prev, hashes) {
+            (_, None) => report.hash_chain_errors.push(height),
+            (Some((prev_height, Some(prev_hash))), Some((_, prev_block)))
+                if prev_height + 1 == height && prev_hash != prev_block =>
+            {
+                report.hash_chain_errors.push(height)
+            }
+            _ => (),
+        }
+
+        prev = Some((height, hashes.map(|(hash, _)| hash)));
+    }
+
+    Ok(report)
+}
+
+/// A handle to a cache database, for monitoring and limiting its size.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::cache::CacheManager;
+///
+/// let manager = CacheManager::new("/path/to/cache.db");
+/// if let Ok(size) = manager.size_bytes() {
+///     if size > 100_000_000 {
+///         manager.prune_automatic(100);
+///     }
+/// }
+/// ```
+pub struct CacheManager {
+    db_cache: PathBuf,
+}
+
+impl CacheManager {
+    /// Creates a manager for the cache database at the given path.
+    pub fn new<P: AsRef<Path>>(db_cache: P) -> Self {
+        CacheManager {
+            db_cache: db_cache.as_ref().to_owned(),
+        }
+    }
+
+    /// Deletes all cached blocks with heights below `below_height`.
+    ///
+    /// See [`prune_cache`].
+    pub fn prune(&self, below_height: u32) -> Result<u64, Error> {
+        prune_cache(&self.db_cache, below_height)
+    }
+
+    /// Deletes all cached blocks with heights below `MAX(height) - keep_last`.
+    ///
+    /// See [`prune_cache_automatic`].
+    pub fn prune_automatic(&self, keep_last: u32) -> Result<u64, Error> {
+        prune_cache_automatic(&self.db_cache, keep_last)
+    }
+
+    /// Returns the size of the cache database in bytes.
+    ///
+    /// SQLite reuses the space freed by pruning for new blocks, rather than returning it
+    /// to the filesystem, so pruning does not reduce this value.
+    pub fn size_bytes(&self) -> Result<u64, Error> {
+        let cache = Connection::open(&self.db_cache)?;
+        let page_count: i64 = cache.query_row("PRAGMA page_count", NO_PARAMS, |row| row.get(0))?;
+        let page_size: i64 = cache.query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))?;
+        Ok((page_count * page_size) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::parse_from_bytes;
+    use rusqlite::{Connection, NO_PARAMS};
+    use tempfile::NamedTempFile;
+    use zcash_primitives::{
+        block::BlockHash,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use zcash_client_backend::{
+        proto::compact_formats::CompactBlock, testing::CompactBlockBuilder,
+        validation::ValidationError,
+    };
+    use zcash_primitives::note_encryption::Memo;
+
+    use super::{
+        compress_cache, enforce_cache_limits, get_scan_completeness, insert_compact_blocks,
+        prune_cache, read_block_stream, verify_cache_integrity, write_block_stream,
+        CacheIntegrityReport, CacheManager, ScanCompleteness,
+    };
+    use crate::{
+        chain::validate_combined_chain,
+        error::ErrorKind,
+        init::{
+            init_accounts_table, init_cache_database, init_cache_database_compressed,
+            init_cache_database_with_limits, init_data_database, CacheConfig,
+        },
+        query::get_balance,
+        scan::scan_cached_blocks,
+        tests::{fake_compact_block, insert_into_cache},
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    #[test]
+    fn block_stream_round_trip() {
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..3 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+
+        let mut stream = vec![];
+        assert_eq!(write_block_stream(blocks.clone(), &mut stream).unwrap(), 3);
+        let read: Vec<_> = read_block_stream(&stream[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, blocks);
+
+        // An empty stream has no blocks
+        assert_eq!(read_block_stream(&b""[..]).count(), 0);
+
+        // A truncated stream returns the complete blocks, then a single error
+        let truncated: Vec<_> = read_block_stream(&stream[..stream.len() - 1]).collect();
+        assert_eq!(truncated.len(), 3);
+        assert!(truncated[..2].iter().all(|block| block.is_ok()));
+        assert!(truncated[2].is_err());
+    }
+
+    #[test]
+    fn cache_manager_prunes_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+        let manager = CacheManager::new(db_cache);
+        assert!(manager.size_bytes().unwrap() > 0);
+
+        // Pruning an empty cache does nothing
+        assert_eq!(manager.prune_automatic(1).unwrap(), 0);
+
+        // Cache five blocks
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let value = Amount::from_u64(5).unwrap();
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..5 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            insert_into_cache(db_cache, &cb);
+            prev_hash = cb.hash();
+        }
+
+        let height = SAPLING_ACTIVATION_HEIGHT as u32;
+        assert_eq!(manager.prune(height + 1).unwrap(), 1);
+        assert_eq!(manager.prune(height + 1).unwrap(), 0);
+
+        // Blocks at or above MAX(height) - keep_last are kept
+        assert_eq!(manager.prune_automatic(2).unwrap(), 1);
+        assert_eq!(manager.prune_automatic(0).unwrap(), 2);
+        assert_eq!(manager.prune_automatic(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn insert_compact_blocks_skips_cached_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..3 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+
+        assert_eq!(insert_compact_blocks(db_cache, &blocks[..2]).unwrap(), 2);
+        assert_eq!(insert_compact_blocks(db_cache, &blocks[..2]).unwrap(), 0);
+        assert_eq!(insert_compact_blocks(db_cache, &blocks).unwrap(), 1);
+    }
+
+    #[test]
+    fn insert_compact_blocks_replaces_reorged_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..3 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+        assert_eq!(insert_compact_blocks(db_cache, &blocks).unwrap(), 3);
+
+        let cached_hashes = || -> Vec<Vec<u8>> {
+            let cache = Connection::open(db_cache).unwrap();
+            let mut stmt_blocks = cache
+                .prepare("SELECT data FROM compactblocks ORDER BY height")
+                .unwrap();
+            let rows = stmt_blocks
+                .query_map(NO_PARAMS, |row| row.get::<_, Vec<u8>>(0))
+                .unwrap();
+            rows.map(|data| {
+                parse_from_bytes::<CompactBlock>(&data.unwrap())
+                    .unwrap()
+                    .hash
+            })
+            .collect()
+        };
+
+        // A malformed block is rejected, and nothing is inserted
+        let (fork, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            blocks[0].hash(),
+            extfvk.clone(),
+            value,
+        );
+        let mut malformed = fork.clone();
+        malformed.hash.truncate(31);
+        match insert_compact_blocks(db_cache, &[fork.clone(), malformed]) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidCompactBlock(height, ValidationError::InvalidHashLength(31)) => {
+                    assert_eq!(*height, SAPLING_ACTIVATION_HEIGHT + 1)
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+        assert_eq!(
+            cached_hashes(),
+            blocks.iter().map(|cb| cb.hash.clone()).collect::<Vec<_>>()
+        );
+
+        // A block from another chain replaces the cached block at its height, and the
+        // cached blocks above it
+        assert_eq!(insert_compact_blocks(db_cache, &[fork.clone()]).unwrap(), 1);
+        assert_eq!(
+            cached_hashes(),
+            vec![blocks[0].hash.clone(), fork.hash.clone()]
+        );
+    }
+
+    #[test]
+    fn cache_limits_keep_unscanned_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        let limits = CacheConfig {
+            max_size_bytes: None,
+            max_blocks: Some(2),
+        };
+        init_cache_database_with_limits(&db_cache, &limits).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Cache five blocks
+        let value = Amount::from_u64(5).unwrap();
+        let mut prev_hash = BlockHash([0; 32]);
+        let mut blocks = vec![];
+        for i in 0..5 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+        insert_compact_blocks(db_cache, &blocks).unwrap();
+        let manager = CacheManager::new(db_cache);
+
+        // Nothing has been scanned, so every block is kept
+        assert_eq!(enforce_cache_limits(db_cache, db_data).unwrap(), 0);
+
+        // Scanning removes the oldest blocks, keeping the last two
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(manager.prune(u32::max_value()).unwrap(), 2);
+    }
+
+    #[test]
+    fn scan_completeness_reports_gaps() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        let start = SAPLING_ACTIVATION_HEIGHT as u32;
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..10 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+
+        // Nothing is cached or scanned yet
+        assert_eq!(
+            get_scan_completeness(db_cache, db_data, start + 9).unwrap(),
+            ScanCompleteness {
+                total_expected: 10,
+                cached: 0,
+                scanned: 0,
+                missing_from_cache: vec![(start, start + 9)],
+            }
+        );
+
+        // Scan the first three blocks, then prune them from the cache
+        for cb in &blocks[..3] {
+            insert_into_cache(db_cache, cb);
+        }
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        prune_cache(db_cache, start + 3).unwrap();
+
+        // Cache blocks 4, 5, 8 and 9, leaving gaps at 3 and 6-7
+        for i in &[4, 5, 8, 9] {
+            insert_into_cache(db_cache, &blocks[*i]);
+        }
+        assert_eq!(
+            get_scan_completeness(db_cache, db_data, start + 11).unwrap(),
+            ScanCompleteness {
+                total_expected: 12,
+                cached: 4,
+                scanned: 3,
+                missing_from_cache: vec![
+                    (start + 3, start + 3),
+                    (start + 6, start + 7),
+                    (start + 10, start + 11),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn verify_cache_integrity_reports_corruption() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let start = SAPLING_ACTIVATION_HEIGHT as u32;
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..7 {
+            // The last block does not build on the block below it
+            if i == 6 {
+                prev_hash = BlockHash([1; 32]);
+            }
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+
+        // An empty cache has nothing to report
+        assert_eq!(
+            verify_cache_integrity(db_cache).unwrap(),
+            CacheIntegrityReport::default()
+        );
+
+        // The first six blocks form a valid chain
+        for cb in &blocks[..6] {
+            insert_into_cache(db_cache, cb);
+        }
+        let report = verify_cache_integrity(db_cache).unwrap();
+        assert_eq!(report.blocks_checked, 6);
+        assert!(report.is_ok());
+
+        // Corrupt block 2, remove block 4, and add the mismatched block 6
+        let cache = Connection::open(db_cache).unwrap();
+        cache
+            .execute(
+                "UPDATE compactblocks SET data = x'ff' WHERE height = ?",
+                &[start + 2],
+            )
+            .unwrap();
+        cache
+            .execute("DELETE FROM compactblocks WHERE height = ?", &[start + 4])
+            .unwrap();
+        insert_into_cache(db_cache, &blocks[6]);
+
+        assert_eq!(
+            verify_cache_integrity(db_cache).unwrap(),
+            CacheIntegrityReport {
+                blocks_checked: 6,
+                parse_errors: vec![start + 2],
+                gap_errors: vec![(start + 4, start + 4)],
+                hash_chain_errors: vec![start + 6],
+            }
+        );
+    }
+
+    /// Creates a block paying `value` to `extfvk`, padded with spends of an all-zero
+    /// nullifier so that it compresses well.
+    fn compressible_block(
+        height: i32,
+        prev_hash: BlockHash,
+        extfvk: &ExtendedFullViewingKey,
+        value: Amount,
+    ) -> CompactBlock {
+        let mut builder = CompactBlockBuilder::new(height as u32, prev_hash.0);
+        for _ in 0..20 {
+            builder.add_sapling_spend(&[0; 32]);
+        }
+        builder.add_sapling_output(extfvk, value, Memo::default());
+        builder.build()
+    }
+
+    #[test]
+    fn compressed_cache_is_scanned() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..4 {
+            let cb = compressible_block(SAPLING_ACTIVATION_HEIGHT + i, prev_hash, &extfvk, value);
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+        let is_compressed = |height: i32| -> bool {
+            Connection::open(db_cache)
+                .unwrap()
+                .query_row(
+                    "SELECT compressed FROM compactblocks WHERE height = ?",
+                    &[height],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+
+        // Compress the blocks already in the cache
+        insert_compact_blocks(db_cache, &blocks[..2]).unwrap();
+        let stats = compress_cache(db_cache).unwrap();
+        assert_eq!(stats.blocks_checked, 2);
+        assert_eq!(stats.blocks_compressed, 2);
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert!(is_compressed(SAPLING_ACTIVATION_HEIGHT));
+
+        // Blocks are now compressed as they are inserted, while blocks inserted directly
+        // are stored uncompressed
+        insert_compact_blocks(db_cache, &blocks[2..3]).unwrap();
+        insert_into_cache(db_cache, &blocks[3]);
+        assert!(is_compressed(SAPLING_ACTIVATION_HEIGHT + 2));
+        assert!(!is_compressed(SAPLING_ACTIVATION_HEIGHT + 3));
+
+        // The mixed cache is read transparently
+        assert!(verify_cache_integrity(db_cache).unwrap().is_ok());
+        validate_combined_chain(db_cache, db_data).unwrap();
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(
+            get_balance(db_data, 0).unwrap(),
+            Amount::from_u64(20).unwrap()
+        );
+
+        // A cache created for compression compresses from the start
+        let compressed_file = NamedTempFile::new().unwrap();
+        let db_compressed = compressed_file.path();
+        init_cache_database_compressed(&db_compressed).unwrap();
+        insert_compact_blocks(db_compressed, &blocks[..1]).unwrap();
+        assert!(Connection::open(db_compressed)
+            .unwrap()
+            .query_row("SELECT compressed FROM compactblocks", NO_PARAMS, |row| {
+                row.get::<_, bool>(0)
+            })
+            .unwrap());
+    }
+}
@@ -56,12 +56,16 @@
 //! ```
 
 use protobuf::parse_from_bytes;
-use rusqlite::{Connection, NO_PARAMS};
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
 use std::path::Path;
 use zcash_client_backend::proto::compact_formats::CompactBlock;
 
 use crate::{
+    cache::{compressed_column, decompress_block},
     error::{Error, ErrorKind},
+    open_data_database,
+    query::open_wallet_readonly,
+    scan::ScanConfig,
     SAPLING_ACTIVATION_HEIGHT,
 };
 
@@ -73,6 +77,14 @@ pub enum ChainInvalidCause {
 struct CompactBlockRow {
     height: i32,
     data: Vec<u8>,
+    compressed: bool,
+}
+
+impl CompactBlockRow {
+    fn parse(self, max_block_size: usize) -> Result<CompactBlock, Error> {
+        let data = decompress_block(self.height, self.data, self.compressed, max_block_size)?;
+        Ok(parse_from_bytes(&data)?)
+    }
 }
 
 /// Checks that the scanned blocks in the data database, when combined with the recent
@@ -95,8 +107,24 @@ pub fn validate_combined_chain<P: AsRef<Path>, Q: AsRef<Path>>(
     db_cache: P,
     db_data: Q,
 ) -> Result<(), Error> {
-    let cache = Connection::open(db_cache)?;
-    let data = Connection::open(db_data)?;
+    validate_combined_chain_with_config(db_cache, db_data, &ScanConfig::default())
+}
+
+/// Checks that the scanned blocks in the data database, when combined with the recent
+/// `CompactBlock`s in the cache database, form a valid chain, reading cached blocks with
+/// the limits in the given [`ScanConfig`].
+///
+/// This should be given the same configuration as [`scan_cached_blocks_with_config`].
+/// See [`validate_combined_chain`] for details.
+///
+/// [`scan_cached_blocks_with_config`]: crate::scan::scan_cached_blocks_with_config
+pub fn validate_combined_chain_with_config<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    config: &ScanConfig,
+) -> Result<(), Error> {
+    let cache = Connection::open_with_flags(db_cache, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let data = open_wallet_readonly(db_data)?;
 
     // Recall where we synced up to previously.
     // If we have never synced, use Sapling activation height to select all cached CompactBlocks.
@@ -108,12 +136,15 @@ pub fn validate_combined_chain<P: AsRef<Path>, Q: AsRef<Path>>(
         })?;
 
     // Fetch the CompactBlocks we need to validate
-    let mut stmt_blocks = cache
-        .prepare("SELECT height, data FROM compactblocks WHERE height > ? ORDER BY height DESC")?;
+    let mut stmt_blocks = cache.prepare(&format!(
+        "SELECT height, data, {} FROM compactblocks WHERE height > ? ORDER BY height DESC",
+        compressed_column(&cache)?
+    ))?;
     let mut rows = stmt_blocks.query_map(&[last_scanned_height], |row| {
         Ok(CompactBlockRow {
             height: row.get(0)?,
             data: row.get(1)?,
+            compressed: row.get(2)?,
         })
     })?;
 
@@ -129,7 +160,7 @@ pub fn validate_combined_chain<P: AsRef<Path>, Q: AsRef<Path>>(
                 return Ok(());
             }
         };
-        let block: CompactBlock = parse_from_bytes(&assumed_correct.data)?;
+        let block = assumed_correct.parse(config.max_block_size_bytes)?;
         (block.height as i32, block.prev_hash())
     };
 
@@ -142,7 +173,7 @@ pub fn validate_combined_chain<P: AsRef<Path>, Q: AsRef<Path>>(
         }
         last_height = row.height;
 
-        let block: CompactBlock = parse_from_bytes(&row.data)?;
+        let block = row.parse(config.max_block_size_bytes)?;
 
         // Cached blocks MUST be hash-chained.
         if block.hash() != last_prev_hash {
@@ -178,7 +209,7 @@ pub fn validate_combined_chain<P: AsRef<Path>, Q: AsRef<Path>>(
 /// If the requested height is greater than or equal to the height of the last scanned
 /// block, this function does nothing.
 pub fn rewind_to_height<P: AsRef<Path>>(db_data: P, height: i32) -> Result<(), Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_data_database(db_data)?;
 
     // Recall where we synced up to previously.
     // If we have never synced, use Sapling activation height.
@@ -207,6 +238,12 @@ pub fn rewind_to_height<P: AsRef<Path>>(db_data: P, height: i32) -> Result<(), E
     // Now that they aren't depended on, delete scanned blocks.
     data.execute("DELETE FROM blocks WHERE height > ?", &[height])?;
 
+    // Forget the scan cursor if it points to a rewound block.
+    data.execute(
+        "DELETE FROM scan_cursor WHERE last_committed_height > ?",
+        &[height],
+    )?;
+
     // Commit the SQL transaction, rewinding atomically.
     data.execute("COMMIT", NO_PARAMS)?;
 
@@ -215,7 +252,8 @@ pub fn rewind_to_height<P: AsRef<Path>>(db_data: P, height: i32) -> Result<(), E
 
 #[cfg(test)]
 mod tests {
-    use tempfile::NamedTempFile;
+    use rusqlite::{Connection, NO_PARAMS};
+    use tempfile::{tempdir, NamedTempFile};
     use zcash_primitives::{
         block::BlockHash,
         transaction::components::Amount,
@@ -287,6 +325,20 @@ mod tests {
         validate_combined_chain(db_cache, db_data).unwrap();
     }
 
+    #[test]
+    fn validate_does_not_create_missing_databases() {
+        let dir = tempdir().unwrap();
+        let db_cache = dir.path().join("cache.db");
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // The cache is opened read-only, so a missing cache is an error
+        assert!(validate_combined_chain(&db_cache, db_data).is_err());
+        assert!(!db_cache.exists());
+    }
+
     #[test]
     fn invalid_chain_cache_disconnected() {
         let cache_file = NamedTempFile::new().unwrap();
@@ -452,13 +504,13 @@ mod tests {
         scan_cached_blocks(db_cache, db_data).unwrap();
 
         // Account balance should reflect both received notes
-        assert_eq!(get_balance(db_data, 0).unwrap(), value + value2);
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value + value2).unwrap());
 
         // "Rewind" to height of last scanned block
         rewind_to_height(db_data, SAPLING_ACTIVATION_HEIGHT + 1).unwrap();
 
         // Account balance should be unaltered
-        assert_eq!(get_balance(db_data, 0).unwrap(), value + value2);
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value + value2).unwrap());
 
         // Rewind so that one block is dropped
         rewind_to_height(db_data, SAPLING_ACTIVATION_HEIGHT).unwrap();
@@ -466,4 +518,107 @@ mod tests {
         // Account balance should only contain the first received note
         assert_eq!(get_balance(db_data, 0).unwrap(), value);
     }
+
+    #[test]
+    fn reorg_handling() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Create ten fake CompactBlocks, each sending value to the address
+        let old_value = Amount::from_u64(1).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..10 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                old_value,
+            );
+            insert_into_cache(db_cache, &cb);
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+
+        // Scan the cache
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(
+            get_balance(db_data, 0).unwrap(),
+            Amount::from_u64(10).unwrap()
+        );
+
+        // Replace the last three blocks in the cache with a longer conflicting chain
+        let fork_height = SAPLING_ACTIVATION_HEIGHT + 6;
+        Connection::open(db_cache)
+            .unwrap()
+            .execute("DELETE FROM compactblocks WHERE height > ?", &[fork_height])
+            .unwrap();
+        let new_value = Amount::from_u64(100).unwrap();
+        let mut prev_hash = blocks[6].hash();
+        for i in 7..12 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                new_value,
+            );
+            insert_into_cache(db_cache, &cb);
+            prev_hash = cb.hash();
+        }
+
+        // Data+cache chain should be invalid at the data/cache boundary
+        match validate_combined_chain(db_cache, db_data) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidChain(upper_bound, _) => {
+                    assert_eq!(*upper_bound, SAPLING_ACTIVATION_HEIGHT + 9)
+                }
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+
+        // Rewind to the fork point, and scan the new blocks
+        rewind_to_height(db_data, fork_height).unwrap();
+        validate_combined_chain(db_cache, db_data).unwrap();
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Only the notes from the first seven blocks and the new blocks should be counted
+        assert_eq!(
+            get_balance(db_data, 0).unwrap(),
+            Amount::from_u64(7 + 5 * 100).unwrap()
+        );
+
+        // The notes from the replaced blocks are no longer in any scanned block
+        let data = Connection::open(db_data).unwrap();
+        let mined_notes: i64 = data
+            .query_row(
+                "SELECT COUNT(*) FROM received_notes
+                INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+                WHERE transactions.block IS NOT NULL",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(mined_notes, 12);
+        let orphaned_notes: i64 = data
+            .query_row(
+                "SELECT COUNT(*) FROM received_notes
+                INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+                WHERE transactions.block IS NULL",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(orphaned_notes, 3);
+    }
 }
@@ -1,30 +1,58 @@
 use std::error;
 use std::fmt;
+use zcash_client_backend::{storage::ScanError, validation::ValidationError};
 use zcash_primitives::{
     sapling::Node,
     transaction::{builder, TxId},
 };
 
+/// The kinds of error that can occur when using the SQLite wallet.
+///
+/// Errors from the underlying libraries are wrapped in their own variants (starting at
+/// [`ErrorKind::Argon2`]), and can be converted into an [`Error`] with `?`.
 #[derive(Debug)]
 pub enum ErrorKind {
+    BlockTooLarge(i32, usize),
+    CacheMissingBlocks(i32, i32),
     CorruptedData(&'static str),
+    CorruptWitness(i64, i32),
     IncorrectHRPExtFVK,
     InsufficientBalance(u64, u64),
+    InvalidAmount,
     InvalidChain(i32, crate::chain::ChainInvalidCause),
+    InvalidChunkSize,
+    InvalidCompactBlock(i32, ValidationError),
+    InvalidEncryptedKey(&'static str),
     InvalidExtSK(u32),
     InvalidHeight(i32, i32),
+    InvalidInterval,
     InvalidMemo(std::str::Utf8Error),
     InvalidNewWitnessAnchor(usize, TxId, i32, Node),
     InvalidNote,
+    InvalidScanRange(i32),
     InvalidWitnessAnchor(i64, i32),
+    InvalidWitnessPosition(i64, u64, usize),
+    KeyDecryptionFailed,
+    MalformedBlock(i32),
+    NetworkMismatch(String, &'static str),
+    NoteNotSpendable(i64),
+    PositionOverflow,
+    RecipientNotInTransaction(usize),
     ScanRequired,
+    SchemaTooNew(i32, i32),
+    SchemaTooOld(i32, i32),
     TableNotEmpty,
+    Unauthorized,
+    UnknownAccount(u32),
+    Argon2(argon2::Error),
     Bech32(bech32::Error),
     Base58(bs58::decode::DecodeError),
     Builder(builder::Error),
     Database(rusqlite::Error),
     Io(std::io::Error),
     Protobuf(protobuf::ProtobufError),
+    #[cfg(feature = "rpc")]
+    Rpc(zcash_client_backend::rpc::Error),
 }
 
 #[derive(Debug)]
@@ -33,16 +61,39 @@ pub struct Error(pub(crate) ErrorKind);
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.0 {
+            ErrorKind::BlockTooLarge(height, max_size) => write!(
+                f,
+                "CompactBlock at height {} is larger than the limit of {} bytes",
+                height, max_size
+            ),
+            ErrorKind::CacheMissingBlocks(from, to) => write!(
+                f,
+                "Cache DB is missing the blocks from height {} to {}",
+                from, to
+            ),
             ErrorKind::CorruptedData(reason) => write!(f, "Data DB is corrupted: {}", reason),
+            ErrorKind::CorruptWitness(id_note, block) => write!(
+                f,
+                "Witness for note {} at block {} is corrupted",
+                id_note, block
+            ),
             ErrorKind::IncorrectHRPExtFVK => write!(f, "Incorrect HRP for extfvk"),
             ErrorKind::InsufficientBalance(have, need) => write!(
                 f,
                 "Insufficient balance (have {}, need {} including fee)",
                 have, need
             ),
+            ErrorKind::InvalidAmount => write!(f, "Amount is out of range"),
             ErrorKind::InvalidChain(upper_bound, cause) => {
                 write!(f, "Invalid chain (upper bound: {}): {:?}", upper_bound, cause)
             }
+            ErrorKind::InvalidChunkSize => write!(f, "Chunk size must be non-zero"),
+            ErrorKind::InvalidCompactBlock(height, e) => {
+                write!(f, "Invalid CompactBlock at height {}: {}", height, e)
+            }
+            ErrorKind::InvalidEncryptedKey(reason) => {
+                write!(f, "Invalid encrypted key: {}", reason)
+            }
             ErrorKind::InvalidExtSK(account) => {
                 write!(f, "Incorrect ExtendedSpendingKey for account {}", account)
             }
@@ -51,6 +102,9 @@ impl fmt::Display for Error {
                 "Expected height of next CompactBlock to be {}, but was {}",
                 expected, actual
             ),
+            ErrorKind::InvalidInterval => {
+                write!(f, "Scheduled payment interval must be at least one block")
+            }
             ErrorKind::InvalidMemo(e) => write!(f, "{}", e),
             ErrorKind::InvalidNewWitnessAnchor(output, txid, last_height, anchor) => write!(
                 f,
@@ -58,19 +112,62 @@ impl fmt::Display for Error {
                 output, txid, last_height, anchor,
             ),
             ErrorKind::InvalidNote => write!(f, "Invalid note"),
+            ErrorKind::InvalidScanRange(start_height) => write!(
+                f,
+                "Scan range starting at height {} does not continue the previous range",
+                start_height
+            ),
             ErrorKind::InvalidWitnessAnchor(id_note, last_height) => write!(
                 f,
                 "Witness for note {} has incorrect anchor after scanning block {}",
                 id_note, last_height
             ),
+            ErrorKind::InvalidWitnessPosition(id_note, position, tree_size) => write!(
+                f,
+                "Note {} has position {}, outside of the Sapling tree of size {}",
+                id_note, position, tree_size
+            ),
+            ErrorKind::KeyDecryptionFailed => {
+                write!(f, "Failed to decrypt key (incorrect password?)")
+            }
+            ErrorKind::MalformedBlock(height) => {
+                write!(f, "CompactBlock at height {} is malformed", height)
+            }
+            ErrorKind::NetworkMismatch(stored, requested) => write!(
+                f,
+                "Data DB is for network {}, but {} was requested",
+                stored, requested
+            ),
+            ErrorKind::NoteNotSpendable(id_note) => write!(f, "Note {} is not spendable", id_note),
+            ErrorKind::PositionOverflow => {
+                write!(f, "Note commitment tree position is too large to store")
+            }
+            ErrorKind::RecipientNotInTransaction(index) => {
+                write!(f, "Transaction has no output paying recipient {}", index)
+            }
             ErrorKind::ScanRequired => write!(f, "Must scan blocks first"),
+            ErrorKind::SchemaTooNew(found, expected) => write!(
+                f,
+                "Data DB has schema version {}, but this library only supports up to version {}",
+                found, expected
+            ),
+            ErrorKind::SchemaTooOld(found, expected) => write!(
+                f,
+                "Data DB has schema version {}, but this library requires version {}; upgrade it with migrate_data_database",
+                found, expected
+            ),
             ErrorKind::TableNotEmpty => write!(f, "Table is not empty"),
+            ErrorKind::Unauthorized => write!(f, "Spend was not authorized"),
+            ErrorKind::UnknownAccount(account) => write!(f, "Account {} does not exist", account),
+            ErrorKind::Argon2(e) => write!(f, "{}", e),
             ErrorKind::Bech32(e) => write!(f, "{}", e),
             ErrorKind::Base58(e) => write!(f, "{}", e),
-            ErrorKind::Builder(e) => write!(f, "{:?}", e),
+            ErrorKind::Builder(e) => write!(f, "{}", e),
             ErrorKind::Database(e) => write!(f, "{}", e),
             ErrorKind::Io(e) => write!(f, "{}", e),
             ErrorKind::Protobuf(e) => write!(f, "{}", e),
+            #[cfg(feature = "rpc")]
+            ErrorKind::Rpc(e) => write!(f, "{}", e),
         }
     }
 }
@@ -78,17 +175,27 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match &self.0 {
+            ErrorKind::InvalidCompactBlock(_, e) => Some(e),
             ErrorKind::InvalidMemo(e) => Some(e),
+            ErrorKind::Argon2(e) => Some(e),
             ErrorKind::Bech32(e) => Some(e),
             ErrorKind::Builder(e) => Some(e),
             ErrorKind::Database(e) => Some(e),
             ErrorKind::Io(e) => Some(e),
             ErrorKind::Protobuf(e) => Some(e),
+            #[cfg(feature = "rpc")]
+            ErrorKind::Rpc(e) => Some(e),
             _ => None,
         }
     }
 }
 
+impl From<argon2::Error> for Error {
+    fn from(e: argon2::Error) -> Self {
+        Error(ErrorKind::Argon2(e))
+    }
+}
+
 impl From<bech32::Error> for Error {
     fn from(e: bech32::Error) -> Self {
         Error(ErrorKind::Bech32(e))
@@ -125,6 +232,32 @@ impl From<protobuf::ProtobufError> for Error {
     }
 }
 
+impl From<ScanError<i64>> for Error {
+    fn from(e: ScanError<i64>) -> Self {
+        match e {
+            ScanError::InvalidHeight(expected, actual) => {
+                Error(ErrorKind::InvalidHeight(expected as i32, actual as i32))
+            }
+            ScanError::InvalidCompactBlock(height, e) => {
+                Error(ErrorKind::InvalidCompactBlock(height as i32, e))
+            }
+            ScanError::InvalidNewWitnessAnchor(output, txid, height, anchor) => Error(
+                ErrorKind::InvalidNewWitnessAnchor(output, txid, height as i32, anchor),
+            ),
+            ScanError::InvalidWitnessAnchor(id_note, height) => {
+                Error(ErrorKind::InvalidWitnessAnchor(id_note, height as i32))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+impl From<zcash_client_backend::rpc::Error> for Error {
+    fn from(e: zcash_client_backend::rpc::Error) -> Self {
+        Error(ErrorKind::Rpc(e))
+    }
+}
+
 impl Error {
     pub fn kind(&self) -> &ErrorKind {
         &self.0
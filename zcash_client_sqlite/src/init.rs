@@ -1,16 +1,76 @@
 //! Functions for initializing the various databases.
 
 use rusqlite::{types::ToSql, Connection, NO_PARAMS};
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use zcash_client_backend::encoding::encode_extended_full_viewing_key;
 use zcash_primitives::{block::BlockHash, zip32::ExtendedFullViewingKey};
 
 use crate::{
-    address_from_extfvk,
+    add_witness_checksum, address_from_extfvk,
+    cache::enable_compression,
+    check_network, check_schema_not_newer, check_schema_version,
     error::{Error, ErrorKind},
-    HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+    open_data_database, read_witness, DATA_DB_VERSION, HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+    NETWORK_TYPE,
 };
 
+/// Storage parameters for an SQLite database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SqliteConfig {
+    /// The size in bytes of each database page. This must be a power of two between 512
+    /// and 65536, and only takes effect when the database is created.
+    pub page_size: u32,
+    /// The number of pages that SQLite will hold in memory per connection. Negative
+    /// values instead set the cache size to approximately `-cache_size_pages` KiB.
+    pub cache_size_pages: i32,
+}
+
+impl Default for SqliteConfig {
+    /// SQLite's own defaults.
+    fn default() -> Self {
+        SqliteConfig {
+            page_size: 4096,
+            cache_size_pages: -2000,
+        }
+    }
+}
+
+impl SqliteConfig {
+    /// A configuration suited to scanning large numbers of blocks, with larger pages and
+    /// a cache of 10000 pages (around 310 MiB).
+    pub fn for_scanning() -> Self {
+        SqliteConfig {
+            page_size: 32768,
+            cache_size_pages: 10000,
+        }
+    }
+
+    /// Applies this configuration to a newly-opened connection.
+    fn apply(&self, conn: &Connection) -> Result<(), Error> {
+        conn.execute(&format!("PRAGMA page_size = {}", self.page_size), NO_PARAMS)?;
+        conn.execute(
+            &format!("PRAGMA cache_size = {}", self.cache_size_pages),
+            NO_PARAMS,
+        )?;
+        Ok(())
+    }
+}
+
+/// Limits on the size of the cache database.
+///
+/// When a limit is exceeded, the oldest cached blocks are deleted until the cache is
+/// within bounds. Blocks at or above the last height scanned into the data database are
+/// never deleted, so the cache may temporarily exceed its limits while it holds many
+/// unscanned blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheConfig {
+    /// The maximum total size in bytes of the cached blocks' serialized data.
+    pub max_size_bytes: Option<u64>,
+    /// The maximum number of cached blocks.
+    pub max_blocks: Option<u32>,
+}
+
 /// Sets up the internal structure of the cache database.
 ///
 /// # Examples
@@ -24,7 +84,33 @@ use crate::{
 /// init_cache_database(&db_cache).unwrap();
 /// ```
 pub fn init_cache_database<P: AsRef<Path>>(db_cache: P) -> Result<(), Error> {
+    init_cache_database_with_config(db_cache, &SqliteConfig::default())
+}
+
+/// Sets up the internal structure of the cache database, using the given
+/// [`SqliteConfig`].
+///
+/// The page size can only be set when the database is first created; it is ignored for
+/// existing databases.
+///
+/// # Examples
+///
+/// ```
+/// use tempfile::NamedTempFile;
+/// use zcash_client_sqlite::init::{init_cache_database_with_config, SqliteConfig};
+///
+/// let data_file = NamedTempFile::new().unwrap();
+/// let db_cache = data_file.path();
+/// init_cache_database_with_config(&db_cache, &SqliteConfig::for_scanning()).unwrap();
+/// ```
+pub fn init_cache_database_with_config<P: AsRef<Path>>(
+    db_cache: P,
+    config: &SqliteConfig,
+) -> Result<(), Error> {
     let cache = Connection::open(db_cache)?;
+    config.apply(&cache)?;
+    // `height` is an alias for the rowid, so it is unique and lookups and range scans by
+    // height use the table's own B-tree without a separate index.
     cache.execute(
         "CREATE TABLE IF NOT EXISTS compactblocks (
             height INTEGER PRIMARY KEY,
@@ -35,8 +121,90 @@ pub fn init_cache_database<P: AsRef<Path>>(db_cache: P) -> Result<(), Error> {
     Ok(())
 }
 
+/// Sets up the internal structure of the cache database, storing blocks compressed with
+/// zstd.
+///
+/// Blocks inserted with [`insert_compact_blocks`] or [`download_blocks`] are compressed
+/// when that makes them smaller, and are decompressed transparently when they are read.
+/// Blocks that are already cached are not compressed; use [`compress_cache`] for this.
+///
+/// # Examples
+///
+/// ```
+/// use tempfile::NamedTempFile;
+/// use zcash_client_sqlite::init::init_cache_database_compressed;
+///
+/// let data_file = NamedTempFile::new().unwrap();
+/// let db_cache = data_file.path();
+/// init_cache_database_compressed(&db_cache).unwrap();
+/// ```
+///
+/// [`insert_compact_blocks`]: crate::cache::insert_compact_blocks
+/// [`download_blocks`]: crate::cache::download_blocks
+/// [`compress_cache`]: crate::cache::compress_cache
+pub fn init_cache_database_compressed<P: AsRef<Path>>(db_cache: P) -> Result<(), Error> {
+    init_cache_database(&db_cache)?;
+    let cache = Connection::open(db_cache)?;
+    enable_compression(&cache)
+}
+
+/// Sets up the internal structure of the cache database, and limits its size with the
+/// given [`CacheConfig`].
+///
+/// The limits are stored in the cache database, and replace any limits set previously.
+/// They are enforced each time [`scan_cached_blocks`] finishes, or by calling
+/// [`enforce_cache_limits`].
+///
+/// # Examples
+///
+/// ```
+/// use tempfile::NamedTempFile;
+/// use zcash_client_sqlite::init::{init_cache_database_with_limits, CacheConfig};
+///
+/// let data_file = NamedTempFile::new().unwrap();
+/// let db_cache = data_file.path();
+/// let limits = CacheConfig {
+///     max_size_bytes: Some(100_000_000),
+///     max_blocks: None,
+/// };
+/// init_cache_database_with_limits(&db_cache, &limits).unwrap();
+/// ```
+///
+/// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+/// [`enforce_cache_limits`]: crate::cache::enforce_cache_limits
+pub fn init_cache_database_with_limits<P: AsRef<Path>>(
+    db_cache: P,
+    limits: &CacheConfig,
+) -> Result<(), Error> {
+    init_cache_database(&db_cache)?;
+
+    let cache = Connection::open(db_cache)?;
+    cache.execute(
+        "CREATE TABLE IF NOT EXISTS cache_config (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            max_size_bytes INTEGER,
+            max_blocks INTEGER
+        )",
+        NO_PARAMS,
+    )?;
+    cache.execute(
+        "INSERT OR REPLACE INTO cache_config (id, max_size_bytes, max_blocks)
+        VALUES (0, ?, ?)",
+        &[
+            limits.max_size_bytes.map(|size| size as i64).to_sql()?,
+            limits.max_blocks.to_sql()?,
+        ],
+    )?;
+    Ok(())
+}
+
 /// Sets up the internal structure of the data database.
 ///
+/// Databases created by earlier versions of this library are rejected with
+/// [`ErrorKind::SchemaTooOld`], and must be upgraded with [`migrate_data_database`]
+/// before use. Databases created by later versions of this library are rejected with
+/// [`ErrorKind::SchemaTooNew`].
+///
 /// # Examples
 ///
 /// ```
@@ -48,12 +216,46 @@ pub fn init_cache_database<P: AsRef<Path>>(db_cache: P) -> Result<(), Error> {
 /// init_data_database(&db_data).unwrap();
 /// ```
 pub fn init_data_database<P: AsRef<Path>>(db_data: P) -> Result<(), Error> {
+    init_data_database_with_config(db_data, &SqliteConfig::default())
+}
+
+/// Sets up the internal structure of the data database, using the given
+/// [`SqliteConfig`].
+///
+/// The page size can only be set when the database is first created; it is ignored for
+/// existing databases.
+///
+/// # Examples
+///
+/// ```
+/// use tempfile::NamedTempFile;
+/// use zcash_client_sqlite::init::{init_data_database_with_config, SqliteConfig};
+///
+/// let data_file = NamedTempFile::new().unwrap();
+/// let db_data = data_file.path();
+/// init_data_database_with_config(&db_data, &SqliteConfig::for_scanning()).unwrap();
+/// ```
+pub fn init_data_database_with_config<P: AsRef<Path>>(
+    db_data: P,
+    config: &SqliteConfig,
+) -> Result<(), Error> {
     let data = Connection::open(db_data)?;
+    config.apply(&data)?;
+    let is_new = !data
+        .prepare("SELECT * FROM sqlite_master WHERE type = 'table' AND name = 'accounts'")?
+        .exists(NO_PARAMS)?;
+    if !is_new {
+        check_schema_version(&data)?;
+    }
+
     data.execute(
         "CREATE TABLE IF NOT EXISTS accounts (
             account INTEGER PRIMARY KEY,
             extfvk TEXT NOT NULL,
-            address TEXT NOT NULL
+            address TEXT NOT NULL,
+            birthday_height INTEGER,
+            orchard_fvk TEXT,
+            orchard_balance_zat INTEGER NOT NULL DEFAULT 0
         )",
         NO_PARAMS,
     )?;
@@ -75,6 +277,7 @@ pub fn init_data_database<P: AsRef<Path>>(db_data: P) -> Result<(), Error> {
             tx_index INTEGER,
             expiry_height INTEGER,
             raw BLOB,
+            failed BOOLEAN NOT NULL DEFAULT 0,
             FOREIGN KEY (block) REFERENCES blocks(height)
         )",
         NO_PARAMS,
@@ -92,6 +295,7 @@ pub fn init_data_database<P: AsRef<Path>>(db_data: P) -> Result<(), Error> {
             is_change BOOLEAN NOT NULL,
             memo BLOB,
             spent INTEGER,
+            commitment_tree_position INTEGER,
             FOREIGN KEY (tx) REFERENCES transactions(id_tx),
             FOREIGN KEY (account) REFERENCES accounts(account),
             FOREIGN KEY (spent) REFERENCES transactions(id_tx),
@@ -126,6 +330,380 @@ pub fn init_data_database<P: AsRef<Path>>(db_data: P) -> Result<(), Error> {
         )",
         NO_PARAMS,
     )?;
+    add_scheduled_payments(&data)?;
+    add_scan_cursor_table(&data)?;
+    #[cfg(feature = "memo-search")]
+    add_memo_search(&data)?;
+
+    add_indices(&data)?;
+
+    // Record the network this database is for, or check it if already recorded.
+    if !add_wallet_config(&data)? {
+        check_network(&data)?;
+    }
+
+    // New databases are created with the current schema.
+    if is_new {
+        set_data_db_version(&data, DATA_DB_VERSION)?;
+    }
+
+    Ok(())
+}
+
+fn set_data_db_version(data: &Connection, version: i32) -> Result<(), Error> {
+    data.execute(&format!("PRAGMA user_version = {}", version), NO_PARAMS)?;
+    Ok(())
+}
+
+/// Upgrades a data database created by an earlier version of this library to the
+/// current schema.
+///
+/// The migration is applied atomically. Calling this on an up-to-date database has no
+/// effect.
+///
+/// If `backup_dir` is provided and the database needs to be migrated, the database file
+/// is first copied to `backup_dir/wallet_backup_<timestamp>.db`, and the path of the
+/// backup is returned. If the migration fails, the database can be restored manually
+/// from the backup. The backup is never removed automatically; once the migrated
+/// database has been checked, call [`discard_migration_backup`] to remove it.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::init::{discard_migration_backup, migrate_data_database};
+///
+/// let backup = migrate_data_database("/path/to/data.db", Some("/path/to/backups"));
+/// if let Ok(Some(backup_path)) = backup {
+///     // Check that the wallet works as expected, and then:
+///     discard_migration_backup(backup_path);
+/// }
+/// ```
+pub fn migrate_data_database<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_data: P,
+    backup_dir: Option<Q>,
+) -> Result<Option<PathBuf>, Error> {
+    let data = Connection::open(&db_data)?;
+    let version = check_schema_not_newer(&data)?;
+    if version >= DATA_DB_VERSION {
+        return Ok(None);
+    }
+
+    // No transaction is open on the connection yet, so the file can be safely copied.
+    let backup_path = match backup_dir {
+        Some(backup_dir) => {
+            let backup_path = backup_dir
+                .as_ref()
+                .join(format!("wallet_backup_{}.db", time::get_time().sec));
+            fs::copy(&db_data, &backup_path)?;
+            Some(backup_path)
+        }
+        None => None,
+    };
+
+    if version < 1 {
+        data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+        add_witness_checksums(&data)?;
+        add_account_birthdays(&data)?;
+        add_scan_cursor_table(&data)?;
+        add_orchard_fvks(&data)?;
+        add_orchard_balances(&data)?;
+        add_failed_transactions(&data)?;
+        // Reads the witnesses, so their checksums must have been added.
+        add_note_positions(&data)?;
+        add_wallet_config(&data)?;
+        add_scheduled_payments(&data)?;
+        add_indices(&data)?;
+        set_data_db_version(&data, 1)?;
+        data.execute("COMMIT", NO_PARAMS)?;
+    }
+
+    Ok(backup_path)
+}
+
+/// Removes a backup created by [`migrate_data_database`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::init::discard_migration_backup;
+///
+/// discard_migration_backup("/path/to/backups/wallet_backup_1234567890.db");
+/// ```
+pub fn discard_migration_backup<P: AsRef<Path>>(backup_path: P) -> Result<(), Error> {
+    fs::remove_file(backup_path)?;
+    Ok(())
+}
+
+/// Prefixes every stored witness with its CRC32 checksum.
+fn add_witness_checksums(data: &Connection) -> Result<(), Error> {
+    let mut stmt_fetch_witnesses =
+        data.prepare("SELECT id_witness, witness FROM sapling_witnesses")?;
+    let witnesses = stmt_fetch_witnesses.query_map(NO_PARAMS, |row| {
+        let id_witness: i64 = row.get(0)?;
+        let witness: Vec<u8> = row.get(1)?;
+        Ok((id_witness, witness))
+    })?;
+    let witnesses: Vec<_> = witnesses.collect::<Result<_, _>>()?;
+
+    let mut stmt_update_witness =
+        data.prepare("UPDATE sapling_witnesses SET witness = ? WHERE id_witness = ?")?;
+    for (id_witness, witness) in witnesses {
+        stmt_update_witness.execute(&[
+            add_witness_checksum(&witness).to_sql()?,
+            id_witness.to_sql()?,
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if `table` has the given column.
+fn table_has_column(data: &Connection, table: &str, name: &str) -> Result<bool, Error> {
+    let mut stmt_columns = data.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt_columns.query_map(NO_PARAMS, |row| row.get::<_, String>(1))?;
+    for column in columns {
+        if column? == name {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Adds the `birthday_height` column to the `accounts` table.
+fn add_account_birthdays(data: &Connection) -> Result<(), Error> {
+    // The accounts table may have been created by init_data_database with the column.
+    if !table_has_column(data, "accounts", "birthday_height")? {
+        data.execute(
+            "ALTER TABLE accounts ADD COLUMN birthday_height INTEGER",
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds the `orchard_fvk` column to the `accounts` table.
+fn add_orchard_fvks(data: &Connection) -> Result<(), Error> {
+    if !table_has_column(data, "accounts", "orchard_fvk")? {
+        data.execute(
+            "ALTER TABLE accounts ADD COLUMN orchard_fvk TEXT",
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds the `orchard_balance_zat` column to the `accounts` table.
+///
+/// Orchard outputs are not yet scanned, so existing accounts start with a zero balance.
+fn add_orchard_balances(data: &Connection) -> Result<(), Error> {
+    if !table_has_column(data, "accounts", "orchard_balance_zat")? {
+        data.execute(
+            "ALTER TABLE accounts ADD COLUMN orchard_balance_zat INTEGER NOT NULL DEFAULT 0",
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds the `failed` column to the `transactions` table.
+///
+/// Existing transactions are not marked as failed; those that have expired unmined are
+/// found by the next call to [`recover_failed_sends`].
+///
+/// [`recover_failed_sends`]: crate::transact::recover_failed_sends
+fn add_failed_transactions(data: &Connection) -> Result<(), Error> {
+    if !table_has_column(data, "transactions", "failed")? {
+        data.execute(
+            "ALTER TABLE transactions ADD COLUMN failed BOOLEAN NOT NULL DEFAULT 0",
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds the `commitment_tree_position` column to the `received_notes` table.
+///
+/// The positions of existing notes are read from their most recent witnesses. Notes
+/// without a stored witness, which can no longer be spent, are left without a position.
+fn add_note_positions(data: &Connection) -> Result<(), Error> {
+    if table_has_column(data, "received_notes", "commitment_tree_position")? {
+        return Ok(());
+    }
+    data.execute(
+        "ALTER TABLE received_notes ADD COLUMN commitment_tree_position INTEGER",
+        NO_PARAMS,
+    )?;
+
+    let mut stmt_fetch_witnesses = data.prepare(
+        "SELECT s.note, s.block, s.witness FROM sapling_witnesses AS s
+        WHERE s.block = (SELECT MAX(w.block) FROM sapling_witnesses AS w WHERE w.note = s.note)",
+    )?;
+    let witnesses = stmt_fetch_witnesses.query_map(NO_PARAMS, |row| {
+        let id_note: i64 = row.get(0)?;
+        let block: i32 = row.get(1)?;
+        let witness: Vec<u8> = row.get(2)?;
+        Ok((id_note, block, witness))
+    })?;
+    let witnesses: Vec<_> = witnesses.collect::<Result<_, _>>()?;
+
+    let mut stmt_update_position =
+        data.prepare("UPDATE received_notes SET commitment_tree_position = ? WHERE id_note = ?")?;
+    for (id_note, block, witness) in witnesses {
+        let position = read_witness(&witness, id_note, block)?.position();
+        stmt_update_position.execute(&[(position as i64).to_sql()?, id_note.to_sql()?])?;
+    }
+
+    Ok(())
+}
+
+/// Creates the `wallet_config` table if necessary, and records this library's network
+/// in it if no network is recorded. Returns `true` if the network was recorded.
+fn add_wallet_config(data: &Connection) -> Result<bool, Error> {
+    data.execute(
+        "CREATE TABLE IF NOT EXISTS wallet_config (
+            network_type TEXT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+    if data
+        .prepare("SELECT * FROM wallet_config LIMIT 1")?
+        .exists(NO_PARAMS)?
+    {
+        Ok(false)
+    } else {
+        data.execute(
+            "INSERT INTO wallet_config (network_type) VALUES (?)",
+            &[NETWORK_TYPE],
+        )?;
+        Ok(true)
+    }
+}
+
+/// Creates the `scheduled_payments` table, which holds the recurring payments managed
+/// by the [`schedule`](crate::schedule) module.
+fn add_scheduled_payments(data: &Connection) -> Result<(), Error> {
+    data.execute(
+        "CREATE TABLE IF NOT EXISTS scheduled_payments (
+            id INTEGER PRIMARY KEY,
+            account INTEGER NOT NULL,
+            to_address TEXT NOT NULL,
+            amount_zat INTEGER NOT NULL,
+            memo BLOB,
+            interval_blocks INTEGER NOT NULL,
+            next_due_block INTEGER NOT NULL,
+            FOREIGN KEY (account) REFERENCES accounts(account)
+        )",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+/// Indexes the unspent notes used for balances and note selection, and the witness
+/// heights used when pruning.
+fn add_indices(data: &Connection) -> Result<(), Error> {
+    data.execute(
+        "CREATE INDEX IF NOT EXISTS idx_unspent_notes
+        ON received_notes(account, value) WHERE spent IS NULL",
+        NO_PARAMS,
+    )?;
+    data.execute(
+        "CREATE INDEX IF NOT EXISTS idx_witnesses_block ON sapling_witnesses(block)",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+/// Returns SQL that evaluates to `true` if the memo in the column `memo` is text, as
+/// defined by ZIP 302.
+#[cfg(feature = "memo-search")]
+fn is_text_memo(memo: &str) -> String {
+    format!("hex(substr({}, 1, 1)) BETWEEN '01' AND 'F4'", memo)
+}
+
+/// Returns SQL that evaluates to the text of the memo in the column `memo`, without its
+/// trailing zero padding.
+#[cfg(feature = "memo-search")]
+fn memo_text(memo: &str) -> String {
+    format!(
+        "CAST(CASE instr({0}, x'00') WHEN 0 THEN {0} ELSE substr({0}, 1, instr({0}, x'00') - 1) END AS TEXT)",
+        memo
+    )
+}
+
+/// Creates the full-text index of received text memos, and the triggers that keep it in
+/// sync with `received_notes`. Memos already in the wallet are indexed when the index is
+/// first created.
+#[cfg(feature = "memo-search")]
+fn add_memo_search(data: &Connection) -> Result<(), Error> {
+    let is_new = !data
+        .prepare("SELECT * FROM sqlite_master WHERE type = 'table' AND name = 'memo_search'")?
+        .exists(NO_PARAMS)?;
+
+    data.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS memo_search
+        USING fts5(id_note UNINDEXED, memo_text)",
+        NO_PARAMS,
+    )?;
+
+    // Each row's rowid is the id_note of the indexed note, so it can be removed cheaply.
+    let insert = format!(
+        "INSERT INTO memo_search (rowid, id_note, memo_text)
+        SELECT new.id_note, new.id_note, {} WHERE {};",
+        memo_text("new.memo"),
+        is_text_memo("new.memo"),
+    );
+    let delete = "DELETE FROM memo_search WHERE rowid = old.id_note;";
+    data.execute(
+        &format!(
+            "CREATE TRIGGER IF NOT EXISTS memo_search_insert
+            AFTER INSERT ON received_notes BEGIN {} END",
+            insert
+        ),
+        NO_PARAMS,
+    )?;
+    data.execute(
+        &format!(
+            "CREATE TRIGGER IF NOT EXISTS memo_search_update
+            AFTER UPDATE OF memo ON received_notes BEGIN {} {} END",
+            delete, insert
+        ),
+        NO_PARAMS,
+    )?;
+    data.execute(
+        &format!(
+            "CREATE TRIGGER IF NOT EXISTS memo_search_delete
+            AFTER DELETE ON received_notes BEGIN {} END",
+            delete
+        ),
+        NO_PARAMS,
+    )?;
+
+    if is_new {
+        data.execute(
+            &format!(
+                "INSERT INTO memo_search (rowid, id_note, memo_text)
+                SELECT id_note, id_note, {} FROM received_notes WHERE {}",
+                memo_text("memo"),
+                is_text_memo("memo"),
+            ),
+            NO_PARAMS,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Creates the `scan_cursor` table, which holds at most one row recording the last block
+/// committed by `scan_cached_blocks`.
+fn add_scan_cursor_table(data: &Connection) -> Result<(), Error> {
+    data.execute(
+        "CREATE TABLE IF NOT EXISTS scan_cursor (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            last_committed_height INTEGER NOT NULL,
+            tree_state_hash BLOB NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
     Ok(())
 }
 
@@ -136,6 +714,11 @@ pub fn init_data_database<P: AsRef<Path>>(db_data: P) -> Result<(), Error> {
 /// be arranged in account-order; that is, the [`ExtendedFullViewingKey`] for ZIP 32
 /// account `i` **MUST** be at `extfvks[i]`.
 ///
+/// Account indices **MUST** be allocated sequentially, starting from zero, so that the
+/// ZIP 32 account index of each key matches its position in the accounts table. Use
+/// [`get_account_count`] to determine the index of the next account, and
+/// [`ExtendedSpendingKey::from_zip32_path`] to derive its key.
+///
 /// # Examples
 ///
 /// ```
@@ -152,6 +735,8 @@ pub fn init_data_database<P: AsRef<Path>>(db_data: P) -> Result<(), Error> {
 /// init_accounts_table(&db_data, &extfvks).unwrap();
 /// ```
 ///
+/// [`get_account_count`]: crate::query::get_account_count
+/// [`ExtendedSpendingKey::from_zip32_path`]: zcash_primitives::zip32::ExtendedSpendingKey::from_zip32_path
 /// [`get_address`]: crate::query::get_address
 /// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
 /// [`create_to_address`]: crate::transact::create_to_address
@@ -159,7 +744,44 @@ pub fn init_accounts_table<P: AsRef<Path>>(
     db_data: P,
     extfvks: &[ExtendedFullViewingKey],
 ) -> Result<(), Error> {
-    let data = Connection::open(db_data)?;
+    init_accounts_table_with_birthdays(db_data, extfvks, &[])
+}
+
+/// Initialises the data database with the given [`ExtendedFullViewingKey`]s and their
+/// birthday heights.
+///
+/// `birthdays[i]` is the height at which the account for `extfvks[i]` was created.
+/// [`scan_cached_blocks`] does not look for notes received by an account in blocks below
+/// its birthday height. Accounts without a corresponding entry in `birthdays` are
+/// scanned in every block.
+///
+/// See [`init_accounts_table`] for the requirements on `extfvks`.
+///
+/// # Examples
+///
+/// ```
+/// use tempfile::NamedTempFile;
+/// use zcash_client_sqlite::init::{init_accounts_table_with_birthdays, init_data_database};
+/// use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+///
+/// let data_file = NamedTempFile::new().unwrap();
+/// let db_data = data_file.path();
+/// init_data_database(&db_data).unwrap();
+///
+/// let extfvks = [
+///     ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0])),
+///     ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1])),
+/// ];
+/// init_accounts_table_with_birthdays(&db_data, &extfvks, &[419_200, 500_000]).unwrap();
+/// ```
+///
+/// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+pub fn init_accounts_table_with_birthdays<P: AsRef<Path>>(
+    db_data: P,
+    extfvks: &[ExtendedFullViewingKey],
+    birthdays: &[u32],
+) -> Result<(), Error> {
+    let data = open_data_database(db_data)?;
 
     let mut empty_check = data.prepare("SELECT * FROM accounts LIMIT 1")?;
     if empty_check.exists(NO_PARAMS)? {
@@ -173,12 +795,13 @@ pub fn init_accounts_table<P: AsRef<Path>>(
         let extfvk =
             encode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, extfvk);
         data.execute(
-            "INSERT INTO accounts (account, extfvk, address)
-            VALUES (?, ?, ?)",
+            "INSERT INTO accounts (account, extfvk, address, birthday_height)
+            VALUES (?, ?, ?, ?)",
             &[
                 (account as u32).to_sql()?,
                 extfvk.to_sql()?,
                 address.to_sql()?,
+                birthdays.get(account).cloned().to_sql()?,
             ],
         )?;
     }
@@ -217,7 +840,7 @@ pub fn init_blocks_table<P: AsRef<Path>>(
     time: u32,
     sapling_tree: &[u8],
 ) -> Result<(), Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_data_database(db_data)?;
 
     let mut empty_check = data.prepare("SELECT * FROM blocks LIMIT 1")?;
     if empty_check.exists(NO_PARAMS)? {
@@ -240,15 +863,39 @@ pub fn init_blocks_table<P: AsRef<Path>>(
 
 #[cfg(test)]
 mod tests {
-    use tempfile::NamedTempFile;
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::fs;
+    use std::path::Path;
+    use tempfile::{tempdir, NamedTempFile};
     use zcash_client_backend::encoding::decode_payment_address;
     use zcash_primitives::{
         block::BlockHash,
+        merkle_tree::{CommitmentTree, Hashable, IncrementalWitness},
+        sapling::Node,
+        transaction::components::Amount,
         zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
     };
 
-    use super::{init_accounts_table, init_blocks_table, init_data_database};
-    use crate::{query::get_address, HRP_SAPLING_PAYMENT_ADDRESS};
+    use super::{
+        discard_migration_backup, init_accounts_table, init_blocks_table, init_cache_database,
+        init_cache_database_with_config, init_data_database, init_data_database_with_config,
+        migrate_data_database, SqliteConfig, DATA_DB_VERSION,
+    };
+    use crate::{
+        error::{Error, ErrorKind},
+        privacy::analyze_transaction_graph,
+        query::{get_account_count, get_address, get_balance, open_wallet_readonly},
+        read_witness,
+        scan::scan_cached_blocks,
+        schedule::list_scheduled_payments,
+        tests::{fake_compact_block, insert_into_cache},
+        HRP_SAPLING_PAYMENT_ADDRESS, SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    fn get_data_db_version(data: &Connection) -> i32 {
+        data.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
 
     #[test]
     fn init_accounts_table_only_works_once() {
@@ -300,4 +947,407 @@ mod tests {
         let pa = decode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &addr).unwrap();
         assert_eq!(pa.unwrap(), extsk.default_address().unwrap().1);
     }
+
+    #[test]
+    fn init_with_config_sets_page_size() {
+        let page_size = |path| -> u32 {
+            Connection::open(path)
+                .unwrap()
+                .query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))
+                .unwrap()
+        };
+        let config = SqliteConfig::for_scanning();
+
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database_with_config(&db_cache, &config).unwrap();
+        assert_eq!(page_size(db_cache), config.page_size);
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database_with_config(&db_data, &config).unwrap();
+        assert_eq!(page_size(db_data), config.page_size);
+
+        // The page size of an existing database is unchanged
+        init_data_database(&db_data).unwrap();
+        assert_eq!(page_size(db_data), config.page_size);
+
+        // The default configuration uses SQLite's default page size
+        let default_file = NamedTempFile::new().unwrap();
+        init_cache_database(default_file.path()).unwrap();
+        assert_eq!(page_size(default_file.path()), 4096);
+    }
+
+    #[test]
+    fn cache_database_is_keyed_by_height() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let cache = Connection::open(db_cache).unwrap();
+        let mut stmt = cache
+            .prepare(
+                "EXPLAIN QUERY PLAN
+                SELECT height, data FROM compactblocks WHERE height > ? ORDER BY height ASC",
+            )
+            .unwrap();
+        let plan = stmt
+            .query_map(&[0], |row| row.get::<_, String>(3))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+            .join("\n");
+        assert!(plan.contains("USING INTEGER PRIMARY KEY"), "{}", plan);
+        assert!(!plan.contains("TEMP B-TREE"), "{}", plan);
+
+        // A block cannot be cached twice at the same height
+        cache
+            .execute(
+                "INSERT INTO compactblocks (height, data) VALUES (1, x'00')",
+                NO_PARAMS,
+            )
+            .unwrap();
+        assert!(cache
+            .execute(
+                "INSERT INTO compactblocks (height, data) VALUES (1, x'01')",
+                NO_PARAMS,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn init_data_database_creates_indices() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        let data = Connection::open(db_data).unwrap();
+        let query_plan = |query: &str| -> String {
+            let mut stmt = data
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", query))
+                .unwrap();
+            let details = stmt
+                .query_map(NO_PARAMS, |row| row.get::<_, String>(3))
+                .unwrap();
+            details.collect::<Result<Vec<_>, _>>().unwrap().join("\n")
+        };
+
+        assert!(query_plan(
+            "SELECT SUM(value) FROM received_notes WHERE account = 0 AND spent IS NULL"
+        )
+        .contains("idx_unspent_notes"));
+        assert!(
+            query_plan("DELETE FROM sapling_witnesses WHERE block < 100")
+                .contains("idx_witnesses_block")
+        );
+    }
+
+    #[test]
+    fn newer_schema_is_rejected() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Pretend the database was created by a newer version of this library
+        Connection::open(db_data)
+            .unwrap()
+            .execute(
+                &format!("PRAGMA user_version = {}", DATA_DB_VERSION + 1),
+                NO_PARAMS,
+            )
+            .unwrap();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        for e in vec![
+            init_data_database(&db_data).unwrap_err(),
+            init_accounts_table(&db_data, &extfvks).unwrap_err(),
+            migrate_data_database(&db_data, None::<&Path>).unwrap_err(),
+        ] {
+            match e.kind() {
+                ErrorKind::SchemaTooNew(found, expected) => {
+                    assert_eq!(*found, DATA_DB_VERSION + 1);
+                    assert_eq!(*expected, DATA_DB_VERSION);
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn older_schema_is_rejected() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Pretend the database was created by an older version of this library
+        Connection::open(db_data)
+            .unwrap()
+            .execute(
+                &format!("PRAGMA user_version = {}", DATA_DB_VERSION - 1),
+                NO_PARAMS,
+            )
+            .unwrap();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        for e in vec![
+            init_data_database(&db_data).unwrap_err(),
+            init_accounts_table(&db_data, &extfvks).unwrap_err(),
+            get_balance(&db_data, 0).unwrap_err(),
+        ] {
+            match e.kind() {
+                ErrorKind::SchemaTooOld(found, expected) => {
+                    assert_eq!(*found, DATA_DB_VERSION - 1);
+                    assert_eq!(*expected, DATA_DB_VERSION);
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            }
+        }
+
+        // Migrating the database makes it usable again
+        migrate_data_database(&db_data, None::<&Path>).unwrap();
+        init_data_database(&db_data).unwrap();
+        init_accounts_table(&db_data, &extfvks).unwrap();
+    }
+
+    #[test]
+    fn init_data_database_records_network() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Initialising the database again is fine
+        init_data_database(&db_data).unwrap();
+
+        // Pretend the database was created for a different network
+        Connection::open(db_data)
+            .unwrap()
+            .execute("UPDATE wallet_config SET network_type = 'other'", NO_PARAMS)
+            .unwrap();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        for e in vec![
+            init_data_database(&db_data).unwrap_err(),
+            init_accounts_table(&db_data, &extfvks).unwrap_err(),
+            get_balance(&db_data, 0).unwrap_err(),
+            analyze_transaction_graph(&db_data, 0).unwrap_err(),
+            list_scheduled_payments(&db_data).unwrap_err(),
+        ] {
+            match e.kind() {
+                ErrorKind::NetworkMismatch(stored, _) => assert_eq!(stored, "other"),
+                _ => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn migrate_data_database_adds_witness_checksums() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // New databases use the current schema
+        let data = Connection::open(db_data).unwrap();
+        assert_eq!(get_data_db_version(&data), DATA_DB_VERSION);
+
+        // Pretend the database was created without witness checksums
+        let mut tree = CommitmentTree::new();
+        tree.append(Node::blank()).unwrap();
+        let witness = IncrementalWitness::from_tree(&tree);
+        let mut encoded = vec![];
+        witness.write(&mut encoded).unwrap();
+        data.execute("PRAGMA user_version = 0", NO_PARAMS).unwrap();
+        data.execute(
+            "INSERT INTO sapling_witnesses (note, block, witness) VALUES (1, 1, ?)",
+            &[&encoded],
+        )
+        .unwrap();
+
+        // Migrating backs up the database and adds the checksum
+        let backup_dir = tempdir().unwrap();
+        let backup_path = migrate_data_database(&db_data, Some(backup_dir.path()))
+            .unwrap()
+            .unwrap();
+        assert!(backup_path.starts_with(backup_dir.path()));
+        let backup = Connection::open(&backup_path).unwrap();
+        assert_eq!(get_data_db_version(&backup), 0);
+        assert_eq!(get_data_db_version(&data), DATA_DB_VERSION);
+        let stored: Vec<u8> = data
+            .query_row("SELECT witness FROM sapling_witnesses", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(read_witness(&stored, 1, 1).unwrap().root(), witness.root());
+
+        // The backup is only removed when requested
+        drop(backup);
+        assert!(backup_path.exists());
+        discard_migration_backup(&backup_path).unwrap();
+        assert!(!backup_path.exists());
+
+        // Migrating again has no effect, and creates no backup
+        assert_eq!(
+            migrate_data_database(&db_data, Some(backup_dir.path())).unwrap(),
+            None
+        );
+        assert_eq!(
+            migrate_data_database(&db_data, None::<&Path>).unwrap(),
+            None
+        );
+        let stored_again: Vec<u8> = data
+            .query_row("SELECT witness FROM sapling_witnesses", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(stored_again, stored);
+
+        // A flipped bit is detected
+        let mut corrupted = stored.clone();
+        corrupted[10] ^= 1;
+        match read_witness(&corrupted, 1, 1) {
+            Err(e) => match e.kind() {
+                ErrorKind::CorruptWitness(1, 1) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+
+    #[test]
+    fn migrate_data_database_adds_orchard_balances() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Pretend the database was created before the column existed
+        let data = Connection::open(db_data).unwrap();
+        data.execute_batch(
+            "DROP TABLE accounts;
+            CREATE TABLE accounts (
+                account INTEGER PRIMARY KEY,
+                extfvk TEXT NOT NULL,
+                address TEXT NOT NULL,
+                birthday_height INTEGER,
+                orchard_fvk TEXT
+            );
+            INSERT INTO accounts (account, extfvk, address) VALUES (0, '', '');
+            PRAGMA user_version = 0;",
+        )
+        .unwrap();
+
+        migrate_data_database(&db_data, None::<&Path>).unwrap();
+        assert_eq!(get_data_db_version(&data), DATA_DB_VERSION);
+        let balance: i64 = data
+            .query_row(
+                "SELECT orchard_balance_zat FROM accounts WHERE account = 0",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(balance, 0);
+    }
+
+    #[test]
+    fn migrate_data_database_adds_failed_transactions() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Pretend the database was created before the column existed
+        let data = Connection::open(db_data).unwrap();
+        data.execute_batch(
+            "DROP TABLE transactions;
+            CREATE TABLE transactions (
+                id_tx INTEGER PRIMARY KEY,
+                txid BLOB NOT NULL UNIQUE,
+                created TEXT,
+                block INTEGER,
+                tx_index INTEGER,
+                expiry_height INTEGER,
+                raw BLOB,
+                FOREIGN KEY (block) REFERENCES blocks(height)
+            );
+            INSERT INTO transactions (txid, expiry_height) VALUES (x'00', 10);
+            PRAGMA user_version = 0;",
+        )
+        .unwrap();
+
+        migrate_data_database(&db_data, None::<&Path>).unwrap();
+        assert_eq!(get_data_db_version(&data), DATA_DB_VERSION);
+        let failed: bool = data
+            .query_row("SELECT failed FROM transactions", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(!failed);
+    }
+
+    #[test]
+    #[cfg(not(feature = "mainnet"))]
+    fn test_cross_version_db_compat() {
+        // The schema versions of released versions of this crate. Each has a testnet
+        // wallet in testdata/ holding the same fixture, written with that schema (see
+        // examples/generate_testdata.rs).
+        const RELEASED_VERSIONS: &[i32] = &[0];
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+
+        for &version in RELEASED_VERSIONS {
+            let fixture = testdata.join(format!("wallet_v{}.db", version));
+            assert!(fixture.exists(), "Missing fixture {}", fixture.display());
+
+            // Work on a copy, so the committed file is never modified
+            let data_file = NamedTempFile::new().unwrap();
+            let db_data = data_file.path();
+            fs::copy(&fixture, db_data).unwrap();
+
+            // The database must be migrated before it can be used
+            if version < DATA_DB_VERSION {
+                let check_too_old = |e: Error| match e.kind() {
+                    ErrorKind::SchemaTooOld(found, expected) => {
+                        assert_eq!(*found, version);
+                        assert_eq!(*expected, DATA_DB_VERSION);
+                    }
+                    _ => panic!("Unexpected error: {:?}", e),
+                };
+                match open_wallet_readonly(db_data) {
+                    Err(e) => check_too_old(e),
+                    Ok(_) => panic!("Should have failed"),
+                }
+                match get_balance(db_data, 0) {
+                    Err(e) => check_too_old(e),
+                    Ok(_) => panic!("Should have failed"),
+                }
+            }
+
+            // Migrating it to the current version preserves the accounts and balance
+            migrate_data_database(db_data, None::<&Path>).unwrap();
+            let data = Connection::open(db_data).unwrap();
+            assert_eq!(get_data_db_version(&data), DATA_DB_VERSION);
+            assert_eq!(get_account_count(db_data).unwrap(), 1);
+            assert_eq!(
+                get_balance(db_data, 0).unwrap(),
+                Amount::from_u64(55_000).unwrap()
+            );
+
+            // The migrated database can be scanned
+            let cache_file = NamedTempFile::new().unwrap();
+            let db_cache = cache_file.path();
+            init_cache_database(&db_cache).unwrap();
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + 2,
+                BlockHash([0; 32]),
+                extfvk.clone(),
+                Amount::from_u64(10_000).unwrap(),
+            );
+            insert_into_cache(db_cache, &cb);
+            scan_cached_blocks(db_cache, db_data).unwrap();
+            assert_eq!(
+                get_balance(db_data, 0).unwrap(),
+                Amount::from_u64(65_000).unwrap()
+            );
+        }
+    }
 }
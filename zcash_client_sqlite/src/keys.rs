@@ -0,0 +1,290 @@
+//! Functions for backing up and restoring key material.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crypto_api_chachapoly::ChachaPolyIetf;
+use rand_core::RngCore;
+use rand_os::OsRng;
+use rusqlite::types::ToSql;
+use std::path::Path;
+use zcash_primitives::{
+    orchard::{OrchardFullViewingKey, ORCHARD_FVK_SIZE},
+    zip32::ExtendedSpendingKey,
+};
+
+use crate::{
+    check_extsk_for_account,
+    error::{Error, ErrorKind},
+    open_data_database,
+};
+
+/// The current version of the encrypted key format.
+const ENCRYPTED_KEY_VERSION: u8 = 1;
+
+const SALT_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+
+/// The size of the header (version, salt, and ciphertext length) that prefixes an
+/// encrypted key.
+const HEADER_SIZE: usize = 1 + SALT_SIZE + 4;
+
+/// Derives a ChaCha20-Poly1305 key from the given password and salt using Argon2id.
+fn derive_key(password: &str, salt: &[u8]) -> Result<Vec<u8>, Error> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        hash_length: 32,
+        ..argon2::Config::default()
+    };
+    Ok(argon2::hash_raw(password.as_bytes(), salt, &config)?)
+}
+
+/// Encrypts the [`ExtendedSpendingKey`] for the given account with a password, for
+/// backing it up.
+///
+/// The encrypted key has the following format:
+///
+/// - A version byte (currently `1`).
+/// - A 16-byte random salt, used to derive the encryption key from the password with
+///   Argon2id.
+/// - The length of the ciphertext, as a 32-bit little-endian integer.
+/// - The serialized [`ExtendedSpendingKey`], encrypted with ChaCha20-Poly1305. The
+///   header is authenticated as associated data.
+///
+/// Returns an error if `extsk` does not correspond to the [`ExtendedFullViewingKey`]
+/// stored for `account`.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{constants::testnet::COIN_TYPE, keys::spending_key};
+/// use zcash_client_sqlite::keys::export_spending_key;
+///
+/// let extsk = spending_key(&[0; 32][..], COIN_TYPE, 0);
+/// let encrypted = export_spending_key("/path/to/data.db", 0, "correct horse", &extsk);
+/// ```
+///
+/// [`ExtendedFullViewingKey`]: zcash_primitives::zip32::ExtendedFullViewingKey
+pub fn export_spending_key<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+    password: &str,
+    extsk: &ExtendedSpendingKey,
+) -> Result<Vec<u8>, Error> {
+    let data = open_data_database(db_data)?;
+    check_extsk_for_account(&data, account, extsk)?;
+
+    let mut plaintext = vec![];
+    extsk.write(&mut plaintext)?;
+
+    let mut encrypted = vec![0; HEADER_SIZE + plaintext.len() + TAG_SIZE];
+    encrypted[0] = ENCRYPTED_KEY_VERSION;
+    OsRng.fill_bytes(&mut encrypted[1..1 + SALT_SIZE]);
+    LittleEndian::write_u32(
+        &mut encrypted[1 + SALT_SIZE..HEADER_SIZE],
+        (plaintext.len() + TAG_SIZE) as u32,
+    );
+
+    // The key is unique to this salt, so we can use a fixed nonce.
+    let key = derive_key(password, &encrypted[1..1 + SALT_SIZE])?;
+    let (header, ciphertext) = encrypted.split_at_mut(HEADER_SIZE);
+    ChachaPolyIetf::aead_cipher()
+        .seal_to(ciphertext, &plaintext, header, &key, &[0u8; 12])
+        .expect("Buffer is the correct size");
+
+    Ok(encrypted)
+}
+
+/// Decrypts an [`ExtendedSpendingKey`] that was encrypted with [`export_spending_key`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::keys::import_spending_key;
+///
+/// let encrypted: Vec<u8> = vec![];
+/// let extsk = import_spending_key(&encrypted, "correct horse");
+/// ```
+pub fn import_spending_key(
+    ciphertext: &[u8],
+    password: &str,
+) -> Result<ExtendedSpendingKey, Error> {
+    if ciphertext.len() < HEADER_SIZE {
+        return Err(Error(ErrorKind::InvalidEncryptedKey("Truncated header")));
+    }
+    if ciphertext[0] != ENCRYPTED_KEY_VERSION {
+        return Err(Error(ErrorKind::InvalidEncryptedKey("Unknown version")));
+    }
+    let (header, body) = ciphertext.split_at(HEADER_SIZE);
+    let body_len = LittleEndian::read_u32(&header[1 + SALT_SIZE..]) as usize;
+    if body.len() != body_len || body_len < TAG_SIZE {
+        return Err(Error(ErrorKind::InvalidEncryptedKey("Incorrect length")));
+    }
+
+    let key = derive_key(password, &header[1..1 + SALT_SIZE])?;
+    let mut plaintext = vec![0; body_len - TAG_SIZE];
+    ChachaPolyIetf::aead_cipher()
+        .open_to(&mut plaintext, body, header, &key, &[0u8; 12])
+        .map_err(|_| Error(ErrorKind::KeyDecryptionFailed))?;
+
+    Ok(ExtendedSpendingKey::read(&plaintext[..])?)
+}
+
+/// Stores the Orchard full viewing key for the given account.
+///
+/// Orchard is not yet supported, so the key is only stored for later use. It is encoded
+/// in the `accounts` table as hex.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::keys::set_orchard_fvk;
+/// use zcash_primitives::orchard::OrchardFullViewingKey;
+///
+/// let fvk = OrchardFullViewingKey::from_bytes([0; 96]);
+/// set_orchard_fvk("/path/to/data.db", 0, &fvk);
+/// ```
+pub fn set_orchard_fvk<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+    fvk: &OrchardFullViewingKey,
+) -> Result<(), Error> {
+    let data = open_data_database(db_data)?;
+
+    let updated = data.execute(
+        "UPDATE accounts SET orchard_fvk = ? WHERE account = ?",
+        &[
+            hex::encode(&fvk.to_bytes()[..]).to_sql()?,
+            account.to_sql()?,
+        ],
+    )?;
+    if updated == 0 {
+        return Err(Error(ErrorKind::UnknownAccount(account)));
+    }
+
+    Ok(())
+}
+
+/// Returns the Orchard full viewing key stored for the given account, or `None` if one
+/// has not been set with [`set_orchard_fvk`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::keys::get_orchard_fvk;
+///
+/// let fvk = get_orchard_fvk("/path/to/data.db", 0);
+/// ```
+pub fn get_orchard_fvk<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+) -> Result<Option<OrchardFullViewingKey>, Error> {
+    let data = open_data_database(db_data)?;
+
+    let fvk: Option<String> = match data.query_row(
+        "SELECT orchard_fvk FROM accounts WHERE account = ?",
+        &[account],
+        |row| row.get(0),
+    ) {
+        Ok(fvk) => fvk,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(Error(ErrorKind::UnknownAccount(account)))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match fvk {
+        Some(fvk) => {
+            let fvk = hex::decode(fvk)
+                .map_err(|_| Error(ErrorKind::CorruptedData("Invalid Orchard FVK encoding")))?;
+            if fvk.len() != ORCHARD_FVK_SIZE {
+                return Err(Error(ErrorKind::CorruptedData(
+                    "Invalid Orchard FVK length",
+                )));
+            }
+            let mut bytes = [0; ORCHARD_FVK_SIZE];
+            bytes.copy_from_slice(&fvk);
+            Ok(Some(OrchardFullViewingKey::from_bytes(bytes)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+    use zcash_primitives::{
+        orchard::OrchardFullViewingKey,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{export_spending_key, get_orchard_fvk, import_spending_key, set_orchard_fvk};
+    use crate::{
+        error::ErrorKind,
+        init::{init_accounts_table, init_data_database},
+    };
+
+    #[test]
+    fn export_then_import_spending_key() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        // We cannot export a key for the wrong account
+        export_spending_key(db_data, 0, "password", &ExtendedSpendingKey::master(&[0]))
+            .unwrap_err();
+
+        let encrypted = export_spending_key(db_data, 0, "password", &extsk).unwrap();
+        assert_eq!(import_spending_key(&encrypted, "password").unwrap(), extsk);
+
+        // An incorrect password fails to decrypt
+        match import_spending_key(&encrypted, "wrong password") {
+            Err(e) => match e.kind() {
+                ErrorKind::KeyDecryptionFailed => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // Truncated ciphertexts are detected
+        match import_spending_key(&encrypted[..encrypted.len() - 1], "password") {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidEncryptedKey(_) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+
+    #[test]
+    fn set_then_get_orchard_fvk() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        // No Orchard key is stored by default
+        assert_eq!(get_orchard_fvk(db_data, 0).unwrap(), None);
+
+        let fvk = OrchardFullViewingKey::from_bytes([7; 96]);
+        set_orchard_fvk(db_data, 0, &fvk).unwrap();
+        assert_eq!(get_orchard_fvk(db_data, 0).unwrap(), Some(fvk));
+
+        // The account must exist
+        match set_orchard_fvk(db_data, 1, &fvk) {
+            Err(e) => match e.kind() {
+                ErrorKind::UnknownAccount(1) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+}
@@ -21,13 +21,44 @@
 //! The `mainnet` feature configures the light client for use with the Zcash mainnet. By
 //! default, the light client is configured for use with the Zcash testnet.
 //!
+//! The data database records the network it was created for. APIs that modify the data
+//! database will return an error if it was created for a different network.
+//!
+//! The `memo-search` feature maintains a full-text index of the text memos of received
+//! notes, which can be queried with [`search_memos`]. It relies on the FTS5 extension,
+//! which the bundled SQLite includes.
+//!
+//! The `multicore` feature enables [`par_scan_block_ranges`], which scans ranges of
+//! blocks in parallel using `rayon`.
+//!
+//! The `perf-tests` feature enables a test that scans 10,000 blocks and fails if
+//! throughput is below `ZCASH_SCAN_BASELINE_BPS` blocks per second (default 500). Run it
+//! in release mode: `cargo test --release --features perf-tests test_scan_throughput`.
+//!
+//! The `transparent-inputs` feature enables [`build_shielding_tx`], which moves funds
+//! from a transparent coin into the shielded pool.
+//!
 //! [`CompactBlock`]: zcash_client_backend::proto::compact_formats::CompactBlock
+//! [`build_shielding_tx`]: crate::transact::build_shielding_tx
 //! [`init_cache_database`]: crate::init::init_cache_database
+//! [`par_scan_block_ranges`]: crate::parallel::par_scan_block_ranges
+//! [`search_memos`]: crate::query::search_memos
 
-use rusqlite::{Connection, NO_PARAMS};
+use byteorder::{ByteOrder, LittleEndian};
+use rusqlite::{types::ToSql, Connection, NO_PARAMS};
 use std::cmp;
-use zcash_client_backend::encoding::encode_payment_address;
-use zcash_primitives::zip32::ExtendedFullViewingKey;
+use std::path::Path;
+use zcash_client_backend::{
+    constants::{
+        OVERWINTER_CONSENSUS_BRANCH_ID, SAPLING_CONSENSUS_BRANCH_ID, SPROUT_CONSENSUS_BRANCH_ID,
+    },
+    encoding::{encode_extended_full_viewing_key, encode_payment_address},
+};
+use zcash_primitives::{
+    merkle_tree::IncrementalWitness,
+    sapling::Node,
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+};
 
 #[cfg(feature = "mainnet")]
 use zcash_client_backend::constants::mainnet::{
@@ -40,29 +71,203 @@ use zcash_client_backend::constants::testnet::{
 };
 
 pub mod address;
+pub mod auth;
+pub mod cache;
 pub mod chain;
 pub mod error;
 pub mod init;
+pub mod keys;
+pub mod parallel;
+pub mod privacy;
 pub mod query;
+pub mod repair;
 pub mod scan;
+pub mod schedule;
+pub mod simulate;
+pub mod storage;
 pub mod transact;
+pub mod utils;
 
 const ANCHOR_OFFSET: u32 = 10;
 
+#[cfg(feature = "mainnet")]
+const OVERWINTER_ACTIVATION_HEIGHT: i32 = 347_500;
+
+#[cfg(not(feature = "mainnet"))]
+const OVERWINTER_ACTIVATION_HEIGHT: i32 = 207_500;
+
 #[cfg(feature = "mainnet")]
 const SAPLING_ACTIVATION_HEIGHT: i32 = 419_200;
 
 #[cfg(not(feature = "mainnet"))]
 const SAPLING_ACTIVATION_HEIGHT: i32 = 280_000;
 
+/// The current version of the data database schema, stored in its `user_version`.
+///
+/// Version history:
+/// - 0: The original schema, as released.
+/// - 1: Witnesses in `sapling_witnesses` are prefixed with a CRC32 checksum. Accounts
+///   have an optional `birthday_height` and `orchard_fvk`, and an
+///   `orchard_balance_zat`. Transactions have a `failed` flag, and received notes have
+///   an optional `commitment_tree_position`. Added the `scan_cursor`, `wallet_config`
+///   and `scheduled_payments` tables, and indices on unspent notes and witness heights.
+const DATA_DB_VERSION: i32 = 1;
+
+/// The network type recorded in the data database.
+#[cfg(feature = "mainnet")]
+const NETWORK_TYPE: &str = "main";
+
+/// The network type recorded in the data database.
+#[cfg(not(feature = "mainnet"))]
+const NETWORK_TYPE: &str = "test";
+
+/// Returns the ID of the consensus branch that is active at `height`.
+fn consensus_branch_id(height: u32) -> u32 {
+    if height >= SAPLING_ACTIVATION_HEIGHT as u32 {
+        SAPLING_CONSENSUS_BRANCH_ID
+    } else if height >= OVERWINTER_ACTIVATION_HEIGHT as u32 {
+        OVERWINTER_CONSENSUS_BRANCH_ID
+    } else {
+        SPROUT_CONSENSUS_BRANCH_ID
+    }
+}
+
 fn address_from_extfvk(extfvk: &ExtendedFullViewingKey) -> String {
     let addr = extfvk.default_address().unwrap().1;
     encode_payment_address(HRP_SAPLING_PAYMENT_ADDRESS, &addr)
 }
 
+/// Checks that the given [`ExtendedSpendingKey`] corresponds to the
+/// [`ExtendedFullViewingKey`] stored for the given account.
+fn check_extsk_for_account(
+    data: &Connection,
+    account: u32,
+    extsk: &ExtendedSpendingKey,
+) -> Result<(), error::Error> {
+    let extfvk = ExtendedFullViewingKey::from(extsk);
+    if data
+        .prepare("SELECT * FROM accounts WHERE account = ? AND extfvk = ?")?
+        .exists(&[
+            account.to_sql()?,
+            encode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &extfvk)
+                .to_sql()?,
+        ])?
+    {
+        Ok(())
+    } else {
+        Err(error::Error(error::ErrorKind::InvalidExtSK(account)))
+    }
+}
+
+/// Checks that the data database was created for the network that this library is
+/// configured for.
+///
+/// Databases created before the network type was recorded have no `wallet_config`
+/// table until they are migrated with [`init::migrate_data_database`], which records
+/// the network of the library performing the migration.
+fn check_network(data: &Connection) -> Result<(), error::Error> {
+    let stored: String =
+        data.query_row("SELECT network_type FROM wallet_config", NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+    if stored == NETWORK_TYPE {
+        Ok(())
+    } else {
+        Err(error::Error(error::ErrorKind::NetworkMismatch(
+            stored,
+            NETWORK_TYPE,
+        )))
+    }
+}
+
+/// Checks that the data database was not created by a newer version of this library,
+/// whose schema we might not write to correctly, and returns its schema version.
+fn check_schema_not_newer(data: &Connection) -> Result<i32, error::Error> {
+    let version: i32 = data.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    if version > DATA_DB_VERSION {
+        Err(error::Error(error::ErrorKind::SchemaTooNew(
+            version,
+            DATA_DB_VERSION,
+        )))
+    } else {
+        Ok(version)
+    }
+}
+
+/// Checks that the data database uses the current schema.
+///
+/// Databases created by an earlier version of this library must be upgraded with
+/// [`init::migrate_data_database`] before they can be used.
+fn check_schema_version(data: &Connection) -> Result<(), error::Error> {
+    let version = check_schema_not_newer(data)?;
+    if version < DATA_DB_VERSION {
+        Err(error::Error(error::ErrorKind::SchemaTooOld(
+            version,
+            DATA_DB_VERSION,
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Opens the data database, checking that it uses the current schema and was created
+/// for the network that this library is configured for.
+///
+/// Databases that are only read should be opened with [`query::open_wallet_readonly`]
+/// instead, which makes the same checks.
+fn open_data_database<P: AsRef<Path>>(db_data: P) -> Result<Connection, error::Error> {
+    let data = Connection::open(db_data)?;
+    check_schema_version(&data)?;
+    check_network(&data)?;
+    Ok(data)
+}
+
+/// Prefixes an encoded witness with its CRC32 checksum, for storage in the
+/// `sapling_witnesses` table.
+fn add_witness_checksum(encoded: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0; 4];
+    LittleEndian::write_u32(&mut ret, crc32fast::hash(encoded));
+    ret.extend_from_slice(encoded);
+    ret
+}
+
+/// Encodes a witness for storage in the `sapling_witnesses` table.
+fn write_witness(witness: &IncrementalWitness<Node>) -> Vec<u8> {
+    let mut encoded = vec![];
+    witness
+        .write(&mut encoded)
+        .expect("Should be able to write to a Vec");
+    add_witness_checksum(&encoded)
+}
+
+/// Decodes a witness stored in the `sapling_witnesses` table for the given note and
+/// block height, verifying its checksum.
+fn read_witness(
+    data: &[u8],
+    id_note: i64,
+    block: i32,
+) -> Result<IncrementalWitness<Node>, error::Error> {
+    let corrupt = || error::Error(error::ErrorKind::CorruptWitness(id_note, block));
+
+    if data.len() < 4 {
+        return Err(corrupt());
+    }
+    let (checksum, encoded) = data.split_at(4);
+    if LittleEndian::read_u32(checksum) != crc32fast::hash(encoded) {
+        return Err(corrupt());
+    }
+    IncrementalWitness::read(encoded).map_err(|_| corrupt())
+}
+
 /// Determines the target height for a transaction, and the height from which to
 /// select anchors, based on the current synchronised block chain.
-fn get_target_and_anchor_heights(data: &Connection) -> Result<(u32, u32), error::Error> {
+///
+/// The anchor is `min_confirmations` blocks back from the target height, so that only
+/// notes with at least that many confirmations are spent.
+fn get_target_and_anchor_heights(
+    data: &Connection,
+    min_confirmations: u32,
+) -> Result<(u32, u32), error::Error> {
     data.query_row_and_then(
         "SELECT MIN(height), MAX(height) FROM blocks",
         NO_PARAMS,
@@ -76,10 +281,10 @@ fn get_target_and_anchor_heights(data: &Connection) -> Result<(u32, u32), error:
             (Ok(min_height), Ok(max_height)) => {
                 let target_height = max_height + 1;
 
-                // Select an anchor ANCHOR_OFFSET back from the target block,
+                // Select an anchor min_confirmations back from the target block,
                 // unless that would be before the earliest block we have.
                 let anchor_height =
-                    cmp::max(target_height.saturating_sub(ANCHOR_OFFSET), min_height);
+                    cmp::max(target_height.saturating_sub(min_confirmations), min_height);
 
                 Ok((target_height, anchor_height))
             }
@@ -89,25 +294,39 @@ fn get_target_and_anchor_heights(data: &Connection) -> Result<(u32, u32), error:
 
 #[cfg(test)]
 mod tests {
-    use ff::{Field, PrimeField, PrimeFieldRepr};
     use pairing::bls12_381::Bls12;
     use protobuf::Message;
-    use rand_core::RngCore;
-    use rand_os::OsRng;
-    use rusqlite::{types::ToSql, Connection};
+    use rusqlite::{types::ToSql, Connection, NO_PARAMS};
     use std::path::Path;
-    use zcash_client_backend::proto::compact_formats::{
-        CompactBlock, CompactOutput, CompactSpend, CompactTx,
+    use zcash_client_backend::{
+        proto::compact_formats::CompactBlock, testing::CompactBlockBuilder,
+        validation::validate_compact_block,
     };
     use zcash_primitives::{
         block::BlockHash,
-        jubjub::fs::Fs,
-        note_encryption::{Memo, SaplingNoteEncryption},
-        primitives::{Note, PaymentAddress},
+        merkle_tree::{CommitmentTree, Hashable, IncrementalWitness},
+        note_encryption::Memo,
+        primitives::PaymentAddress,
+        prover::TxProver,
+        sapling::Node,
         transaction::components::Amount,
         zip32::ExtendedFullViewingKey,
         JUBJUB,
     };
+    use zcash_proofs::prover::LocalTxProver;
+
+    use super::write_witness;
+
+    /// Returns a prover using the Sapling parameters in their default location, or
+    /// panics if they have not been downloaded.
+    pub(crate) fn test_prover() -> impl TxProver {
+        match LocalTxProver::with_default_location() {
+            Some(tx_prover) => tx_prover,
+            None => {
+                panic!("Cannot locate the Zcash parameters. Please run zcash-fetch-params or fetch-params.sh to download the parameters, and then re-run the tests.");
+            }
+        }
+    }
 
     /// Create a fake CompactBlock at the given height, containing a single output paying
     /// the given address. Returns the CompactBlock and the nullifier for the new note.
@@ -117,46 +336,9 @@ mod tests {
         extfvk: ExtendedFullViewingKey,
         value: Amount,
     ) -> (CompactBlock, Vec<u8>) {
-        let to = extfvk.default_address().unwrap().1;
-
-        // Create a fake Note for the account
-        let mut rng = OsRng;
-        let note = Note {
-            g_d: to.diversifier.g_d::<Bls12>(&JUBJUB).unwrap(),
-            pk_d: to.pk_d.clone(),
-            value: value.into(),
-            r: Fs::random(&mut rng),
-        };
-        let encryptor = SaplingNoteEncryption::new(
-            extfvk.fvk.ovk,
-            note.clone(),
-            to.clone(),
-            Memo::default(),
-            &mut rng,
-        );
-        let mut cmu = vec![];
-        note.cm(&JUBJUB).into_repr().write_le(&mut cmu).unwrap();
-        let mut epk = vec![];
-        encryptor.epk().write(&mut epk).unwrap();
-        let enc_ciphertext = encryptor.encrypt_note_plaintext();
-
-        // Create a fake CompactBlock containing the note
-        let mut cout = CompactOutput::new();
-        cout.set_cmu(cmu);
-        cout.set_epk(epk);
-        cout.set_ciphertext(enc_ciphertext[..52].to_vec());
-        let mut ctx = CompactTx::new();
-        let mut txid = vec![0; 32];
-        rng.fill_bytes(&mut txid);
-        ctx.set_hash(txid);
-        ctx.outputs.push(cout);
-        let mut cb = CompactBlock::new();
-        cb.set_height(height as u64);
-        cb.hash.resize(32, 0);
-        rng.fill_bytes(&mut cb.hash);
-        cb.prevHash.extend_from_slice(&prev_hash.0);
-        cb.vtx.push(ctx);
-        (cb, note.nf(&extfvk.fvk.vk, 0, &JUBJUB))
+        let mut builder = CompactBlockBuilder::new(height as u32, prev_hash.0);
+        let note = builder.add_sapling_output(&extfvk, value, Memo::default());
+        (builder.build(), note.nf(&extfvk.fvk.vk, 0, &JUBJUB))
     }
 
     /// Create a fake CompactBlock at the given height, spending a single note from the
@@ -169,85 +351,116 @@ mod tests {
         to: PaymentAddress<Bls12>,
         value: Amount,
     ) -> CompactBlock {
-        let mut rng = OsRng;
-
-        // Create a fake CompactBlock containing the note
-        let mut cspend = CompactSpend::new();
-        cspend.set_nf(nf);
-        let mut ctx = CompactTx::new();
-        let mut txid = vec![0; 32];
-        rng.fill_bytes(&mut txid);
-        ctx.set_hash(txid);
-        ctx.spends.push(cspend);
+        let mut builder = CompactBlockBuilder::new(height as u32, prev_hash.0);
+        builder.add_sapling_spend(&nf);
 
         // Create a fake Note for the payment
-        ctx.outputs.push({
-            let note = Note {
-                g_d: to.diversifier.g_d::<Bls12>(&JUBJUB).unwrap(),
-                pk_d: to.pk_d.clone(),
-                value: value.into(),
-                r: Fs::random(&mut rng),
-            };
-            let encryptor = SaplingNoteEncryption::new(
-                extfvk.fvk.ovk,
-                note.clone(),
-                to,
-                Memo::default(),
-                &mut rng,
-            );
-            let mut cmu = vec![];
-            note.cm(&JUBJUB).into_repr().write_le(&mut cmu).unwrap();
-            let mut epk = vec![];
-            encryptor.epk().write(&mut epk).unwrap();
-            let enc_ciphertext = encryptor.encrypt_note_plaintext();
-
-            let mut cout = CompactOutput::new();
-            cout.set_cmu(cmu);
-            cout.set_epk(epk);
-            cout.set_ciphertext(enc_ciphertext[..52].to_vec());
-            cout
-        });
+        builder.add_sapling_output_to(extfvk.fvk.ovk, to, value, Memo::default());
 
         // Create a fake Note for the change
-        ctx.outputs.push({
-            let change_addr = extfvk.default_address().unwrap().1;
-            let note = Note {
-                g_d: change_addr.diversifier.g_d::<Bls12>(&JUBJUB).unwrap(),
-                pk_d: change_addr.pk_d.clone(),
-                value: (in_value - value).into(),
-                r: Fs::random(&mut rng),
-            };
-            let encryptor = SaplingNoteEncryption::new(
-                extfvk.fvk.ovk,
-                note.clone(),
-                change_addr,
-                Memo::default(),
-                &mut rng,
-            );
-            let mut cmu = vec![];
-            note.cm(&JUBJUB).into_repr().write_le(&mut cmu).unwrap();
-            let mut epk = vec![];
-            encryptor.epk().write(&mut epk).unwrap();
-            let enc_ciphertext = encryptor.encrypt_note_plaintext();
-
-            let mut cout = CompactOutput::new();
-            cout.set_cmu(cmu);
-            cout.set_epk(epk);
-            cout.set_ciphertext(enc_ciphertext[..52].to_vec());
-            cout
-        });
-
-        let mut cb = CompactBlock::new();
-        cb.set_height(height as u64);
-        cb.hash.resize(32, 0);
-        rng.fill_bytes(&mut cb.hash);
-        cb.prevHash.extend_from_slice(&prev_hash.0);
-        cb.vtx.push(ctx);
-        cb
+        builder.add_sapling_output(&extfvk, (in_value - value).unwrap(), Memo::default());
+
+        builder.build()
+    }
+
+    /// Insert a received note directly into the data DB, in a new transaction mined at the
+    /// given height, along with a witness for it at that height. The note's other fields
+    /// are dummy values, so it cannot be spent. Returns the note's row ID.
+    pub(crate) fn insert_test_received_note(
+        db_data: &Connection,
+        account: usize,
+        value: Amount,
+        block_height: u32,
+        is_change: bool,
+    ) -> i64 {
+        // A single-leaf tree, so the witness is valid against the block's tree.
+        let mut tree = CommitmentTree::new();
+        tree.append(Node::blank()).unwrap();
+        let witness = IncrementalWitness::from_tree(&tree);
+        let mut encoded_tree = Vec::new();
+        tree.write(&mut encoded_tree).unwrap();
+
+        let mut hash = [0; 32];
+        hash[..4].copy_from_slice(&block_height.to_le_bytes());
+        db_data
+            .execute(
+                "INSERT OR IGNORE INTO blocks (height, hash, time, sapling_tree)
+                VALUES (?, ?, 0, ?)",
+                &[
+                    block_height.to_sql().unwrap(),
+                    hash.to_sql().unwrap(),
+                    encoded_tree.to_sql().unwrap(),
+                ],
+            )
+            .unwrap();
+
+        // Derive a unique txid and nullifier from the row IDs they will be stored in.
+        let id_tx: i64 = db_data
+            .query_row(
+                "SELECT COALESCE(MAX(id_tx), 0) + 1 FROM transactions",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        let mut txid = [0; 32];
+        txid[..8].copy_from_slice(&id_tx.to_le_bytes());
+        db_data
+            .execute(
+                "INSERT INTO transactions (id_tx, txid, block, tx_index) VALUES (?, ?, ?, 0)",
+                &[
+                    id_tx.to_sql().unwrap(),
+                    txid.to_sql().unwrap(),
+                    block_height.to_sql().unwrap(),
+                ],
+            )
+            .unwrap();
+
+        let id_note: i64 = db_data
+            .query_row(
+                "SELECT COALESCE(MAX(id_note), 0) + 1 FROM received_notes",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        let mut nf = [0xff; 32];
+        nf[..8].copy_from_slice(&id_note.to_le_bytes());
+        db_data
+            .execute(
+                "INSERT INTO received_notes (
+                    id_note, tx, output_index, account, diversifier, value, rcm, nf,
+                    is_change, commitment_tree_position
+                )
+                VALUES (?, ?, 0, ?, ?, ?, ?, ?, ?, 0)",
+                &[
+                    id_note.to_sql().unwrap(),
+                    id_tx.to_sql().unwrap(),
+                    (account as i64).to_sql().unwrap(),
+                    [0u8; 11].to_sql().unwrap(),
+                    i64::from(value).to_sql().unwrap(),
+                    [0u8; 32].to_sql().unwrap(),
+                    nf.to_sql().unwrap(),
+                    is_change.to_sql().unwrap(),
+                ],
+            )
+            .unwrap();
+
+        db_data
+            .execute(
+                "INSERT INTO sapling_witnesses (note, block, witness) VALUES (?, ?, ?)",
+                &[
+                    id_note.to_sql().unwrap(),
+                    block_height.to_sql().unwrap(),
+                    write_witness(&witness).to_sql().unwrap(),
+                ],
+            )
+            .unwrap();
+
+        id_note
     }
 
     /// Insert a fake CompactBlock into the cache DB.
     pub(crate) fn insert_into_cache<P: AsRef<Path>>(db_cache: P, cb: &CompactBlock) {
+        validate_compact_block(cb).unwrap();
         let cb_bytes = cb.write_to_bytes().unwrap();
         let cache = Connection::open(&db_cache).unwrap();
         cache
@@ -259,4 +472,79 @@ mod tests {
             ])
             .unwrap();
     }
+
+    #[cfg(feature = "perf-tests")]
+    mod performance {
+        use std::env;
+        use std::time::Instant;
+        use tempfile::NamedTempFile;
+        use zcash_primitives::{
+            block::BlockHash,
+            transaction::components::Amount,
+            zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+        };
+
+        use super::fake_compact_block;
+        use crate::{
+            cache::insert_compact_blocks,
+            init::{init_accounts_table, init_cache_database, init_data_database},
+            scan::scan_cached_blocks,
+            SAPLING_ACTIVATION_HEIGHT,
+        };
+
+        const SCAN_BLOCKS: i32 = 10_000;
+
+        /// The minimum acceptable throughput, if `ZCASH_SCAN_BASELINE_BPS` is not set.
+        const DEFAULT_BASELINE_BPS: f64 = 500.0;
+
+        #[test]
+        fn test_scan_throughput() {
+            let baseline = env::var("ZCASH_SCAN_BASELINE_BPS")
+                .map(|bps| {
+                    bps.parse()
+                        .expect("ZCASH_SCAN_BASELINE_BPS must be a number")
+                })
+                .unwrap_or(DEFAULT_BASELINE_BPS);
+
+            let cache_file = NamedTempFile::new().unwrap();
+            let db_cache = cache_file.path();
+            init_cache_database(&db_cache).unwrap();
+
+            let data_file = NamedTempFile::new().unwrap();
+            let db_data = data_file.path();
+            init_data_database(&db_data).unwrap();
+
+            let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+            init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+            // Every block has an output to trial-decrypt, but only one in a hundred is
+            // ours, so the number of witnesses being updated stays realistic.
+            let other = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1]));
+            let value = Amount::from_u64(5).unwrap();
+            let mut blocks = vec![];
+            let mut prev_hash = BlockHash([0; 32]);
+            for i in 0..SCAN_BLOCKS {
+                let to = if i % 100 == 0 { &extfvk } else { &other };
+                let (cb, _) =
+                    fake_compact_block(SAPLING_ACTIVATION_HEIGHT + i, prev_hash, to.clone(), value);
+                prev_hash = cb.hash();
+                blocks.push(cb);
+            }
+            insert_compact_blocks(db_cache, &blocks).unwrap();
+
+            let start = Instant::now();
+            scan_cached_blocks(db_cache, db_data).unwrap();
+            let elapsed = start.elapsed();
+
+            // Duration::as_secs_f64 requires Rust 1.38.
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            let bps = f64::from(SCAN_BLOCKS) / secs;
+            assert!(
+                bps > baseline,
+                "Scan throughput {:.0} blocks/second is below the baseline of {:.0}",
+                bps,
+                baseline
+            );
+        }
+    }
 }
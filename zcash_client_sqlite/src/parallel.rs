@@ -0,0 +1,1321 @@
+//! Functions for scanning cached blocks on several threads.
+//!
+//! Trial-decrypting outputs is the expensive part of scanning, and each block can be
+//! trial-decrypted independently as long as the commitment tree as of its parent is
+//! known. [`plan_scan_ranges`] splits a range of cached blocks into sub-ranges and
+//! computes the starting tree of each one by replaying only the note commitments.
+//! Each [`ScanRange`] can then be passed to [`scan_range`] on its own thread, and the
+//! results combined in chain order by [`merge_scan_results`].
+//!
+//! [`plan_scan_ranges_with_priority`] instead splits the blocks by [`ScanPriority`], so
+//! that the blocks near the chain tip can be trial-decrypted first. Their results are
+//! still only written once every range has been scanned and merged.
+//!
+//! With the `multicore` feature, [`par_scan_block_ranges`] scans ranges of blocks that
+//! are already in memory on the [`rayon`] thread pool.
+//!
+//! Once merged, the results are written to the data database in height order by
+//! [`apply_scan_result`].
+
+use ff::PrimeField;
+use protobuf::parse_from_bytes;
+use rusqlite::{Connection, NO_PARAMS};
+use std::collections::HashMap;
+use std::path::Path;
+use zcash_client_backend::{
+    proto::compact_formats::CompactBlock,
+    storage::{StorageBackend, WITNESS_RETENTION_BLOCKS},
+    validation::{validate_compact_block, ValidationError},
+    wallet::{NullifierType, WalletShieldedSpend, WalletTx},
+    welding_rig::scan_block,
+};
+use zcash_primitives::{
+    merkle_tree::{CommitmentTree, IncrementalWitness},
+    sapling::Node,
+    transaction::TxId,
+    zip32::ExtendedFullViewingKey,
+    JUBJUB,
+};
+
+use crate::{
+    cache::{compressed_column, decompress_block},
+    error::{Error, ErrorKind},
+    open_data_database,
+    scan::ScanConfig,
+    storage::SqliteBackend,
+};
+
+#[cfg(feature = "multicore")]
+use rayon::prelude::*;
+
+/// A range of cached blocks that can be scanned independently of other ranges.
+#[derive(Clone)]
+pub struct ScanRange {
+    pub start_height: u32,
+    /// The height of the last block in the range (inclusive).
+    pub end_height: u32,
+    /// The Sapling commitment tree as of the end of the block before `start_height`.
+    pub tree: CommitmentTree<Node>,
+}
+
+/// The number of blocks after the last scanned block that are scanned first, to detect
+/// chain reorganizations early.
+pub const VERIFY_BLOCKS: u32 = 10;
+
+/// The number of blocks ending at the chain tip that are scanned with
+/// [`ScanPriority::ChainTip`].
+pub const CHAIN_TIP_BLOCKS: u32 = 100;
+
+/// The priority with which a [`ScanRange`] should be scanned.
+///
+/// Priorities are ordered from lowest to highest, so that sorting ranges by descending
+/// priority gives the order in which they should be scanned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ScanPriority {
+    /// The range has already been scanned.
+    Scanned,
+    /// The range contains historic blocks, which are not expected to be of interest.
+    Historic,
+    /// The range is adjacent to a range at a higher priority.
+    OpenAdjacent,
+    /// The range is expected to contain notes belonging to the wallet.
+    FoundNote,
+    /// The range ends at the chain tip, and contains the user's most recent activity.
+    ChainTip,
+    /// The range directly follows the last scanned block, and must be scanned to detect
+    /// a chain reorganization.
+    Verify,
+}
+
+/// A block that has been scanned as part of a [`ScanRange`].
+pub struct ScannedBlock {
+    pub block: CompactBlock,
+    /// The transactions in the block that are relevant to the wallet.
+    ///
+    /// After [`merge_scan_results`], the witnesses of the received notes are current as
+    /// of the end of the merged range.
+    pub txs: Vec<WalletTx>,
+    /// The nullifiers of the notes received in this block, with their accounts.
+    nullifiers: Vec<([u8; 32], usize)>,
+}
+
+/// The result of scanning a single [`ScanRange`] with [`scan_range`].
+pub struct ScanRangeResult {
+    start_height: u32,
+    end_height: u32,
+    start_root: Node,
+    blocks: Vec<ScannedBlock>,
+    tree: CommitmentTree<Node>,
+}
+
+/// The combined results of scanning several contiguous [`ScanRange`]s.
+pub struct ScanResult {
+    /// The scanned blocks, in height order.
+    pub blocks: Vec<ScannedBlock>,
+    /// The Sapling commitment tree as of the end of the last block.
+    pub tree: CommitmentTree<Node>,
+    /// The root of the Sapling commitment tree as of the end of the block before the
+    /// first block.
+    start_root: Node,
+}
+
+/// Returns the note commitments of the outputs in `block`, in order.
+fn block_cmus(block: &CompactBlock) -> impl Iterator<Item = Node> + '_ {
+    block.vtx.iter().flat_map(|tx| {
+        tx.outputs.iter().map(|output| {
+            // validate_compact_block has checked that the cmu is valid.
+            Node::new(output.cmu().unwrap().into_repr())
+        })
+    })
+}
+
+/// Fetches, parses, and validates the cached blocks from `from` to `to` (inclusive),
+/// rejecting any that are larger than `max_block_size` bytes.
+fn fetch_blocks(
+    cache: &Connection,
+    from: u32,
+    to: u32,
+    max_block_size: usize,
+) -> Result<Vec<CompactBlock>, Error> {
+    let mut stmt_blocks = cache.prepare(&format!(
+        "SELECT height, data, {} FROM compactblocks
+        WHERE height >= ? AND height <= ?
+        ORDER BY height ASC",
+        compressed_column(cache)?
+    ))?;
+    let rows = stmt_blocks.query_map(&[from, to], |row| {
+        let height: u32 = row.get(0)?;
+        let data: Vec<u8> = row.get(1)?;
+        let compressed: bool = row.get(2)?;
+        Ok((height, data, compressed))
+    })?;
+
+    let mut blocks = vec![];
+    let mut expected = from;
+    for row in rows {
+        let (height, data, compressed) = row?;
+        if height != expected {
+            return Err(Error(ErrorKind::InvalidHeight(
+                expected as i32,
+                height as i32,
+            )));
+        }
+        expected += 1;
+
+        let data = decompress_block(height as i32, data, compressed, max_block_size)?;
+        let block: CompactBlock = parse_from_bytes(&data)?;
+        validate_compact_block(&block)
+            .map_err(|e| Error(ErrorKind::InvalidCompactBlock(height as i32, e)))?;
+        blocks.push(block);
+    }
+    if expected != to + 1 {
+        // The cache is missing blocks at the end of the range.
+        return Err(Error(ErrorKind::InvalidHeight(expected as i32, to as i32)));
+    }
+
+    Ok(blocks)
+}
+
+/// Returns the Sapling commitment tree as of the end of the block before `from`, which
+/// must be the height after the last block in the data database, or any height if the
+/// data database does not contain any blocks.
+fn tree_before(data: &Connection, from: u32) -> Result<CommitmentTree<Node>, Error> {
+    let max_height: Option<u32> =
+        data.query_row("SELECT MAX(height) FROM blocks", NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+    match max_height {
+        Some(max_height) if max_height + 1 == from => {
+            let tree: Vec<u8> = data.query_row(
+                "SELECT sapling_tree FROM blocks WHERE height = ?",
+                &[max_height],
+                |row| row.get(0),
+            )?;
+            Ok(CommitmentTree::read(&tree[..])?)
+        }
+        Some(max_height) => Err(Error(ErrorKind::InvalidHeight(
+            (max_height + 1) as i32,
+            from as i32,
+        ))),
+        None => Ok(CommitmentTree::new()),
+    }
+}
+
+/// Appends the note commitments of `block` to `tree`.
+fn append_block(tree: &mut CommitmentTree<Node>, block: &CompactBlock) -> Result<(), Error> {
+    for cmu in block_cmus(block) {
+        tree.append(cmu)
+            .map_err(|_| Error(ErrorKind::CorruptedData("Sapling tree is full")))?;
+    }
+    Ok(())
+}
+
+/// Splits the cached blocks from `from` to `to` (inclusive) into at most `thread_count`
+/// contiguous [`ScanRange`]s of similar sizes.
+///
+/// `from` must be the height after the last block in the data database, or the first
+/// block to scan if the data database does not contain any blocks. The cache must
+/// contain every block in the range. Cached blocks are read with the limits in the given
+/// [`ScanConfig`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::{parallel::plan_scan_ranges, scan::ScanConfig};
+///
+/// let ranges = plan_scan_ranges(
+///     "/path/to/cache.db",
+///     "/path/to/data.db",
+///     500_000,
+///     509_999,
+///     4,
+///     &ScanConfig::default(),
+/// );
+/// ```
+pub fn plan_scan_ranges<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    from: u32,
+    to: u32,
+    thread_count: usize,
+    config: &ScanConfig,
+) -> Result<Vec<ScanRange>, Error> {
+    if from > to {
+        return Ok(vec![]);
+    }
+
+    let cache = Connection::open(db_cache)?;
+    let data = open_data_database(db_data)?;
+
+    // Fetch the tree as of the start of the range.
+    let mut tree = tree_before(&data, from)?;
+
+    let blocks = fetch_blocks(&cache, from, to, config.max_block_size_bytes)?;
+    let thread_count = thread_count.max(1);
+    let range_size = (blocks.len() + thread_count - 1) / thread_count;
+
+    let mut ranges = vec![];
+    for (i, chunk) in blocks.chunks(range_size).enumerate() {
+        let start_height = from + (i * range_size) as u32;
+        ranges.push(ScanRange {
+            start_height,
+            end_height: start_height + chunk.len() as u32 - 1,
+            tree: tree.clone(),
+        });
+
+        for block in chunk {
+            append_block(&mut tree, block)?;
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// Splits the cached blocks that remain to be scanned into [`ScanRange`]s annotated with
+/// a [`ScanPriority`], in descending order of priority.
+///
+/// Scanning starts after `last_scanned`, the last block in the data database, or at
+/// `wallet_birthday` if no blocks have been scanned. The [`VERIFY_BLOCKS`] blocks after
+/// `last_scanned` are scanned first, followed by the [`CHAIN_TIP_BLOCKS`] blocks ending
+/// at `chain_tip`, and then any blocks in between. The cache must contain every block in
+/// the range. Cached blocks are read with the limits in the given [`ScanConfig`].
+///
+/// The ranges may be scanned with [`scan_range`] in the returned order, but must be
+/// combined with [`merge_scan_results`] before they can be written to the data
+/// database, so nothing found in a high-priority range shows up in the wallet's
+/// balance until every range has been scanned. Already scanned blocks are not
+/// returned.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::{parallel::plan_scan_ranges_with_priority, scan::ScanConfig};
+///
+/// let ranges = plan_scan_ranges_with_priority(
+///     "/path/to/cache.db",
+///     "/path/to/data.db",
+///     419_200,
+///     Some(500_000),
+///     509_999,
+///     &ScanConfig::default(),
+/// );
+/// ```
+pub fn plan_scan_ranges_with_priority<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    wallet_birthday: u32,
+    last_scanned: Option<u32>,
+    chain_tip: u32,
+    config: &ScanConfig,
+) -> Result<Vec<(ScanRange, ScanPriority)>, Error> {
+    let from = last_scanned.map_or(wallet_birthday, |h| h + 1);
+    if from > chain_tip {
+        return Ok(vec![]);
+    }
+
+    let cache = Connection::open(db_cache)?;
+    let data = open_data_database(db_data)?;
+
+    let mut tree = tree_before(&data, from)?;
+    let blocks = fetch_blocks(&cache, from, chain_tip, config.max_block_size_bytes)?;
+
+    // The heights at which each range starts. There is nothing to verify if no blocks
+    // have been scanned yet.
+    let verify_end = match last_scanned {
+        Some(_) => (from + VERIFY_BLOCKS).min(chain_tip + 1),
+        None => from,
+    };
+    let tip_start = (chain_tip + 1)
+        .saturating_sub(CHAIN_TIP_BLOCKS)
+        .max(verify_end);
+    let boundaries = [
+        (from, ScanPriority::Verify),
+        (verify_end, ScanPriority::Historic),
+        (tip_start, ScanPriority::ChainTip),
+        (chain_tip + 1, ScanPriority::Scanned),
+    ];
+
+    let mut ranges = vec![];
+    let mut blocks = blocks.iter();
+    for window in boundaries.windows(2) {
+        let ((start_height, priority), (end, _)) = (window[0], window[1]);
+        if start_height == end {
+            continue;
+        }
+
+        ranges.push((
+            ScanRange {
+                start_height,
+                end_height: end - 1,
+                tree: tree.clone(),
+            },
+            priority,
+        ));
+        for block in blocks.by_ref().take((end - start_height) as usize) {
+            append_block(&mut tree, block)?;
+        }
+    }
+
+    // Sort by descending priority; the sort is stable, so ties remain in height order.
+    ranges.sort_by(|(_, a), (_, b)| b.cmp(a));
+    Ok(ranges)
+}
+
+/// Scans the cached blocks in the given [`ScanRange`] for transactions relevant to
+/// `extfvks`.
+///
+/// `birthdays` are the accounts' birthday heights, as for [`scan_block`]. `nullifiers`
+/// maps the nullifiers of the wallet's unspent notes as of the start of the range to
+/// their accounts, as returned by [`init_nullifier_map`]. Spends of notes received in
+/// earlier ranges are detected by [`merge_scan_results`].
+///
+/// This opens its own connection to the cache database, and so can be called for
+/// several ranges concurrently. Cached blocks are read with the limits in the given
+/// [`ScanConfig`].
+///
+/// [`init_nullifier_map`]: crate::scan::init_nullifier_map
+pub fn scan_range<P: AsRef<Path>>(
+    db_cache: P,
+    range: &ScanRange,
+    extfvks: &[ExtendedFullViewingKey],
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
+    config: &ScanConfig,
+) -> Result<ScanRangeResult, Error> {
+    let cache = Connection::open(db_cache)?;
+    let blocks = fetch_blocks(
+        &cache,
+        range.start_height,
+        range.end_height,
+        config.max_block_size_bytes,
+    )?;
+
+    Ok(scan_blocks(
+        range.start_height,
+        range.tree.clone(),
+        blocks,
+        extfvks,
+        birthdays,
+        nullifiers,
+    ))
+}
+
+/// Scans a range of consecutive blocks starting at `start_height`, given the tree as of
+/// the end of the block before them.
+fn scan_blocks(
+    start_height: u32,
+    start_tree: CommitmentTree<Node>,
+    blocks: Vec<CompactBlock>,
+    extfvks: &[ExtendedFullViewingKey],
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
+) -> ScanRangeResult {
+    let end_height = start_height + blocks.len() as u32 - 1;
+    let start_root = start_tree.root();
+    let mut tree = start_tree;
+    let mut nullifiers = nullifiers.clone();
+    let mut scanned: Vec<ScannedBlock> = vec![];
+    for block in blocks {
+        let txs = {
+            let mut witness_refs: Vec<_> = scanned
+                .iter_mut()
+                .flat_map(|b| b.txs.iter_mut())
+                .flat_map(|tx| tx.shielded_outputs.iter_mut())
+                .map(|output| &mut output.witness)
+                .collect();
+            scan_block(
+                block.clone(),
+                extfvks,
+                birthdays,
+                &nullifiers,
+                &mut tree,
+                &mut witness_refs[..],
+            )
+        };
+
+        let mut block_nullifiers = vec![];
+        for tx in &txs {
+            for spend in &tx.shielded_spends {
+                nullifiers.remove(&spend.nf[..]);
+            }
+            for output in &tx.shielded_outputs {
+                let position = output.witness.position() as u64;
+                let mut nf = [0; 32];
+                nf.copy_from_slice(&output.note.nf(
+                    &extfvks[output.account].fvk.vk,
+                    position,
+                    &JUBJUB,
+                ));
+                nullifiers.insert(nf, output.account);
+                block_nullifiers.push((nf, output.account));
+            }
+        }
+
+        scanned.push(ScannedBlock {
+            block,
+            txs,
+            nullifiers: block_nullifiers,
+        });
+    }
+
+    ScanRangeResult {
+        start_height,
+        end_height,
+        start_root,
+        blocks: scanned,
+        tree,
+    }
+}
+
+/// Scans several ranges of blocks for transactions relevant to `extfvks`, in parallel on
+/// the global [`rayon`] thread pool.
+///
+/// Each entry of `ranges` holds the height of the first block in the range and the
+/// range's blocks in height order. `starting_trees` holds, for each range, the Sapling
+/// commitment tree as of the end of the block before it, such as [`ScanRange::tree`].
+/// `birthdays` and `nullifiers` are as for [`scan_range`].
+///
+/// Empty ranges are skipped. The results are returned in the order of the remaining
+/// ranges, and must be combined with [`merge_scan_results`], which advances witnesses and
+/// detects spends across ranges in height order, before they are written to the data
+/// database.
+///
+/// # Panics
+///
+/// Panics if `ranges` and `starting_trees` have different lengths.
+#[cfg(feature = "multicore")]
+pub fn par_scan_block_ranges(
+    ranges: Vec<(u32, Vec<CompactBlock>)>,
+    extfvks: &[ExtendedFullViewingKey],
+    birthdays: &[u32],
+    nullifiers: &HashMap<[u8; 32], usize>,
+    starting_trees: Vec<CommitmentTree<Node>>,
+) -> Result<Vec<ScanRangeResult>, Error> {
+    assert_eq!(ranges.len(), starting_trees.len());
+
+    ranges
+        .into_par_iter()
+        .zip(starting_trees)
+        .filter(|((_, blocks), _)| !blocks.is_empty())
+        .map(|((start_height, blocks), tree)| {
+            for (expected, block) in (start_height..).zip(&blocks) {
+                let height = block.height as i32;
+                if height != expected as i32 {
+                    return Err(Error(ErrorKind::InvalidHeight(expected as i32, height)));
+                }
+                validate_compact_block(block)
+                    .map_err(|e| Error(ErrorKind::InvalidCompactBlock(height, e)))?;
+            }
+
+            Ok(scan_blocks(
+                start_height,
+                tree,
+                blocks,
+                extfvks,
+                birthdays,
+                nullifiers,
+            ))
+        })
+        .collect()
+}
+
+/// Combines the results of scanning contiguous [`ScanRange`]s into a single result.
+///
+/// The results may be given in any order, but must together cover a contiguous range of
+/// blocks, each starting from the tree that the previous range ended with. The witnesses
+/// of notes received in each range are updated with the note commitments of the later
+/// ranges, and spends of those notes in later ranges are added to the transactions of
+/// the blocks they occur in. The witnesses of notes received before the first range are
+/// not part of the result; [`apply_scan_result`] advances them when it is written to the
+/// data database.
+pub fn merge_scan_results(mut results: Vec<ScanRangeResult>) -> Result<ScanResult, Error> {
+    results.sort_by_key(|result| result.start_height);
+
+    let mut merged: Vec<ScannedBlock> = vec![];
+    let mut known_nullifiers: HashMap<[u8; 32], usize> = HashMap::new();
+    let mut start_root = None;
+    let mut tree: Option<CommitmentTree<Node>> = None;
+    let mut next_height = None;
+    for result in results {
+        start_root.get_or_insert(result.start_root);
+        if let (Some(tree), Some(next_height)) = (&tree, next_height) {
+            if result.start_height != next_height {
+                return Err(Error(ErrorKind::InvalidHeight(
+                    next_height as i32,
+                    result.start_height as i32,
+                )));
+            }
+            if result.start_root != tree.root() {
+                return Err(Error(ErrorKind::InvalidScanRange(
+                    result.start_height as i32,
+                )));
+            }
+        }
+
+        let mut blocks = result.blocks;
+        for scanned in blocks.iter_mut() {
+            // Advance the witnesses of notes received in earlier ranges.
+            for cmu in block_cmus(&scanned.block) {
+                for output in merged
+                    .iter_mut()
+                    .flat_map(|b| b.txs.iter_mut())
+                    .flat_map(|tx| tx.shielded_outputs.iter_mut())
+                {
+                    output
+                        .witness
+                        .append(cmu)
+                        .map_err(|_| Error(ErrorKind::CorruptedData("Sapling tree is full")))?;
+                }
+            }
+
+            // Detect spends of notes received in earlier ranges.
+            for (tx_index, tx) in scanned.block.vtx.iter().enumerate() {
+                let spends: Vec<_> = tx
+                    .spends
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, spend)| {
+                        known_nullifiers
+                            .get(&spend.nf[..])
+                            .map(|&account| WalletShieldedSpend {
+                                index,
+                                nf: spend.nf.clone(),
+                                nf_type: NullifierType::Sapling,
+                                account,
+                            })
+                    })
+                    .collect();
+                if spends.is_empty() {
+                    continue;
+                }
+
+                let index = tx.index as usize;
+                match scanned.txs.iter().position(|wtx| wtx.index >= index) {
+                    Some(pos) if scanned.txs[pos].index == index => {
+                        let wtx = &mut scanned.txs[pos];
+                        wtx.shielded_spends.extend(spends);
+                        wtx.shielded_spends.sort_by_key(|spend| spend.index);
+                    }
+                    pos => {
+                        let pos = pos.unwrap_or(scanned.txs.len());
+                        if tx.hash.len() != 32 {
+                            return Err(Error(ErrorKind::InvalidCompactBlock(
+                                scanned.block.height as i32,
+                                ValidationError::InvalidTxIdLength(tx_index),
+                            )));
+                        }
+                        let mut txid = TxId([0; 32]);
+                        txid.0.copy_from_slice(&tx.hash);
+                        let wtx = WalletTx {
+                            txid,
+                            index,
+                            num_spends: tx.spends.len(),
+                            num_outputs: tx.outputs.len(),
+                            shielded_spends: spends,
+                            shielded_outputs: vec![],
+                        };
+                        scanned.txs.insert(pos, wtx);
+                    }
+                }
+            }
+        }
+
+        for scanned in &blocks {
+            known_nullifiers.extend(scanned.nullifiers.iter().cloned());
+        }
+        merged.extend(blocks);
+        tree = Some(result.tree);
+        next_height = Some(result.end_height + 1);
+    }
+
+    Ok(ScanResult {
+        blocks: merged,
+        tree: tree.unwrap_or_else(CommitmentTree::new),
+        start_root: start_root.unwrap_or_else(|| CommitmentTree::<Node>::new().root()),
+    })
+}
+
+/// Writes the merged results of scanning several [`ScanRange`]s to the data database, in
+/// height order.
+///
+/// The first block of `result` must follow the last block in the data database, and must
+/// have been scanned from the commitment tree stored for that block. Each block is
+/// written in its own database transaction, as by [`scan_cached_blocks`], with the
+/// commitment tree as of its end and the witnesses of the wallet's notes, including
+/// those received before `result`.
+/// If writing fails part-way through, the blocks before the failing one remain stored.
+/// Blocks are reported and the WAL is checkpointed as set out in `config`.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::{
+///     parallel::{apply_scan_result, merge_scan_results},
+///     scan::ScanConfig,
+/// };
+///
+/// if let Ok(result) = merge_scan_results(vec![]) {
+///     apply_scan_result("/path/to/data.db", result, &ScanConfig::default());
+/// }
+/// ```
+///
+/// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+pub fn apply_scan_result<P: AsRef<Path>>(
+    db_data: P,
+    result: ScanResult,
+    config: &ScanConfig,
+) -> Result<(), Error> {
+    let first_height = match result.blocks.first() {
+        Some(scanned) => scanned.block.height as u32,
+        None => return Ok(()),
+    };
+    let mut tree = tree_before(&open_data_database(&db_data)?, first_height)?;
+    if tree.root() != result.start_root {
+        return Err(Error(ErrorKind::InvalidScanRange(first_height as i32)));
+    }
+
+    // Advance the witnesses of the notes received before the first block, along with
+    // those of the notes received in `result`.
+    let mut data = SqliteBackend::for_data_with_config(&db_data, config)?;
+    let mut witnesses = data.get_witnesses(first_height - 1)?;
+    for scanned in result.blocks {
+        let height = scanned.block.height as u32;
+
+        // Replay the block's note commitments, to find the tree as of the end of the
+        // block, and the witnesses of the notes received in it.
+        let mut received: Vec<IncrementalWitness<Node>> = vec![];
+        for tx in &scanned.block.vtx {
+            let wtx = scanned
+                .txs
+                .iter()
+                .find(|wtx| wtx.index == tx.index as usize);
+            for (index, output) in tx.outputs.iter().enumerate() {
+                // validate_compact_block has checked that the cmu is valid.
+                let node = Node::new(output.cmu().unwrap().into_repr());
+                for witness in witnesses
+                    .iter_mut()
+                    .map(|(_, witness)| witness)
+                    .chain(received.iter_mut())
+                {
+                    witness
+                        .append(node)
+                        .map_err(|_| Error(ErrorKind::CorruptedData("Sapling tree is full")))?;
+                }
+                tree.append(node)
+                    .map_err(|_| Error(ErrorKind::CorruptedData("Sapling tree is full")))?;
+
+                if wtx.map_or(false, |wtx| {
+                    wtx.shielded_outputs
+                        .iter()
+                        .any(|wallet_output| wallet_output.index == index)
+                }) {
+                    received.push(IncrementalWitness::from_tree(&tree));
+                }
+            }
+        }
+
+        // The received notes, their witnesses, and their nullifiers are all in the
+        // order of the notes' positions in the tree.
+        let mut received = received.into_iter();
+        let mut nullifiers = scanned.nullifiers.iter();
+        data.transactionally(|data| {
+            data.store_block(height, scanned.block.hash(), scanned.block.time, &tree)?;
+
+            for tx in &scanned.txs {
+                let tx_ref = data.store_tx(height, tx.txid, tx.index)?;
+                for spend in &tx.shielded_spends {
+                    data.mark_spent(tx_ref, &spend.nf)?;
+                }
+                for output in &tx.shielded_outputs {
+                    let witness = received
+                        .next()
+                        .expect("a witness is created for each received note");
+                    let (nf, _) = nullifiers
+                        .next()
+                        .expect("a nullifier is recorded for each received note");
+                    let note_ref = data.store_note(tx_ref, output, nf)?;
+                    witnesses.push((note_ref, witness));
+                }
+            }
+
+            for (note_ref, witness) in &witnesses {
+                data.store_witness(*note_ref, height, witness)?;
+            }
+            data.prune_witnesses(height.saturating_sub(WITNESS_RETENTION_BLOCKS))?;
+            data.update_expired_notes(height)
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use tempfile::NamedTempFile;
+    use zcash_client_backend::{proto::compact_formats::CompactBlock, validation::ValidationError};
+    use zcash_primitives::{
+        block::BlockHash,
+        merkle_tree::CommitmentTree,
+        sapling::Node,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{
+        apply_scan_result, merge_scan_results, plan_scan_ranges, plan_scan_ranges_with_priority,
+        scan_range, ScanPriority, ScanResult, CHAIN_TIP_BLOCKS, VERIFY_BLOCKS,
+    };
+
+    #[cfg(feature = "multicore")]
+    use super::{append_block, par_scan_block_ranges};
+    use crate::{
+        error::ErrorKind,
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        query::get_balance,
+        read_witness,
+        scan::{init_nullifier_map, scan_cached_blocks, ScanConfig},
+        tests::{fake_compact_block, fake_compact_block_spending, insert_into_cache},
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    /// Returns the commitment trees stored in the data database, in height order, and
+    /// the witnesses stored for the last block.
+    fn wallet_state(db_data: &Path) -> (Vec<Vec<u8>>, Vec<(i64, Vec<u8>)>) {
+        let data = Connection::open(db_data).unwrap();
+        let trees = data
+            .prepare("SELECT sapling_tree FROM blocks ORDER BY height")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let witnesses = data
+            .prepare(
+                "SELECT note, witness FROM sapling_witnesses
+                WHERE block = (SELECT MAX(height) FROM blocks)
+                ORDER BY note",
+            )
+            .unwrap()
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        (trees, witnesses)
+    }
+
+    /// Returns four blocks starting at the Sapling activation height, which receive a note
+    /// for `extfvk` in each of the first three blocks, and spend the first note in the
+    /// fourth block.
+    fn fake_blocks(extfvk: &ExtendedFullViewingKey) -> Vec<CompactBlock> {
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        let mut first_nf = None;
+        for i in 0..3 {
+            let (cb, nf) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            first_nf.get_or_insert(nf);
+            blocks.push(cb);
+        }
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+        blocks.push(fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 3,
+            prev_hash,
+            (first_nf.unwrap(), value),
+            extfvk.clone(),
+            to,
+            Amount::from_u64(2).unwrap(),
+        ));
+        blocks
+    }
+
+    /// Checks that the wallets in `db_parallel` and `db_sequential` have the same
+    /// balance, trees, and witnesses, and that the witnesses are current as of the last
+    /// block.
+    fn assert_same_wallet_state(db_parallel: &Path, db_sequential: &Path) {
+        assert_eq!(
+            get_balance(db_parallel, 0).unwrap(),
+            Amount::from_u64(13).unwrap()
+        );
+        assert_eq!(
+            get_balance(db_parallel, 0).unwrap(),
+            get_balance(db_sequential, 0).unwrap()
+        );
+        let (trees, witnesses) = wallet_state(db_parallel);
+        assert_eq!(
+            (trees.clone(), witnesses.clone()),
+            wallet_state(db_sequential)
+        );
+
+        let tree = CommitmentTree::<Node>::read(&trees[3][..]).unwrap();
+        assert_eq!(witnesses.len(), 4);
+        for (id_note, witness) in &witnesses {
+            let witness = read_witness(witness, *id_note, SAPLING_ACTIVATION_HEIGHT + 3).unwrap();
+            assert_eq!(witness.root(), tree.root());
+        }
+    }
+
+    /// Scans the cached blocks from `from` to `to` for `db_data` in `thread_count`
+    /// ranges, and merges the results.
+    fn parallel_scan(
+        db_cache: &Path,
+        db_data: &Path,
+        from: u32,
+        to: u32,
+        thread_count: usize,
+        extfvk: &ExtendedFullViewingKey,
+    ) -> ScanResult {
+        let config = ScanConfig::default();
+        let nullifiers = init_nullifier_map(db_data).unwrap();
+        let results = plan_scan_ranges(db_cache, db_data, from, to, thread_count, &config)
+            .unwrap()
+            .iter()
+            .map(|range| {
+                scan_range(
+                    db_cache,
+                    range,
+                    &[extfvk.clone()],
+                    &[],
+                    &nullifiers,
+                    &config,
+                )
+                .unwrap()
+            })
+            .collect();
+        merge_scan_results(results).unwrap()
+    }
+
+    #[test]
+    fn parallel_scan_tracks_notes_across_ranges() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+        let config = ScanConfig::default();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+
+        // Receive a note in each of the first three blocks, and spend the first note in
+        // the fourth block
+        let value = Amount::from_u64(5).unwrap();
+        let mut prev_hash = BlockHash([0; 32]);
+        let mut first_nf = None;
+        for i in 0..3 {
+            let (cb, nf) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            insert_into_cache(db_cache, &cb);
+            prev_hash = cb.hash();
+            first_nf.get_or_insert(nf);
+        }
+        let nf = first_nf.unwrap();
+        let cb = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 3,
+            prev_hash,
+            (nf.clone(), value),
+            extfvk.clone(),
+            to,
+            Amount::from_u64(2).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb);
+
+        // Split the four blocks across three threads
+        let from = SAPLING_ACTIVATION_HEIGHT as u32;
+        let ranges = plan_scan_ranges(db_cache, db_data, from, from + 3, 3, &config).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(
+            (ranges[0].start_height, ranges[0].end_height),
+            (from, from + 1)
+        );
+        assert_eq!(
+            (ranges[1].start_height, ranges[1].end_height),
+            (from + 2, from + 3)
+        );
+        assert_eq!(ranges[1].tree.size(), 2);
+
+        // Scan the ranges, and merge them in either order
+        let results = ranges
+            .iter()
+            .rev()
+            .map(|range| {
+                scan_range(
+                    db_cache,
+                    range,
+                    &[extfvk.clone()],
+                    &[],
+                    &HashMap::new(),
+                    &config,
+                )
+                .unwrap()
+            })
+            .collect();
+        let merged = merge_scan_results(results).unwrap();
+        assert_eq!(merged.blocks.len(), 4);
+        assert_eq!(merged.tree.size(), 5);
+
+        // All witnesses are current as of the last block
+        let root = merged.tree.root();
+        for block in &merged.blocks {
+            for tx in &block.txs {
+                for output in &tx.shielded_outputs {
+                    assert_eq!(output.witness.root(), root);
+                }
+            }
+        }
+
+        // The spend of the note received in the first range was detected
+        let spends = &merged.blocks[3].txs[0].shielded_spends;
+        assert_eq!(spends.len(), 1);
+        assert_eq!(spends[0].nf, nf);
+
+        // A spend-only transaction with a malformed txid is rejected
+        let mut results: Vec<_> = ranges
+            .iter()
+            .map(|range| {
+                scan_range(
+                    db_cache,
+                    range,
+                    &[extfvk.clone()],
+                    &[],
+                    &HashMap::new(),
+                    &config,
+                )
+                .unwrap()
+            })
+            .collect();
+        results[1].blocks[1].txs.clear();
+        results[1].blocks[1].block.vtx[0].hash.truncate(31);
+        match merge_scan_results(results) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidCompactBlock(_, ValidationError::InvalidTxIdLength(0)) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // Ranges must not overlap
+        let results = vec![
+            scan_range(
+                db_cache,
+                &ranges[0],
+                &[extfvk.clone()],
+                &[],
+                &HashMap::new(),
+                &config,
+            )
+            .unwrap(),
+            scan_range(
+                db_cache,
+                &ranges[0],
+                &[extfvk],
+                &[],
+                &HashMap::new(),
+                &config,
+            )
+            .unwrap(),
+        ];
+        assert!(merge_scan_results(results).is_err());
+    }
+
+    #[test]
+    fn apply_scan_result_matches_sequential_scan() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        // Two wallets with the same account, one scanned in parallel and one sequentially
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let data_files = [NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap()];
+        for data_file in &data_files {
+            init_data_database(data_file.path()).unwrap();
+            init_accounts_table(data_file.path(), &[extfvk.clone()]).unwrap();
+        }
+        let (db_parallel, db_sequential) = (data_files[0].path(), data_files[1].path());
+        for cb in fake_blocks(&extfvk) {
+            insert_into_cache(db_cache, &cb);
+        }
+
+        let from = SAPLING_ACTIVATION_HEIGHT as u32;
+        let result = parallel_scan(db_cache, db_parallel, from, from + 3, 3, &extfvk);
+        let result_again = parallel_scan(db_cache, db_parallel, from, from + 3, 3, &extfvk);
+        let config = ScanConfig::default();
+        apply_scan_result(db_parallel, result, &config).unwrap();
+        scan_cached_blocks(db_cache, db_sequential).unwrap();
+        assert_same_wallet_state(db_parallel, db_sequential);
+
+        // The same blocks cannot be written twice
+        match apply_scan_result(db_parallel, result_again, &config) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidHeight(expected, actual) => {
+                    assert_eq!(*expected, SAPLING_ACTIVATION_HEIGHT + 4);
+                    assert_eq!(*actual, SAPLING_ACTIVATION_HEIGHT);
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+
+    #[test]
+    fn apply_scan_result_advances_existing_witnesses() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let data_files = [NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap()];
+        for data_file in &data_files {
+            init_data_database(data_file.path()).unwrap();
+            init_accounts_table(data_file.path(), &[extfvk.clone()]).unwrap();
+        }
+        let (db_parallel, db_sequential) = (data_files[0].path(), data_files[1].path());
+
+        // Both wallets receive the first note before the parallel scan
+        let blocks = fake_blocks(&extfvk);
+        insert_into_cache(db_cache, &blocks[0]);
+        scan_cached_blocks(db_cache, db_parallel).unwrap();
+        scan_cached_blocks(db_cache, db_sequential).unwrap();
+        for cb in &blocks[1..] {
+            insert_into_cache(db_cache, cb);
+        }
+
+        // The witness of the first note is advanced through the parallel scan, and its
+        // spend in the last block is detected
+        let from = SAPLING_ACTIVATION_HEIGHT as u32 + 1;
+        let result = parallel_scan(db_cache, db_parallel, from, from + 2, 2, &extfvk);
+        apply_scan_result(db_parallel, result, &ScanConfig::default()).unwrap();
+        scan_cached_blocks(db_cache, db_sequential).unwrap();
+        assert_same_wallet_state(db_parallel, db_sequential);
+    }
+
+    #[test]
+    fn priority_ranges_scan_chain_tip_first() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+        let config = ScanConfig::default();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let value = Amount::from_u64(5).unwrap();
+        let mut prev_hash = BlockHash([0; 32]);
+        let mut add_blocks = |count: i32, start: i32| {
+            for i in 0..count {
+                let (cb, _) = fake_compact_block(start + i, prev_hash, extfvk.clone(), value);
+                insert_into_cache(db_cache, &cb);
+                prev_hash = cb.hash();
+            }
+        };
+
+        // A chain long enough to need every range
+        let from = SAPLING_ACTIVATION_HEIGHT as u32;
+        let len = VERIFY_BLOCKS + CHAIN_TIP_BLOCKS + 10;
+        add_blocks(len as i32, SAPLING_ACTIVATION_HEIGHT);
+        let tip = from + len - 1;
+
+        // Nothing has been scanned, so there is nothing to verify
+        let ranges =
+            plan_scan_ranges_with_priority(db_cache, db_data, from, None, tip, &config).unwrap();
+        let summary: Vec<_> = ranges
+            .iter()
+            .map(|(r, p)| (r.start_height, r.end_height, *p))
+            .collect();
+        let tip_start = tip + 1 - CHAIN_TIP_BLOCKS;
+        assert_eq!(
+            summary,
+            vec![
+                (tip_start, tip, ScanPriority::ChainTip),
+                (from, tip_start - 1, ScanPriority::Historic),
+            ]
+        );
+
+        // Each range starts from the tree as of its start height, with one note
+        // commitment per block
+        for (range, _) in &ranges {
+            assert_eq!(range.tree.size() as u32, range.start_height - from);
+        }
+
+        // Scanning the ranges in priority order finds every note
+        let results = ranges
+            .iter()
+            .map(|(range, _)| {
+                scan_range(
+                    db_cache,
+                    range,
+                    &[extfvk.clone()],
+                    &[],
+                    &HashMap::new(),
+                    &config,
+                )
+                .unwrap()
+            })
+            .collect();
+        let merged = merge_scan_results(results).unwrap();
+        assert_eq!(merged.blocks.len() as u32, len);
+
+        // Once some blocks have been scanned, the blocks after them are verified first
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        let last_scanned = tip;
+        add_blocks(len as i32, (tip + 1) as i32);
+        let tip = tip + len;
+        let ranges = plan_scan_ranges_with_priority(
+            db_cache,
+            db_data,
+            from,
+            Some(last_scanned),
+            tip,
+            &config,
+        )
+        .unwrap();
+        let summary: Vec<_> = ranges
+            .iter()
+            .map(|(r, p)| (r.start_height, r.end_height, *p))
+            .collect();
+        let tip_start = tip + 1 - CHAIN_TIP_BLOCKS;
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    last_scanned + 1,
+                    last_scanned + VERIFY_BLOCKS,
+                    ScanPriority::Verify
+                ),
+                (tip_start, tip, ScanPriority::ChainTip),
+                (
+                    last_scanned + VERIFY_BLOCKS + 1,
+                    tip_start - 1,
+                    ScanPriority::Historic
+                ),
+            ]
+        );
+        for (range, _) in &ranges {
+            assert_eq!(range.tree.size() as u32, range.start_height - from);
+        }
+
+        // The last scanned height must match the data database
+        assert!(plan_scan_ranges_with_priority(
+            db_cache,
+            db_data,
+            from,
+            Some(tip - 1),
+            tip,
+            &config
+        )
+        .is_err());
+        assert!(
+            plan_scan_ranges_with_priority(db_cache, db_data, from, Some(tip), tip, &config)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[cfg(feature = "multicore")]
+    #[test]
+    fn par_scan_matches_sequential_scan() {
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+
+        // Receive a note in each of the first five blocks, and spend the first note in
+        // the sixth block
+        let value = Amount::from_u64(5).unwrap();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        let mut first_nf = None;
+        for i in 0..5 {
+            let (cb, nf) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            prev_hash = cb.hash();
+            first_nf.get_or_insert(nf);
+            blocks.push(cb);
+        }
+        let nf = first_nf.unwrap();
+        blocks.push(fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 5,
+            prev_hash,
+            (nf.clone(), value),
+            extfvk.clone(),
+            to,
+            Amount::from_u64(2).unwrap(),
+        ));
+
+        // Split the blocks into three ranges, plus an empty one
+        let from = SAPLING_ACTIVATION_HEIGHT as u32;
+        let mut tree = CommitmentTree::new();
+        let mut ranges = vec![];
+        let mut starting_trees = vec![];
+        for (i, chunk) in blocks.chunks(2).enumerate() {
+            ranges.push((from + 2 * i as u32, chunk.to_vec()));
+            starting_trees.push(tree.clone());
+            for block in chunk {
+                append_block(&mut tree, block).unwrap();
+            }
+        }
+        ranges.push((from + 6, vec![]));
+        starting_trees.push(tree.clone());
+
+        let results = par_scan_block_ranges(
+            ranges.clone(),
+            &[extfvk.clone()],
+            &[],
+            &HashMap::new(),
+            starting_trees.clone(),
+        )
+        .unwrap();
+        assert_eq!(results.len(), 3);
+        let merged = merge_scan_results(results).unwrap();
+        assert_eq!(merged.blocks.len(), 6);
+        assert_eq!(merged.tree.root(), tree.root());
+
+        // All witnesses are current as of the last block
+        for block in &merged.blocks {
+            for tx in &block.txs {
+                for output in &tx.shielded_outputs {
+                    assert_eq!(output.witness.root(), tree.root());
+                }
+            }
+        }
+
+        // The spend of the note received in the first range was detected
+        let spends = &merged.blocks[5].txs[0].shielded_spends;
+        assert_eq!(spends.len(), 1);
+        assert_eq!(spends[0].nf, nf);
+
+        // Each range must start at the height of its first block
+        ranges[1].0 += 1;
+        assert!(
+            par_scan_block_ranges(ranges, &[extfvk], &[], &HashMap::new(), starting_trees).is_err()
+        );
+    }
+}
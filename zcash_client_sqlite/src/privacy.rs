@@ -0,0 +1,206 @@
+//! Functions for analysing the privacy of an account's notes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::{error::Error, query::open_wallet_readonly};
+
+/// A summary of how an account's notes can be linked together by an observer of the
+/// account's transactions.
+#[derive(Debug, PartialEq)]
+pub struct PrivacyReport {
+    /// Sets of notes that are linked to each other, identified by their row indices in
+    /// the `received_notes` table. Each cluster contains at least two notes, and is
+    /// sorted in ascending order.
+    pub linked_clusters: Vec<Vec<i64>>,
+}
+
+/// A minimal union-find structure over note row indices.
+struct Clusters(HashMap<i64, i64>);
+
+impl Clusters {
+    fn find(&mut self, id_note: i64) -> i64 {
+        let parent = *self.0.entry(id_note).or_insert(id_note);
+        if parent == id_note {
+            id_note
+        } else {
+            let root = self.find(parent);
+            self.0.insert(id_note, root);
+            root
+        }
+    }
+
+    fn union(&mut self, a: i64, b: i64) {
+        let root_a = self.find(a);
+        let root_b = self.find(b);
+        if root_a != root_b {
+            self.0.insert(root_a, root_b);
+        }
+    }
+}
+
+/// Finds clusters of notes received by the given account that can be linked together.
+///
+/// Two notes are considered linked if:
+/// - they were spent in the same transaction (common-input ownership), or
+/// - one is a change note created by the transaction that spent the other.
+///
+/// Links are transitive, so each returned cluster is a connected component of this
+/// relation. Notes that are not linked to any other note are omitted.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::privacy::analyze_transaction_graph;
+///
+/// let report = analyze_transaction_graph("/path/to/data.db", 0);
+/// ```
+pub fn analyze_transaction_graph<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+) -> Result<PrivacyReport, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let mut stmt_notes =
+        data.prepare("SELECT id_note, tx, is_change, spent FROM received_notes WHERE account = ?")?;
+    let notes = stmt_notes.query_map(&[account], |row| {
+        let id_note: i64 = row.get(0)?;
+        let tx: i64 = row.get(1)?;
+        let is_change: bool = row.get(2)?;
+        let spent: Option<i64> = row.get(3)?;
+        Ok((id_note, tx, is_change, spent))
+    })?;
+    let notes: Vec<_> = notes.collect::<Result<_, _>>()?;
+
+    // Group notes by the transaction that spent them.
+    let mut spent_in: HashMap<i64, Vec<i64>> = HashMap::new();
+    for (id_note, _, _, spent) in &notes {
+        if let Some(spent) = spent {
+            spent_in
+                .entry(*spent)
+                .or_insert_with(Vec::new)
+                .push(*id_note);
+        }
+    }
+
+    let mut clusters = Clusters(HashMap::new());
+
+    // Notes spent in the same transaction are linked.
+    for inputs in spent_in.values() {
+        for pair in inputs.windows(2) {
+            clusters.union(pair[0], pair[1]);
+        }
+    }
+
+    // Change notes are linked to the notes spent by the transaction that created them.
+    for (id_note, tx, is_change, _) in &notes {
+        if *is_change {
+            if let Some(inputs) = spent_in.get(tx) {
+                clusters.union(*id_note, inputs[0]);
+            }
+        }
+    }
+
+    // Collect the connected components, ignoring unlinked notes.
+    let mut components: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for (id_note, _, _, _) in &notes {
+        let root = clusters.find(*id_note);
+        components
+            .entry(root)
+            .or_insert_with(Vec::new)
+            .push(*id_note);
+    }
+    let mut linked_clusters: Vec<_> = components
+        .into_iter()
+        .map(|(_, mut cluster)| {
+            cluster.sort();
+            cluster
+        })
+        .filter(|cluster| cluster.len() > 1)
+        .collect();
+    linked_clusters.sort();
+
+    Ok(PrivacyReport { linked_clusters })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+    use zcash_primitives::{
+        block::BlockHash,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::analyze_transaction_graph;
+    use crate::{
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        scan::scan_cached_blocks,
+        tests::{fake_compact_block, fake_compact_block_spending, insert_into_cache},
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    #[test]
+    fn change_notes_are_linked_to_their_inputs() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive two notes
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Unspent notes are not linked
+        assert!(analyze_transaction_graph(db_data, 0)
+            .unwrap()
+            .linked_clusters
+            .is_empty());
+
+        // Spend the first note, creating a change note
+        let extsk2 = ExtendedSpendingKey::master(&[0]);
+        let to2 = extsk2.default_address().unwrap().1;
+        insert_into_cache(
+            db_cache,
+            &fake_compact_block_spending(
+                SAPLING_ACTIVATION_HEIGHT + 2,
+                cb2.hash(),
+                (nf, value),
+                extfvk,
+                to2,
+                Amount::from_u64(2).unwrap(),
+            ),
+        );
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // The change note is linked to the first note only
+        assert_eq!(
+            analyze_transaction_graph(db_data, 0)
+                .unwrap()
+                .linked_clusters,
+            vec![vec![1, 3]]
+        );
+    }
+}
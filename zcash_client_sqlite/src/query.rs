@@ -1,14 +1,80 @@
 //! Functions for querying information in the data database.
 
-use rusqlite::Connection;
-use std::path::Path;
-use zcash_primitives::{note_encryption::Memo, transaction::components::Amount};
+use ff::{PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::Bls12;
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use zcash_client_backend::encoding::decode_extended_full_viewing_key;
+use zcash_primitives::{
+    block::BlockHash,
+    jubjub::fs::{Fs, FsRepr},
+    merkle_tree::{CommitmentTree, IncrementalWitness},
+    note_encryption::Memo,
+    primitives::{Diversifier, PaymentAddress},
+    sapling::Node,
+    transaction::{components::Amount, TxId},
+    zip32::ExtendedFullViewingKey,
+};
 
 use crate::{
+    check_network, check_schema_version,
     error::{Error, ErrorKind},
-    get_target_and_anchor_heights,
+    get_target_and_anchor_heights, read_witness, ANCHOR_OFFSET,
+    HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
 };
 
+/// The target time between blocks, in seconds.
+const BLOCK_SPACING: u64 = 75;
+
+/// How far the most recent scanned block may lag the current time, in seconds, before
+/// the wallet is considered to still be syncing.
+const SYNC_LAG_THRESHOLD: u64 = 3600;
+
+/// Opens the data database for reading only.
+///
+/// Checks that the database uses the current schema and was created for the network
+/// this library is configured for. Every query in this module opens the data database
+/// with this function. Databases created by earlier versions of this library must first
+/// be upgraded with [`migrate_data_database`].
+///
+/// [`migrate_data_database`]: crate::init::migrate_data_database
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::open_wallet_readonly;
+///
+/// let data = open_wallet_readonly("/path/to/data.db");
+/// ```
+pub fn open_wallet_readonly<P: AsRef<Path>>(db_data: P) -> Result<Connection, Error> {
+    let data = Connection::open_with_flags(db_data, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    check_schema_version(&data)?;
+    check_network(&data)?;
+    Ok(data)
+}
+
+/// Returns the number of accounts in the data database.
+///
+/// Accounts are numbered sequentially from zero, so this is also the index of the next
+/// account to be added.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_account_count;
+///
+/// let count = get_account_count("/path/to/data.db");
+/// ```
+pub fn get_account_count<P: AsRef<Path>>(db_data: P) -> Result<usize, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let count: i64 =
+        data.query_row("SELECT COUNT(*) FROM accounts", NO_PARAMS, |row| row.get(0))?;
+
+    Ok(count as usize)
+}
+
 /// Returns the address for the account.
 ///
 /// # Examples
@@ -19,7 +85,7 @@ use crate::{
 /// let addr = get_address("/path/to/data.db", 0);
 /// ```
 pub fn get_address<P: AsRef<Path>>(db_data: P, account: u32) -> Result<String, Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_wallet_readonly(db_data)?;
 
     let addr = data.query_row(
         "SELECT address FROM accounts
@@ -31,6 +97,134 @@ pub fn get_address<P: AsRef<Path>>(db_data: P, account: u32) -> Result<String, E
     Ok(addr)
 }
 
+/// Returns the [`ExtendedFullViewingKey`] stored for the account.
+///
+/// Returns an error with kind [`ErrorKind::UnknownAccount`] if the account does not exist.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_extfvk_for_account;
+///
+/// let extfvk = get_extfvk_for_account("/path/to/data.db", 0);
+/// ```
+pub fn get_extfvk_for_account<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+) -> Result<ExtendedFullViewingKey, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let extfvk: String = match data.query_row(
+        "SELECT extfvk FROM accounts WHERE account = ?",
+        &[account],
+        |row| row.get(0),
+    ) {
+        Ok(extfvk) => extfvk,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(Error(ErrorKind::UnknownAccount(account)))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    decode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &extfvk)?
+        .ok_or(Error(ErrorKind::IncorrectHRPExtFVK))
+}
+
+/// Caches the [`ExtendedFullViewingKey`]s of accounts in the data database, so that
+/// they are only decoded once.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::WalletState;
+///
+/// let mut wallet = WalletState::new("/path/to/data.db");
+/// if let Ok(extfvk) = wallet.extfvk_for_account(0) {
+///     let (_, addr) = extfvk.default_address().unwrap();
+/// }
+/// ```
+pub struct WalletState {
+    db_data: PathBuf,
+    extfvks: HashMap<u32, ExtendedFullViewingKey>,
+}
+
+impl WalletState {
+    /// Creates an empty cache for the data database at the given path.
+    pub fn new<P: AsRef<Path>>(db_data: P) -> Self {
+        WalletState {
+            db_data: db_data.as_ref().to_owned(),
+            extfvks: HashMap::new(),
+        }
+    }
+
+    /// Returns the [`ExtendedFullViewingKey`] stored for the account.
+    ///
+    /// See [`get_extfvk_for_account`].
+    pub fn extfvk_for_account(&mut self, account: u32) -> Result<&ExtendedFullViewingKey, Error> {
+        if !self.extfvks.contains_key(&account) {
+            let extfvk = get_extfvk_for_account(&self.db_data, account)?;
+            self.extfvks.insert(account, extfvk);
+        }
+        Ok(&self.extfvks[&account])
+    }
+}
+
+/// An account in the data database.
+#[derive(Clone, Debug)]
+pub struct AccountInfo {
+    pub account: u32,
+    pub default_address: PaymentAddress<Bls12>,
+    pub extfvk: ExtendedFullViewingKey,
+    /// The height from which the account is scanned, if it was created with a birthday.
+    pub birthday: Option<u32>,
+}
+
+/// Returns all accounts in the data database, ordered by account index.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::list_accounts;
+///
+/// if let Ok(accounts) = list_accounts("/path/to/data.db") {
+///     for info in accounts {
+///         println!("Account {}", info.account);
+///     }
+/// }
+/// ```
+pub fn list_accounts<P: AsRef<Path>>(db_data: P) -> Result<Vec<AccountInfo>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let mut stmt_accounts = data.prepare(
+        "SELECT account, extfvk, birthday_height FROM accounts
+        ORDER BY account ASC",
+    )?;
+    let rows = stmt_accounts.query_map(NO_PARAMS, |row| {
+        Ok((
+            row.get(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<u32>>(2)?,
+        ))
+    })?;
+
+    let mut accounts = vec![];
+    for row in rows {
+        let (account, extfvk, birthday) = row?;
+        let extfvk =
+            decode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &extfvk)?
+                .ok_or(Error(ErrorKind::IncorrectHRPExtFVK))?;
+        let default_address = extfvk.default_address().unwrap().1;
+        accounts.push(AccountInfo {
+            account,
+            default_address,
+            extfvk,
+            birthday,
+        });
+    }
+
+    Ok(accounts)
+}
+
 /// Returns the balance for the account, including all mined unspent notes that we know
 /// about.
 ///
@@ -42,7 +236,7 @@ pub fn get_address<P: AsRef<Path>>(db_data: P, account: u32) -> Result<String, E
 /// let addr = get_balance("/path/to/data.db", 0);
 /// ```
 pub fn get_balance<P: AsRef<Path>>(db_data: P, account: u32) -> Result<Amount, Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_wallet_readonly(db_data)?;
 
     let balance = data.query_row(
         "SELECT SUM(value) FROM received_notes
@@ -60,6 +254,104 @@ pub fn get_balance<P: AsRef<Path>>(db_data: P, account: u32) -> Result<Amount, E
     }
 }
 
+/// The balances of every account in the wallet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotalBalance {
+    /// The sum of the balances of all accounts.
+    pub total: Amount,
+    /// The balance of each account, ordered by account.
+    pub by_account: Vec<(u32, Amount)>,
+}
+
+/// Returns the balance of every account in the wallet, and their total, with the same
+/// semantics as [`get_balance`].
+///
+/// Accounts without any mined unspent notes are included with a zero balance.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_total_wallet_balance;
+///
+/// let balance = get_total_wallet_balance("/path/to/data.db");
+/// ```
+pub fn get_total_wallet_balance<P: AsRef<Path>>(db_data: P) -> Result<TotalBalance, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    // SQLite has no ROLLUP, so the total is computed from the per-account sums.
+    let mut stmt_balances = data.prepare(
+        "SELECT accounts.account, SUM(notes.value) FROM accounts
+        LEFT JOIN (
+            SELECT received_notes.account, received_notes.value FROM received_notes
+            INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+            WHERE received_notes.spent IS NULL AND transactions.block IS NOT NULL
+        ) AS notes ON notes.account = accounts.account
+        GROUP BY accounts.account
+        ORDER BY accounts.account ASC",
+    )?;
+    let rows = stmt_balances.query_map(NO_PARAMS, |row| {
+        let account: u32 = row.get(0)?;
+        let balance: Option<i64> = row.get(1)?;
+        Ok((account, balance.unwrap_or(0)))
+    })?;
+
+    let mut total = Amount::zero();
+    let mut by_account = vec![];
+    for row in rows {
+        let (account, balance) = row?;
+        let balance = match Amount::from_i64(balance) {
+            Ok(amount) if !amount.is_negative() => amount,
+            _ => {
+                return Err(Error(ErrorKind::CorruptedData(
+                    "Sum of values in received_notes is out of range",
+                )))
+            }
+        };
+        total = (total + balance).ok_or(Error(ErrorKind::CorruptedData(
+            "Total wallet balance is out of range",
+        )))?;
+        by_account.push((account, balance));
+    }
+
+    Ok(TotalBalance { total, by_account })
+}
+
+/// Returns the Orchard balance for the account.
+///
+/// Orchard outputs are not yet scanned, so this is currently always zero; the column it
+/// is read from exists so that Orchard scanning can be added without another schema
+/// change.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_orchard_balance;
+///
+/// let balance = get_orchard_balance("/path/to/data.db", 0);
+/// ```
+pub fn get_orchard_balance<P: AsRef<Path>>(db_data: P, account: u32) -> Result<Amount, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let balance = match data.query_row(
+        "SELECT orchard_balance_zat FROM accounts WHERE account = ?",
+        &[account],
+        |row| row.get(0),
+    ) {
+        Ok(balance) => balance,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(Error(ErrorKind::UnknownAccount(account)))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match Amount::from_i64(balance) {
+        Ok(amount) if !amount.is_negative() => Ok(amount),
+        _ => Err(Error(ErrorKind::CorruptedData(
+            "orchard_balance_zat is out of range",
+        ))),
+    }
+}
+
 /// Returns the verified balance for the account, which ignores notes that have been
 /// received too recently and are not yet deemed spendable.
 ///
@@ -71,9 +363,9 @@ pub fn get_balance<P: AsRef<Path>>(db_data: P, account: u32) -> Result<Amount, E
 /// let addr = get_verified_balance("/path/to/data.db", 0);
 /// ```
 pub fn get_verified_balance<P: AsRef<Path>>(db_data: P, account: u32) -> Result<Amount, Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_wallet_readonly(db_data)?;
 
-    let (_, anchor_height) = get_target_and_anchor_heights(&data)?;
+    let (_, anchor_height) = get_target_and_anchor_heights(&data, ANCHOR_OFFSET)?;
 
     let balance = data.query_row(
         "SELECT SUM(value) FROM received_notes
@@ -91,6 +383,547 @@ pub fn get_verified_balance<P: AsRef<Path>>(db_data: P, account: u32) -> Result<
     }
 }
 
+/// A received note that can be spent.
+#[derive(Debug, PartialEq)]
+pub struct SpendableNote {
+    /// The row index of the note in the `received_notes` table.
+    pub id_note: i64,
+    pub value: Amount,
+    /// The position of the note's commitment in the global note commitment tree.
+    pub commitment_tree_position: u64,
+}
+
+/// Returns the notes that can currently be spent by the account, ordered by their
+/// positions in the note commitment tree.
+///
+/// This uses the same verification rules as [`get_verified_balance`], so the values of
+/// the returned notes sum to the verified balance.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_spendable_notes;
+///
+/// let notes = get_spendable_notes("/path/to/data.db", 0);
+/// ```
+pub fn get_spendable_notes<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+) -> Result<Vec<SpendableNote>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let (_, anchor_height) = get_target_and_anchor_heights(&data, ANCHOR_OFFSET)?;
+
+    let mut stmt_select_notes = data.prepare(
+        "SELECT id_note, value, commitment_tree_position FROM received_notes
+        INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+        WHERE account = ? AND spent IS NULL AND transactions.block <= ?
+        ORDER BY commitment_tree_position ASC",
+    )?;
+    let notes = stmt_select_notes.query_and_then(&[account, anchor_height], |row| {
+        let value = Amount::from_i64(row.get(1)?)
+            .map_err(|_| Error(ErrorKind::CorruptedData("Note value is out of range")))?;
+        let position: Option<i64> = row.get(2)?;
+        let position = position.ok_or(Error(ErrorKind::CorruptedData(
+            "Missing commitment tree position",
+        )))?;
+
+        Ok(SpendableNote {
+            id_note: row.get(0)?,
+            value,
+            commitment_tree_position: position as u64,
+        })
+    })?;
+
+    notes.collect()
+}
+
+/// Returns the height of the most recent block that has at least `min_confirmations`
+/// blocks after it, for use as the anchor of a transaction's spends.
+///
+/// Returns an error with kind [`ErrorKind::ScanRequired`] if the data database does not
+/// contain a block at that height.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::{get_anchor_height, get_sapling_tree_at_height};
+///
+/// if let Ok(anchor_height) = get_anchor_height("/path/to/data.db", 10) {
+///     let tree = get_sapling_tree_at_height("/path/to/data.db", anchor_height);
+/// }
+/// ```
+pub fn get_anchor_height<P: AsRef<Path>>(db_data: P, min_confirmations: u32) -> Result<u32, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let (min_height, max_height): (Option<u32>, Option<u32>) = data.query_row(
+        "SELECT MIN(height), MAX(height) FROM blocks",
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    match (min_height, max_height) {
+        (Some(min_height), Some(max_height)) => match max_height.checked_sub(min_confirmations) {
+            Some(anchor_height) if anchor_height >= min_height => Ok(anchor_height),
+            _ => Err(Error(ErrorKind::ScanRequired)),
+        },
+        // If there are no blocks, the query returns NULL.
+        _ => Err(Error(ErrorKind::ScanRequired)),
+    }
+}
+
+/// Returns the height and hash of the most recent block in the data database.
+///
+/// Returns `None` if no blocks have been scanned yet.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_chain_tip;
+///
+/// if let Ok(Some((height, hash))) = get_chain_tip("/path/to/data.db") {
+///     println!("Scanned up to block {} ({})", height, hash);
+/// }
+/// ```
+pub fn get_chain_tip<P: AsRef<Path>>(db_data: P) -> Result<Option<(u32, BlockHash)>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    match data.query_row(
+        "SELECT height, hash FROM blocks ORDER BY height DESC LIMIT 1",
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get::<_, Vec<u8>>(1)?)),
+    ) {
+        Ok((height, hash)) => {
+            if hash.len() != 32 {
+                return Err(Error(ErrorKind::CorruptedData("Invalid block hash length")));
+            }
+            Ok(Some((height, BlockHash::from_slice(&hash))))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How far the wallet's scanned chain lags behind the network, as estimated by
+/// [`estimate_sync_lag`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncLagEstimate {
+    /// The approximate number of blocks mined since the most recent scanned block.
+    pub blocks_behind: u32,
+    /// The time elapsed since the most recent scanned block was mined.
+    pub seconds_behind: u64,
+    /// Whether the wallet is still catching up to the network, in which case its
+    /// balances may be out of date.
+    pub is_syncing: bool,
+}
+
+/// Estimates how far the wallet's scanned chain lags behind the network, by comparing
+/// the time of the most recent scanned block to `current_time` (in seconds since the
+/// Unix epoch).
+///
+/// The wallet is considered to be syncing if that block is more than an hour old. Block
+/// times are set by miners and are only loosely tied to the wall clock, so this is a
+/// heuristic for displaying sync progress, and the estimated number of blocks assumes
+/// the target block spacing of 75 seconds.
+///
+/// Returns [`ErrorKind::ScanRequired`] if no blocks have been scanned yet.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{SystemTime, UNIX_EPOCH};
+/// use zcash_client_sqlite::query::estimate_sync_lag;
+///
+/// let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+/// if let Ok(lag) = estimate_sync_lag("/path/to/data.db", now) {
+///     if lag.is_syncing {
+///         println!("Syncing... about {} blocks to go", lag.blocks_behind);
+///     }
+/// }
+/// ```
+pub fn estimate_sync_lag<P: AsRef<Path>>(
+    db_data: P,
+    current_time: u64,
+) -> Result<SyncLagEstimate, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let block_time: i64 = match data.query_row(
+        "SELECT time FROM blocks ORDER BY height DESC LIMIT 1",
+        NO_PARAMS,
+        |row| row.get(0),
+    ) {
+        Ok(time) => time,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error(ErrorKind::ScanRequired)),
+        Err(e) => return Err(e.into()),
+    };
+
+    // A block time ahead of the current time means the wallet is up to date.
+    let seconds_behind = current_time.saturating_sub(block_time.max(0) as u64);
+    Ok(SyncLagEstimate {
+        blocks_behind: (seconds_behind / BLOCK_SPACING) as u32,
+        seconds_behind,
+        is_syncing: seconds_behind > SYNC_LAG_THRESHOLD,
+    })
+}
+
+/// Returns the height of the block in which the given transaction was mined.
+///
+/// Returns `None` if the transaction is not yet mined, or is not known to the wallet.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_block_height_for_tx;
+/// use zcash_primitives::transaction::TxId;
+///
+/// let height = get_block_height_for_tx("/path/to/data.db", &TxId([0; 32]));
+/// ```
+pub fn get_block_height_for_tx<P: AsRef<Path>>(
+    db_data: P,
+    txid: &TxId,
+) -> Result<Option<u32>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    match data.query_row(
+        "SELECT block FROM transactions WHERE txid = ?",
+        &[txid.0.to_vec()],
+        |row| row.get(0),
+    ) {
+        Ok(height) => Ok(height),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the timestamp of the block in which the given transaction was mined.
+///
+/// Returns `None` if the transaction is not yet mined, or is not known to the wallet.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_block_time_for_tx;
+/// use zcash_primitives::transaction::TxId;
+///
+/// let time = get_block_time_for_tx("/path/to/data.db", &TxId([0; 32]));
+/// ```
+pub fn get_block_time_for_tx<P: AsRef<Path>>(
+    db_data: P,
+    txid: &TxId,
+) -> Result<Option<u32>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    match data.query_row(
+        "SELECT blocks.time FROM transactions
+        INNER JOIN blocks ON blocks.height = transactions.block
+        WHERE transactions.txid = ?",
+        &[txid.0.to_vec()],
+        |row| row.get(0),
+    ) {
+        Ok(time) => Ok(Some(time)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the serialized Sapling [`CommitmentTree`] as of the end of the block at the
+/// given height.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_sapling_tree_at_height;
+///
+/// let tree = get_sapling_tree_at_height("/path/to/data.db", 500_000);
+/// ```
+///
+/// [`CommitmentTree`]: zcash_primitives::merkle_tree::CommitmentTree
+pub fn get_sapling_tree_at_height<P: AsRef<Path>>(
+    db_data: P,
+    height: u32,
+) -> Result<Vec<u8>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let tree = data.query_row(
+        "SELECT sapling_tree FROM blocks WHERE height = ?",
+        &[height],
+        |row| row.get(0),
+    )?;
+
+    Ok(tree)
+}
+
+/// Returns the Sapling [`CommitmentTree`] as of the end of the block at the given height.
+///
+/// Returns an error with kind [`ErrorKind::ScanRequired`] if the data database does not
+/// contain a block at that height, or [`ErrorKind::CorruptedData`] if the stored tree
+/// cannot be parsed.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_sapling_tree;
+///
+/// let tree = get_sapling_tree("/path/to/data.db", 500_000);
+/// ```
+pub fn get_sapling_tree<P: AsRef<Path>>(
+    db_data: P,
+    at_height: u32,
+) -> Result<CommitmentTree<Node>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let tree: Vec<u8> = match data.query_row(
+        "SELECT sapling_tree FROM blocks WHERE height = ?",
+        &[at_height],
+        |row| row.get(0),
+    ) {
+        Ok(tree) => tree,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error(ErrorKind::ScanRequired)),
+        Err(e) => return Err(e.into()),
+    };
+
+    CommitmentTree::read(&tree[..])
+        .map_err(|_| Error(ErrorKind::CorruptedData("Invalid Sapling tree")))
+}
+
+/// Returns the serialized Sapling [`CommitmentTree`] as of the end of the block at the
+/// given height, for use by external provers.
+///
+/// Unlike [`get_sapling_tree_at_height`], the stored tree is parsed and re-serialized,
+/// so the returned bytes are always a valid canonical encoding of the tree. Errors are
+/// the same as for [`get_sapling_tree`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_sapling_tree_serialized;
+///
+/// let tree = get_sapling_tree_serialized("/path/to/data.db", 500_000);
+/// ```
+pub fn get_sapling_tree_serialized<P: AsRef<Path>>(
+    db_data: P,
+    at_height: u32,
+) -> Result<Vec<u8>, Error> {
+    let tree = get_sapling_tree(db_data, at_height)?;
+
+    let mut encoded = vec![];
+    tree.write(&mut encoded)
+        .expect("Should be able to write to a Vec");
+    Ok(encoded)
+}
+
+/// The full details of a received note.
+#[derive(Clone)]
+pub struct NoteDetails {
+    pub id_note: i64,
+    /// The transaction in which the note was received.
+    pub txid: TxId,
+    /// The height of the block containing the transaction, or `None` if it is not mined.
+    pub block_height: Option<u32>,
+    pub output_index: usize,
+    pub account: u32,
+    pub diversifier: Diversifier,
+    pub value: Amount,
+    pub rcm: Fs,
+    pub nf: [u8; 32],
+    pub is_change: bool,
+    /// The transaction in which the note was spent, if any.
+    pub spent: Option<TxId>,
+    /// The most recent stored witness for the note, or `None` if its witnesses have been
+    /// pruned.
+    pub witness: Option<IncrementalWitness<Node>>,
+}
+
+/// Returns the details of a received note.
+///
+/// The note is identified by its row index in the `received_notes` table within the data
+/// database.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_note_details;
+///
+/// let note = get_note_details("/path/to/data.db", 27);
+/// ```
+pub fn get_note_details<P: AsRef<Path>>(db_data: P, id_note: i64) -> Result<NoteDetails, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let to_txid = |bytes: Vec<u8>| {
+        if bytes.len() != 32 {
+            return Err(Error(ErrorKind::CorruptedData("Invalid txid length")));
+        }
+        let mut txid = TxId([0; 32]);
+        txid.0.copy_from_slice(&bytes);
+        Ok(txid)
+    };
+
+    let row = data.query_row(
+        "SELECT transactions.txid, transactions.block, received_notes.output_index,
+            received_notes.account, received_notes.diversifier, received_notes.value,
+            received_notes.rcm, received_notes.nf, received_notes.is_change, spent_tx.txid
+        FROM received_notes
+        INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+        LEFT JOIN transactions AS spent_tx ON spent_tx.id_tx = received_notes.spent
+        WHERE received_notes.id_note = ?",
+        &[id_note],
+        |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get(1)?,
+                row.get::<_, i64>(2)?,
+                row.get(3)?,
+                row.get::<_, Vec<u8>>(4)?,
+                row.get::<_, i64>(5)?,
+                row.get::<_, Vec<u8>>(6)?,
+                row.get::<_, Vec<u8>>(7)?,
+                row.get(8)?,
+                row.get::<_, Option<Vec<u8>>>(9)?,
+            ))
+        },
+    )?;
+    let (txid, block_height, output_index, account, d, value, rcm, nf, is_change, spent) = row;
+
+    let diversifier = {
+        if d.len() != 11 {
+            return Err(Error(ErrorKind::CorruptedData(
+                "Invalid diversifier length",
+            )));
+        }
+        let mut tmp = [0; 11];
+        tmp.copy_from_slice(&d);
+        Diversifier(tmp)
+    };
+
+    let value = match Amount::from_i64(value) {
+        Ok(value) if !value.is_negative() => value,
+        _ => return Err(Error(ErrorKind::CorruptedData("Invalid note value"))),
+    };
+
+    let rcm = {
+        let mut tmp = FsRepr::default();
+        tmp.read_le(&rcm[..])?;
+        Fs::from_repr(tmp).map_err(|_| Error(ErrorKind::InvalidNote))?
+    };
+
+    let nf = {
+        if nf.len() != 32 {
+            return Err(Error(ErrorKind::CorruptedData("Invalid nullifier length")));
+        }
+        let mut tmp = [0; 32];
+        tmp.copy_from_slice(&nf);
+        tmp
+    };
+
+    let witness = match data.query_row(
+        "SELECT block, witness FROM sapling_witnesses
+        WHERE note = ?
+        ORDER BY block DESC LIMIT 1",
+        &[id_note],
+        |row| Ok((row.get::<_, i32>(0)?, row.get::<_, Vec<u8>>(1)?)),
+    ) {
+        Ok((block, witness)) => Some(read_witness(&witness, id_note, block)?),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(NoteDetails {
+        id_note,
+        txid: to_txid(txid)?,
+        block_height,
+        output_index: output_index as usize,
+        account,
+        diversifier,
+        value,
+        rcm,
+        nf,
+        is_change,
+        spent: spent.map(to_txid).transpose()?,
+        witness,
+    })
+}
+
+/// A received note that has been spent in a mined transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpendRecord {
+    pub id_note: i64,
+    /// The height of the block in which the note was received.
+    pub received_height: u32,
+    /// The height of the block in which the note was spent.
+    pub spent_height: u32,
+    /// The transaction in which the note was spent.
+    pub spending_txid: TxId,
+    pub value: Amount,
+}
+
+/// Returns every note received by the account that has been spent in a mined
+/// transaction, ordered by the height at which it was spent.
+///
+/// Notes spent in transactions that have not been mined are not included.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::get_spend_history;
+///
+/// let spends = get_spend_history("/path/to/data.db", 0);
+/// ```
+pub fn get_spend_history<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+) -> Result<Vec<SpendRecord>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let mut stmt_spends = data.prepare(
+        "SELECT received_notes.id_note, received_tx.block, spent_tx.block, spent_tx.txid,
+            received_notes.value
+        FROM received_notes
+        INNER JOIN transactions AS received_tx ON received_tx.id_tx = received_notes.tx
+        INNER JOIN transactions AS spent_tx ON spent_tx.id_tx = received_notes.spent
+        WHERE received_notes.account = ?
+        AND received_tx.block IS NOT NULL AND spent_tx.block IS NOT NULL
+        ORDER BY spent_tx.block ASC, received_notes.id_note ASC",
+    )?;
+    let rows = stmt_spends.query_map(&[account], |row| {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get::<_, Vec<u8>>(3)?,
+            row.get::<_, i64>(4)?,
+        ))
+    })?;
+
+    let mut spends = vec![];
+    for row in rows {
+        let (id_note, received_height, spent_height, txid, value) = row?;
+
+        let spending_txid = {
+            if txid.len() != 32 {
+                return Err(Error(ErrorKind::CorruptedData("Invalid txid length")));
+            }
+            let mut tmp = TxId([0; 32]);
+            tmp.0.copy_from_slice(&txid);
+            tmp
+        };
+
+        let value = match Amount::from_i64(value) {
+            Ok(value) if !value.is_negative() => value,
+            _ => return Err(Error(ErrorKind::CorruptedData("Invalid note value"))),
+        };
+
+        spends.push(SpendRecord {
+            id_note,
+            received_height,
+            spent_height,
+            spending_txid,
+            value,
+        });
+    }
+
+    Ok(spends)
+}
+
 /// Returns the memo for a received note, if it is known and a valid UTF-8 string.
 ///
 /// The note is identified by its row index in the `received_notes` table within the data
@@ -106,7 +939,7 @@ pub fn get_received_memo_as_utf8<P: AsRef<Path>>(
     db_data: P,
     id_note: i64,
 ) -> Result<Option<String>, Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_wallet_readonly(db_data)?;
 
     let memo: Vec<_> = data.query_row(
         "SELECT memo FROM received_notes
@@ -140,7 +973,7 @@ pub fn get_sent_memo_as_utf8<P: AsRef<Path>>(
     db_data: P,
     id_note: i64,
 ) -> Result<Option<String>, Error> {
-    let data = Connection::open(db_data)?;
+    let data = open_wallet_readonly(db_data)?;
 
     let memo: Vec<_> = data.query_row(
         "SELECT memo FROM sent_notes
@@ -159,33 +992,93 @@ pub fn get_sent_memo_as_utf8<P: AsRef<Path>>(
     }
 }
 
+/// Returns the row indices in the `received_notes` table of the notes whose text memos
+/// match `query`, best matches first.
+///
+/// `query` is an [FTS5 query], so for example `rent*` matches memos containing words
+/// starting with "rent". Returns an error if the query is malformed.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::query::search_memos;
+///
+/// if let Ok(notes) = search_memos("/path/to/data.db", "invoice") {
+///     println!("{} notes mention an invoice", notes.len());
+/// }
+/// ```
+///
+/// [FTS5 query]: https://www.sqlite.org/fts5.html#full_text_query_syntax
+#[cfg(feature = "memo-search")]
+pub fn search_memos<P: AsRef<Path>>(db_data: P, query: &str) -> Result<Vec<i64>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let mut stmt_search = data.prepare(
+        "SELECT id_note FROM memo_search
+        WHERE memo_search MATCH ?
+        ORDER BY rank",
+    )?;
+    let rows = stmt_search.query_map(&[query], |row| row.get(0))?;
+
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
 #[cfg(test)]
 mod tests {
+    use rusqlite::Connection;
     use tempfile::NamedTempFile;
     use zcash_primitives::{
-        transaction::components::Amount,
+        block::BlockHash,
+        merkle_tree::CommitmentTree,
+        sapling::Node,
+        transaction::{components::Amount, TxId},
         zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
     };
 
-    use super::{get_address, get_balance, get_verified_balance};
+    use super::{
+        estimate_sync_lag, get_account_count, get_address, get_anchor_height, get_balance,
+        get_block_height_for_tx, get_block_time_for_tx, get_chain_tip, get_extfvk_for_account,
+        get_note_details, get_orchard_balance, get_sapling_tree, get_sapling_tree_at_height,
+        get_sapling_tree_serialized, get_spend_history, get_spendable_notes,
+        get_total_wallet_balance, get_verified_balance, list_accounts, SpendRecord, SpendableNote,
+        SyncLagEstimate, TotalBalance, WalletState,
+    };
+
+    #[cfg(feature = "memo-search")]
+    use rusqlite::types::ToSql;
+
+    #[cfg(feature = "memo-search")]
+    use super::search_memos;
     use crate::{
+        chain::rewind_to_height,
         error::ErrorKind,
-        init::{init_accounts_table, init_data_database},
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        scan::scan_cached_blocks,
+        tests::{
+            fake_compact_block, fake_compact_block_spending, insert_into_cache,
+            insert_test_received_note,
+        },
+        SAPLING_ACTIVATION_HEIGHT,
     };
+    #[cfg(feature = "memo-search")]
+    use zcash_primitives::note_encryption::Memo;
 
     #[test]
     fn empty_database_has_no_balance() {
         let data_file = NamedTempFile::new().unwrap();
         let db_data = data_file.path();
         init_data_database(&db_data).unwrap();
+        assert_eq!(get_account_count(db_data).unwrap(), 0);
 
         // Add an account to the wallet
         let extsk = ExtendedSpendingKey::master(&[]);
         let extfvks = [ExtendedFullViewingKey::from(&extsk)];
         init_accounts_table(&db_data, &extfvks).unwrap();
+        assert_eq!(get_account_count(db_data).unwrap(), 1);
 
         // The account should be empty
         assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+        assert_eq!(get_orchard_balance(db_data, 0).unwrap(), Amount::zero());
 
         // The account should have no verified balance, as we haven't scanned any blocks
         let e = get_verified_balance(db_data, 0).unwrap_err();
@@ -197,5 +1090,577 @@ mod tests {
         // An invalid account has zero balance
         assert!(get_address(db_data, 1).is_err());
         assert_eq!(get_balance(db_data, 1).unwrap(), Amount::zero());
+        match get_orchard_balance(db_data, 1).unwrap_err().kind() {
+            ErrorKind::UnknownAccount(1) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn extfvk_for_account() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        assert_eq!(get_extfvk_for_account(db_data, 0).unwrap(), extfvk);
+        match get_extfvk_for_account(db_data, 1).unwrap_err().kind() {
+            ErrorKind::UnknownAccount(1) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        // The cached key matches the stored key
+        let mut wallet = WalletState::new(db_data);
+        assert_eq!(wallet.extfvk_for_account(0).unwrap(), &extfvk);
+        assert_eq!(wallet.extfvk_for_account(0).unwrap(), &extfvk);
+        assert!(wallet.extfvk_for_account(1).is_err());
+    }
+
+    #[test]
+    fn total_wallet_balance() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // An empty wallet has no balance
+        assert_eq!(
+            get_total_wallet_balance(db_data).unwrap(),
+            TotalBalance {
+                total: Amount::zero(),
+                by_account: vec![],
+            }
+        );
+
+        // Add three accounts to the wallet
+        let extfvks = [
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[])),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1])),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[2])),
+        ];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        // Receive notes to the first and third accounts
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvks[0].clone(),
+            Amount::from_u64(5).unwrap(),
+        );
+        let (cb2, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            extfvks[2].clone(),
+            Amount::from_u64(7).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        assert_eq!(
+            get_total_wallet_balance(db_data).unwrap(),
+            TotalBalance {
+                total: Amount::from_u64(12).unwrap(),
+                by_account: vec![
+                    (0, Amount::from_u64(5).unwrap()),
+                    (1, Amount::zero()),
+                    (2, Amount::from_u64(7).unwrap()),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn balance_of_inserted_notes() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add two accounts to the wallet
+        let extfvks = [
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[])),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1])),
+        ];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        let height = SAPLING_ACTIVATION_HEIGHT as u32;
+        let (note, change) = {
+            let data = Connection::open(db_data).unwrap();
+            let note =
+                insert_test_received_note(&data, 0, Amount::from_u64(5).unwrap(), height, false);
+            let change =
+                insert_test_received_note(&data, 0, Amount::from_u64(3).unwrap(), height, true);
+            insert_test_received_note(&data, 1, Amount::from_u64(7).unwrap(), height + 1, false);
+            (note, change)
+        };
+
+        assert_eq!(
+            get_balance(db_data, 0).unwrap(),
+            Amount::from_u64(8).unwrap()
+        );
+        assert_eq!(
+            get_balance(db_data, 1).unwrap(),
+            Amount::from_u64(7).unwrap()
+        );
+        assert_eq!(get_chain_tip(db_data).unwrap().unwrap().0, height + 1);
+
+        let details = get_note_details(db_data, note).unwrap();
+        assert_eq!(details.value, Amount::from_u64(5).unwrap());
+        assert!(!details.is_change);
+        assert_eq!(details.block_height, Some(height));
+        assert!(details.witness.is_some());
+        assert!(get_note_details(db_data, change).unwrap().is_change);
+    }
+
+    #[test]
+    fn list_all_accounts() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // An empty wallet has no accounts
+        assert!(list_accounts(db_data).unwrap().is_empty());
+
+        // Add two accounts to the wallet
+        let extfvks = [
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[])),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1])),
+        ];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        let accounts = list_accounts(db_data).unwrap();
+        assert_eq!(accounts.len(), 2);
+        for (i, (info, extfvk)) in accounts.iter().zip(extfvks.iter()).enumerate() {
+            assert_eq!(info.account, i as u32);
+            assert_eq!(&info.extfvk, extfvk);
+            assert_eq!(info.default_address, extfvk.default_address().unwrap().1);
+            assert_eq!(info.birthday, None);
+        }
+    }
+
+    #[test]
+    fn spendable_notes_have_positions() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive two notes in consecutive blocks
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Only the first note is at or below the anchor height
+        assert_eq!(
+            get_spendable_notes(db_data, 0).unwrap(),
+            vec![SpendableNote {
+                id_note: 1,
+                value,
+                commitment_tree_position: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn anchor_height_and_tree() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // We can't select an anchor before scanning
+        match get_anchor_height(db_data, 0) {
+            Err(e) => match e.kind() {
+                ErrorKind::ScanRequired => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // Scan two blocks, each with a single note
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        let height = SAPLING_ACTIVATION_HEIGHT as u32;
+        assert_eq!(get_anchor_height(db_data, 0).unwrap(), height + 1);
+        assert_eq!(get_anchor_height(db_data, 1).unwrap(), height);
+        assert!(get_anchor_height(db_data, 2).is_err());
+
+        // The tree at each height contains the notes received up to that height
+        for (anchor_height, size) in vec![(height, 1), (height + 1, 2)] {
+            let tree = get_sapling_tree_at_height(db_data, anchor_height).unwrap();
+            assert_eq!(
+                get_sapling_tree_serialized(db_data, anchor_height).unwrap(),
+                tree
+            );
+            let tree = CommitmentTree::<Node>::read(&tree[..]).unwrap();
+            assert_eq!(tree.size(), size);
+            assert_eq!(
+                get_sapling_tree(db_data, anchor_height).unwrap().size(),
+                size
+            );
+        }
+
+        // There is no tree for a block that hasn't been scanned
+        match get_sapling_tree(db_data, height + 2) {
+            Err(e) => match e.kind() {
+                ErrorKind::ScanRequired => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+
+    #[test]
+    fn chain_tip_is_latest_scanned_block() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // There is no chain tip before scanning
+        assert_eq!(get_chain_tip(db_data).unwrap(), None);
+
+        // Scan two blocks
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        assert_eq!(
+            get_chain_tip(db_data).unwrap(),
+            Some((SAPLING_ACTIVATION_HEIGHT as u32 + 1, cb2.hash()))
+        );
+    }
+
+    #[test]
+    fn block_height_and_time_for_tx() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note in a block with a known timestamp
+        let (mut cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk,
+            Amount::from_u64(5).unwrap(),
+        );
+        cb.set_time(1_234_567);
+        insert_into_cache(db_cache, &cb);
+        let mut txid = TxId([0; 32]);
+        txid.0.copy_from_slice(&cb.vtx[0].hash);
+
+        // Unknown transactions have no block
+        let unknown = TxId([7; 32]);
+        assert_eq!(get_block_height_for_tx(db_data, &unknown).unwrap(), None);
+        assert_eq!(get_block_time_for_tx(db_data, &unknown).unwrap(), None);
+
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(
+            get_block_height_for_tx(db_data, &txid).unwrap(),
+            Some(SAPLING_ACTIVATION_HEIGHT as u32)
+        );
+        assert_eq!(
+            get_block_time_for_tx(db_data, &txid).unwrap(),
+            Some(1_234_567)
+        );
+
+        // After rewinding, the transaction is no longer mined
+        rewind_to_height(db_data, SAPLING_ACTIVATION_HEIGHT - 1).unwrap();
+        assert_eq!(get_block_height_for_tx(db_data, &txid).unwrap(), None);
+        assert_eq!(get_block_time_for_tx(db_data, &txid).unwrap(), None);
+    }
+
+    #[test]
+    fn note_details() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note, then spend it with change in the next block
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let to2 = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+        let cb2 = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            (nf.clone(), value),
+            extfvk.clone(),
+            to2,
+            Amount::from_u64(2).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        let mut txid = TxId([0; 32]);
+        txid.0.copy_from_slice(&cb.vtx[0].hash);
+        let mut txid2 = TxId([0; 32]);
+        txid2.0.copy_from_slice(&cb2.vtx[0].hash);
+
+        let note = get_note_details(db_data, 1).unwrap();
+        assert_eq!(note.id_note, 1);
+        assert_eq!(note.txid, txid);
+        assert_eq!(note.block_height, Some(SAPLING_ACTIVATION_HEIGHT as u32));
+        assert_eq!(note.output_index, 0);
+        assert_eq!(note.account, 0);
+        assert_eq!(note.diversifier, extfvk.default_address().unwrap().0);
+        assert_eq!(note.value, value);
+        assert_eq!(&note.nf[..], &nf[..]);
+        assert!(!note.is_change);
+        assert_eq!(note.spent, Some(txid2));
+        assert_eq!(note.witness.unwrap().position(), 0);
+
+        // The change note is unspent
+        let change = get_note_details(db_data, 2).unwrap();
+        assert_eq!(change.txid, txid2);
+        assert_eq!(change.value, Amount::from_u64(3).unwrap());
+        assert!(change.is_change);
+        assert_eq!(change.spent, None);
+
+        assert!(get_note_details(db_data, 3).is_err());
+    }
+
+    #[test]
+    fn spend_history() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note; nothing has been spent yet
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert!(get_spend_history(db_data, 0).unwrap().is_empty());
+
+        // Spend it in the next block
+        let to2 = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+        let cb2 = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            (nf, value),
+            extfvk,
+            to2,
+            Amount::from_u64(2).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        let mut txid2 = TxId([0; 32]);
+        txid2.0.copy_from_slice(&cb2.vtx[0].hash);
+        assert_eq!(
+            get_spend_history(db_data, 0).unwrap(),
+            vec![SpendRecord {
+                id_note: 1,
+                received_height: SAPLING_ACTIVATION_HEIGHT as u32,
+                spent_height: SAPLING_ACTIVATION_HEIGHT as u32 + 1,
+                spending_txid: txid2,
+                value,
+            }]
+        );
+
+        // Other accounts have no history
+        assert!(get_spend_history(db_data, 1).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "memo-search")]
+    #[test]
+    fn search_received_memos() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        init_accounts_table(&db_data, &[extfvk]).unwrap();
+
+        let height = SAPLING_ACTIVATION_HEIGHT as u32;
+        let data = Connection::open(db_data).unwrap();
+        let value = Amount::from_u64(5).unwrap();
+        let set_memo = |id_note: i64, memo: &[u8]| {
+            data.execute(
+                "UPDATE received_notes SET memo = ? WHERE id_note = ?",
+                &[memo.to_sql().unwrap(), id_note.to_sql().unwrap()],
+            )
+            .unwrap();
+        };
+
+        let rent = insert_test_received_note(&data, 0, value, height, false);
+        set_memo(rent, Memo::from_str("Rent for March").unwrap().as_bytes());
+        let lunch = insert_test_received_note(&data, 0, value, height, false);
+        set_memo(lunch, Memo::from_str("Lunch, and rent").unwrap().as_bytes());
+        let binary = insert_test_received_note(&data, 0, value, height, false);
+        set_memo(binary, &[0xff; 512]);
+
+        assert_eq!(search_memos(db_data, "rent").unwrap().len(), 2);
+        assert_eq!(search_memos(db_data, "lunch").unwrap(), vec![lunch]);
+        assert_eq!(search_memos(db_data, "mar*").unwrap(), vec![rent]);
+        assert!(search_memos(db_data, "invoice").unwrap().is_empty());
+
+        // The index follows changes to the memos
+        set_memo(rent, Memo::from_str("Invoice 17").unwrap().as_bytes());
+        assert_eq!(search_memos(db_data, "rent").unwrap(), vec![lunch]);
+        assert_eq!(search_memos(db_data, "invoice").unwrap(), vec![rent]);
+        data.execute("DELETE FROM received_notes WHERE id_note = ?", &[lunch])
+            .unwrap();
+        assert!(search_memos(db_data, "lunch").unwrap().is_empty());
+
+        // Malformed queries are rejected
+        assert!(search_memos(db_data, "\"unterminated").is_err());
+    }
+
+    #[test]
+    fn sync_lag_from_latest_block_time() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Nothing has been scanned
+        match estimate_sync_lag(db_data, 1_600_000_000) {
+            Err(e) => match e.kind() {
+                ErrorKind::ScanRequired => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        let data = Connection::open(db_data).unwrap();
+        let insert_block = |height: u32, time: u32| {
+            data.execute(
+                "INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (?, x'00', ?, x'00')",
+                &[height, time],
+            )
+            .unwrap();
+        };
+        let height = SAPLING_ACTIVATION_HEIGHT as u32;
+        insert_block(height, 1_599_990_000);
+        insert_block(height + 1, 1_599_992_500);
+
+        // The lag is measured from the most recent block
+        assert_eq!(
+            estimate_sync_lag(db_data, 1_600_000_000).unwrap(),
+            SyncLagEstimate {
+                blocks_behind: 100,
+                seconds_behind: 7500,
+                is_syncing: true,
+            }
+        );
+        assert_eq!(
+            estimate_sync_lag(db_data, 1_599_996_100).unwrap(),
+            SyncLagEstimate {
+                blocks_behind: 48,
+                seconds_behind: 3600,
+                is_syncing: false,
+            }
+        );
+
+        // A block time after the current time is not a lag
+        assert_eq!(
+            estimate_sync_lag(db_data, 1_599_992_000).unwrap(),
+            SyncLagEstimate {
+                blocks_behind: 0,
+                seconds_behind: 0,
+                is_syncing: false,
+            }
+        );
     }
 }
@@ -0,0 +1,656 @@
+//! Functions for repairing a corrupted data database.
+
+use ff::PrimeField;
+use protobuf::parse_from_bytes;
+use rusqlite::{types::ToSql, Connection, NO_PARAMS};
+use std::path::Path;
+use zcash_client_backend::{
+    proto::compact_formats::CompactBlock, validation::validate_compact_block,
+};
+use zcash_primitives::{merkle_tree::CommitmentTree, sapling::Node};
+
+use crate::{
+    cache::{compressed_column, decompress_block},
+    error::{Error, ErrorKind},
+    open_data_database,
+    query::{get_sapling_tree, open_wallet_readonly},
+    read_witness,
+    scan::ScanConfig,
+    write_witness,
+};
+
+/// Returns true if `tree` has the same root as every witness stored at `height`.
+fn tree_matches_witnesses(
+    data: &Connection,
+    height: u32,
+    tree: &CommitmentTree<Node>,
+) -> Result<bool, Error> {
+    let mut stmt_fetch_witnesses =
+        data.prepare("SELECT note, witness FROM sapling_witnesses WHERE block = ?")?;
+    let witnesses = stmt_fetch_witnesses.query_map(&[height], |row| {
+        let id_note: i64 = row.get(0)?;
+        let witness: Vec<u8> = row.get(1)?;
+        Ok((id_note, witness))
+    })?;
+
+    let root = tree.root();
+    for witness in witnesses {
+        let (id_note, witness) = witness?;
+        if read_witness(&witness, id_note, height as i32)?.root() != root {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Fetches the note commitments of the cached blocks after `after` up to `to`
+/// (inclusive), grouped by block in height order.
+///
+/// Returns an [`ErrorKind::CacheMissingBlocks`] error if the cache is missing any of the
+/// blocks, or an error if any of them is larger than `max_block_size` bytes.
+fn fetch_cached_cmus(
+    cache: &Connection,
+    after: u32,
+    to: u32,
+    max_block_size: usize,
+) -> Result<Vec<(u32, Vec<Node>)>, Error> {
+    let mut stmt_blocks = cache.prepare(&format!(
+        "SELECT height, data, {} FROM compactblocks
+        WHERE height > ? AND height <= ?
+        ORDER BY height ASC",
+        compressed_column(cache)?
+    ))?;
+    let rows = stmt_blocks.query_map(&[after, to], |row| {
+        let height: u32 = row.get(0)?;
+        let data: Vec<u8> = row.get(1)?;
+        let compressed: bool = row.get(2)?;
+        Ok((height, data, compressed))
+    })?;
+
+    let mut cmus = vec![];
+    let mut last_height = after;
+    for row in rows {
+        let (block_height, block, compressed) = row?;
+        if block_height != last_height + 1 {
+            return Err(Error(ErrorKind::CacheMissingBlocks(
+                (last_height + 1) as i32,
+                (block_height - 1) as i32,
+            )));
+        }
+        last_height = block_height;
+
+        let block = decompress_block(block_height as i32, block, compressed, max_block_size)?;
+        let block: CompactBlock = parse_from_bytes(&block)?;
+        validate_compact_block(&block)
+            .map_err(|e| Error(ErrorKind::InvalidCompactBlock(block_height as i32, e)))?;
+        let block_cmus = block
+            .vtx
+            .iter()
+            .flat_map(|tx| tx.outputs.iter())
+            // validate_compact_block has checked that the cmu is valid.
+            .map(|output| Node::new(output.cmu().unwrap().into_repr()))
+            .collect();
+        cmus.push((block_height, block_cmus));
+    }
+    if last_height != to {
+        return Err(Error(ErrorKind::CacheMissingBlocks(
+            (last_height + 1) as i32,
+            to as i32,
+        )));
+    }
+
+    Ok(cmus)
+}
+
+/// Reconstructs the Sapling [`CommitmentTree`] as of the end of the block at `height`.
+///
+/// This is a repair tool for data databases in which the `sapling_tree` column of the
+/// `blocks` table has been corrupted. Starting from the most recent intact tree below
+/// `height`, the note commitments of the intervening blocks are replayed from the cache
+/// database, which must still contain those blocks. A stored tree is considered intact
+/// if it can be parsed and has the same root as the witnesses stored at its height.
+///
+/// The reconstructed tree is checked against the witnesses stored at `height` before it
+/// is returned. It is not written to the data database.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::repair::recover_sapling_tree;
+///
+/// let tree = recover_sapling_tree("/path/to/cache.db", "/path/to/data.db", 500_000);
+/// ```
+pub fn recover_sapling_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    height: u32,
+) -> Result<CommitmentTree<Node>, Error> {
+    recover_sapling_tree_with_config(db_cache, db_data, height, &ScanConfig::default())
+}
+
+/// Reconstructs the Sapling [`CommitmentTree`] as of the end of the block at `height`,
+/// reading cached blocks with the limits in the given [`ScanConfig`].
+///
+/// See [`recover_sapling_tree`] for details.
+pub fn recover_sapling_tree_with_config<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    height: u32,
+    config: &ScanConfig,
+) -> Result<CommitmentTree<Node>, Error> {
+    let cache = Connection::open(db_cache)?;
+    let data = open_data_database(db_data)?;
+
+    // Find the most recent intact tree below the requested height.
+    let mut stmt_fetch_trees = data
+        .prepare("SELECT height, sapling_tree FROM blocks WHERE height < ? ORDER BY height DESC")?;
+    let trees = stmt_fetch_trees.query_map(&[height], |row| {
+        let height: u32 = row.get(0)?;
+        let tree: Vec<u8> = row.get(1)?;
+        Ok((height, tree))
+    })?;
+    let mut start = None;
+    for row in trees {
+        let (tree_height, tree) = row?;
+        if let Ok(tree) = CommitmentTree::read(&tree[..]) {
+            if tree_matches_witnesses(&data, tree_height, &tree)? {
+                start = Some((tree_height, tree));
+                break;
+            }
+        }
+    }
+    let (start_height, mut tree) = start.ok_or(Error(ErrorKind::CorruptedData(
+        "No intact Sapling tree below the requested height",
+    )))?;
+
+    // Replay the note commitments from the cached blocks.
+    for (_, cmus) in fetch_cached_cmus(&cache, start_height, height, config.max_block_size_bytes)? {
+        for cmu in cmus {
+            tree.append(cmu)
+                .map_err(|_| Error(ErrorKind::CorruptedData("Sapling tree is full")))?;
+        }
+    }
+
+    if !tree_matches_witnesses(&data, height, &tree)? {
+        return Err(Error(ErrorKind::CorruptedData(
+            "Recovered Sapling tree does not match the stored witnesses",
+        )));
+    }
+
+    Ok(tree)
+}
+
+/// Brings the witnesses of notes that may still be spent up to date with the block at
+/// `from_height`.
+///
+/// If scanning was interrupted, or the data database was partially restored, the most
+/// recent witness stored for a note may be older than `from_height`. Such witnesses are
+/// no longer updated by [`scan_cached_blocks`], and the note cannot be spent. This
+/// replays the note commitments of the missing blocks from the cache database, which
+/// must still contain them, and stores the repaired witnesses at `from_height`. Each
+/// repaired witness is checked against the Sapling tree stored for `from_height`.
+///
+/// Returns the number of witnesses that were repaired, or an
+/// [`ErrorKind::CacheMissingBlocks`] error if the cache no longer contains the blocks
+/// needed to repair them. This is called by [`scan_cached_blocks`] with the last scanned
+/// height before it starts scanning; if the blocks are missing, scanning continues and
+/// the witnesses are left as they are.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::repair::repair_witnesses;
+///
+/// let repaired = repair_witnesses("/path/to/cache.db", "/path/to/data.db", 500_000);
+/// ```
+///
+/// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+pub fn repair_witnesses<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    from_height: u32,
+) -> Result<usize, Error> {
+    repair_witnesses_with_config(db_cache, db_data, from_height, &ScanConfig::default())
+}
+
+/// Brings the witnesses of notes that may still be spent up to date with the block at
+/// `from_height`, reading cached blocks with the limits in the given [`ScanConfig`].
+///
+/// See [`repair_witnesses`] for details.
+pub fn repair_witnesses_with_config<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    from_height: u32,
+    config: &ScanConfig,
+) -> Result<usize, Error> {
+    let data = open_data_database(&db_data)?;
+
+    // Find the latest witness of each note that is behind.
+    let mut stmt_fetch_behind = data.prepare(
+        "SELECT sapling_witnesses.note, sapling_witnesses.block, sapling_witnesses.witness
+        FROM sapling_witnesses
+        INNER JOIN (
+            SELECT note, MAX(block) AS block FROM sapling_witnesses GROUP BY note
+        ) AS latest
+        ON latest.note = sapling_witnesses.note AND latest.block = sapling_witnesses.block
+        INNER JOIN received_notes ON received_notes.id_note = sapling_witnesses.note
+        LEFT OUTER JOIN transactions ON transactions.id_tx = received_notes.spent
+        WHERE sapling_witnesses.block < ?
+        AND (received_notes.spent IS NULL OR transactions.block IS NULL)",
+    )?;
+    let rows = stmt_fetch_behind.query_map(&[from_height], |row| {
+        let id_note: i64 = row.get(0)?;
+        let block: u32 = row.get(1)?;
+        let witness: Vec<u8> = row.get(2)?;
+        Ok((id_note, block, witness))
+    })?;
+    let mut witnesses = vec![];
+    for row in rows {
+        let (id_note, block, witness) = row?;
+        log::warn!("Witness for note {} is stale", id_note);
+        witnesses.push((
+            id_note,
+            block,
+            read_witness(&witness, id_note, block as i32)?,
+        ));
+    }
+    let oldest = match witnesses.iter().map(|(_, block, _)| *block).min() {
+        Some(oldest) => oldest,
+        None => return Ok(0),
+    };
+
+    // Replay the missing note commitments into each witness.
+    let cache = Connection::open(db_cache)?;
+    for (block_height, cmus) in
+        fetch_cached_cmus(&cache, oldest, from_height, config.max_block_size_bytes)?
+    {
+        for (_, block, witness) in witnesses.iter_mut() {
+            if block_height <= *block {
+                continue;
+            }
+            for cmu in &cmus {
+                witness
+                    .append(*cmu)
+                    .map_err(|_| Error(ErrorKind::CorruptedData("Sapling tree is full")))?;
+            }
+        }
+    }
+
+    let root = get_sapling_tree(&db_data, from_height)?.root();
+    for (id_note, _, witness) in &witnesses {
+        if witness.root() != root {
+            return Err(Error(ErrorKind::InvalidWitnessAnchor(
+                *id_note,
+                from_height as i32,
+            )));
+        }
+    }
+
+    let mut stmt_insert_witness = data.prepare(
+        "INSERT OR REPLACE INTO sapling_witnesses (note, block, witness)
+        VALUES (?, ?, ?)",
+    )?;
+    data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+    for (id_note, _, witness) in &witnesses {
+        stmt_insert_witness.execute(&[
+            id_note.to_sql()?,
+            from_height.to_sql()?,
+            write_witness(witness).to_sql()?,
+        ])?;
+    }
+    data.execute("COMMIT", NO_PARAMS)?;
+
+    Ok(witnesses.len())
+}
+
+/// Checks the commitment tree position stored for each received note against the
+/// position of its stored witnesses.
+///
+/// Returns the IDs of the notes whose `commitment_tree_position` is missing or does not
+/// match any of their witnesses, in ascending order. Notes without stored witnesses
+/// (for example, notes spent more than 100 blocks ago) cannot be checked and are
+/// skipped.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::repair::check_note_positions;
+///
+/// let mismatched = check_note_positions("/path/to/data.db");
+/// ```
+pub fn check_note_positions<P: AsRef<Path>>(db_data: P) -> Result<Vec<i64>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+
+    let mut stmt_fetch_witnesses = data.prepare(
+        "SELECT received_notes.id_note, received_notes.commitment_tree_position,
+            sapling_witnesses.block, sapling_witnesses.witness
+        FROM sapling_witnesses
+        INNER JOIN received_notes ON received_notes.id_note = sapling_witnesses.note
+        ORDER BY received_notes.id_note ASC",
+    )?;
+    let witnesses = stmt_fetch_witnesses.query_map(NO_PARAMS, |row| {
+        let id_note: i64 = row.get(0)?;
+        let position: Option<i64> = row.get(1)?;
+        let height: i32 = row.get(2)?;
+        let witness: Vec<u8> = row.get(3)?;
+        Ok((id_note, position, height, witness))
+    })?;
+
+    let mut mismatched = vec![];
+    for row in witnesses {
+        let (id_note, position, height, witness) = row?;
+        let witness = read_witness(&witness, id_note, height)?;
+        if position != Some(witness.position() as i64) && mismatched.last() != Some(&id_note) {
+            mismatched.push(id_note);
+        }
+    }
+
+    Ok(mismatched)
+}
+
+/// Checks that the commitment tree position of every note received at or below
+/// `at_height` lies within the Sapling [`CommitmentTree`] stored for that height.
+///
+/// Returns an error with kind [`ErrorKind::InvalidWitnessPosition`] for the first note
+/// whose position is not less than the size of the tree. Notes without a stored position
+/// are skipped.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::repair::validate_witness_positions;
+///
+/// let result = validate_witness_positions("/path/to/data.db", 500_000);
+/// ```
+pub fn validate_witness_positions<P: AsRef<Path>>(db_data: P, at_height: u32) -> Result<(), Error> {
+    let tree_size = get_sapling_tree(&db_data, at_height)?.size();
+
+    let data = open_wallet_readonly(db_data)?;
+    let mut stmt_fetch_positions = data.prepare(
+        "SELECT received_notes.id_note, received_notes.commitment_tree_position
+        FROM received_notes
+        INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+        WHERE transactions.block <= ?
+        AND received_notes.commitment_tree_position IS NOT NULL
+        ORDER BY received_notes.id_note ASC",
+    )?;
+    let positions = stmt_fetch_positions.query_map(&[at_height], |row| {
+        let id_note: i64 = row.get(0)?;
+        let position: i64 = row.get(1)?;
+        Ok((id_note, position))
+    })?;
+
+    for row in positions {
+        let (id_note, position) = row?;
+        if position < 0 || position as u64 >= tree_size as u64 {
+            return Err(Error(ErrorKind::InvalidWitnessPosition(
+                id_note,
+                position as u64,
+                tree_size,
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{Connection, NO_PARAMS};
+    use tempfile::NamedTempFile;
+    use zcash_primitives::{
+        block::BlockHash,
+        merkle_tree::CommitmentTree,
+        sapling::Node,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{
+        check_note_positions, recover_sapling_tree, repair_witnesses, validate_witness_positions,
+    };
+    use crate::{
+        error::ErrorKind,
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        query::{get_balance, get_note_details, get_sapling_tree, get_sapling_tree_at_height},
+        scan::scan_cached_blocks,
+        tests::{fake_compact_block, insert_into_cache},
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    #[test]
+    fn recover_corrupted_sapling_tree() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Scan three blocks
+        let value = Amount::from_u64(5).unwrap();
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..3 {
+            let (cb, _) = fake_compact_block(
+                SAPLING_ACTIVATION_HEIGHT + i,
+                prev_hash,
+                extfvk.clone(),
+                value,
+            );
+            insert_into_cache(db_cache, &cb);
+            prev_hash = cb.hash();
+        }
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        let height = SAPLING_ACTIVATION_HEIGHT as u32 + 2;
+        let expected = get_sapling_tree_at_height(db_data, height).unwrap();
+        let expected = CommitmentTree::<Node>::read(&expected[..]).unwrap();
+
+        // Corrupt the trees for the last two blocks
+        Connection::open(db_data)
+            .unwrap()
+            .execute(
+                "UPDATE blocks SET sapling_tree = x'ff' WHERE height > ?",
+                &[SAPLING_ACTIVATION_HEIGHT],
+            )
+            .unwrap();
+
+        let tree = recover_sapling_tree(db_cache, db_data, height).unwrap();
+        assert_eq!(tree.size(), 3);
+        assert_eq!(tree.root(), expected.root());
+    }
+
+    #[test]
+    fn repair_witnesses_behind_tree() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note in each of three blocks
+        let value = Amount::from_u64(5).unwrap();
+        let mut prev_hash = BlockHash([0; 32]);
+        let mut add_block = |height| {
+            let (cb, _) = fake_compact_block(height, prev_hash, extfvk.clone(), value);
+            insert_into_cache(db_cache, &cb);
+            prev_hash = cb.hash();
+        };
+        for i in 0..3 {
+            add_block(SAPLING_ACTIVATION_HEIGHT + i);
+        }
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Nothing to repair
+        let height = SAPLING_ACTIVATION_HEIGHT as u32 + 2;
+        assert_eq!(repair_witnesses(db_cache, db_data, height).unwrap(), 0);
+
+        // Lose the witnesses of the first note after its first block
+        let data = Connection::open(db_data).unwrap();
+        let drop_witnesses = || {
+            data.execute(
+                "DELETE FROM sapling_witnesses WHERE note = 1 AND block > ?",
+                &[SAPLING_ACTIVATION_HEIGHT],
+            )
+            .unwrap();
+        };
+        drop_witnesses();
+        assert_eq!(repair_witnesses(db_cache, db_data, height).unwrap(), 1);
+        let witness = get_note_details(db_data, 1).unwrap().witness.unwrap();
+        assert_eq!(witness.position(), 0);
+        assert_eq!(
+            witness.root(),
+            get_sapling_tree(db_data, height).unwrap().root()
+        );
+        assert_eq!(repair_witnesses(db_cache, db_data, height).unwrap(), 0);
+
+        // Scanning repairs the witness before scanning new blocks
+        drop_witnesses();
+        add_block(SAPLING_ACTIVATION_HEIGHT + 3);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        let witness = get_note_details(db_data, 1).unwrap().witness.unwrap();
+        assert_eq!(
+            witness.root(),
+            get_sapling_tree(db_data, height + 1).unwrap().root()
+        );
+
+        // Once the blocks needed to repair the witness are pruned from the cache, it
+        // cannot be repaired
+        drop_witnesses();
+        Connection::open(db_cache)
+            .unwrap()
+            .execute(
+                "DELETE FROM compactblocks WHERE height < ?",
+                &[SAPLING_ACTIVATION_HEIGHT + 3],
+            )
+            .unwrap();
+        match repair_witnesses(db_cache, db_data, height + 1) {
+            Err(e) => match e.kind() {
+                ErrorKind::CacheMissingBlocks(from, to) => assert_eq!(
+                    (*from, *to),
+                    (SAPLING_ACTIVATION_HEIGHT + 1, SAPLING_ACTIVATION_HEIGHT + 2)
+                ),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // Scanning still finds new notes, and leaves the witness as it is
+        add_block(SAPLING_ACTIVATION_HEIGHT + 4);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(
+            get_balance(db_data, 0).unwrap(),
+            Amount::from_u64(25).unwrap()
+        );
+        let witness = get_note_details(db_data, 1).unwrap().witness.unwrap();
+        assert_eq!(witness.position(), 0);
+        assert_ne!(
+            witness.root(),
+            get_sapling_tree(db_data, height + 2).unwrap().root()
+        );
+    }
+
+    #[test]
+    fn note_positions_match_witnesses() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note in each of two blocks
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(check_note_positions(db_data).unwrap(), Vec::<i64>::new());
+
+        // Corrupt the position of the second note
+        Connection::open(db_data)
+            .unwrap()
+            .execute(
+                "UPDATE received_notes SET commitment_tree_position = 7 WHERE id_note = 2",
+                NO_PARAMS,
+            )
+            .unwrap();
+        assert_eq!(check_note_positions(db_data).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn witness_positions_within_tree() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note in each of two blocks
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        let height = SAPLING_ACTIVATION_HEIGHT as u32;
+        validate_witness_positions(db_data, height).unwrap();
+        validate_witness_positions(db_data, height + 1).unwrap();
+
+        // Move the first note past the end of the tree at its block
+        Connection::open(db_data)
+            .unwrap()
+            .execute(
+                "UPDATE received_notes SET commitment_tree_position = 1 WHERE id_note = 1",
+                NO_PARAMS,
+            )
+            .unwrap();
+        match validate_witness_positions(db_data, height)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidWitnessPosition(1, 1, 1) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        validate_witness_positions(db_data, height + 1).unwrap();
+    }
+}
@@ -1,33 +1,226 @@
 //! Functions for scanning the chain and extracting relevant information.
 
-use ff::{PrimeField, PrimeFieldRepr};
-use protobuf::parse_from_bytes;
-use rusqlite::{types::ToSql, Connection, NO_PARAMS};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rusqlite::{Connection, NO_PARAMS};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::path::Path;
+use std::sync::Arc;
 use zcash_client_backend::{
-    encoding::decode_extended_full_viewing_key, proto::compact_formats::CompactBlock,
-    welding_rig::scan_block,
+    encoding::decode_extended_full_viewing_key,
+    storage::{scan_cached_blocks_with_backend, StorageBackend},
+    welding_rig::WalletKeyCache,
 };
 use zcash_primitives::{
-    merkle_tree::{CommitmentTree, IncrementalWitness},
+    merkle_tree::{CommitmentTree, Hashable},
     sapling::Node,
-    JUBJUB,
 };
 
 use crate::{
+    cache::apply_cache_limits,
+    chain::rewind_to_height,
     error::{Error, ErrorKind},
+    open_data_database,
+    query::{get_chain_tip, open_wallet_readonly},
+    repair::repair_witnesses_with_config,
+    storage::SqliteBackend,
     HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, SAPLING_ACTIVATION_HEIGHT,
 };
 
-struct CompactBlockRow {
-    height: i32,
-    data: Vec<u8>,
+/// A record of the last block committed by [`scan_cached_blocks`], stored in the data
+/// database so that an interrupted scan can be checked when scanning resumes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanCursor {
+    /// The height of the last block whose scan was committed.
+    pub last_committed_height: u32,
+
+    /// The root of the Sapling commitment tree as of the end of that block.
+    pub tree_state_hash: [u8; 32],
+}
+
+impl ScanCursor {
+    /// Creates the cursor for a block at `height` with the given commitment tree.
+    pub(crate) fn new(height: u32, tree: &CommitmentTree<Node>) -> Self {
+        ScanCursor {
+            last_committed_height: height,
+            tree_state_hash: tree_root(tree),
+        }
+    }
+
+    /// Reads a cursor in the format written by [`ScanCursor::write`].
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let last_committed_height = reader.read_u32::<LittleEndian>()?;
+        let mut tree_state_hash = [0; 32];
+        reader.read_exact(&mut tree_state_hash)?;
+        Ok(ScanCursor {
+            last_committed_height,
+            tree_state_hash,
+        })
+    }
+
+    /// Writes the cursor as its little-endian height followed by the tree root.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.last_committed_height)?;
+        writer.write_all(&self.tree_state_hash)
+    }
+}
+
+/// Returns the encoding of the root of `tree`.
+fn tree_root(tree: &CommitmentTree<Node>) -> [u8; 32] {
+    let mut root = [0; 32];
+    tree.root()
+        .write(&mut root[..])
+        .expect("Should be able to write to a 32-byte buffer");
+    root
+}
+
+/// Returns the scan cursor stored in the data database, or `None` if no block has been
+/// scanned since it was created or last rewound.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::scan::get_scan_cursor;
+///
+/// let cursor = get_scan_cursor("/path/to/data.db");
+/// ```
+pub fn get_scan_cursor<P: AsRef<Path>>(db_data: P) -> Result<Option<ScanCursor>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+    read_scan_cursor(&data)
+}
+
+fn read_scan_cursor(data: &Connection) -> Result<Option<ScanCursor>, Error> {
+    match data.query_row(
+        "SELECT last_committed_height, tree_state_hash FROM scan_cursor WHERE id = 0",
+        NO_PARAMS,
+        |row| {
+            let height: u32 = row.get(0)?;
+            let hash: Vec<u8> = row.get(1)?;
+            Ok((height, hash))
+        },
+    ) {
+        Ok((last_committed_height, hash)) => {
+            if hash.len() != 32 {
+                return Err(Error(ErrorKind::CorruptedData(
+                    "Scan cursor tree hash is not 32 bytes",
+                )));
+            }
+            let mut tree_state_hash = [0; 32];
+            tree_state_hash.copy_from_slice(&hash);
+            Ok(Some(ScanCursor {
+                last_committed_height,
+                tree_state_hash,
+            }))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
 }
 
+/// Checks the scan cursor against the commitment tree stored for its block, and rewinds
+/// the data database if they do not match.
+///
+/// A mismatch means that the tree committed at `last_committed_height` is missing or
+/// corrupted, so the data database is rewound to the block before it, from which
+/// scanning can safely resume. Returns the height that was rewound to, or `None` if the
+/// cursor was consistent (or absent).
+///
+/// This is called by [`scan_cached_blocks`] before it starts scanning.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::scan::verify_scan_cursor;
+///
+/// let rewound_to = verify_scan_cursor("/path/to/data.db");
+/// ```
+pub fn verify_scan_cursor<P: AsRef<Path>>(db_data: P) -> Result<Option<u32>, Error> {
+    let data = open_data_database(&db_data)?;
+
+    let cursor = match read_scan_cursor(&data)? {
+        Some(cursor) => cursor,
+        None => return Ok(None),
+    };
+
+    let stored_root = match data.query_row(
+        "SELECT sapling_tree FROM blocks WHERE height = ?",
+        &[cursor.last_committed_height],
+        |row| row.get::<_, Vec<u8>>(0),
+    ) {
+        Ok(tree) => CommitmentTree::<Node>::read(&tree[..])
+            .ok()
+            .map(|tree| tree_root(&tree)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+    if stored_root == Some(cursor.tree_state_hash) {
+        return Ok(None);
+    }
+
+    // Roll back to the last block we can trust, and drop the cursor so that it is
+    // rewritten when the next block is scanned.
+    drop(data);
+    let height = cursor.last_committed_height - 1;
+    rewind_to_height(&db_data, height as i32)?;
+    Connection::open(&db_data)?.execute("DELETE FROM scan_cursor", NO_PARAMS)?;
+    Ok(Some(height))
+}
+
+/// Options for [`scan_cached_blocks_with_config`].
 #[derive(Clone)]
-struct WitnessRow {
-    id_note: i64,
-    witness: IncrementalWitness<Node>,
+pub struct ScanConfig {
+    /// The number of blocks to scan between WAL checkpoints, or zero to never checkpoint.
+    ///
+    /// This only has an effect if the data database is in WAL mode, in which case it
+    /// prevents the WAL file from growing without bound during long scans. Checkpoints
+    /// are passive: they never wait for concurrent readers or writers.
+    pub checkpoint_every_n_blocks: u32,
+
+    /// The maximum size in bytes of a cached block, after decompression. Larger blocks
+    /// are rejected without being parsed.
+    ///
+    /// This limit also applies to the cached blocks read by the functions that take a
+    /// `ScanConfig`, such as [`validate_combined_chain_with_config`] and
+    /// [`repair_witnesses_with_config`].
+    ///
+    /// [`validate_combined_chain_with_config`]: crate::chain::validate_combined_chain_with_config
+    /// [`repair_witnesses_with_config`]: crate::repair::repair_witnesses_with_config
+    pub max_block_size_bytes: usize,
+
+    /// Whether to check that the hash of each cached block matches its header, to
+    /// detect tampering with the cache. Blocks without headers are not checked.
+    pub verify_block_hashes: bool,
+
+    /// Called with the height of each scanned block, once it has been committed to the
+    /// data database.
+    ///
+    /// The block is visible to other connections by the time this is called, so the
+    /// callback can query the wallet, but scanning does not continue until it returns.
+    pub on_block_scanned: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            checkpoint_every_n_blocks: 1000,
+            // The consensus limit on the size of a full block.
+            max_block_size_bytes: 2_000_000,
+            verify_block_hashes: false,
+            on_block_scanned: None,
+        }
+    }
+}
+
+impl fmt::Debug for ScanConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScanConfig")
+            .field("checkpoint_every_n_blocks", &self.checkpoint_every_n_blocks)
+            .field("max_block_size_bytes", &self.max_block_size_bytes)
+            .field("verify_block_hashes", &self.verify_block_hashes)
+            .field("on_block_scanned", &self.on_block_scanned.is_some())
+            .finish()
+    }
 }
 
 /// Scans new blocks added to the cache for any transactions received by the tracked
@@ -45,6 +238,19 @@ struct WitnessRow {
 /// Scanned blocks are required to be height-sequential. If a block is missing from the
 /// cache, an error will be returned with kind [`ErrorKind::InvalidHeight`].
 ///
+/// Each block is scanned in its own database transaction, which is committed as soon as
+/// the block has been processed. Concurrent readers (for example, calls to
+/// [`get_balance`]) therefore observe each newly-scanned block immediately, without
+/// waiting for the rest of the cache to be scanned. If scanning fails part-way through,
+/// all blocks before the failing one remain committed.
+///
+/// The last committed block is also recorded in a [`ScanCursor`], which is checked by
+/// [`verify_scan_cursor`] before scanning resumes. If the commitment tree stored for
+/// that block has been lost or corrupted, the data database is rewound to the previous
+/// block and the affected block is rescanned. Witnesses that have fallen behind the last
+/// scanned block are then brought up to date with [`repair_witnesses`], if the cache
+/// still contains the blocks needed to do so.
+///
 /// # Examples
 ///
 /// ```
@@ -54,29 +260,18 @@ struct WitnessRow {
 /// ```
 ///
 /// [`init_blocks_table`]: crate::init::init_blocks_table
+/// [`get_balance`]: crate::query::get_balance
+/// [`repair_witnesses`]: crate::repair::repair_witnesses
 pub fn scan_cached_blocks<P: AsRef<Path>, Q: AsRef<Path>>(
     db_cache: P,
     db_data: Q,
 ) -> Result<(), Error> {
-    let cache = Connection::open(db_cache)?;
-    let data = Connection::open(db_data)?;
-
-    // Recall where we synced up to previously.
-    // If we have never synced, use sapling activation height to select all cached CompactBlocks.
-    let mut last_height = data.query_row("SELECT MAX(height) FROM blocks", NO_PARAMS, |row| {
-        row.get(0).or(Ok(SAPLING_ACTIVATION_HEIGHT - 1))
-    })?;
-
-    // Fetch the CompactBlocks we need to scan
-    let mut stmt_blocks = cache
-        .prepare("SELECT height, data FROM compactblocks WHERE height > ? ORDER BY height ASC")?;
-    let rows = stmt_blocks.query_map(&[last_height], |row| {
-        Ok(CompactBlockRow {
-            height: row.get(0)?,
-            data: row.get(1)?,
-        })
-    })?;
+    scan_cached_blocks_with_config(db_cache, db_data, &ScanConfig::default())
+}
 
+/// Fetches the keys and birthday heights of the accounts in the data database, ordered
+/// by account.
+pub(crate) fn fetch_accounts(data: &Connection) -> Result<(WalletKeyCache, Vec<u32>), Error> {
     // Fetch the ExtendedFullViewingKeys we are tracking
     let mut stmt_fetch_accounts =
         data.prepare("SELECT extfvk FROM accounts ORDER BY account ASC")?;
@@ -90,245 +285,256 @@ pub fn scan_cached_blocks<P: AsRef<Path>, Q: AsRef<Path>>(
         .collect::<Result<Result<Option<_>, _>, _>>()??
         .ok_or(Error(ErrorKind::IncorrectHRPExtFVK))?;
 
-    // Get the most recent CommitmentTree
-    let mut stmt_fetch_tree = data.prepare("SELECT sapling_tree FROM blocks WHERE height = ?")?;
-    let mut tree = stmt_fetch_tree
-        .query_row(&[last_height], |row| {
-            row.get(0).map(|data: Vec<_>| {
-                CommitmentTree::read(&data[..]).unwrap_or_else(|_| CommitmentTree::new())
-            })
-        })
-        .unwrap_or_else(|_| CommitmentTree::new());
-
-    // Get most recent incremental witnesses for the notes we are tracking
-    let mut stmt_fetch_witnesses =
-        data.prepare("SELECT note, witness FROM sapling_witnesses WHERE block = ?")?;
-    let witnesses = stmt_fetch_witnesses.query_map(&[last_height], |row| {
-        let id_note = row.get(0)?;
-        let data: Vec<_> = row.get(1)?;
-        Ok(IncrementalWitness::read(&data[..]).map(|witness| WitnessRow { id_note, witness }))
+    // Fetch the birthday heights of the accounts. Accounts without a birthday are
+    // scanned in every block.
+    let mut stmt_fetch_birthdays =
+        data.prepare("SELECT birthday_height FROM accounts ORDER BY account ASC")?;
+    let birthdays = stmt_fetch_birthdays.query_map(NO_PARAMS, |row| {
+        row.get(0)
+            .map(|birthday: Option<u32>| birthday.unwrap_or(0))
     })?;
-    let mut witnesses: Vec<_> = witnesses.collect::<Result<Result<_, _>, _>>()??;
+    let birthdays: Vec<_> = birthdays.collect::<Result<_, _>>()?;
+
+    Ok((WalletKeyCache::new(extfvks), birthdays))
+}
 
-    // Get the nullifiers for the notes we are tracking
+/// Fetches the nullifiers of the unspent notes in the data database, mapped to the
+/// accounts that received them.
+pub(crate) fn fetch_nullifiers(data: &Connection) -> Result<HashMap<[u8; 32], usize>, Error> {
     let mut stmt_fetch_nullifiers =
-        data.prepare("SELECT id_note, nf, account FROM received_notes WHERE spent IS NULL")?;
+        data.prepare("SELECT nf, account FROM received_notes WHERE spent IS NULL")?;
     let nullifiers = stmt_fetch_nullifiers.query_map(NO_PARAMS, |row| {
-        let nf: Vec<_> = row.get(1)?;
-        let account: i64 = row.get(2)?;
+        let nf: Vec<_> = row.get(0)?;
+        let account: i64 = row.get(1)?;
         Ok((nf, account as usize))
     })?;
-    let mut nullifiers: Vec<_> = nullifiers.collect::<Result<_, _>>()?;
 
-    // Prepare per-block SQL statements
-    let mut stmt_insert_block = data.prepare(
-        "INSERT INTO blocks (height, hash, time, sapling_tree)
-        VALUES (?, ?, ?, ?)",
-    )?;
-    let mut stmt_update_tx = data.prepare(
-        "UPDATE transactions
-        SET block = ?, tx_index = ? WHERE txid = ?",
-    )?;
-    let mut stmt_insert_tx = data.prepare(
-        "INSERT INTO transactions (txid, block, tx_index)
-        VALUES (?, ?, ?)",
-    )?;
-    let mut stmt_select_tx = data.prepare("SELECT id_tx FROM transactions WHERE txid = ?")?;
-    let mut stmt_mark_spent_note =
-        data.prepare("UPDATE received_notes SET spent = ? WHERE nf = ?")?;
-    let mut stmt_insert_note = data.prepare(
-        "INSERT INTO received_notes (tx, output_index, account, diversifier, value, rcm, nf, is_change)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
-    )?;
-    let mut stmt_insert_witness = data.prepare(
-        "INSERT INTO sapling_witnesses (note, block, witness)
-        VALUES (?, ?, ?)",
-    )?;
-    let mut stmt_prune_witnesses = data.prepare("DELETE FROM sapling_witnesses WHERE block < ?")?;
-    let mut stmt_update_expired = data.prepare(
-        "UPDATE received_notes SET spent = NULL WHERE EXISTS (
-            SELECT id_tx FROM transactions
-            WHERE id_tx = received_notes.spent AND block IS NULL AND expiry_height < ?
-        )",
-    )?;
+    let mut map = HashMap::new();
+    for row in nullifiers {
+        let (nf, account) = row?;
+        if nf.len() != 32 {
+            return Err(Error(ErrorKind::CorruptedData("nullifier is not 32 bytes")));
+        }
+        let mut nf_key = [0; 32];
+        nf_key.copy_from_slice(&nf);
+        map.insert(nf_key, account);
+    }
+    Ok(map)
+}
 
-    for row in rows {
-        let row = row?;
+/// Builds a map from the nullifiers of the unspent notes in the data database to the
+/// accounts that received them.
+///
+/// The map can be passed to [`scan_block`] to detect spends of the wallet's notes, with
+/// an O(1) lookup for each spend in the block. [`scan_cached_blocks`] builds
+/// this map itself when it starts scanning, and keeps it up to date as notes are
+/// received and spent.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::scan::init_nullifier_map;
+///
+/// if let Ok(nullifiers) = init_nullifier_map("/path/to/data.db") {
+///     println!("Tracking {} unspent notes", nullifiers.len());
+/// }
+/// ```
+///
+/// [`scan_block`]: zcash_client_backend::welding_rig::scan_block
+pub fn init_nullifier_map<P: AsRef<Path>>(db_data: P) -> Result<HashMap<[u8; 32], usize>, Error> {
+    let data = open_data_database(db_data)?;
+    fetch_nullifiers(&data)
+}
 
-        // Start an SQL transaction for this block.
-        data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+/// Scans new blocks added to the cache for any transactions received by the tracked
+/// accounts, using the given [`ScanConfig`].
+///
+/// See [`scan_cached_blocks`] for details.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::scan::{scan_cached_blocks_with_config, ScanConfig};
+///
+/// let config = ScanConfig {
+///     checkpoint_every_n_blocks: 100,
+///     ..ScanConfig::default()
+/// };
+/// scan_cached_blocks_with_config("/path/to/cache.db", "/path/to/data.db", &config);
+/// ```
+pub fn scan_cached_blocks_with_config<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    config: &ScanConfig,
+) -> Result<(), Error> {
+    scan_blocks(db_cache, db_data, config, None)
+}
 
-        // Scanned blocks MUST be height-sequential.
-        if row.height != (last_height + 1) {
-            return Err(Error(ErrorKind::InvalidHeight(last_height + 1, row.height)));
-        }
-        last_height = row.height;
-
-        let block: CompactBlock = parse_from_bytes(&row.data)?;
-        let block_hash = block.hash.clone();
-        let block_time = block.time;
-
-        let txs = {
-            let nf_refs: Vec<_> = nullifiers.iter().map(|(nf, acc)| (&nf[..], *acc)).collect();
-            let mut witness_refs: Vec<_> = witnesses.iter_mut().map(|w| &mut w.witness).collect();
-            scan_block(
-                block,
-                &extfvks[..],
-                &nf_refs,
-                &mut tree,
-                &mut witness_refs[..],
-            )
-        };
+/// Scans the cached blocks from `from` to `to` (inclusive) in a single database
+/// transaction, so that either every block is scanned or none are.
+///
+/// `from` must be the height after the last block in the data database (or the Sapling
+/// activation height for a new database), and the cache must contain every block in the
+/// range. If any block fails to scan, the data database is left as it was before the
+/// call. Unlike [`scan_cached_blocks`], concurrent readers do not observe any of the
+/// blocks until the whole range has been scanned.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::scan::scan_cached_blocks_atomic;
+///
+/// scan_cached_blocks_atomic("/path/to/cache.db", "/path/to/data.db", 500_000, 500_999);
+/// ```
+pub fn scan_cached_blocks_atomic<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    from: u32,
+    to: u32,
+) -> Result<(), Error> {
+    scan_blocks(db_cache, db_data, &ScanConfig::default(), Some((from, to)))
+}
 
-        // Enforce that all roots match. This is slow, so only include in debug builds.
-        #[cfg(debug_assertions)]
-        {
-            let cur_root = tree.root();
-            for row in &witnesses {
-                if row.witness.root() != cur_root {
-                    return Err(Error(ErrorKind::InvalidWitnessAnchor(
-                        row.id_note,
-                        last_height,
-                    )));
-                }
-            }
-            for tx in &txs {
-                for output in tx.shielded_outputs.iter() {
-                    if output.witness.root() != cur_root {
-                        return Err(Error(ErrorKind::InvalidNewWitnessAnchor(
-                            output.index,
-                            tx.txid,
-                            last_height,
-                            output.witness.root(),
-                        )));
-                    }
+/// Scans new cached blocks into the data database.
+///
+/// If `range` is `None`, every new block is scanned in its own transaction. Otherwise,
+/// exactly the blocks in the given inclusive range are scanned in a single transaction.
+fn scan_blocks<P: AsRef<Path>, Q: AsRef<Path>>(
+    db_cache: P,
+    db_data: Q,
+    config: &ScanConfig,
+    range: Option<(u32, u32)>,
+) -> Result<(), Error> {
+    // Recover from a previous scan whose final state cannot be trusted.
+    verify_scan_cursor(&db_data)?;
+
+    // Bring any witnesses that fell behind up to date with the last scanned block, so
+    // that they continue to be updated. If the blocks needed to do so have been pruned
+    // from the cache, the witnesses are left as they are; this shouldn't prevent newer
+    // notes from being found.
+    if let Some((height, _)) = get_chain_tip(&db_data)? {
+        if let Err(e) = repair_witnesses_with_config(&db_cache, &db_data, height, config) {
+            match e.kind() {
+                ErrorKind::CacheMissingBlocks(..) => {
+                    log::warn!("Cannot repair stale witnesses: {}", e)
                 }
+                _ => return Err(e),
             }
         }
+    }
 
-        // Insert the block into the database.
-        let mut encoded_tree = Vec::new();
-        tree.write(&mut encoded_tree)
-            .expect("Should be able to write to a Vec");
-        stmt_insert_block.execute(&[
-            row.height.to_sql()?,
-            block_hash.to_sql()?,
-            block_time.to_sql()?,
-            encoded_tree.to_sql()?,
-        ])?;
-
-        for tx in txs {
-            // First try update an existing transaction in the database.
-            let txid = tx.txid.0.to_vec();
-            let tx_row = if stmt_update_tx.execute(&[
-                row.height.to_sql()?,
-                (tx.index as i64).to_sql()?,
-                txid.to_sql()?,
-            ])? == 0
-            {
-                // It isn't there, so insert our transaction into the database.
-                stmt_insert_tx.execute(&[
-                    txid.to_sql()?,
-                    row.height.to_sql()?,
-                    (tx.index as i64).to_sql()?,
-                ])?;
-                data.last_insert_rowid()
-            } else {
-                // It was there, so grab its row number.
-                stmt_select_tx.query_row(&[txid], |row| row.get(0))?
-            };
-
-            // Mark notes as spent and remove them from the scanning cache
-            for spend in &tx.shielded_spends {
-                stmt_mark_spent_note.execute(&[tx_row.to_sql()?, spend.nf.to_sql()?])?;
-            }
-            nullifiers = nullifiers
-                .into_iter()
-                .filter(|(nf, _acc)| {
-                    tx.shielded_spends
-                        .iter()
-                        .find(|spend| &spend.nf == nf)
-                        .is_none()
-                })
-                .collect();
-
-            for output in tx.shielded_outputs {
-                let mut rcm = [0; 32];
-                output.note.r.into_repr().write_le(&mut rcm[..])?;
-                let nf = output.note.nf(
-                    &extfvks[output.account].fvk.vk,
-                    output.witness.position() as u64,
-                    &JUBJUB,
-                );
-
-                // Insert received note into the database.
-                // Assumptions:
-                // - A transaction will not contain more than 2^63 shielded outputs.
-                // - A note value will never exceed 2^63 zatoshis.
-                stmt_insert_note.execute(&[
-                    tx_row.to_sql()?,
-                    (output.index as i64).to_sql()?,
-                    (output.account as i64).to_sql()?,
-                    output.to.diversifier.0.to_sql()?,
-                    (output.note.value as i64).to_sql()?,
-                    rcm.to_sql()?,
-                    nf.to_sql()?,
-                    output.is_change.to_sql()?,
-                ])?;
-                let note_row = data.last_insert_rowid();
-
-                // Save witness for note.
-                witnesses.push(WitnessRow {
-                    id_note: note_row,
-                    witness: output.witness,
-                });
-
-                // Cache nullifier for note (to detect subsequent spends in this scan).
-                nullifiers.push((nf, output.account));
-            }
-        }
+    let mut cache = SqliteBackend::for_cache_with_config(&db_cache, config)?;
+    let mut data = SqliteBackend::for_data_with_config(&db_data, config)?;
+    let last_height = data
+        .get_max_height()?
+        .unwrap_or(SAPLING_ACTIVATION_HEIGHT as u32 - 1);
 
-        // Insert current witnesses into the database.
-        let mut encoded = Vec::new();
-        for witness_row in witnesses.iter() {
-            encoded.clear();
-            witness_row
-                .witness
-                .write(&mut encoded)
-                .expect("Should be able to write to a Vec");
-            stmt_insert_witness.execute(&[
-                witness_row.id_note.to_sql()?,
-                last_height.to_sql()?,
-                encoded.to_sql()?,
-            ])?;
-        }
+    match range {
+        None => scan_cached_blocks_with_backend(&cache, &mut data)?,
+        Some((from, to)) => {
+            if from != last_height + 1 {
+                return Err(Error(ErrorKind::InvalidHeight(
+                    last_height as i32 + 1,
+                    from as i32,
+                )));
+            }
+            cache.set_max_height(to);
 
-        // Prune the stored witnesses (we only expect rollbacks of at most 100 blocks).
-        stmt_prune_witnesses.execute(&[last_height - 100])?;
+            // A single SQL transaction covers every block, so if any block fails, none
+            // of them are stored.
+            data.transactionally(|data| {
+                scan_cached_blocks_with_backend(&cache, data)?;
 
-        // Update now-expired transactions that didn't get mined.
-        stmt_update_expired.execute(&[last_height])?;
+                // The cache must contain the whole range.
+                let scanned_to = data.get_max_height()?.unwrap_or(last_height);
+                if scanned_to != to {
+                    return Err(Error(ErrorKind::InvalidHeight(
+                        scanned_to as i32 + 1,
+                        to as i32,
+                    )));
+                }
+                Ok(())
+            })?;
+        }
+    }
 
-        // Commit the SQL transaction, writing this block's data atomically.
-        data.execute("COMMIT", NO_PARAMS)?;
+    // Remove old blocks from the cache, if it has size limits.
+    let scanned_to = data.get_max_height()?.unwrap_or(last_height);
+    if scanned_to != last_height {
+        apply_cache_limits(&Connection::open(&db_cache)?, scanned_to)?;
     }
 
     Ok(())
 }
 
+/// The default number of confirmations that a spend must have before
+/// [`prune_spent_notes`] deletes the note it spent.
+pub const DEFAULT_SPENT_NOTE_RETENTION: u32 = 1000;
+
+/// Deletes received notes that were spent in transactions mined at or below
+/// `MAX(height) - min_confirmations`.
+///
+/// Notes that still have stored witnesses are kept; witnesses of spent notes are pruned
+/// 100 blocks after scanning, so `min_confirmations` should be larger than that (see
+/// [`DEFAULT_SPENT_NOTE_RETENTION`]). Deleted notes no longer appear in the wallet's
+/// transaction history. Returns the number of notes that were deleted.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::scan::{prune_spent_notes, DEFAULT_SPENT_NOTE_RETENTION};
+///
+/// let deleted = prune_spent_notes("/path/to/data.db", DEFAULT_SPENT_NOTE_RETENTION);
+/// ```
+pub fn prune_spent_notes<P: AsRef<Path>>(db_data: P, min_confirmations: u32) -> Result<u64, Error> {
+    let data = open_data_database(db_data)?;
+
+    let deleted = data.execute(
+        "DELETE FROM received_notes
+        WHERE spent IN (
+            SELECT id_tx FROM transactions
+            WHERE block <= (SELECT MAX(height) FROM blocks) - ?
+        )
+        AND NOT EXISTS (
+            SELECT note FROM sapling_witnesses
+            WHERE sapling_witnesses.note = received_notes.id_note
+        )",
+        &[min_confirmations],
+    )?;
+
+    Ok(deleted as u64)
+}
+
 #[cfg(test)]
 mod tests {
+    use ff::PrimeField;
+    use rand_os::OsRng;
+    use rusqlite::{types::ToSql, Connection, NO_PARAMS};
+    use std::sync::{Arc, Mutex};
     use tempfile::NamedTempFile;
+    use zcash_client_backend::{proto::compact_formats::CompactTx, testing::CompactBlockBuilder};
     use zcash_primitives::{
         block::BlockHash,
+        merkle_tree::CommitmentTree,
+        note_encryption::Memo,
+        sapling::Node,
         transaction::components::Amount,
         zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
     };
 
-    use super::scan_cached_blocks;
+    use super::{
+        get_scan_cursor, init_nullifier_map, prune_spent_notes, scan_cached_blocks,
+        scan_cached_blocks_atomic, scan_cached_blocks_with_config, verify_scan_cursor, ScanConfig,
+        ScanCursor,
+    };
     use crate::{
-        init::{init_accounts_table, init_cache_database, init_data_database},
-        query::get_balance,
+        cache::{compress_cache, insert_compact_blocks},
+        error::ErrorKind,
+        init::{
+            init_accounts_table, init_accounts_table_with_birthdays, init_cache_database,
+            init_data_database,
+        },
+        query::{get_balance, get_chain_tip},
         tests::{fake_compact_block, fake_compact_block_spending, insert_into_cache},
         SAPLING_ACTIVATION_HEIGHT,
     };
@@ -438,7 +644,49 @@ mod tests {
         scan_cached_blocks(db_cache, db_data).unwrap();
 
         // Account balance should reflect both received notes
-        assert_eq!(get_balance(db_data, 0).unwrap(), value + value2);
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value + value2).unwrap());
+    }
+
+    #[test]
+    fn scan_cached_blocks_respects_account_birthdays() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add two accounts to the wallet, the second created one block later
+        let extfvk0 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0]));
+        let extfvk1 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1]));
+        init_accounts_table_with_birthdays(
+            &db_data,
+            &[extfvk0.clone(), extfvk1.clone()],
+            &[
+                SAPLING_ACTIVATION_HEIGHT as u32,
+                SAPLING_ACTIVATION_HEIGHT as u32 + 1,
+            ],
+        )
+        .unwrap();
+
+        // Send a note to both accounts in the first block, and to the second account
+        // in the second block
+        let value = Amount::from_u64(5).unwrap();
+        let mut builder = CompactBlockBuilder::new(SAPLING_ACTIVATION_HEIGHT as u32, [0; 32]);
+        builder.add_sapling_output(&extfvk0, value, Memo::default());
+        builder.add_sapling_output(&extfvk1, value, Memo::default());
+        let cb1 = builder.build();
+        insert_into_cache(db_cache, &cb1);
+        let (cb2, _) =
+            fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb1.hash(), extfvk1, value);
+        insert_into_cache(db_cache, &cb2);
+
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // The second account only sees the note received after its birthday
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+        assert_eq!(get_balance(db_data, 1).unwrap(), value);
     }
 
     #[test]
@@ -495,6 +743,602 @@ mod tests {
         scan_cached_blocks(db_cache, db_data).unwrap();
 
         // Account balance should equal the change
-        assert_eq!(get_balance(db_data, 0).unwrap(), value - value2);
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value - value2).unwrap());
+    }
+
+    #[test]
+    fn spends_detected_within_a_single_scan() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+        assert!(init_nullifier_map(db_data).unwrap().is_empty());
+
+        // Receive a note, and spend it in the next block
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let to2 = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+        let value2 = Amount::from_u64(2).unwrap();
+        let cb2 = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            (nf.clone(), value),
+            extfvk,
+            to2,
+            value2,
+        );
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+
+        // The spend is detected using the nullifier of the note received earlier in the
+        // same scan
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value - value2).unwrap());
+
+        // Only the change note remains unspent
+        let nullifiers = init_nullifier_map(db_data).unwrap();
+        assert_eq!(nullifiers.len(), 1);
+        assert!(!nullifiers.contains_key(&nf[..]));
+        assert_eq!(nullifiers.values().collect::<Vec<_>>(), vec![&0]);
+    }
+
+    #[test]
+    fn scan_cached_blocks_carries_witnesses_through_blocks_without_outputs() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note, then scan a block without outputs, and then a block with an
+        // output that doesn't involve the wallet
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) =
+            fake_compact_block(SAPLING_ACTIVATION_HEIGHT, BlockHash([0; 32]), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        let cb2 =
+            CompactBlockBuilder::new((SAPLING_ACTIVATION_HEIGHT + 1) as u32, cb.hash().0).build();
+        insert_into_cache(db_cache, &cb2);
+        let (cb3, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 2,
+            cb2.hash(),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0])),
+            value,
+        );
+        insert_into_cache(db_cache, &cb3);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // The note's witness is stored at every height, and only changes when a block
+        // has outputs
+        let data = Connection::open(db_data).unwrap();
+        let witnesses: Vec<(i32, Vec<u8>)> = data
+            .prepare("SELECT block, witness FROM sapling_witnesses WHERE note = 1 ORDER BY block")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let heights: Vec<_> = witnesses.iter().map(|(height, _)| *height).collect();
+        assert_eq!(
+            heights,
+            vec![
+                SAPLING_ACTIVATION_HEIGHT,
+                SAPLING_ACTIVATION_HEIGHT + 1,
+                SAPLING_ACTIVATION_HEIGHT + 2
+            ]
+        );
+        assert_eq!(witnesses[0].1, witnesses[1].1);
+        assert_ne!(witnesses[1].1, witnesses[2].1);
+    }
+
+    #[test]
+    fn scan_cached_blocks_stops_updating_spent_witnesses() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note, and then spend it in the next block
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        let extsk2 = ExtendedSpendingKey::master(&[0]);
+        let to2 = extsk2.default_address().unwrap().1;
+        let cb2 = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            (nf, value),
+            extfvk,
+            to2,
+            Amount::from_u64(2).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Scan a block that doesn't involve the wallet
+        let (cb3, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 2,
+            cb2.hash(),
+            ExtendedFullViewingKey::from(&extsk2),
+            value,
+        );
+        insert_into_cache(db_cache, &cb3);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Only the change note's witness was updated
+        let data = Connection::open(db_data).unwrap();
+        let notes: Vec<i64> = data
+            .prepare("SELECT note FROM sapling_witnesses WHERE block = ?")
+            .unwrap()
+            .query_map(&[SAPLING_ACTIVATION_HEIGHT + 2], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(notes, vec![2]);
+    }
+
+    #[test]
+    fn scan_cached_blocks_checkpoints_wal() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+        let journal_mode: String = Connection::open(db_data)
+            .unwrap()
+            .query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Scan two blocks, checkpointing after each of them
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        let config = ScanConfig {
+            checkpoint_every_n_blocks: 1,
+            ..ScanConfig::default()
+        };
+        scan_cached_blocks_with_config(db_cache, db_data, &config).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value + value).unwrap());
+    }
+
+    #[test]
+    fn scan_cached_blocks_reports_committed_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path().to_owned();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+
+        // Record each reported height, along with the chain tip that other connections
+        // can see at that point
+        let seen = Arc::new(Mutex::new(vec![]));
+        let config = ScanConfig {
+            on_block_scanned: Some({
+                let seen = seen.clone();
+                let db_data = db_data.clone();
+                Arc::new(move |height: u32| {
+                    let tip = get_chain_tip(&db_data).unwrap().map(|(tip, _)| tip);
+                    seen.lock().unwrap().push((height, tip));
+                })
+            }),
+            ..ScanConfig::default()
+        };
+        scan_cached_blocks_with_config(db_cache, &db_data, &config).unwrap();
+
+        let first = SAPLING_ACTIVATION_HEIGHT as u32;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(first, Some(first)), (first + 1, Some(first + 1))]
+        );
+
+        // Nothing is reported when there are no new blocks
+        scan_cached_blocks_with_config(db_cache, &db_data, &config).unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn scan_cached_blocks_rejects_oversized_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) =
+            fake_compact_block(SAPLING_ACTIVATION_HEIGHT, BlockHash([0; 32]), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+
+        // The block is rejected if it is larger than the configured maximum
+        let config = ScanConfig {
+            max_block_size_bytes: 10,
+            ..ScanConfig::default()
+        };
+        match scan_cached_blocks_with_config(db_cache, db_data, &config) {
+            Err(e) => match e.kind() {
+                ErrorKind::BlockTooLarge(height, _) => {
+                    assert_eq!(*height, SAPLING_ACTIVATION_HEIGHT)
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+
+        // The default maximum allows it
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+    }
+
+    #[test]
+    fn scan_cached_blocks_limits_decompressed_block_size() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Pad a block with empty transactions, so that it compresses well
+        let value = Amount::from_u64(5).unwrap();
+        let (mut cb, _) =
+            fake_compact_block(SAPLING_ACTIVATION_HEIGHT, BlockHash([0; 32]), extfvk, value);
+        for _ in 0..300 {
+            let mut ctx = CompactTx::new();
+            ctx.set_hash(vec![0; 32]);
+            cb.vtx.push(ctx);
+        }
+        insert_into_cache(db_cache, &cb);
+        let stats = compress_cache(db_cache).unwrap();
+        assert_eq!(stats.blocks_compressed, 1);
+        assert!(stats.bytes_after < 5_000 && stats.bytes_before > 5_000);
+
+        // The maximum applies to the decompressed block
+        let config = ScanConfig {
+            max_block_size_bytes: 5_000,
+            ..ScanConfig::default()
+        };
+        match scan_cached_blocks_with_config(db_cache, db_data, &config) {
+            Err(e) => match e.kind() {
+                ErrorKind::BlockTooLarge(height, max_size) => {
+                    assert_eq!((*height, *max_size), (SAPLING_ACTIVATION_HEIGHT, 5_000))
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+
+        // The default maximum allows it
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+    }
+
+    #[test]
+    fn scan_cursor_recovers_corrupted_tree() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+        assert_eq!(get_scan_cursor(db_data).unwrap(), None);
+
+        // Scan two blocks, the second of which doesn't involve the wallet
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, _) =
+            fake_compact_block(SAPLING_ACTIVATION_HEIGHT, BlockHash([0; 32]), extfvk, value);
+        let extfvk2 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0]));
+        let (cb2, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 1, cb.hash(), extfvk2, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        let cursor = get_scan_cursor(db_data).unwrap().unwrap();
+        assert_eq!(
+            cursor.last_committed_height,
+            SAPLING_ACTIVATION_HEIGHT as u32 + 1
+        );
+        assert_eq!(verify_scan_cursor(db_data).unwrap(), None);
+
+        // The cursor round-trips through its encoding
+        let mut encoded = vec![];
+        cursor.write(&mut encoded).unwrap();
+        assert_eq!(encoded.len(), 36);
+        assert_eq!(ScanCursor::read(&encoded[..]).unwrap(), cursor);
+
+        // Corrupt the tree stored for the last block
+        Connection::open(db_data)
+            .unwrap()
+            .execute(
+                "UPDATE blocks SET sapling_tree = x'ff' WHERE height = ?",
+                &[SAPLING_ACTIVATION_HEIGHT + 1],
+            )
+            .unwrap();
+
+        // Scanning rewinds past the corrupted block and scans it again
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_scan_cursor(db_data).unwrap(), Some(cursor));
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+    }
+
+    #[test]
+    fn prune_spent_notes_after_witnesses() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Receive a note, spend it with change in the next block, then receive another
+        let value = Amount::from_u64(5).unwrap();
+        let (cb, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let to2 = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+        let cb2 = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            (nf, value),
+            extfvk.clone(),
+            to2,
+            Amount::from_u64(2).unwrap(),
+        );
+        let (cb3, _) = fake_compact_block(SAPLING_ACTIVATION_HEIGHT + 2, cb2.hash(), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        insert_into_cache(db_cache, &cb2);
+        insert_into_cache(db_cache, &cb3);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        let balance = get_balance(db_data, 0).unwrap();
+
+        // The spent note still has witnesses, so it is kept
+        assert_eq!(prune_spent_notes(db_data, 1).unwrap(), 0);
+
+        // Once its witnesses are pruned, it is deleted after enough confirmations
+        let data = Connection::open(db_data).unwrap();
+        data.execute("DELETE FROM sapling_witnesses WHERE note = 1", NO_PARAMS)
+            .unwrap();
+        assert_eq!(prune_spent_notes(db_data, 2).unwrap(), 0);
+        assert_eq!(prune_spent_notes(db_data, 1).unwrap(), 1);
+        let remaining: i64 = data
+            .query_row("SELECT COUNT(*) FROM received_notes", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(remaining, 2);
+        assert_eq!(get_balance(db_data, 0).unwrap(), balance);
+    }
+
+    #[test]
+    fn scan_cached_blocks_atomic_rolls_back_on_failure() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Cache two valid blocks followed by a malformed one
+        let value = Amount::from_u64(5).unwrap();
+        let (cb1, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb1.hash(),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb1);
+        insert_into_cache(db_cache, &cb2);
+        Connection::open(db_cache)
+            .unwrap()
+            .execute(
+                "INSERT INTO compactblocks (height, data) VALUES (?, ?)",
+                &[
+                    (SAPLING_ACTIVATION_HEIGHT + 2).to_sql().unwrap(),
+                    vec![0xff; 10].to_sql().unwrap(),
+                ],
+            )
+            .unwrap();
+
+        // Scanning all three blocks fails, and none of them are stored
+        let from = SAPLING_ACTIVATION_HEIGHT as u32;
+        assert!(scan_cached_blocks_atomic(db_cache, db_data, from, from + 2).is_err());
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+        let blocks: i64 = Connection::open(db_data)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM blocks", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(blocks, 0);
+
+        // The valid blocks can be scanned on their own
+        scan_cached_blocks_atomic(db_cache, db_data, from, from + 1).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value + value).unwrap());
+
+        // The range must start after the last scanned block
+        match scan_cached_blocks_atomic(db_cache, db_data, from, from + 1) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidHeight(expected, actual) => {
+                    assert_eq!(*expected, SAPLING_ACTIVATION_HEIGHT + 2);
+                    assert_eq!(*actual, SAPLING_ACTIVATION_HEIGHT);
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+
+    #[test]
+    fn scan_stores_correct_tree_root() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Create 1000 blocks, each with five random outputs, and append every output to a
+        // reference tree in chain order. Only one block in a hundred has an output of
+        // ours, as every witness is updated (and checked in debug builds) at each block.
+        let value = Amount::from_u64(5).unwrap();
+        let mut expected_tree = CommitmentTree::new();
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for i in 0..1000 {
+            let mut builder =
+                CompactBlockBuilder::new((SAPLING_ACTIVATION_HEIGHT + i) as u32, prev_hash.0);
+            for _ in 0..5 {
+                builder.add_random_tx(&mut OsRng);
+            }
+            if i % 100 == 0 {
+                builder.add_sapling_output(&extfvk, value, Memo::default());
+            }
+            let cb = builder.build();
+
+            for tx in &cb.vtx {
+                for output in &tx.outputs {
+                    let node = Node::new(output.cmu().unwrap().into_repr());
+                    expected_tree.append(node).unwrap();
+                }
+            }
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+        insert_compact_blocks(db_cache, &blocks).unwrap();
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // The tree stored for the last block matches the reference tree
+        let last_height = SAPLING_ACTIVATION_HEIGHT + 999;
+        let data = Connection::open(db_data).unwrap();
+        let encoded_tree: Vec<u8> = data
+            .query_row(
+                "SELECT sapling_tree FROM blocks WHERE height = ?",
+                &[last_height],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let tree = CommitmentTree::<Node>::read(&encoded_tree[..]).unwrap();
+        assert_eq!(tree.size(), 5010);
+        assert_eq!(tree.root(), expected_tree.root());
+        assert_eq!(
+            get_balance(db_data, 0).unwrap(),
+            Amount::from_u64(50).unwrap()
+        );
     }
 }
@@ -0,0 +1,471 @@
+//! Functions for managing recurring payments.
+
+use rusqlite::{types::ToSql, Connection, NO_PARAMS};
+use std::path::Path;
+use zcash_primitives::{
+    note_encryption::Memo,
+    prover::TxProver,
+    transaction::{components::Amount, TxId},
+    zip32::ExtendedSpendingKey,
+};
+
+use crate::{
+    address::RecipientAddress,
+    check_extsk_for_account,
+    error::{Error, ErrorKind},
+    open_data_database,
+    query::open_wallet_readonly,
+    transact::{build_transaction, store_sent_tx, Recipient, ShieldedSendRequest},
+};
+
+/// A payment that is made from an account at a regular interval.
+#[derive(Debug, PartialEq)]
+pub struct ScheduledPayment {
+    /// The row index of this payment in the `scheduled_payments` table.
+    pub id: i64,
+    pub account: u32,
+    /// The encoded address that the payment is sent to.
+    pub to_address: String,
+    pub amount: Amount,
+    pub memo: Option<Memo>,
+    /// The number of blocks between consecutive payments.
+    pub interval_blocks: u32,
+    /// The height at or after which the next payment will be made.
+    pub next_due_block: u32,
+}
+
+/// Schedules a payment of `value` from `account` to `to`, to be made every
+/// `interval_blocks` blocks starting at height `first_due_block`.
+///
+/// Returns the row index of the new payment in the `scheduled_payments` table. Returns an
+/// error with kind [`ErrorKind::InvalidInterval`] if `interval_blocks` is zero,
+/// [`ErrorKind::InvalidAmount`] if `value` is not positive, or
+/// [`ErrorKind::UnknownAccount`] if `account` does not exist.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{constants::testnet::COIN_TYPE, keys::spending_key};
+/// use zcash_client_sqlite::schedule::add_scheduled_payment;
+/// use zcash_primitives::transaction::components::Amount;
+///
+/// let extsk = spending_key(&[0; 32][..], COIN_TYPE, 0);
+/// let to = extsk.default_address().unwrap().1.into();
+/// let id = add_scheduled_payment(
+///     "/path/to/data.db",
+///     0,
+///     &to,
+///     Amount::from_u64(1).unwrap(),
+///     None,
+///     576,
+///     500_000,
+/// );
+/// ```
+pub fn add_scheduled_payment<P: AsRef<Path>>(
+    db_data: P,
+    account: u32,
+    to: &RecipientAddress,
+    value: Amount,
+    memo: Option<Memo>,
+    interval_blocks: u32,
+    first_due_block: u32,
+) -> Result<i64, Error> {
+    // A payment with no interval would be due again as soon as it was made.
+    if interval_blocks == 0 {
+        return Err(Error(ErrorKind::InvalidInterval));
+    }
+    if !value.is_positive() {
+        return Err(Error(ErrorKind::InvalidAmount));
+    }
+
+    let data = open_data_database(db_data)?;
+    if !data
+        .prepare("SELECT * FROM accounts WHERE account = ?")?
+        .exists(&[account])?
+    {
+        return Err(Error(ErrorKind::UnknownAccount(account)));
+    }
+
+    data.execute(
+        "INSERT INTO scheduled_payments
+        (account, to_address, amount_zat, memo, interval_blocks, next_due_block)
+        VALUES (?, ?, ?, ?, ?, ?)",
+        &[
+            account.to_sql()?,
+            to.to_string().to_sql()?,
+            i64::from(value).to_sql()?,
+            memo.as_ref().map(|m| m.as_bytes()).to_sql()?,
+            interval_blocks.to_sql()?,
+            first_due_block.to_sql()?,
+        ],
+    )?;
+
+    Ok(data.last_insert_rowid())
+}
+
+/// Returns all scheduled payments, ordered by the height at which they are next due.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::schedule::list_scheduled_payments;
+///
+/// let payments = list_scheduled_payments("/path/to/data.db");
+/// ```
+pub fn list_scheduled_payments<P: AsRef<Path>>(db_data: P) -> Result<Vec<ScheduledPayment>, Error> {
+    let data = open_wallet_readonly(db_data)?;
+    select_scheduled_payments(&data, "1", &[])
+}
+
+fn select_scheduled_payments(
+    data: &Connection,
+    condition: &str,
+    params: &[&dyn ToSql],
+) -> Result<Vec<ScheduledPayment>, Error> {
+    let mut stmt_payments = data.prepare(&format!(
+        "SELECT id, account, to_address, amount_zat, memo, interval_blocks, next_due_block
+        FROM scheduled_payments
+        WHERE {}
+        ORDER BY next_due_block, id",
+        condition
+    ))?;
+    let payments = stmt_payments.query_and_then(params, |row| {
+        let amount = Amount::from_i64(row.get(3)?)
+            .map_err(|_| Error(ErrorKind::CorruptedData("Invalid scheduled payment amount")))?;
+        let memo =
+            match row.get::<_, Option<Vec<u8>>>(4)? {
+                Some(memo) => Some(Memo::from_bytes(&memo).ok_or(Error(
+                    ErrorKind::CorruptedData("Invalid scheduled payment memo"),
+                ))?),
+                None => None,
+            };
+
+        Ok(ScheduledPayment {
+            id: row.get(0)?,
+            account: row.get(1)?,
+            to_address: row.get(2)?,
+            amount,
+            memo,
+            interval_blocks: row.get(5)?,
+            next_due_block: row.get(6)?,
+        })
+    })?;
+
+    payments.collect()
+}
+
+/// Creates transactions for all of the given account's scheduled payments that are due
+/// at `current_height`, and reschedules each of them for `interval_blocks` later.
+///
+/// Returns the IDs of the created transactions. As with [`create_to_address`], the caller
+/// is responsible for broadcasting them, using the raw transaction bytes stored in the
+/// `transactions` table.
+///
+/// Each transaction is stored in the same database transaction that reschedules its
+/// payment, so a payment is never recorded as sent without being rescheduled, or vice
+/// versa. Payments are made in the order they fell due. If a payment cannot be made (for
+/// example, because the account has insufficient funds), an error is returned; any
+/// payments made before it remain recorded and rescheduled.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{
+///     constants::{testnet::COIN_TYPE, SAPLING_CONSENSUS_BRANCH_ID},
+///     keys::spending_key,
+/// };
+/// use zcash_client_sqlite::schedule::process_due_payments;
+/// use zcash_proofs::prover::LocalTxProver;
+///
+/// let tx_prover = match LocalTxProver::with_default_location() {
+///     Some(tx_prover) => tx_prover,
+///     None => {
+///         panic!("Cannot locate the Zcash parameters. Please run zcash-fetch-params or fetch-params.sh to download the parameters, and then re-run the tests.");
+///     }
+/// };
+///
+/// let account = 0;
+/// let extsk = spending_key(&[0; 32][..], COIN_TYPE, account);
+/// let txids = process_due_payments(
+///     "/path/to/data.db",
+///     SAPLING_CONSENSUS_BRANCH_ID,
+///     tx_prover,
+///     (account, &extsk),
+///     500_000,
+/// );
+/// ```
+///
+/// [`create_to_address`]: crate::transact::create_to_address
+pub fn process_due_payments<P: AsRef<Path>>(
+    db_data: P,
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    (account, extsk): (u32, &ExtendedSpendingKey),
+    current_height: u32,
+) -> Result<Vec<TxId>, Error> {
+    let data = open_data_database(db_data)?;
+    check_extsk_for_account(&data, account, extsk)?;
+
+    let due = select_scheduled_payments(
+        &data,
+        "account = ? AND next_due_block <= ?",
+        &[&account, &current_height],
+    )?;
+
+    let mut txids = vec![];
+    for payment in due {
+        let to = RecipientAddress::from_str(&payment.to_address).ok_or(Error(
+            ErrorKind::CorruptedData("Invalid scheduled payment address"),
+        ))?;
+        let request = ShieldedSendRequest::new(
+            account,
+            vec![Recipient {
+                to,
+                amount: payment.amount,
+                memo: payment.memo,
+            }],
+        );
+
+        let (tx, output_indices) =
+            build_transaction(&data, consensus_branch_id, &prover, extsk, &request)?;
+
+        // Record the payment and reschedule it atomically, so that it is neither made
+        // twice nor skipped if the process is interrupted.
+        data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+        store_sent_tx(
+            &data,
+            &tx,
+            account,
+            &[(output_indices[0], &request.recipients[0])],
+        )?;
+        data.execute(
+            "UPDATE scheduled_payments SET next_due_block = next_due_block + interval_blocks
+            WHERE id = ?",
+            &[payment.id],
+        )?;
+        data.execute("COMMIT", NO_PARAMS)?;
+
+        txids.push(tx.txid());
+    }
+
+    Ok(txids)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::path::Path;
+    use tempfile::NamedTempFile;
+    use zcash_primitives::{
+        block::BlockHash,
+        note_encryption::Memo,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{add_scheduled_payment, list_scheduled_payments, process_due_payments};
+    use crate::{
+        error::ErrorKind,
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        query::get_balance,
+        scan::scan_cached_blocks,
+        tests::{fake_compact_block, insert_into_cache, test_prover},
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    /// Sets up a wallet whose account 0 has received a single note of `value`.
+    fn funded_wallet(db_cache: &Path, db_data: &Path, value: Amount) -> ExtendedSpendingKey {
+        init_cache_database(db_cache).unwrap();
+        init_data_database(db_data).unwrap();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(db_data, &[extfvk.clone()]).unwrap();
+
+        let (cb, _) =
+            fake_compact_block(SAPLING_ACTIVATION_HEIGHT, BlockHash([0; 32]), extfvk, value);
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+
+        extsk
+    }
+
+    #[test]
+    fn add_and_list_scheduled_payments() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        // No payments are scheduled initially
+        assert!(list_scheduled_payments(db_data).unwrap().is_empty());
+
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1
+            .into();
+        let value = Amount::from_u64(5).unwrap();
+        let memo = Memo::from_str("rent");
+        let id_late = add_scheduled_payment(db_data, 0, &to, value, None, 100, 2000).unwrap();
+        let id_early =
+            add_scheduled_payment(db_data, 0, &to, value, memo.clone(), 10, 1000).unwrap();
+
+        // Payments are listed in the order they fall due
+        let payments = list_scheduled_payments(db_data).unwrap();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].id, id_early);
+        assert_eq!(payments[0].memo, memo);
+        assert_eq!(payments[0].interval_blocks, 10);
+        assert_eq!(payments[0].next_due_block, 1000);
+        assert_eq!(payments[1].id, id_late);
+        assert_eq!(payments[1].to_address, to.to_string());
+        assert_eq!(payments[1].amount, value);
+        assert_eq!(payments[1].memo, None);
+    }
+
+    #[test]
+    fn add_scheduled_payment_rejects_invalid_payments() {
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvks = [ExtendedFullViewingKey::from(&extsk)];
+        init_accounts_table(&db_data, &extfvks).unwrap();
+
+        let to = extsk.default_address().unwrap().1.into();
+        let value = Amount::from_u64(5).unwrap();
+
+        // The interval must be non-zero
+        match add_scheduled_payment(db_data, 0, &to, value, None, 0, 1000) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidInterval => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // The amount must be positive
+        match add_scheduled_payment(db_data, 0, &to, Amount::zero(), None, 10, 1000) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidAmount => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // The account must exist
+        match add_scheduled_payment(db_data, 1, &to, value, None, 10, 1000) {
+            Err(e) => match e.kind() {
+                ErrorKind::UnknownAccount(1) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+        assert!(list_scheduled_payments(db_data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn process_due_payments_sends_and_reschedules() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        let extsk = funded_wallet(cache_file.path(), db_data, Amount::from_u64(50000).unwrap());
+
+        let due = SAPLING_ACTIVATION_HEIGHT as u32;
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1
+            .into();
+        let value = Amount::from_u64(15000).unwrap();
+        add_scheduled_payment(db_data, 0, &to, value, None, 10, due).unwrap();
+
+        // Nothing is sent before the payment is due
+        assert!(
+            process_due_payments(db_data, 1, test_prover(), (0, &extsk), due - 1)
+                .unwrap()
+                .is_empty()
+        );
+
+        // Once it is due, the payment is sent and rescheduled
+        let txids = process_due_payments(db_data, 1, test_prover(), (0, &extsk), due).unwrap();
+        assert_eq!(txids.len(), 1);
+        let data = Connection::open(db_data).unwrap();
+        let txid: Vec<u8> = data
+            .query_row(
+                "SELECT txid FROM transactions WHERE raw IS NOT NULL",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(txid, txids[0].0.to_vec());
+        assert_eq!(
+            list_scheduled_payments(db_data).unwrap()[0].next_due_block,
+            due + 10
+        );
+
+        // It is not sent again until the next interval
+        assert!(
+            process_due_payments(db_data, 1, test_prover(), (0, &extsk), due + 9)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn process_due_payments_is_atomic() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        let value = Amount::from_u64(50000).unwrap();
+        let extsk = funded_wallet(cache_file.path(), db_data, value);
+
+        let due = SAPLING_ACTIVATION_HEIGHT as u32;
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1
+            .into();
+        add_scheduled_payment(
+            db_data,
+            0,
+            &to,
+            Amount::from_u64(15000).unwrap(),
+            None,
+            10,
+            due,
+        )
+        .unwrap();
+
+        // Make rescheduling the payment fail
+        let data = Connection::open(db_data).unwrap();
+        data.execute_batch(
+            "CREATE TRIGGER fail_reschedule BEFORE UPDATE ON scheduled_payments
+            BEGIN SELECT RAISE(ABORT, 'rescheduling failed'); END;",
+        )
+        .unwrap();
+        assert!(process_due_payments(db_data, 1, test_prover(), (0, &extsk), due).is_err());
+
+        // The payment was not recorded as sent, so its note is still unspent
+        let sent: i64 = data
+            .query_row(
+                "SELECT COUNT(*) FROM transactions WHERE raw IS NOT NULL",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(sent, 0);
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+        assert_eq!(
+            list_scheduled_payments(db_data).unwrap()[0].next_due_block,
+            due
+        );
+    }
+}
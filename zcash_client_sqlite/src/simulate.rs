@@ -0,0 +1,296 @@
+//! Functions for checking transactions before they are broadcast.
+
+use pairing::bls12_381::Bls12;
+use std::path::Path;
+use zcash_primitives::{
+    jubjub::{edwards, fs::FsRepr, FixedGenerators, JubjubParams, Unknown},
+    redjubjub::PublicKey,
+    transaction::{
+        components::Amount, signature_hash, Transaction, SAPLING_TX_VERSION,
+        SAPLING_VERSION_GROUP_ID, SIGHASH_ALL,
+    },
+    JUBJUB,
+};
+
+use crate::{consensus_branch_id, error::Error, open_data_database};
+
+/// The outcome of [`simulate_transaction`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationResult {
+    /// Whether the transaction passed every check that would cause it to be rejected.
+    pub is_valid: bool,
+    /// The fee paid by the transaction. If it has transparent inputs, their values are
+    /// not known, and are not included.
+    pub fee: Amount,
+    /// A description of each problem found, including those that make the transaction
+    /// invalid.
+    pub warnings: Vec<String>,
+}
+
+/// Returns `true` if the binding signature of `tx` is valid for its value commitments
+/// and value balance.
+fn verify_binding_sig(tx: &Transaction, consensus_branch_id: u32) -> bool {
+    let binding_sig = match tx.binding_sig {
+        Some(binding_sig) => binding_sig,
+        None => return tx.shielded_spends.is_empty() && tx.shielded_outputs.is_empty(),
+    };
+
+    // bvk = sum(cv of spends) - sum(cv of outputs) - value_balance * G
+    let mut bvk = edwards::Point::<Bls12, Unknown>::zero();
+    for spend in &tx.shielded_spends {
+        bvk = bvk.add(&spend.cv, &JUBJUB);
+    }
+    for output in &tx.shielded_outputs {
+        bvk = bvk.add(&output.cv.negate(), &JUBJUB);
+    }
+    let abs = match i64::from(tx.value_balance).checked_abs() {
+        Some(abs) => abs as u64,
+        None => return false,
+    };
+    let value_balance = JUBJUB
+        .generator(FixedGenerators::ValueCommitmentValue)
+        .mul(FsRepr::from(abs), &JUBJUB);
+    let value_balance: edwards::Point<Bls12, Unknown> = if tx.value_balance.is_negative() {
+        value_balance.into()
+    } else {
+        value_balance.negate().into()
+    };
+    bvk = bvk.add(&value_balance, &JUBJUB);
+
+    let mut msg = [0; 64];
+    bvk.write(&mut msg[..32]).expect("bvk is 32 bytes");
+    msg[32..].copy_from_slice(&signature_hash(tx, consensus_branch_id, SIGHASH_ALL, None));
+
+    PublicKey(bvk).verify(
+        &msg,
+        &binding_sig,
+        FixedGenerators::ValueCommitmentRandomness,
+        &JUBJUB,
+    )
+}
+
+/// Checks whether a transaction spending from this wallet would be accepted in a block
+/// at `current_height`, without broadcasting it.
+///
+/// The following are checked:
+/// - it is a Sapling (v4) transaction;
+/// - it has not expired by `current_height`;
+/// - each Sapling spend is of a note in the data database that is unspent, or spent
+///   only by this transaction (as after [`create_to_address`]);
+/// - its value balance is non-negative and covers its transparent outputs, unless it
+///   has transparent inputs, which cannot be checked;
+/// - its binding signature is valid for the consensus branch that is active at
+///   `current_height`.
+///
+/// Proofs and spend authorization signatures are not verified. The network is the one
+/// this library is configured for.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::simulate::simulate_transaction;
+///
+/// let raw_tx = vec![];
+/// match simulate_transaction("/path/to/data.db", &raw_tx, 500_000) {
+///     Ok(result) if result.is_valid => (),
+///     Ok(result) => {
+///         for warning in result.warnings {
+///             println!("{}", warning);
+///         }
+///     }
+///     Err(e) => (),
+/// }
+/// ```
+///
+/// [`create_to_address`]: crate::transact::create_to_address
+pub fn simulate_transaction<P: AsRef<Path>>(
+    db_data: P,
+    raw_tx: &[u8],
+    current_height: u32,
+) -> Result<SimulationResult, Error> {
+    let data = open_data_database(db_data)?;
+
+    let tx = Transaction::read(raw_tx)?;
+    let txid = tx.txid();
+    let mut is_valid = true;
+    let mut warnings = vec![];
+
+    if !(tx.overwintered
+        && tx.version == SAPLING_TX_VERSION
+        && tx.version_group_id == SAPLING_VERSION_GROUP_ID)
+    {
+        is_valid = false;
+        warnings.push(format!(
+            "Transaction version {} (group ID {:#010x}) is not Sapling",
+            tx.version, tx.version_group_id
+        ));
+    }
+
+    if tx.expiry_height == 0 {
+        warnings.push("Transaction never expires".to_owned());
+    } else if tx.expiry_height < current_height {
+        is_valid = false;
+        warnings.push(format!(
+            "Transaction expired at height {}",
+            tx.expiry_height
+        ));
+    }
+
+    let mut stmt_find_note = data.prepare(
+        "SELECT transactions.txid FROM received_notes
+        LEFT OUTER JOIN transactions ON transactions.id_tx = received_notes.spent
+        WHERE received_notes.nf = ?",
+    )?;
+    for (i, spend) in tx.shielded_spends.iter().enumerate() {
+        match stmt_find_note.query_row(&[&spend.nullifier[..]], |row| {
+            row.get::<_, Option<Vec<u8>>>(0)
+        }) {
+            Ok(None) => (),
+            Ok(Some(ref spent_by)) if spent_by[..] == txid.0[..] => (),
+            Ok(Some(_)) => {
+                is_valid = false;
+                warnings.push(format!("Spend {} is of a note that is already spent", i));
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                is_valid = false;
+                warnings.push(format!("Spend {} is not of a note in this wallet", i));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let transparent_out = tx.vout.iter().map(|out| out.value).sum::<Option<Amount>>();
+    let fee = match transparent_out.and_then(|out| tx.value_balance - out) {
+        Some(fee) => fee,
+        None => {
+            is_valid = false;
+            warnings.push("Transparent outputs are out of range".to_owned());
+            tx.value_balance
+        }
+    };
+    if !tx.vin.is_empty() {
+        warnings.push(format!(
+            "The values of the {} transparent inputs cannot be checked",
+            tx.vin.len()
+        ));
+    } else if tx.value_balance.is_negative() {
+        is_valid = false;
+        warnings.push("Value balance is negative".to_owned());
+    } else if fee.is_negative() {
+        is_valid = false;
+        warnings.push("Transparent outputs exceed the value balance".to_owned());
+    }
+
+    if !verify_binding_sig(&tx, consensus_branch_id(current_height)) {
+        is_valid = false;
+        warnings.push("Binding signature is invalid".to_owned());
+    }
+
+    Ok(SimulationResult {
+        is_valid,
+        fee,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use tempfile::NamedTempFile;
+    use zcash_client_backend::constants::SAPLING_CONSENSUS_BRANCH_ID;
+    use zcash_primitives::{
+        block::BlockHash,
+        transaction::components::{amount::DEFAULT_FEE, Amount},
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::simulate_transaction;
+    use crate::{
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        scan::scan_cached_blocks,
+        tests::{fake_compact_block, insert_into_cache, test_prover},
+        transact::build_split_tx,
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    #[test]
+    fn simulate_created_transaction() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Add funds to the wallet, and create a transaction spending them
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            Amount::from_u64(50000).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        let id_tx = build_split_tx(
+            db_data,
+            SAPLING_CONSENSUS_BRANCH_ID,
+            test_prover(),
+            (0, &extsk),
+            1,
+            &[Amount::from_u64(20000).unwrap()],
+        )
+        .unwrap();
+        let (raw, expiry_height): (Vec<u8>, u32) = Connection::open(db_data)
+            .unwrap()
+            .query_row(
+                "SELECT raw, expiry_height FROM transactions WHERE id_tx = ?",
+                &[id_tx],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+
+        // The transaction spends a note that only it has spent
+        let result = simulate_transaction(db_data, &raw, expiry_height).unwrap();
+        assert!(result.is_valid, "{:?}", result.warnings);
+        assert_eq!(result.fee, DEFAULT_FEE);
+        assert!(result.warnings.is_empty());
+
+        // It cannot be mined after it expires
+        let result = simulate_transaction(db_data, &raw, expiry_height + 1).unwrap();
+        assert!(!result.is_valid);
+        assert_eq!(
+            result.warnings,
+            vec![format!("Transaction expired at height {}", expiry_height)]
+        );
+
+        // The binding signature commits to the consensus branch ID, which is derived
+        // from the height
+        let pre_sapling = SAPLING_ACTIVATION_HEIGHT as u32 - 1;
+        let result = simulate_transaction(db_data, &raw, pre_sapling).unwrap();
+        assert!(!result.is_valid);
+        assert_eq!(result.warnings, vec!["Binding signature is invalid"]);
+
+        // The binding signature is the last field of the transaction
+        let mut tampered = raw.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let result = simulate_transaction(db_data, &tampered, expiry_height).unwrap();
+        assert!(!result.is_valid);
+
+        // Another wallet does not hold the spent note
+        let other_file = NamedTempFile::new().unwrap();
+        let db_other = other_file.path();
+        init_data_database(&db_other).unwrap();
+        let result = simulate_transaction(db_other, &raw, expiry_height).unwrap();
+        assert!(!result.is_valid);
+        assert_eq!(
+            result.warnings,
+            vec!["Spend 0 is not of a note in this wallet"]
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! An implementation of [`StorageBackend`] for the SQLite cache and data databases.
+//!
+//! [`SqliteBackend`] lets the storage-agnostic scanner in `zcash_client_backend` be used
+//! with the databases created by this crate. [`scan_cached_blocks`] is built on it, and
+//! remains the recommended way to scan into a SQLite wallet, as it also verifies the
+//! scan cursor before scanning, repairs stale witnesses, and enforces cache limits.
+//!
+//! [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+
+use ff::{PrimeField, PrimeFieldRepr};
+use protobuf::parse_from_bytes;
+use rusqlite::{types::ToSql, Connection, NO_PARAMS};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+use std::panic;
+use std::path::Path;
+use zcash_client_backend::{
+    proto::compact_formats::CompactBlock,
+    storage::StorageBackend,
+    validation::{validate_block_hash, validate_compact_block},
+    wallet::WalletShieldedOutput,
+};
+use zcash_primitives::{
+    block::BlockHash,
+    merkle_tree::{CommitmentTree, IncrementalWitness},
+    sapling::Node,
+    transaction::{components::Amount, TxId},
+    zip32::ExtendedFullViewingKey,
+};
+
+use crate::{
+    cache::{compressed_column, decompress_block},
+    error::{Error, ErrorKind},
+    open_data_database, read_witness,
+    scan::{fetch_accounts, fetch_nullifiers, ScanConfig, ScanCursor},
+    write_witness, SAPLING_ACTIVATION_HEIGHT,
+};
+
+/// A cache or data database, accessed through the [`StorageBackend`] trait.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::storage::scan_cached_blocks_with_backend;
+/// use zcash_client_sqlite::storage::SqliteBackend;
+///
+/// if let (Ok(cache), Ok(mut data)) = (
+///     SqliteBackend::for_cache("/path/to/cache.db"),
+///     SqliteBackend::for_data("/path/to/data.db"),
+/// ) {
+///     scan_cached_blocks_with_backend(&cache, &mut data);
+/// }
+/// ```
+pub struct SqliteBackend {
+    conn: Connection,
+    config: ScanConfig,
+    /// The highest cached block to return, if any.
+    max_height: Option<u32>,
+    /// Whether a call to [`StorageBackend::transactionally`] is in progress.
+    in_transaction: bool,
+    /// The heights of the blocks stored in the current SQL transaction.
+    uncommitted_heights: Vec<u32>,
+    /// The number of blocks stored in committed SQL transactions.
+    blocks_committed: u32,
+}
+
+impl SqliteBackend {
+    fn new(conn: Connection, config: &ScanConfig) -> Self {
+        SqliteBackend {
+            conn,
+            config: config.clone(),
+            max_height: None,
+            in_transaction: false,
+            uncommitted_heights: vec![],
+            blocks_committed: 0,
+        }
+    }
+
+    /// Opens a cache database created with [`init_cache_database`].
+    ///
+    /// [`init_cache_database`]: crate::init::init_cache_database
+    pub fn for_cache<P: AsRef<Path>>(db_cache: P) -> Result<Self, Error> {
+        SqliteBackend::for_cache_with_config(db_cache, &ScanConfig::default())
+    }
+
+    /// Opens a cache database created with [`init_cache_database`], reading its blocks
+    /// with the limits and checks in `config`.
+    ///
+    /// [`init_cache_database`]: crate::init::init_cache_database
+    pub fn for_cache_with_config<P: AsRef<Path>>(
+        db_cache: P,
+        config: &ScanConfig,
+    ) -> Result<Self, Error> {
+        Ok(SqliteBackend::new(Connection::open(db_cache)?, config))
+    }
+
+    /// Opens a data database created with [`init_data_database`], checking that it is
+    /// for the network this library is configured for.
+    ///
+    /// [`init_data_database`]: crate::init::init_data_database
+    pub fn for_data<P: AsRef<Path>>(db_data: P) -> Result<Self, Error> {
+        SqliteBackend::for_data_with_config(db_data, &ScanConfig::default())
+    }
+
+    /// Opens a data database created with [`init_data_database`], checkpointing it and
+    /// reporting each scanned block as set out in `config`.
+    ///
+    /// [`init_data_database`]: crate::init::init_data_database
+    pub fn for_data_with_config<P: AsRef<Path>>(
+        db_data: P,
+        config: &ScanConfig,
+    ) -> Result<Self, Error> {
+        Ok(SqliteBackend::new(open_data_database(db_data)?, config))
+    }
+
+    /// Limits the cached blocks that are scanned to those at or below `height`.
+    pub(crate) fn set_max_height(&mut self, height: u32) {
+        self.max_height = Some(height);
+    }
+
+    /// Reports the blocks stored in the SQL transaction that was just committed, and
+    /// periodically moves the WAL contents into the database.
+    fn report_committed_blocks(&mut self) -> Result<(), Error> {
+        for height in mem::replace(&mut self.uncommitted_heights, vec![]) {
+            self.blocks_committed += 1;
+            if let Some(on_block_scanned) = &self.config.on_block_scanned {
+                on_block_scanned(height);
+            }
+            if self.config.checkpoint_every_n_blocks > 0
+                && self.blocks_committed % self.config.checkpoint_every_n_blocks == 0
+            {
+                self.conn
+                    .query_row("PRAGMA wal_checkpoint(PASSIVE)", NO_PARAMS, |_| Ok(()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    type Error = Error;
+    type TxRef = i64;
+    type NoteRef = i64;
+
+    fn with_cached_blocks<F>(
+        &self,
+        last_scanned: Option<u32>,
+        mut with_block: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(CompactBlock) -> Result<(), Error>,
+    {
+        let mut stmt_blocks = self.conn.prepare(&format!(
+            "SELECT height, data, {} FROM compactblocks
+            WHERE height > ? AND height <= ?
+            ORDER BY height ASC",
+            compressed_column(&self.conn)?
+        ))?;
+        let rows = stmt_blocks.query_map(
+            &[
+                last_scanned.map_or(-1, i64::from),
+                self.max_height.map_or(i64::max_value(), i64::from),
+            ],
+            |row| Ok((row.get(0)?, row.get::<_, Vec<u8>>(1)?, row.get(2)?)),
+        )?;
+
+        for row in rows {
+            let (height, data, compressed) = row?;
+            let data =
+                decompress_block(height, data, compressed, self.config.max_block_size_bytes)?;
+
+            // The protobuf parser can panic on some malformed inputs.
+            let block: CompactBlock = panic::catch_unwind(|| parse_from_bytes(&data))
+                .map_err(|_| Error(ErrorKind::MalformedBlock(height)))??;
+            if self.config.verify_block_hashes {
+                validate_compact_block(&block)
+                    .and_then(|()| validate_block_hash(&block))
+                    .map_err(|e| Error(ErrorKind::InvalidCompactBlock(height, e)))?;
+            }
+            with_block(block)?;
+        }
+        Ok(())
+    }
+
+    fn get_max_height(&self) -> Result<Option<u32>, Error> {
+        // A new database is scanned from the Sapling activation height.
+        let height: Option<u32> =
+            self.conn
+                .query_row("SELECT MAX(height) FROM blocks", NO_PARAMS, |row| {
+                    row.get(0)
+                })?;
+        Ok(Some(height.unwrap_or(SAPLING_ACTIVATION_HEIGHT as u32 - 1)))
+    }
+
+    fn get_accounts(&self) -> Result<Vec<(ExtendedFullViewingKey, u32)>, Error> {
+        let (keys, birthdays) = fetch_accounts(&self.conn)?;
+        Ok(keys.extfvks().iter().cloned().zip(birthdays).collect())
+    }
+
+    fn get_commitment_tree(&self, height: u32) -> Result<Option<CommitmentTree<Node>>, Error> {
+        match self.conn.query_row(
+            "SELECT sapling_tree FROM blocks WHERE height = ?",
+            &[height],
+            |row| row.get::<_, Vec<u8>>(0),
+        ) {
+            Ok(tree) => Ok(Some(CommitmentTree::read(&tree[..])?)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_witnesses(&self, height: u32) -> Result<Vec<(i64, IncrementalWitness<Node>)>, Error> {
+        let mut stmt_fetch_witnesses = self.conn.prepare(
+            "SELECT sapling_witnesses.note, sapling_witnesses.witness
+            FROM sapling_witnesses
+            INNER JOIN received_notes ON received_notes.id_note = sapling_witnesses.note
+            LEFT OUTER JOIN transactions ON transactions.id_tx = received_notes.spent
+            WHERE sapling_witnesses.block = ?
+            AND (received_notes.spent IS NULL OR transactions.block IS NULL)",
+        )?;
+        let witnesses = stmt_fetch_witnesses.query_map(&[height], |row| {
+            let id_note = row.get(0)?;
+            let data: Vec<_> = row.get(1)?;
+            Ok(read_witness(&data, id_note, height as i32).map(|witness| (id_note, witness)))
+        })?;
+        witnesses.collect::<Result<Result<_, _>, _>>()?
+    }
+
+    fn get_nullifiers(&self) -> Result<HashMap<[u8; 32], usize>, Error> {
+        fetch_nullifiers(&self.conn)
+    }
+
+    fn transactionally<F, A>(&mut self, f: F) -> Result<A, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<A, Error>,
+    {
+        // Nested calls are part of the outermost SQL transaction, which is rolled back
+        // if any of them fail.
+        if self.in_transaction {
+            return f(self);
+        }
+
+        self.conn.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+        self.in_transaction = true;
+        let result = f(self);
+        self.in_transaction = false;
+        match result {
+            Ok(result) => {
+                self.conn.execute("COMMIT", NO_PARAMS)?;
+                self.report_committed_blocks()?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.uncommitted_heights.clear();
+                self.conn.execute("ROLLBACK", NO_PARAMS)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn store_block(
+        &mut self,
+        height: u32,
+        hash: BlockHash,
+        time: u32,
+        tree: &CommitmentTree<Node>,
+    ) -> Result<(), Error> {
+        let mut encoded_tree = Vec::new();
+        tree.write(&mut encoded_tree)
+            .expect("Should be able to write to a Vec");
+        self.conn.execute(
+            "INSERT INTO blocks (height, hash, time, sapling_tree)
+            VALUES (?, ?, ?, ?)",
+            &[
+                height.to_sql()?,
+                hash.0.to_sql()?,
+                time.to_sql()?,
+                encoded_tree.to_sql()?,
+            ],
+        )?;
+
+        // Record this block in the scan cursor.
+        let cursor = ScanCursor::new(height, tree);
+        self.conn.execute(
+            "INSERT OR REPLACE INTO scan_cursor (id, last_committed_height, tree_state_hash)
+            VALUES (0, ?, ?)",
+            &[
+                cursor.last_committed_height.to_sql()?,
+                cursor.tree_state_hash.to_sql()?,
+            ],
+        )?;
+
+        self.uncommitted_heights.push(height);
+        Ok(())
+    }
+
+    fn store_tx(&mut self, height: u32, txid: TxId, index: usize) -> Result<i64, Error> {
+        let txid = txid.0.to_vec();
+        if self.conn.execute(
+            "UPDATE transactions SET block = ?, tx_index = ? WHERE txid = ?",
+            &[height.to_sql()?, (index as i64).to_sql()?, txid.to_sql()?],
+        )? == 0
+        {
+            self.conn.execute(
+                "INSERT INTO transactions (txid, block, tx_index) VALUES (?, ?, ?)",
+                &[txid.to_sql()?, height.to_sql()?, (index as i64).to_sql()?],
+            )?;
+            Ok(self.conn.last_insert_rowid())
+        } else {
+            Ok(self.conn.query_row(
+                "SELECT id_tx FROM transactions WHERE txid = ?",
+                &[txid],
+                |row| row.get(0),
+            )?)
+        }
+    }
+
+    fn mark_spent(&mut self, tx_ref: i64, nf: &[u8]) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE received_notes SET spent = ? WHERE nf = ?",
+            &[tx_ref.to_sql()?, nf.to_sql()?],
+        )?;
+        Ok(())
+    }
+
+    fn store_note(
+        &mut self,
+        tx_ref: i64,
+        output: &WalletShieldedOutput,
+        nf: &[u8],
+    ) -> Result<i64, Error> {
+        let mut rcm = [0; 32];
+        output.note.r.into_repr().write_le(&mut rcm[..])?;
+
+        // Positions are stored as i64. The Sapling tree holds at most 2^32 notes, so this
+        // only fails if the tree is corrupted.
+        let position = i64::try_from(output.witness.position())
+            .map_err(|_| Error(ErrorKind::PositionOverflow))?;
+
+        // Note values are stored as i64. A valid note is worth at most MAX_MONEY, so
+        // reject any larger value rather than storing a wrapped one.
+        let value = Amount::from_u64(u64::from(output.value()))
+            .map_err(|_| Error(ErrorKind::InvalidAmount))?;
+
+        // Assumptions:
+        // - A transaction will not contain more than u32::MAX shielded outputs
+        //   (asserted by scan_block).
+        self.conn.execute(
+            "INSERT INTO received_notes (
+                tx, output_index, account, diversifier, value, rcm, nf, is_change,
+                commitment_tree_position
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            &[
+                tx_ref.to_sql()?,
+                (output.index as i64).to_sql()?,
+                (output.account as i64).to_sql()?,
+                output.to.diversifier.0.to_sql()?,
+                i64::from(value).to_sql()?,
+                rcm.to_sql()?,
+                nf.to_sql()?,
+                output.is_change.to_sql()?,
+                position.to_sql()?,
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn store_witness(
+        &mut self,
+        note_ref: i64,
+        height: u32,
+        witness: &IncrementalWitness<Node>,
+    ) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO sapling_witnesses (note, block, witness) VALUES (?, ?, ?)",
+            &[
+                note_ref.to_sql()?,
+                height.to_sql()?,
+                write_witness(witness).to_sql()?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn copy_witnesses(&mut self, from_height: u32, to_height: u32) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO sapling_witnesses (note, block, witness)
+            SELECT sapling_witnesses.note, ?, sapling_witnesses.witness
+            FROM sapling_witnesses
+            INNER JOIN received_notes ON received_notes.id_note = sapling_witnesses.note
+            LEFT OUTER JOIN transactions ON transactions.id_tx = received_notes.spent
+            WHERE sapling_witnesses.block = ?
+            AND (received_notes.spent IS NULL OR transactions.block IS NULL)",
+            &[to_height, from_height],
+        )?;
+        Ok(())
+    }
+
+    fn prune_witnesses(&mut self, height: u32) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM sapling_witnesses WHERE block < ?", &[height])?;
+        Ok(())
+    }
+
+    fn update_expired_notes(&mut self, height: u32) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE received_notes SET spent = NULL WHERE EXISTS (
+                SELECT id_tx FROM transactions
+                WHERE id_tx = received_notes.spent AND block IS NULL AND expiry_height < ?
+            )",
+            &[height],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::path::Path;
+    use tempfile::NamedTempFile;
+    use zcash_client_backend::storage::scan_cached_blocks_with_backend;
+    use zcash_primitives::{
+        block::BlockHash,
+        transaction::components::Amount,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::SqliteBackend;
+    use crate::{
+        error::ErrorKind,
+        init::{init_accounts_table, init_cache_database, init_data_database},
+        query::get_balance,
+        scan::{get_scan_cursor, scan_cached_blocks},
+        tests::{fake_compact_block, fake_compact_block_spending, insert_into_cache},
+        SAPLING_ACTIVATION_HEIGHT,
+    };
+
+    #[test]
+    fn backend_scan_matches_scan_cached_blocks() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        // Two wallets with the same account, one scanned by each scanner
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let data_files = [NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap()];
+        for data_file in &data_files {
+            init_data_database(data_file.path()).unwrap();
+            init_accounts_table(data_file.path(), &[extfvk.clone()]).unwrap();
+        }
+        let (db_sqlite, db_backend) = (data_files[0].path(), data_files[1].path());
+
+        // Receive two notes, and spend the first
+        let value = Amount::from_u64(50000).unwrap();
+        let (cb1, nf) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        let (cb2, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb1.hash(),
+            extfvk.clone(),
+            value,
+        );
+        let to = ExtendedSpendingKey::master(&[1])
+            .default_address()
+            .unwrap()
+            .1;
+        let cb3 = fake_compact_block_spending(
+            SAPLING_ACTIVATION_HEIGHT + 2,
+            cb2.hash(),
+            (nf, value),
+            extfvk,
+            to,
+            Amount::from_u64(20000).unwrap(),
+        );
+        insert_into_cache(db_cache, &cb1);
+        insert_into_cache(db_cache, &cb2);
+
+        // Scan in two steps, so the second resumes from the stored tree and witnesses
+        let cache = SqliteBackend::for_cache(db_cache).unwrap();
+        let mut data = SqliteBackend::for_data(db_backend).unwrap();
+        scan_cached_blocks(db_cache, db_sqlite).unwrap();
+        scan_cached_blocks_with_backend(&cache, &mut data).unwrap();
+        insert_into_cache(db_cache, &cb3);
+        scan_cached_blocks(db_cache, db_sqlite).unwrap();
+        scan_cached_blocks_with_backend(&cache, &mut data).unwrap();
+
+        let notes = |db_data: &Path| {
+            let data = Connection::open(db_data).unwrap();
+            let mut stmt_notes = data
+                .prepare(
+                    "SELECT value, is_change, spent IS NOT NULL FROM received_notes
+                    ORDER BY id_note",
+                )
+                .unwrap();
+            let rows = stmt_notes
+                .query_map(NO_PARAMS, |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?))
+                })
+                .unwrap();
+            rows.collect::<Result<Vec<(i64, bool, bool)>, _>>().unwrap()
+        };
+        assert_eq!(notes(db_backend), notes(db_sqlite));
+        assert_eq!(
+            get_scan_cursor(db_backend).unwrap(),
+            get_scan_cursor(db_sqlite).unwrap()
+        );
+        assert_eq!(
+            get_balance(db_backend, 0).unwrap(),
+            Amount::from_u64(80000).unwrap()
+        );
+
+        // A gap in the cache is rejected
+        let (cb5, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 4,
+            BlockHash([0; 32]),
+            ExtendedFullViewingKey::from(&extsk),
+            value,
+        );
+        insert_into_cache(db_cache, &cb5);
+        match scan_cached_blocks_with_backend(&cache, &mut data) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidHeight(expected, actual) => {
+                    assert_eq!(*expected, SAPLING_ACTIVATION_HEIGHT + 3);
+                    assert_eq!(*actual, SAPLING_ACTIVATION_HEIGHT + 4);
+                }
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+    }
+}
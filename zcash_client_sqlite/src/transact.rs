@@ -4,7 +4,6 @@ use ff::{PrimeField, PrimeFieldRepr};
 use pairing::bls12_381::Bls12;
 use rusqlite::{types::ToSql, Connection, NO_PARAMS};
 use std::path::Path;
-use zcash_client_backend::encoding::encode_extended_full_viewing_key;
 use zcash_primitives::{
     jubjub::fs::{Fs, FsRepr},
     merkle_tree::IncrementalWitness,
@@ -13,8 +12,9 @@ use zcash_primitives::{
     prover::TxProver,
     sapling::Node,
     transaction::{
-        builder::Builder,
-        components::{amount::DEFAULT_FEE, Amount},
+        builder::{Builder, DEFAULT_TX_EXPIRY_DELTA},
+        components::{amount::DEFAULT_FEE, Amount, Zatoshi},
+        Transaction, TxId,
     },
     zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
     JUBJUB,
@@ -22,16 +22,166 @@ use zcash_primitives::{
 
 use crate::{
     address::RecipientAddress,
+    auth::{AlwaysAllow, SpendAuthorizer},
+    check_extsk_for_account,
     error::{Error, ErrorKind},
-    get_target_and_anchor_heights, HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+    get_target_and_anchor_heights, open_data_database, read_witness, ANCHOR_OFFSET,
 };
 
+#[cfg(feature = "rpc")]
+use zcash_client_backend::rpc::ZcashdClient;
+#[cfg(feature = "rpc")]
+use zcash_primitives::note_encryption::try_sapling_output_recovery;
+
+#[cfg(feature = "transparent-inputs")]
+use zcash_client_backend::wallet::Utxo;
+#[cfg(feature = "transparent-inputs")]
+use zcash_primitives::{
+    keys::OutgoingViewingKey, primitives::PaymentAddress, transaction::builder::TransparentSigner,
+};
+
+#[cfg(feature = "rpc")]
+use crate::query::get_extfvk_for_account;
+
+/// A single payment, made on its own by [`create_to_address_with_authorizer`] or as
+/// part of a [`ShieldedSendRequest`].
+#[derive(Clone)]
+pub struct Recipient {
+    pub to: RecipientAddress,
+    pub amount: Amount,
+    pub memo: Option<Memo>,
+}
+
+/// The fee that a transaction pays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeePolicy {
+    /// The standard fee of [`DEFAULT_FEE`] zatoshis.
+    Default,
+    /// A fixed fee of the given amount.
+    Fixed(Amount),
+}
+
+impl FeePolicy {
+    /// Returns the fee that this policy requires.
+    pub fn fee(&self) -> Amount {
+        match self {
+            FeePolicy::Default => DEFAULT_FEE,
+            FeePolicy::Fixed(fee) => *fee,
+        }
+    }
+}
+
+/// The parameters of a transaction that pays one or more recipients from an account,
+/// which is created with [`create_transaction`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::transact::{FeePolicy, Recipient, ShieldedSendRequest};
+/// use zcash_primitives::{
+///     note_encryption::Memo, transaction::components::Amount, zip32::ExtendedSpendingKey,
+/// };
+///
+/// let to = ExtendedSpendingKey::master(&[]).default_address().unwrap().1;
+/// let mut request = ShieldedSendRequest::new(
+///     0,
+///     vec![Recipient {
+///         to: to.into(),
+///         amount: Amount::from_u64(1).unwrap(),
+///         memo: Memo::from_str("Thanks"),
+///     }],
+/// );
+/// request.fee_policy = FeePolicy::Fixed(Amount::from_u64(20_000).unwrap());
+/// ```
+pub struct ShieldedSendRequest {
+    /// The account to spend notes from.
+    pub from_account: u32,
+    pub recipients: Vec<Recipient>,
+    /// The number of confirmations that a note must have before it can be spent.
+    pub min_confirmations: u32,
+    pub fee_policy: FeePolicy,
+    /// The number of blocks after the target height at which the transaction expires.
+    pub expiry_offset: u32,
+}
+
+impl ShieldedSendRequest {
+    /// Creates a request to pay `recipients` from `from_account`, with the default
+    /// confirmation depth, fee, and expiry.
+    pub fn new(from_account: u32, recipients: Vec<Recipient>) -> Self {
+        ShieldedSendRequest {
+            from_account,
+            recipients,
+            min_confirmations: ANCHOR_OFFSET,
+            fee_policy: FeePolicy::Default,
+            expiry_offset: DEFAULT_TX_EXPIRY_DELTA,
+        }
+    }
+
+    /// Returns the total value that must be spent to fulfil this request, including the
+    /// fee, or `None` if it overflows.
+    pub fn total_value(&self) -> Option<Amount> {
+        self.recipients
+            .iter()
+            .try_fold(self.fee_policy.fee(), |acc, recipient| {
+                acc + recipient.amount
+            })
+    }
+}
+
 struct SelectedNoteRow {
     diversifier: Diversifier,
     note: Note<Bls12>,
     witness: IncrementalWitness<Node>,
 }
 
+/// Parses a row containing the diversifier, value, rcm, witness, note row index, and
+/// witness height of a note belonging to the given [`ExtendedFullViewingKey`].
+fn read_selected_note(
+    row: &rusqlite::Row,
+    extfvk: &ExtendedFullViewingKey,
+) -> Result<SelectedNoteRow, Error> {
+    let diversifier = {
+        let d: Vec<_> = row.get(0)?;
+        if d.len() != 11 {
+            return Err(Error(ErrorKind::CorruptedData(
+                "Invalid diversifier length",
+            )));
+        }
+        let mut tmp = [0; 11];
+        tmp.copy_from_slice(&d);
+        Diversifier(tmp)
+    };
+
+    let note_value = Zatoshi::from(row.get::<_, i64>(1)? as u64);
+
+    let rcm = {
+        let d: Vec<_> = row.get(2)?;
+        let mut tmp = FsRepr::default();
+        tmp.read_le(&d[..])?;
+        Fs::from_repr(tmp).map_err(|_| Error(ErrorKind::InvalidNote))?
+    };
+
+    let from = extfvk
+        .fvk
+        .vk
+        .into_payment_address(diversifier, &JUBJUB)
+        .unwrap();
+    let note = from
+        .create_note(u64::from(note_value), rcm, &JUBJUB)
+        .unwrap();
+
+    let witness = {
+        let d: Vec<_> = row.get(3)?;
+        read_witness(&d, row.get(4)?, row.get(5)?)?
+    };
+
+    Ok(SelectedNoteRow {
+        diversifier,
+        note,
+        witness,
+    })
+}
+
 /// Creates a transaction paying the specified address from the given account.
 ///
 /// Returns the row index of the newly-created transaction in the `transactions` table
@@ -84,26 +234,158 @@ pub fn create_to_address<P: AsRef<Path>>(
     value: Amount,
     memo: Option<Memo>,
 ) -> Result<i64, Error> {
-    let data = Connection::open(db_data)?;
+    create_to_address_with_authorizer(
+        db_data,
+        consensus_branch_id,
+        prover,
+        (account, extsk),
+        &Recipient {
+            to: to.clone(),
+            amount: value,
+            memo,
+        },
+        &AlwaysAllow,
+    )
+}
+
+/// Creates a transaction paying the specified address from the given account, if the
+/// given [`SpendAuthorizer`] approves the spend.
+///
+/// This behaves identically to [`create_to_address`] when paying `payment.amount` to
+/// `payment.to`, except that `authorizer` is consulted before any notes are selected
+/// or proofs are created. If it rejects the spend, its error is returned and the data
+/// database is left unmodified.
+pub fn create_to_address_with_authorizer<P: AsRef<Path>>(
+    db_data: P,
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    (account, extsk): (u32, &ExtendedSpendingKey),
+    payment: &Recipient,
+    authorizer: &impl SpendAuthorizer,
+) -> Result<i64, Error> {
+    let data = open_data_database(db_data)?;
 
     // Check that the ExtendedSpendingKey we have been given corresponds to the
     // ExtendedFullViewingKey for the account we are spending from.
+    check_extsk_for_account(&data, account, extsk)?;
+
+    // Check that this spend has been approved.
+    authorizer.authorize(account, &payment.to.to_string(), payment.amount)?;
+
+    let request = ShieldedSendRequest::new(account, vec![payment.clone()]);
+    create_transaction_with_connection(&data, consensus_branch_id, prover, extsk, &request)
+}
+
+/// Creates a transaction that fulfils the given [`ShieldedSendRequest`].
+///
+/// Notes are selected from `request.from_account` that have at least
+/// `request.min_confirmations` confirmations, and the transaction pays the fee given by
+/// `request.fee_policy` and expires `request.expiry_offset` blocks after the next block.
+/// A sent note is recorded for each recipient.
+///
+/// Returns the row index of the newly-created transaction in the `transactions` table
+/// within the data database, as with [`create_to_address`].
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{
+///     constants::{testnet::COIN_TYPE, SAPLING_CONSENSUS_BRANCH_ID},
+///     keys::spending_key,
+/// };
+/// use zcash_client_sqlite::transact::{create_transaction, Recipient, ShieldedSendRequest};
+/// use zcash_primitives::transaction::components::Amount;
+/// use zcash_proofs::prover::LocalTxProver;
+///
+/// let tx_prover = match LocalTxProver::with_default_location() {
+///     Some(tx_prover) => tx_prover,
+///     None => {
+///         panic!("Cannot locate the Zcash parameters. Please run zcash-fetch-params or fetch-params.sh to download the parameters, and then re-run the tests.");
+///     }
+/// };
+///
+/// let extsk = spending_key(&[0; 32][..], COIN_TYPE, 0);
+/// let mut request = ShieldedSendRequest::new(
+///     0,
+///     vec![Recipient {
+///         to: extsk.default_address().unwrap().1.into(),
+///         amount: Amount::from_u64(1).unwrap(),
+///         memo: None,
+///     }],
+/// );
+/// request.min_confirmations = 1;
+/// match create_transaction(
+///     "/path/to/data.db",
+///     SAPLING_CONSENSUS_BRANCH_ID,
+///     tx_prover,
+///     &extsk,
+///     &request,
+/// ) {
+///     Ok(tx_row) => (),
+///     Err(e) => (),
+/// }
+/// ```
+pub fn create_transaction<P: AsRef<Path>>(
+    db_data: P,
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    extsk: &ExtendedSpendingKey,
+    request: &ShieldedSendRequest,
+) -> Result<i64, Error> {
+    let data = open_data_database(db_data)?;
+
+    // Check that the ExtendedSpendingKey we have been given corresponds to the
+    // ExtendedFullViewingKey for the account we are spending from.
+    check_extsk_for_account(&data, request.from_account, extsk)?;
+
+    create_transaction_with_connection(&data, consensus_branch_id, prover, extsk, request)
+}
+
+fn create_transaction_with_connection(
+    data: &Connection,
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    extsk: &ExtendedSpendingKey,
+    request: &ShieldedSendRequest,
+) -> Result<i64, Error> {
+    let (tx, output_indices) =
+        build_transaction(data, consensus_branch_id, prover, extsk, request)?;
+    let outputs: Vec<_> = output_indices
+        .into_iter()
+        .zip(request.recipients.iter())
+        .collect();
+
+    // Update the database atomically, to ensure the result is internally consistent.
+    data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+    let id_tx = store_sent_tx(data, &tx, request.from_account, &outputs)?;
+    data.execute("COMMIT", NO_PARAMS)?;
+
+    // Return the row number of the transaction, so the caller can fetch it for sending.
+    Ok(id_tx)
+}
+
+/// Selects notes from `request.from_account` and builds a transaction fulfilling
+/// `request`.
+///
+/// Returns the transaction and the index of the output paying each recipient: within
+/// the Sapling outputs of the transaction for shielded recipients, or within its
+/// transparent outputs for transparent recipients. The data database is not modified;
+/// the transaction must be saved with [`store_sent_tx`] before any further transactions
+/// are built, or its notes will be selected again.
+pub(crate) fn build_transaction(
+    data: &Connection,
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    extsk: &ExtendedSpendingKey,
+    request: &ShieldedSendRequest,
+) -> Result<(Transaction, Vec<i64>), Error> {
     let extfvk = ExtendedFullViewingKey::from(extsk);
-    if !data
-        .prepare("SELECT * FROM accounts WHERE account = ? AND extfvk = ?")?
-        .exists(&[
-            account.to_sql()?,
-            encode_extended_full_viewing_key(HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &extfvk)
-                .to_sql()?,
-        ])?
-    {
-        return Err(Error(ErrorKind::InvalidExtSK(account)));
-    }
     let ovk = extfvk.fvk.ovk;
 
     // Target the next block, assuming we are up-to-date.
     let (height, anchor_height) = {
-        let (target_height, anchor_height) = get_target_and_anchor_heights(&data)?;
+        let (target_height, anchor_height) =
+            get_target_and_anchor_heights(data, request.min_confirmations)?;
         (target_height, i64::from(anchor_height))
     };
 
@@ -125,7 +407,11 @@ pub fn create_to_address<P: AsRef<Path>>(
     //    required value, bringing the sum of all selected notes across the threshold.
     //
     // 4) Match the selected notes against the witnesses at the desired height.
-    let target_value = i64::from(value + DEFAULT_FEE);
+    let target_value = i64::from(
+        request
+            .total_value()
+            .ok_or(Error(ErrorKind::InvalidAmount))?,
+    );
     let mut stmt_select_notes = data.prepare(
         "WITH selected AS (
             WITH eligible AS (
@@ -140,10 +426,11 @@ pub fn create_to_address<P: AsRef<Path>>(
             UNION
             SELECT * FROM (SELECT * FROM eligible WHERE so_far >= ? LIMIT 1)
         ), witnesses AS (
-            SELECT note, witness FROM sapling_witnesses
+            SELECT note, block, witness FROM sapling_witnesses
             WHERE block = ?
         )
-        SELECT selected.diversifier, selected.value, selected.rcm, witnesses.witness
+        SELECT selected.diversifier, selected.value, selected.rcm, witnesses.witness,
+            witnesses.note, witnesses.block
         FROM selected
         INNER JOIN witnesses ON selected.id_note = witnesses.note",
     )?;
@@ -151,52 +438,13 @@ pub fn create_to_address<P: AsRef<Path>>(
     // Select notes
     let notes = stmt_select_notes.query_and_then::<_, Error, _, _>(
         &[
-            i64::from(account),
+            i64::from(request.from_account),
             anchor_height,
             target_value,
             target_value,
             anchor_height,
         ],
-        |row| {
-            let diversifier = {
-                let d: Vec<_> = row.get(0)?;
-                if d.len() != 11 {
-                    return Err(Error(ErrorKind::CorruptedData(
-                        "Invalid diversifier length",
-                    )));
-                }
-                let mut tmp = [0; 11];
-                tmp.copy_from_slice(&d);
-                Diversifier(tmp)
-            };
-
-            let note_value: i64 = row.get(1)?;
-
-            let rcm = {
-                let d: Vec<_> = row.get(2)?;
-                let mut tmp = FsRepr::default();
-                tmp.read_le(&d[..])?;
-                Fs::from_repr(tmp).map_err(|_| Error(ErrorKind::InvalidNote))?
-            };
-
-            let from = extfvk
-                .fvk
-                .vk
-                .into_payment_address(diversifier, &JUBJUB)
-                .unwrap();
-            let note = from.create_note(note_value as u64, rcm, &JUBJUB).unwrap();
-
-            let witness = {
-                let d: Vec<_> = row.get(3)?;
-                IncrementalWitness::read(&d[..])?
-            };
-
-            Ok(SelectedNoteRow {
-                diversifier,
-                note,
-                witness,
-            })
-        },
+        |row| read_selected_note(row, &extfvk),
     )?;
     let notes: Vec<SelectedNoteRow> = notes.collect::<Result<_, _>>()?;
 
@@ -213,6 +461,8 @@ pub fn create_to_address<P: AsRef<Path>>(
 
     // Create the transaction
     let mut builder = Builder::new(height);
+    builder.set_fee(request.fee_policy.fee())?;
+    builder.set_expiry_height(height.saturating_add(request.expiry_offset));
     for selected in notes {
         builder.add_sapling_spend(
             extsk.clone(),
@@ -221,22 +471,58 @@ pub fn create_to_address<P: AsRef<Path>>(
             selected.witness,
         )?;
     }
-    match to {
-        RecipientAddress::Shielded(to) => {
-            builder.add_sapling_output(ovk, to.clone(), value, memo.clone())
-        }
-        RecipientAddress::Transparent(to) => builder.add_transparent_output(&to, value),
-    }?;
+    for recipient in &request.recipients {
+        match &recipient.to {
+            RecipientAddress::Shielded(to) => builder.add_sapling_output(
+                ovk,
+                to.clone(),
+                recipient.amount,
+                recipient.memo.clone(),
+            ),
+            RecipientAddress::Transparent(to) => {
+                builder.add_transparent_output(to, recipient.amount)
+            }
+        }?;
+    }
     let (tx, tx_metadata) = builder.build(consensus_branch_id, prover)?;
-    // We only called add_sapling_output() once.
-    let output_index = match tx_metadata.output_index(0) {
-        Some(idx) => idx as i64,
-        None => panic!("Output 0 should exist in the transaction"),
-    };
-    let created = time::get_time();
 
-    // Update the database atomically, to ensure the result is internally consistent.
-    data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+    // The builder shuffles the Sapling outputs, but keeps the transparent outputs in
+    // the order they were added.
+    let mut sapling_outputs = 0..;
+    let mut transparent_outputs = 0..;
+    let output_indices = request
+        .recipients
+        .iter()
+        .map(|recipient| match recipient.to {
+            RecipientAddress::Shielded(_) => {
+                let n = sapling_outputs.next().unwrap();
+                match tx_metadata.output_index(n) {
+                    Some(idx) => idx as i64,
+                    None => panic!("Output {} should exist in the transaction", n),
+                }
+            }
+            RecipientAddress::Transparent(_) => transparent_outputs.next().unwrap(),
+        })
+        .collect();
+
+    Ok((tx, output_indices))
+}
+
+/// Saves a transaction sent from `account` in the data database, and marks the notes it
+/// spends as spent.
+///
+/// A sent note is recorded for each of `outputs`, which are the index of an output of
+/// `tx`, as returned by [`build_transaction`], and the recipient it pays.
+///
+/// Returns the row index of the transaction in the `transactions` table. The caller is
+/// responsible for wrapping this in an SQL transaction.
+pub(crate) fn store_sent_tx(
+    data: &Connection,
+    tx: &Transaction,
+    account: u32,
+    outputs: &[(i64, &Recipient)],
+) -> Result<i64, Error> {
+    let created = time::get_time();
 
     // Save the transaction in the database.
     let mut raw_tx = vec![];
@@ -267,69 +553,516 @@ pub fn create_to_address<P: AsRef<Path>>(
         stmt_mark_spent_note.execute(&[id_tx.to_sql()?, spend.nullifier.to_sql()?])?;
     }
 
-    // Save the sent note in the database.
-    // TODO: Decide how to save transparent output information.
-    let to_str = to.to_string();
-    if let Some(memo) = memo {
-        let mut stmt_insert_sent_note = data.prepare(
-            "INSERT INTO sent_notes (tx, output_index, from_account, address, value, memo)
-            VALUES (?, ?, ?, ?, ?, ?)",
-        )?;
-        stmt_insert_sent_note.execute(&[
-            id_tx.to_sql()?,
-            output_index.to_sql()?,
-            account.to_sql()?,
-            to_str.to_sql()?,
-            i64::from(value).to_sql()?,
-            memo.as_bytes().to_sql()?,
-        ])?;
-    } else {
-        let mut stmt_insert_sent_note = data.prepare(
-            "INSERT INTO sent_notes (tx, output_index, from_account, address, value)
-            VALUES (?, ?, ?, ?, ?)",
-        )?;
-        stmt_insert_sent_note.execute(&[
-            id_tx.to_sql()?,
-            output_index.to_sql()?,
-            account.to_sql()?,
-            to_str.to_sql()?,
-            i64::from(value).to_sql()?,
-        ])?;
-    }
+    // Save the sent notes in the database.
+    // TODO: Decide how to save transparent output information.
+    let mut stmt_insert_sent_note = data.prepare(
+        "INSERT INTO sent_notes (tx, output_index, from_account, address, value, memo)
+        VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    for (output_index, recipient) in outputs {
+        stmt_insert_sent_note.execute(&[
+            id_tx.to_sql()?,
+            output_index.to_sql()?,
+            account.to_sql()?,
+            recipient.to.to_string().to_sql()?,
+            i64::from(recipient.amount).to_sql()?,
+            recipient
+                .memo
+                .as_ref()
+                .map(|memo| memo.as_bytes())
+                .to_sql()?,
+        ])?;
+    }
+
+    Ok(id_tx)
+}
+
+/// Creates a transaction that splits a single note into several smaller notes.
+///
+/// The note identified by `id_note` (its row index in the `received_notes` table) is
+/// spent, and a new note is created for each entry in `piece_values`, paid to the
+/// account's default address. Any remaining value (after the default fee) is returned to
+/// the account as a change note.
+///
+/// This lets privacy-conscious users pre-split large notes, so that subsequent spends
+/// do not create change notes that reveal the account's remaining balance.
+///
+/// Returns the row index of the newly-created transaction in the `transactions` table
+/// within the data database, as with [`create_to_address`].
+///
+/// Returns an error if the note does not belong to `account`, is not spendable, or if
+/// `sum(piece_values)` plus the fee exceeds the note's value. `piece_values` must be
+/// non-empty and each piece must be positive, or [`ErrorKind::InvalidAmount`] is
+/// returned.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::{
+///     constants::{testnet::COIN_TYPE, SAPLING_CONSENSUS_BRANCH_ID},
+///     keys::spending_key,
+/// };
+/// use zcash_client_sqlite::transact::build_split_tx;
+/// use zcash_primitives::transaction::components::Amount;
+/// use zcash_proofs::prover::LocalTxProver;
+///
+/// let tx_prover = match LocalTxProver::with_default_location() {
+///     Some(tx_prover) => tx_prover,
+///     None => {
+///         panic!("Cannot locate the Zcash parameters. Please run zcash-fetch-params or fetch-params.sh to download the parameters, and then re-run the tests.");
+///     }
+/// };
+///
+/// let account = 0;
+/// let extsk = spending_key(&[0; 32][..], COIN_TYPE, account);
+/// let pieces = [Amount::from_u64(10000).unwrap(), Amount::from_u64(20000).unwrap()];
+/// match build_split_tx(
+///     "/path/to/data.db",
+///     SAPLING_CONSENSUS_BRANCH_ID,
+///     tx_prover,
+///     (account, &extsk),
+///     27,
+///     &pieces,
+/// ) {
+///     Ok(tx_row) => (),
+///     Err(e) => (),
+/// }
+/// ```
+pub fn build_split_tx<P: AsRef<Path>>(
+    db_data: P,
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    (account, extsk): (u32, &ExtendedSpendingKey),
+    id_note: i64,
+    piece_values: &[Amount],
+) -> Result<i64, Error> {
+    if piece_values.is_empty() || piece_values.iter().any(|value| !value.is_positive()) {
+        return Err(Error(ErrorKind::InvalidAmount));
+    }
+
+    let data = open_data_database(db_data)?;
+
+    // Check that the ExtendedSpendingKey we have been given corresponds to the
+    // ExtendedFullViewingKey for the account we are spending from.
+    check_extsk_for_account(&data, account, extsk)?;
+    let extfvk = ExtendedFullViewingKey::from(extsk);
+    let ovk = extfvk.fvk.ovk;
+    let to = extfvk.default_address().unwrap().1;
+
+    // Target the next block, assuming we are up-to-date.
+    let (height, anchor_height) = get_target_and_anchor_heights(&data, ANCHOR_OFFSET)?;
+
+    // Fetch the note, along with its witness at the anchor height.
+    let selected = match data.query_row_and_then(
+        "SELECT diversifier, value, rcm, witness, sapling_witnesses.note, sapling_witnesses.block
+        FROM received_notes
+        INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+        INNER JOIN sapling_witnesses ON sapling_witnesses.note = received_notes.id_note
+        WHERE id_note = ? AND account = ? AND spent IS NULL
+            AND transactions.block <= ? AND sapling_witnesses.block = ?",
+        &[
+            id_note,
+            i64::from(account),
+            i64::from(anchor_height),
+            i64::from(anchor_height),
+        ],
+        |row| read_selected_note(row, &extfvk),
+    ) {
+        Ok(selected) => selected,
+        Err(Error(ErrorKind::Database(rusqlite::Error::QueryReturnedNoRows))) => {
+            return Err(Error(ErrorKind::NoteNotSpendable(id_note)))
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Confirm that the note can cover all of the pieces
+    let target_value = piece_values
+        .iter()
+        .cloned()
+        .sum::<Option<Amount>>()
+        .and_then(|value| value + DEFAULT_FEE)
+        .map(i64::from)
+        .ok_or(Error(ErrorKind::InvalidAmount))?;
+    if selected.note.value < target_value as u64 {
+        return Err(Error(ErrorKind::InsufficientBalance(
+            selected.note.value,
+            target_value as u64,
+        )));
+    }
+
+    // Create the transaction. The builder sends the remaining value back to the
+    // address of the spent note as change.
+    let mut builder = Builder::new(height);
+    builder.add_sapling_spend(
+        extsk.clone(),
+        selected.diversifier,
+        selected.note,
+        selected.witness,
+    )?;
+    for value in piece_values {
+        builder.add_sapling_output(ovk, to.clone(), *value, None)?;
+    }
+    let (tx, tx_metadata) = builder.build(consensus_branch_id, prover)?;
+
+    // Record a sent note for each piece.
+    let pieces: Vec<_> = piece_values
+        .iter()
+        .map(|value| Recipient {
+            to: to.clone().into(),
+            amount: *value,
+            memo: None,
+        })
+        .collect();
+    let outputs: Vec<_> = pieces
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| match tx_metadata.output_index(i) {
+            Some(idx) => (idx as i64, piece),
+            None => panic!("Output {} should exist in the transaction", i),
+        })
+        .collect();
+
+    // Update the database atomically, to ensure the result is internally consistent.
+    // The spent note is locked until this transaction is mined or expires.
+    data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+    let id_tx = store_sent_tx(&data, &tx, account, &outputs)?;
+    data.execute("COMMIT", NO_PARAMS)?;
+
+    Ok(id_tx)
+}
+
+/// Broadcasts a transaction sent from `from_account`, and records it in the data
+/// database as pending.
+///
+/// Each of `recipients` is matched to an output of `tx` before anything is broadcast:
+/// Sapling outputs are recovered with the account's outgoing viewing key, and
+/// transparent outputs are compared by script. Once the transaction has been accepted by
+/// the node, it is saved without a block height, a sent note is recorded for each
+/// recipient, and the notes it spends are marked as spent, so the wallet shows the
+/// payment immediately.
+///
+/// Change outputs are not passed in or recorded here. A received note is stored with its
+/// nullifier, which depends on the note's position in the commitment tree and so is not
+/// known until the transaction is mined; [`scan_cached_blocks`] records the change note
+/// at that point. Until then, the change is not included in [`get_balance`].
+///
+/// Recording a transaction that was created by [`create_to_address`] is harmless; the
+/// existing records are kept.
+///
+/// Returns an error with kind [`ErrorKind::RecipientNotInTransaction`] if a recipient is
+/// not paid by `tx`, in which case nothing is broadcast.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_backend::rpc::ZcashdClient;
+/// use zcash_client_sqlite::transact::{send_and_track, Recipient};
+/// use zcash_primitives::transaction::Transaction;
+///
+/// let client = ZcashdClient::new("http://127.0.0.1:8232");
+/// if let Ok(tx) = Transaction::read(&[][..]) {
+///     let recipients: Vec<Recipient> = vec![];
+///     let txid = send_and_track("/path/to/data.db", &client, &tx, 0, &recipients);
+/// }
+/// ```
+///
+/// [`scan_cached_blocks`]: crate::scan::scan_cached_blocks
+/// [`get_balance`]: crate::query::get_balance
+#[cfg(feature = "rpc")]
+pub fn send_and_track<P: AsRef<Path>>(
+    db_data: P,
+    client: &ZcashdClient,
+    tx: &Transaction,
+    from_account: u32,
+    recipients: &[Recipient],
+) -> Result<TxId, Error> {
+    let data = open_data_database(&db_data)?;
+
+    // Recover the Sapling outputs that were sent with this account's key.
+    let ovk = get_extfvk_for_account(&db_data, from_account)?.fvk.ovk;
+    let recovered: Vec<_> = tx
+        .shielded_outputs
+        .iter()
+        .map(|output| {
+            output
+                .ephemeral_key
+                .as_prime_order(&JUBJUB)
+                .and_then(|epk| {
+                    try_sapling_output_recovery(
+                        &ovk,
+                        &output.cv,
+                        &output.cmu,
+                        &epk,
+                        &output.enc_ciphertext,
+                        &output.out_ciphertext,
+                    )
+                })
+        })
+        .collect();
+
+    // Find the output paying each recipient, using each output at most once.
+    let mut used_shielded = vec![false; tx.shielded_outputs.len()];
+    let mut used_transparent = vec![false; tx.vout.len()];
+    let mut output_indices = Vec::with_capacity(recipients.len());
+    for (i, recipient) in recipients.iter().enumerate() {
+        let found = match &recipient.to {
+            RecipientAddress::Shielded(to) => {
+                let found = recovered.iter().enumerate().position(|(j, output)| {
+                    !used_shielded[j]
+                        && output.as_ref().map_or(false, |(note, addr, _)| {
+                            addr == to && Amount::from_u64(note.value) == Ok(recipient.amount)
+                        })
+                });
+                found.map(|j| {
+                    used_shielded[j] = true;
+                    j
+                })
+            }
+            RecipientAddress::Transparent(to) => {
+                let script = to.script();
+                let found = tx.vout.iter().enumerate().position(|(j, out)| {
+                    !used_transparent[j]
+                        && out.script_pubkey.0 == script.0
+                        && out.value == recipient.amount
+                });
+                found.map(|j| {
+                    used_transparent[j] = true;
+                    j
+                })
+            }
+        };
+        output_indices.push(found.ok_or(Error(ErrorKind::RecipientNotInTransaction(i)))?);
+    }
+
+    let mut raw_tx = vec![];
+    tx.write(&mut raw_tx)?;
+    client.send_raw_transaction(&hex::encode(&raw_tx))?;
+    let txid = tx.txid();
+    let created = time::get_time();
+
+    // Update the database atomically, to ensure the result is internally consistent.
+    data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+
+    data.execute(
+        "INSERT OR IGNORE INTO transactions (txid, created, expiry_height, raw)
+        VALUES (?, ?, ?, ?)",
+        &[
+            txid.0.to_sql()?,
+            created.to_sql()?,
+            tx.expiry_height.to_sql()?,
+            raw_tx.to_sql()?,
+        ],
+    )?;
+    let id_tx: i64 = data.query_row(
+        "SELECT id_tx FROM transactions WHERE txid = ?",
+        &[&txid.0[..]],
+        |row| row.get(0),
+    )?;
+
+    // Lock the spent notes until the transaction is mined or expires.
+    let mut stmt_mark_spent_note =
+        data.prepare("UPDATE received_notes SET spent = ? WHERE nf = ?")?;
+    for spend in &tx.shielded_spends {
+        stmt_mark_spent_note.execute(&[id_tx.to_sql()?, spend.nullifier.to_sql()?])?;
+    }
+
+    let mut stmt_insert_sent_note = data.prepare(
+        "INSERT OR IGNORE INTO sent_notes (tx, output_index, from_account, address, value, memo)
+        VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    for (recipient, output_index) in recipients.iter().zip(output_indices) {
+        stmt_insert_sent_note.execute(&[
+            id_tx.to_sql()?,
+            (output_index as i64).to_sql()?,
+            from_account.to_sql()?,
+            recipient.to.to_string().to_sql()?,
+            i64::from(recipient.amount).to_sql()?,
+            recipient
+                .memo
+                .as_ref()
+                .map(|memo| memo.as_bytes())
+                .to_sql()?,
+        ])?;
+    }
+
+    data.execute("COMMIT", NO_PARAMS)?;
+
+    Ok(txid)
+}
+
+/// Marks the sent transactions that expired before `current_height` without being mined
+/// as failed, and unlocks the notes they spent so that they can be spent again.
+///
+/// Returns the IDs of the transactions newly marked as failed, so that the user can be
+/// told that those payments were not made. Transactions that were already marked as
+/// failed are not returned again.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::transact::recover_failed_sends;
+///
+/// if let Ok(txids) = recover_failed_sends("/path/to/data.db", 1_000_000) {
+///     for txid in txids {
+///         println!("Transaction {} expired without being mined", txid);
+///     }
+/// }
+/// ```
+pub fn recover_failed_sends<P: AsRef<Path>>(
+    db_data: P,
+    current_height: u32,
+) -> Result<Vec<TxId>, Error> {
+    let data = open_data_database(db_data)?;
+
+    // Update the database atomically, so the notes are unlocked along with the flag.
+    data.execute("BEGIN IMMEDIATE", NO_PARAMS)?;
+
+    let mut stmt_expired = data.prepare(
+        "SELECT txid FROM transactions
+        WHERE block IS NULL AND expiry_height < ? AND failed = 0",
+    )?;
+    let txids = stmt_expired.query_map(&[current_height], |row| {
+        let txid: Vec<u8> = row.get(0)?;
+        Ok(txid)
+    })?;
+    let txids = txids
+        .map(|txid| {
+            let txid = txid?;
+            if txid.len() != 32 {
+                return Err(Error(ErrorKind::CorruptedData("txid is not 32 bytes")));
+            }
+            let mut id = [0; 32];
+            id.copy_from_slice(&txid);
+            Ok(TxId(id))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    data.execute(
+        "UPDATE received_notes SET spent = NULL WHERE EXISTS (
+            SELECT id_tx FROM transactions
+            WHERE id_tx = received_notes.spent AND block IS NULL AND expiry_height < ?
+                AND failed = 0
+        )",
+        &[current_height],
+    )?;
+    data.execute(
+        "UPDATE transactions SET failed = 1
+        WHERE block IS NULL AND expiry_height < ? AND failed = 0",
+        &[current_height],
+    )?;
 
     data.execute("COMMIT", NO_PARAMS)?;
 
-    // Return the row number of the transaction, so the caller can fetch it for sending.
-    Ok(id_tx)
+    Ok(txids)
+}
+
+/// Creates a transaction that shields a single transparent coin, such as a coinbase
+/// output, by sending its value less `fee` to the Sapling address `to`.
+///
+/// The coin is signed by `signer`, which must hold the key that the coin is locked to.
+/// No Sapling notes are spent, so the only proof created is for the Sapling output, which
+/// can be recovered with `ovk`. The transaction expires after `expiry_height`.
+///
+/// The transaction is not recorded in a data database. If `to` belongs to the wallet,
+/// the shielded note will be found when the block containing the transaction is scanned.
+///
+/// Returns an error if the coin is not a P2PKH output locked to `signer`'s key, or if
+/// its value does not cover `fee`.
+#[cfg(feature = "transparent-inputs")]
+pub fn build_shielding_tx<S: TransparentSigner + 'static>(
+    consensus_branch_id: u32,
+    prover: impl TxProver,
+    signer: S,
+    utxo: &Utxo,
+    (ovk, to): (OutgoingViewingKey, &PaymentAddress<Bls12>),
+    fee: Amount,
+    expiry_height: u32,
+) -> Result<Transaction, Error> {
+    let value = match utxo.txout.value - fee {
+        Some(value) if !value.is_negative() => value,
+        _ => {
+            return Err(Error(ErrorKind::InsufficientBalance(
+                i64::from(utxo.txout.value) as u64,
+                i64::from(fee) as u64,
+            )))
+        }
+    };
+
+    let mut builder = Builder::new(0);
+    builder.set_fee(fee)?;
+    builder.set_expiry_height(expiry_height);
+    builder.add_transparent_input_with_signer(signer, utxo.outpoint.clone(), utxo.txout.clone())?;
+    builder.add_sapling_output(ovk, to.clone(), value, None)?;
+    let (tx, _) = builder.build(consensus_branch_id, prover)?;
+
+    Ok(tx)
 }
 
 #[cfg(test)]
 mod tests {
+    use rusqlite::{types::ToSql, Connection, NO_PARAMS};
     use tempfile::NamedTempFile;
+    use zcash_client_backend::encoding::encode_payment_address;
     use zcash_primitives::{
         block::BlockHash,
-        prover::TxProver,
-        transaction::components::Amount,
+        note_encryption::Memo,
+        transaction::{components::Amount, Transaction, TxId},
         zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
     };
-    use zcash_proofs::prover::LocalTxProver;
 
-    use super::create_to_address;
+    use super::{
+        build_split_tx, create_to_address, create_transaction, recover_failed_sends, FeePolicy,
+        Recipient, ShieldedSendRequest,
+    };
     use crate::{
+        error::ErrorKind,
         init::{init_accounts_table, init_blocks_table, init_cache_database, init_data_database},
         query::{get_balance, get_verified_balance},
         scan::scan_cached_blocks,
-        tests::{fake_compact_block, insert_into_cache},
-        SAPLING_ACTIVATION_HEIGHT,
+        tests::{fake_compact_block, insert_into_cache, test_prover},
+        HRP_SAPLING_PAYMENT_ADDRESS, SAPLING_ACTIVATION_HEIGHT,
     };
 
-    fn test_prover() -> impl TxProver {
-        match LocalTxProver::with_default_location() {
-            Some(tx_prover) => tx_prover,
-            None => {
-                panic!("Cannot locate the Zcash parameters. Please run zcash-fetch-params or fetch-params.sh to download the parameters, and then re-run the tests.");
-            }
-        }
+    #[cfg(feature = "rpc")]
+    use zcash_client_backend::rpc::ZcashdClient;
+
+    #[cfg(feature = "rpc")]
+    use super::send_and_track;
+
+    #[cfg(feature = "transparent-inputs")]
+    use super::build_shielding_tx;
+    #[cfg(feature = "transparent-inputs")]
+    use zcash_client_backend::wallet::Utxo;
+    #[cfg(feature = "transparent-inputs")]
+    use zcash_primitives::{
+        legacy::TransparentAddress,
+        transaction::{
+            builder::{SecretKeySigner, TransparentSigner},
+            components::{OutPoint, TxOut},
+        },
+    };
+
+    #[test]
+    fn send_request_total_includes_fee() {
+        let to = ExtendedSpendingKey::master(&[])
+            .default_address()
+            .unwrap()
+            .1;
+        let recipient = |amount| Recipient {
+            to: to.clone().into(),
+            amount: Amount::from_u64(amount).unwrap(),
+            memo: None,
+        };
+
+        let mut request = ShieldedSendRequest::new(0, vec![recipient(1), recipient(2)]);
+        assert_eq!(request.total_value(), Amount::from_u64(10_003).ok());
+
+        request.fee_policy = FeePolicy::Fixed(Amount::zero());
+        assert_eq!(request.total_value(), Amount::from_u64(3).ok());
+
+        // The total cannot exceed the maximum amount
+        request.recipients.push(recipient(21_000_000 * 100_000_000));
+        assert_eq!(request.total_value(), None);
     }
 
     #[test]
@@ -477,7 +1210,7 @@ mod tests {
         scan_cached_blocks(db_cache, db_data).unwrap();
 
         // Verified balance does not include the second note
-        assert_eq!(get_balance(db_data, 0).unwrap(), value + value);
+        assert_eq!(get_balance(db_data, 0).unwrap(), (value + value).unwrap());
         assert_eq!(get_verified_balance(db_data, 0).unwrap(), value);
 
         // Spend fails because there are insufficient verified notes
@@ -662,4 +1395,442 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn create_transaction_applies_request_parameters() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Add funds to the wallet in two notes, in consecutive blocks
+        let value = Amount::from_u64(50000).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT + 1,
+            cb.hash(),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+
+        // Pay two recipients, one with a memo
+        let to = ExtendedSpendingKey::master(&[0])
+            .default_address()
+            .unwrap()
+            .1;
+        let mut request = ShieldedSendRequest::new(
+            0,
+            vec![
+                Recipient {
+                    to: to.clone().into(),
+                    amount: Amount::from_u64(20000).unwrap(),
+                    memo: None,
+                },
+                Recipient {
+                    to: to.into(),
+                    amount: Amount::from_u64(30000).unwrap(),
+                    memo: Memo::from_str("Thanks"),
+                },
+            ],
+        );
+        request.fee_policy = FeePolicy::Fixed(Amount::from_u64(20000).unwrap());
+        request.expiry_offset = 5;
+
+        // With the default confirmation depth, only the first note can be spent
+        match create_transaction(db_data, 1, test_prover(), &extsk, &request) {
+            Err(e) => match e.kind() {
+                ErrorKind::InsufficientBalance(50000, 70000) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // Both notes can be spent once a single confirmation is enough
+        request.min_confirmations = 1;
+        let id_tx = create_transaction(db_data, 1, test_prover(), &extsk, &request).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+
+        let data = Connection::open(db_data).unwrap();
+        let raw: Vec<u8> = data
+            .query_row(
+                "SELECT raw FROM transactions WHERE id_tx = ?",
+                &[id_tx],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let tx = Transaction::read(&raw[..]).unwrap();
+        assert_eq!(tx.expiry_height, SAPLING_ACTIVATION_HEIGHT as u32 + 2 + 5);
+        assert_eq!(tx.value_balance, Amount::from_u64(20000).unwrap());
+
+        // A sent note is recorded for each recipient
+        let mut stmt_sent_notes = data
+            .prepare("SELECT value, memo FROM sent_notes WHERE tx = ? ORDER BY value")
+            .unwrap();
+        let sent_notes: Vec<(i64, Option<Vec<u8>>)> = stmt_sent_notes
+            .query_map(&[id_tx], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            sent_notes,
+            vec![
+                (20000, None),
+                (
+                    30000,
+                    Some(Memo::from_str("Thanks").unwrap().as_bytes().to_vec())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_split_tx_spends_single_note() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Add funds to the wallet in a single note
+        let value = Amount::from_u64(50000).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+
+        // The pieces cannot exceed the value of the note
+        match build_split_tx(
+            db_data,
+            1,
+            test_prover(),
+            (0, &extsk),
+            1,
+            &[
+                Amount::from_u64(20000).unwrap(),
+                Amount::from_u64(20001).unwrap(),
+            ],
+        ) {
+            Ok(_) => panic!("Should have failed"),
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "Insufficient balance (have 50000, need 50001 including fee)"
+            ),
+        }
+
+        // There must be at least one piece, and every piece must have a value
+        for pieces in &[
+            vec![],
+            vec![Amount::from_u64(20000).unwrap(), Amount::zero()],
+            vec![Amount::from_i64(-1).unwrap()],
+        ] {
+            match build_split_tx(db_data, 1, test_prover(), (0, &extsk), 1, pieces) {
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidAmount => (),
+                    _ => panic!("Unexpected error: {:?}", e),
+                },
+                Ok(_) => panic!("Should have failed"),
+            }
+        }
+
+        // An unknown note cannot be split
+        match build_split_tx(
+            db_data,
+            1,
+            test_prover(),
+            (0, &extsk),
+            2,
+            &[Amount::from_u64(20000).unwrap()],
+        ) {
+            Ok(_) => panic!("Should have failed"),
+            Err(e) => assert_eq!(e.to_string(), "Note 2 is not spendable"),
+        }
+
+        // Split the note
+        let id_tx = build_split_tx(
+            db_data,
+            1,
+            test_prover(),
+            (0, &extsk),
+            1,
+            &[
+                Amount::from_u64(20000).unwrap(),
+                Amount::from_u64(15000).unwrap(),
+            ],
+        )
+        .unwrap();
+
+        // The note is now locked
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+
+        // A sent note is recorded for each piece
+        let data = Connection::open(db_data).unwrap();
+        let address = encode_payment_address(
+            HRP_SAPLING_PAYMENT_ADDRESS,
+            &extfvk.default_address().unwrap().1,
+        );
+        let mut stmt_sent_notes = data
+            .prepare("SELECT address, value FROM sent_notes WHERE tx = ? ORDER BY value")
+            .unwrap();
+        let sent_notes: Vec<(String, i64)> = stmt_sent_notes
+            .query_map(&[id_tx], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(sent_notes, vec![(address.clone(), 15000), (address, 20000)]);
+    }
+
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn send_and_track_checks_recipients_before_broadcasting() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Add funds to the wallet in a single note, and split off a piece (leaving 25000
+        // of change) to create a transaction
+        let value = Amount::from_u64(50000).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        let id_tx = build_split_tx(
+            db_data,
+            1,
+            test_prover(),
+            (0, &extsk),
+            1,
+            &[Amount::from_u64(15000).unwrap()],
+        )
+        .unwrap();
+
+        let data = Connection::open(db_data).unwrap();
+        let raw: Vec<u8> = data
+            .query_row(
+                "SELECT raw FROM transactions WHERE id_tx = ?",
+                &[id_tx],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let tx = Transaction::read(&raw[..]).unwrap();
+        let sent_notes = || -> i64 {
+            data.query_row("SELECT COUNT(*) FROM sent_notes", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        let sent_before = sent_notes();
+
+        // Nothing listens on port 1 locally
+        let client = ZcashdClient::new("http://127.0.0.1:1");
+        let to = extfvk.default_address().unwrap().1;
+        let recipient = |amount| Recipient {
+            to: to.clone().into(),
+            amount: Amount::from_u64(amount).unwrap(),
+            memo: None,
+        };
+
+        // A recipient that the transaction does not pay is rejected before broadcasting
+        match send_and_track(db_data, &client, &tx, 0, &[recipient(15001)]) {
+            Err(e) => match e.kind() {
+                ErrorKind::RecipientNotInTransaction(0) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // Outputs can only pay one recipient each
+        match send_and_track(
+            db_data,
+            &client,
+            &tx,
+            0,
+            &[recipient(15000), recipient(15000)],
+        ) {
+            Err(e) => match e.kind() {
+                ErrorKind::RecipientNotInTransaction(1) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        // A matching transaction is broadcast, and nothing is recorded if that fails
+        match send_and_track(db_data, &client, &tx, 0, &[recipient(15000)]) {
+            Err(e) => match e.kind() {
+                ErrorKind::Rpc(_) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+        assert_eq!(sent_notes(), sent_before);
+    }
+
+    #[test]
+    fn recover_failed_sends_unlocks_expired_notes() {
+        let cache_file = NamedTempFile::new().unwrap();
+        let db_cache = cache_file.path();
+        init_cache_database(&db_cache).unwrap();
+
+        let data_file = NamedTempFile::new().unwrap();
+        let db_data = data_file.path();
+        init_data_database(&db_data).unwrap();
+
+        // Add an account to the wallet
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        init_accounts_table(&db_data, &[extfvk.clone()]).unwrap();
+
+        // Add funds to the wallet in a single note
+        let value = Amount::from_u64(50000).unwrap();
+        let (cb, _) = fake_compact_block(
+            SAPLING_ACTIVATION_HEIGHT,
+            BlockHash([0; 32]),
+            extfvk.clone(),
+            value,
+        );
+        insert_into_cache(db_cache, &cb);
+        scan_cached_blocks(db_cache, db_data).unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+
+        // Pretend the note was spent by a broadcast transaction that was never mined
+        let expiry_height = (SAPLING_ACTIVATION_HEIGHT + 10) as u32;
+        let data = Connection::open(db_data).unwrap();
+        data.execute(
+            "INSERT INTO transactions (txid, expiry_height) VALUES (?, ?)",
+            &[[1u8; 32].to_sql().unwrap(), expiry_height.to_sql().unwrap()],
+        )
+        .unwrap();
+        data.execute(
+            "UPDATE received_notes SET spent = ?",
+            &[data.last_insert_rowid()],
+        )
+        .unwrap();
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+
+        // The transaction can still be mined at its expiry height
+        assert!(recover_failed_sends(db_data, expiry_height)
+            .unwrap()
+            .is_empty());
+        assert_eq!(get_balance(db_data, 0).unwrap(), Amount::zero());
+
+        // Once it has expired, it is marked as failed and the note is unlocked
+        assert_eq!(
+            recover_failed_sends(db_data, expiry_height + 1).unwrap(),
+            vec![TxId([1; 32])]
+        );
+        assert_eq!(get_balance(db_data, 0).unwrap(), value);
+        let failed: bool = data
+            .query_row("SELECT failed FROM transactions", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(failed);
+
+        // It is only reported once
+        assert!(recover_failed_sends(db_data, expiry_height + 2)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "transparent-inputs")]
+    #[test]
+    fn shield_transparent_coin() {
+        use ripemd160::Ripemd160;
+        use sha2::{Digest, Sha256};
+
+        let new_signer =
+            || SecretKeySigner::new(secp256k1::SecretKey::from_slice(&[1; 32]).unwrap());
+
+        let signer = new_signer();
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&Ripemd160::digest(&Sha256::digest(
+            &signer.public_key().serialize()[..],
+        )));
+        let utxo = Utxo {
+            outpoint: OutPoint::new([7; 32], 0),
+            txout: TxOut {
+                value: Amount::from_u64(50000).unwrap(),
+                script_pubkey: TransparentAddress::PublicKey(hash).script(),
+            },
+        };
+
+        let extfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[]));
+        let to = extfvk.default_address().unwrap().1;
+        let fee = Amount::from_u64(10000).unwrap();
+
+        // The coin must cover the fee
+        match build_shielding_tx(
+            1,
+            test_prover(),
+            new_signer(),
+            &utxo,
+            (extfvk.fvk.ovk, &to),
+            Amount::from_u64(60000).unwrap(),
+            100,
+        ) {
+            Err(e) => match e.kind() {
+                ErrorKind::InsufficientBalance(50000, 60000) => (),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+            Ok(_) => panic!("Should have failed"),
+        }
+
+        let tx = build_shielding_tx(
+            1,
+            test_prover(),
+            signer,
+            &utxo,
+            (extfvk.fvk.ovk, &to),
+            fee,
+            100,
+        )
+        .unwrap();
+        assert_eq!(tx.vin.len(), 1);
+        assert_eq!(tx.vin[0].prevout, utxo.outpoint);
+        assert!(tx.vout.is_empty());
+        assert!(tx.shielded_spends.is_empty());
+        assert_eq!(tx.shielded_outputs.len(), 1);
+        assert_eq!(tx.value_balance, Amount::from_i64(-40000).unwrap());
+        assert_eq!(tx.expiry_height, 100);
+    }
 }
@@ -0,0 +1,64 @@
+//! Helpers for presenting wallet data to users.
+
+use chrono::{DateTime, TimeZone, Utc};
+
+/// Converts a block time, as stored in the `time` column of the `blocks` table, into a
+/// UTC date and time.
+///
+/// Block times are unsigned 32-bit Unix timestamps, so they are upcast to `i64` before
+/// conversion; times after the signed 32-bit overflow in 2038 are handled correctly.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::utils::block_time_to_datetime;
+///
+/// let time = block_time_to_datetime(1_477_641_360);
+/// assert_eq!(time.to_rfc3339(), "2016-10-28T07:56:00+00:00");
+/// ```
+pub fn block_time_to_datetime(time: u32) -> DateTime<Utc> {
+    Utc.timestamp_opt(i64::from(time), 0)
+        .single()
+        .expect("every u32 timestamp is a valid date")
+}
+
+/// Formats a block time using a [`strftime`]-style format string.
+///
+/// # Examples
+///
+/// ```
+/// use zcash_client_sqlite::utils::format_block_time;
+///
+/// assert_eq!(format_block_time(1_477_641_360, "%Y-%m-%d %H:%M"), "2016-10-28 07:56");
+/// ```
+///
+/// [`strftime`]: chrono::format::strftime
+pub fn format_block_time(time: u32, fmt: &str) -> String {
+    block_time_to_datetime(time).format(fmt).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_time_to_datetime, format_block_time};
+
+    #[test]
+    fn epoch() {
+        assert_eq!(
+            block_time_to_datetime(0).to_rfc3339(),
+            "1970-01-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn beyond_2038() {
+        // One second past the largest signed 32-bit timestamp
+        assert_eq!(
+            block_time_to_datetime(1 << 31).to_rfc3339(),
+            "2038-01-19T03:14:08+00:00"
+        );
+        assert_eq!(
+            format_block_time(u32::max_value(), "%Y-%m-%d %H:%M:%S"),
+            "2106-02-07 06:28:15"
+        );
+    }
+}
@@ -61,21 +61,42 @@ impl BlockHeaderData {
     pub fn freeze(self) -> io::Result<BlockHeader> {
         BlockHeader::from_data(self)
     }
+
+    fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_i32::<LittleEndian>(self.version)?;
+        writer.write_all(&self.prev_block.0)?;
+        writer.write_all(&self.merkle_root)?;
+        writer.write_all(&self.final_sapling_root)?;
+        writer.write_u32::<LittleEndian>(self.time)?;
+        writer.write_u32::<LittleEndian>(self.bits)?;
+        writer.write_all(&self.nonce)?;
+        Vector::write(&mut writer, &self.solution, |w, b| w.write_u8(*b))?;
+
+        Ok(())
+    }
+}
+
+/// Computes the hash of a block header, as the double SHA-256 of its serialization
+/// (including the Equihash solution).
+///
+/// This is the same hash as returned by [`BlockHeader::hash`], and can be used to check
+/// the hash claimed for a block against its header. A [`BlockHeader`] can be passed
+/// directly, as it dereferences to [`BlockHeaderData`].
+pub fn compute_block_header_hash(header: &BlockHeaderData) -> BlockHash {
+    let mut raw = vec![];
+    header
+        .write(&mut raw)
+        .expect("Should be able to write to a Vec");
+    let mut hash = BlockHash([0; 32]);
+    hash.0
+        .copy_from_slice(&Sha256::digest(&Sha256::digest(&raw)));
+    hash
 }
 
 impl BlockHeader {
     fn from_data(data: BlockHeaderData) -> io::Result<Self> {
-        let mut header = BlockHeader {
-            hash: BlockHash([0; 32]),
-            data,
-        };
-        let mut raw = vec![];
-        header.write(&mut raw)?;
-        header
-            .hash
-            .0
-            .copy_from_slice(&Sha256::digest(&Sha256::digest(&raw)));
-        Ok(header)
+        let hash = compute_block_header_hash(&data);
+        Ok(BlockHeader { hash, data })
     }
 
     /// Returns the hash of this header.
@@ -115,23 +136,14 @@ impl BlockHeader {
         })
     }
 
-    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
-        writer.write_i32::<LittleEndian>(self.version)?;
-        writer.write_all(&self.prev_block.0)?;
-        writer.write_all(&self.merkle_root)?;
-        writer.write_all(&self.final_sapling_root)?;
-        writer.write_u32::<LittleEndian>(self.time)?;
-        writer.write_u32::<LittleEndian>(self.bits)?;
-        writer.write_all(&self.nonce)?;
-        Vector::write(&mut writer, &self.solution, |w, b| w.write_u8(*b))?;
-
-        Ok(())
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.data.write(writer)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BlockHeader;
+    use super::{compute_block_header_hash, BlockHeader};
 
     const HEADER_MAINNET_415000: [u8; 1487] = [
         0x04, 0x00, 0x00, 0x00, 0x52, 0x74, 0xb4, 0x3b, 0x9e, 0x4a, 0xd8, 0xf4, 0x3e, 0x93, 0xf7,
@@ -243,6 +255,7 @@ mod tests {
             format!("{}", header.hash()),
             "0000000001ab37793ce771262b2ffa082519aa3fe891250a1adb43baaf856168"
         );
+        assert_eq!(compute_block_header_hash(&header), header.hash());
         let mut encoded = Vec::with_capacity(HEADER_MAINNET_415000.len());
         header.write(&mut encoded).unwrap();
         assert_eq!(&HEADER_MAINNET_415000[..], &encoded[..]);
@@ -15,6 +15,9 @@ extern crate rand_core;
 extern crate rand_os;
 extern crate sha2;
 
+#[cfg(any(test, feature = "deterministic-encryption"))]
+extern crate rand_chacha;
+
 #[cfg(feature = "transparent-inputs")]
 extern crate ripemd160;
 
@@ -36,6 +39,7 @@ pub mod keys;
 pub mod legacy;
 pub mod merkle_tree;
 pub mod note_encryption;
+pub mod orchard;
 pub mod pedersen_hash;
 pub mod primitives;
 pub mod prover;
@@ -13,6 +13,10 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crypto_api_chachapoly::{ChaCha20Ietf, ChachaPolyIetf};
 use ff::{PrimeField, PrimeFieldRepr};
 use pairing::bls12_381::{Bls12, Fr};
+#[cfg(any(test, feature = "deterministic-encryption"))]
+use rand_chacha::ChaCha20Rng;
+#[cfg(any(test, feature = "deterministic-encryption"))]
+use rand_core::SeedableRng;
 use rand_core::{CryptoRng, RngCore};
 use std::fmt;
 use std::str;
@@ -278,6 +282,26 @@ impl SaplingNoteEncryption {
         }
     }
 
+    /// Creates a new encryption context for the given note, with an ephemeral secret key
+    /// derived deterministically from `seed`.
+    ///
+    /// Encrypting the same note with the same seed always produces the same ciphertexts.
+    /// This is intended for tests and debugging tools: reusing a seed for different notes
+    /// leaks information about them, so this must never be used to create transactions.
+    ///
+    /// Only available with the `deterministic-encryption` feature.
+    #[cfg(any(test, feature = "deterministic-encryption"))]
+    pub fn new_deterministic(
+        ovk: OutgoingViewingKey,
+        note: Note<Bls12>,
+        to: PaymentAddress<Bls12>,
+        memo: Memo,
+        seed: &[u8; 32],
+    ) -> SaplingNoteEncryption {
+        let mut rng = ChaCha20Rng::from_seed(*seed);
+        Self::new(ovk, note, to, memo, &mut rng)
+    }
+
     /// Exposes the ephemeral secret key being used to encrypt this note.
     pub fn esk(&self) -> &Fs {
         &self.esk
@@ -1366,4 +1390,38 @@ mod tests {
             assert_eq!(&ne.encrypt_outgoing_plaintext(&cv, &cmu)[..], &tv.c_out[..]);
         }
     }
+
+    #[test]
+    fn deterministic_encryption_is_repeatable() {
+        let diversifier = find_valid_diversifier();
+        let ivk = Fs::random(&mut OsRng);
+        let pk_d = diversifier.g_d::<Bls12>(&JUBJUB).unwrap().mul(ivk, &JUBJUB);
+        let to = PaymentAddress { diversifier, pk_d };
+        let note = to
+            .create_note(100, Fs::random(&mut OsRng), &JUBJUB)
+            .unwrap();
+        let ovk = OutgoingViewingKey([0; 32]);
+
+        let encrypt = |seed: &[u8; 32]| {
+            let ne = SaplingNoteEncryption::new_deterministic(
+                ovk,
+                note.clone(),
+                to.clone(),
+                Memo::default(),
+                seed,
+            );
+            (ne.epk().clone(), ne.encrypt_note_plaintext())
+        };
+
+        let (epk1, c_enc1) = encrypt(&[1; 32]);
+        let (epk2, c_enc2) = encrypt(&[1; 32]);
+        let (epk3, _) = encrypt(&[2; 32]);
+        assert_eq!(epk1, epk2);
+        assert_eq!(&c_enc1[..], &c_enc2[..]);
+        assert!(epk1 != epk3);
+
+        // The ciphertext decrypts as usual
+        let cmu = note.cm(&JUBJUB);
+        assert!(try_sapling_note_decryption(&ivk, &epk1, &cmu, &c_enc1).is_some());
+    }
 }
@@ -0,0 +1,68 @@
+//! Placeholder types for Orchard keys.
+//!
+//! Orchard is not yet supported by this crate. These types hold the raw encodings of
+//! Orchard viewing keys so that APIs which will eventually use them can be introduced
+//! without breaking changes. They do not check that the encodings are valid, and no key
+//! derivation or note decryption is implemented for them.
+
+/// The length in bytes of an encoded [`OrchardFullViewingKey`].
+pub const ORCHARD_FVK_SIZE: usize = 96;
+
+/// The length in bytes of an encoded [`OrchardIncomingViewingKey`].
+pub const ORCHARD_IVK_SIZE: usize = 64;
+
+/// An opaque Orchard full viewing key, stored as its 96-byte encoding.
+#[derive(Clone, Copy)]
+pub struct OrchardFullViewingKey([u8; ORCHARD_FVK_SIZE]);
+
+impl OrchardFullViewingKey {
+    /// Wraps the encoding of an Orchard full viewing key.
+    pub fn from_bytes(bytes: [u8; ORCHARD_FVK_SIZE]) -> Self {
+        OrchardFullViewingKey(bytes)
+    }
+
+    /// Returns the encoding of this key.
+    pub fn to_bytes(&self) -> [u8; ORCHARD_FVK_SIZE] {
+        self.0
+    }
+}
+
+impl std::fmt::Debug for OrchardFullViewingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "OrchardFullViewingKey(..)")
+    }
+}
+
+impl PartialEq for OrchardFullViewingKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0[..] == other.0[..]
+    }
+}
+
+/// An opaque Orchard incoming viewing key, stored as its 64-byte encoding.
+#[derive(Clone, Copy)]
+pub struct OrchardIncomingViewingKey([u8; ORCHARD_IVK_SIZE]);
+
+impl OrchardIncomingViewingKey {
+    /// Wraps the encoding of an Orchard incoming viewing key.
+    pub fn from_bytes(bytes: [u8; ORCHARD_IVK_SIZE]) -> Self {
+        OrchardIncomingViewingKey(bytes)
+    }
+
+    /// Returns the encoding of this key.
+    pub fn to_bytes(&self) -> [u8; ORCHARD_IVK_SIZE] {
+        self.0
+    }
+}
+
+impl std::fmt::Debug for OrchardIncomingViewingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "OrchardIncomingViewingKey(..)")
+    }
+}
+
+impl PartialEq for OrchardIncomingViewingKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0[..] == other.0[..]
+    }
+}
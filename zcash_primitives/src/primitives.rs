@@ -12,6 +12,10 @@ use jubjub::{edwards, FixedGenerators, JubjubEngine, JubjubParams, PrimeOrder};
 
 use blake2s_simd::Params as Blake2sParams;
 
+use hex;
+
+use std::fmt;
+
 #[derive(Clone)]
 pub struct ValueCommitment<E: JubjubEngine> {
     pub value: u64,
@@ -133,6 +137,17 @@ impl<E: JubjubEngine> PartialEq for PaymentAddress<E> {
     }
 }
 
+impl<E: JubjubEngine> fmt::Display for PaymentAddress<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PaymentAddress(d = {}, pk_d = {})",
+            hex::encode(&self.diversifier.0),
+            point_to_hex(&self.pk_d)
+        )
+    }
+}
+
 impl<E: JubjubEngine> PaymentAddress<E> {
     pub fn g_d(&self, params: &E::Params) -> Option<edwards::Point<E, PrimeOrder>> {
         self.diversifier.g_d(params)
@@ -174,6 +189,27 @@ impl<E: JubjubEngine> PartialEq for Note<E> {
     }
 }
 
+/// Formats the note's value and address. The commitment randomness is not shown.
+impl<E: JubjubEngine> fmt::Display for Note<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Note(value = {} zat, g_d = {}, pk_d = {})",
+            self.value,
+            point_to_hex(&self.g_d),
+            point_to_hex(&self.pk_d)
+        )
+    }
+}
+
+/// Returns the hex encoding of the given point.
+fn point_to_hex<E: JubjubEngine>(p: &edwards::Point<E, PrimeOrder>) -> String {
+    let mut bytes = vec![];
+    p.write(&mut bytes)
+        .expect("Should be able to write to a Vec");
+    hex::encode(bytes)
+}
+
 impl<E: JubjubEngine> Note<E> {
     pub fn uncommitted() -> E::Fr {
         // The smallest u-coordinate that is not on the curve
@@ -20,7 +20,10 @@ use crate::{
     redjubjub::PrivateKey,
     sapling::{spend_sig, Node},
     transaction::{
-        components::{amount::DEFAULT_FEE, Amount, OutputDescription, SpendDescription, TxOut},
+        components::{
+            amount::{DEFAULT_DUST_THRESHOLD, DEFAULT_FEE},
+            Amount, OutputDescription, SpendDescription, TxOut,
+        },
         signature_hash_data, Transaction, TransactionData, SIGHASH_ALL,
     },
     JUBJUB,
@@ -32,7 +35,9 @@ use crate::{
     transaction::components::{OutPoint, TxIn},
 };
 
-const DEFAULT_TX_EXPIRY_DELTA: u32 = 20;
+/// The number of blocks after its target height at which a transaction built by a
+/// [`Builder`] expires, unless set with [`Builder::set_expiry_height`].
+pub const DEFAULT_TX_EXPIRY_DELTA: u32 = 20;
 
 /// If there are any shielded inputs, always have at least two shielded outputs, padding
 /// with dummy outputs if necessary. See https://github.com/zcash/zcash/issues/3615
@@ -43,6 +48,7 @@ pub enum Error {
     AnchorMismatch,
     BindingSig,
     ChangeIsNegative(Amount),
+    DustOutput(Amount),
     InvalidAddress,
     InvalidAmount,
     InvalidWitness,
@@ -60,6 +66,13 @@ impl fmt::Display for Error {
             Error::ChangeIsNegative(amount) => {
                 write!(f, "Change is negative ({:?} zatoshis)", amount)
             }
+            Error::DustOutput(amount) => {
+                write!(
+                    f,
+                    "Output is below the dust threshold ({:?} zatoshis)",
+                    amount
+                )
+            }
             Error::InvalidAddress => write!(f, "Invalid address"),
             Error::InvalidAmount => write!(f, "Invalid amount"),
             Error::InvalidWitness => write!(f, "Invalid note witness"),
@@ -71,6 +84,35 @@ impl fmt::Display for Error {
 
 impl error::Error for Error {}
 
+/// A policy for handling outputs whose value is below the dust threshold.
+///
+/// Sapling outputs with a value of zero, which are used to send a memo on its own, are
+/// never treated as dust.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DustPolicy {
+    /// Outputs must be worth at least the given amount. Change below this amount is added
+    /// to the transaction fee instead of being sent to the change address.
+    Threshold(Amount),
+    /// Outputs of any value are permitted. This is intended for tests that deliberately
+    /// create dust outputs.
+    AllowDust,
+}
+
+impl Default for DustPolicy {
+    fn default() -> Self {
+        DustPolicy::Threshold(DEFAULT_DUST_THRESHOLD)
+    }
+}
+
+impl DustPolicy {
+    fn is_dust(&self, value: Amount) -> bool {
+        match self {
+            DustPolicy::Threshold(threshold) => i64::from(value) < i64::from(*threshold),
+            DustPolicy::AllowDust => false,
+        }
+    }
+}
+
 struct SpendDescriptionInfo {
     extsk: ExtendedSpendingKey,
     diversifier: Diversifier,
@@ -79,6 +121,12 @@ struct SpendDescriptionInfo {
     witness: CommitmentTreeWitness<Node>,
 }
 
+/// A recipient of funds in a transaction, in either the Sapling or transparent pool.
+pub enum OutputDescriptor {
+    Sapling(PaymentAddress<Bls12>, Amount, Option<Memo>),
+    Transparent(TransparentAddress, Amount),
+}
+
 pub struct SaplingOutput {
     ovk: OutgoingViewingKey,
     to: PaymentAddress<Bls12>,
@@ -159,9 +207,57 @@ impl SaplingOutput {
     }
 }
 
+/// A source of signatures for transparent inputs that does not reveal its secret key to
+/// the [`Builder`], such as a hardware wallet.
 #[cfg(feature = "transparent-inputs")]
-struct TransparentInputInfo {
+pub trait TransparentSigner {
+    /// Returns the public key that the spent coin is locked to.
+    fn public_key(&self) -> secp256k1::PublicKey;
+
+    /// Signs the given transparent signature hash.
+    fn sign(&self, msg: &secp256k1::Message) -> secp256k1::Signature;
+}
+
+/// A [`TransparentSigner`] that holds its secret key in memory.
+///
+/// This is mainly useful for testing code that accepts an external signer.
+#[cfg(feature = "transparent-inputs")]
+pub struct SecretKeySigner {
+    secp: secp256k1::Secp256k1<secp256k1::All>,
     sk: secp256k1::SecretKey,
+}
+
+#[cfg(feature = "transparent-inputs")]
+impl SecretKeySigner {
+    /// Creates a signer for the given secret key.
+    pub fn new(sk: secp256k1::SecretKey) -> Self {
+        SecretKeySigner {
+            secp: secp256k1::Secp256k1::new(),
+            sk,
+        }
+    }
+}
+
+#[cfg(feature = "transparent-inputs")]
+impl TransparentSigner for SecretKeySigner {
+    fn public_key(&self) -> secp256k1::PublicKey {
+        secp256k1::PublicKey::from_secret_key(&self.secp, &self.sk)
+    }
+
+    fn sign(&self, msg: &secp256k1::Message) -> secp256k1::Signature {
+        self.secp.sign(msg, &self.sk)
+    }
+}
+
+#[cfg(feature = "transparent-inputs")]
+enum TransparentInputSigner {
+    SecretKey(secp256k1::SecretKey),
+    External(Box<dyn TransparentSigner>),
+}
+
+#[cfg(feature = "transparent-inputs")]
+struct TransparentInputInfo {
+    signer: TransparentInputSigner,
     pubkey: [u8; secp256k1::constants::PUBLIC_KEY_SIZE],
     coin: TxOut,
 }
@@ -187,18 +283,18 @@ impl Default for TransparentInputs {
 struct TransparentInputs;
 
 impl TransparentInputs {
-    fn input_sum(&self) -> Amount {
+    fn input_sum(&self) -> Option<Amount> {
         #[cfg(feature = "transparent-inputs")]
         {
             self.inputs
                 .iter()
                 .map(|input| input.coin.value)
-                .sum::<Amount>()
+                .sum::<Option<Amount>>()
         }
 
         #[cfg(not(feature = "transparent-inputs"))]
         {
-            Amount::zero()
+            Some(Amount::zero())
         }
     }
 }
@@ -246,6 +342,7 @@ pub struct Builder<R: RngCore + CryptoRng> {
     rng: R,
     mtx: TransactionData,
     fee: Amount,
+    dust_policy: DustPolicy,
     anchor: Option<Fr>,
     spends: Vec<SpendDescriptionInfo>,
     outputs: Vec<SaplingOutput>,
@@ -263,6 +360,9 @@ impl Builder<OsRng> {
     /// expiry delta (20 blocks).
     ///
     /// The fee will be set to the default fee (0.0001 ZEC).
+    ///
+    /// Outputs below 1000 zatoshis will be rejected, and change below 1000 zatoshis will
+    /// be added to the fee.
     pub fn new(height: u32) -> Self {
         Builder::new_with_rng(height, OsRng)
     }
@@ -278,6 +378,9 @@ impl<R: RngCore + CryptoRng> Builder<R> {
     /// expiry delta (20 blocks).
     ///
     /// The fee will be set to the default fee (0.0001 ZEC).
+    ///
+    /// Outputs below 1000 zatoshis will be rejected, and change below 1000 zatoshis will
+    /// be added to the fee.
     pub fn new_with_rng(height: u32, rng: R) -> Builder<R> {
         let mut mtx = TransactionData::new();
         mtx.expiry_height = height + DEFAULT_TX_EXPIRY_DELTA;
@@ -286,6 +389,7 @@ impl<R: RngCore + CryptoRng> Builder<R> {
             rng,
             mtx,
             fee: DEFAULT_FEE,
+            dust_policy: DustPolicy::default(),
             anchor: None,
             spends: vec![],
             outputs: vec![],
@@ -318,7 +422,9 @@ impl<R: RngCore + CryptoRng> Builder<R> {
 
         let alpha = Fs::random(&mut self.rng);
 
-        self.mtx.value_balance += Amount::from_u64(note.value).map_err(|_| Error::InvalidAmount)?;
+        self.mtx.value_balance = (self.mtx.value_balance
+            + Amount::from_u64(note.value).map_err(|_| Error::InvalidAmount)?)
+        .ok_or(Error::InvalidAmount)?;
 
         self.spends.push(SpendDescriptionInfo {
             extsk,
@@ -341,7 +447,7 @@ impl<R: RngCore + CryptoRng> Builder<R> {
     ) -> Result<(), Error> {
         let output = SaplingOutput::new(&mut self.rng, ovk, to, value, memo)?;
 
-        self.mtx.value_balance -= value;
+        self.mtx.value_balance = (self.mtx.value_balance - value).ok_or(Error::InvalidAmount)?;
 
         self.outputs.push(output);
 
@@ -355,12 +461,41 @@ impl<R: RngCore + CryptoRng> Builder<R> {
         sk: secp256k1::SecretKey,
         utxo: OutPoint,
         coin: TxOut,
+    ) -> Result<(), Error> {
+        let pubkey = secp256k1::PublicKey::from_secret_key(&self.legacy.secp, &sk).serialize();
+        self.add_transparent_input_inner(TransparentInputSigner::SecretKey(sk), pubkey, utxo, coin)
+    }
+
+    /// Adds a transparent coin to be spent in this transaction, signed by `signer` when
+    /// the transaction is built.
+    #[cfg(feature = "transparent-inputs")]
+    pub fn add_transparent_input_with_signer<S: TransparentSigner + 'static>(
+        &mut self,
+        signer: S,
+        utxo: OutPoint,
+        coin: TxOut,
+    ) -> Result<(), Error> {
+        let pubkey = signer.public_key().serialize();
+        self.add_transparent_input_inner(
+            TransparentInputSigner::External(Box::new(signer)),
+            pubkey,
+            utxo,
+            coin,
+        )
+    }
+
+    #[cfg(feature = "transparent-inputs")]
+    fn add_transparent_input_inner(
+        &mut self,
+        signer: TransparentInputSigner,
+        pubkey: [u8; secp256k1::constants::PUBLIC_KEY_SIZE],
+        utxo: OutPoint,
+        coin: TxOut,
     ) -> Result<(), Error> {
         if coin.value.is_negative() {
             return Err(Error::InvalidAmount);
         }
 
-        let pubkey = secp256k1::PublicKey::from_secret_key(&self.legacy.secp, &sk).serialize();
         match coin.script_pubkey.address() {
             Some(TransparentAddress::PublicKey(hash)) => {
                 use ripemd160::Ripemd160;
@@ -374,9 +509,11 @@ impl<R: RngCore + CryptoRng> Builder<R> {
         }
 
         self.mtx.vin.push(TxIn::new(utxo));
-        self.legacy
-            .inputs
-            .push(TransparentInputInfo { sk, pubkey, coin });
+        self.legacy.inputs.push(TransparentInputInfo {
+            signer,
+            pubkey,
+            coin,
+        });
 
         Ok(())
     }
@@ -399,6 +536,34 @@ impl<R: RngCore + CryptoRng> Builder<R> {
         Ok(())
     }
 
+    /// Adds a recipient of funds in either the Sapling or transparent pool.
+    ///
+    /// Sapling outputs are encrypted so that they can be recovered with `ovk`.
+    pub fn add_output(
+        &mut self,
+        ovk: OutgoingViewingKey,
+        output: OutputDescriptor,
+    ) -> Result<(), Error> {
+        match output {
+            OutputDescriptor::Sapling(to, value, memo) => {
+                self.add_sapling_output(ovk, to, value, memo)
+            }
+            OutputDescriptor::Transparent(to, value) => self.add_transparent_output(&to, value),
+        }
+    }
+
+    /// Adds each of the given recipients with [`Builder::add_output`].
+    pub fn add_outputs(
+        &mut self,
+        ovk: OutgoingViewingKey,
+        outputs: Vec<OutputDescriptor>,
+    ) -> Result<(), Error> {
+        for output in outputs {
+            self.add_output(ovk, output)?;
+        }
+        Ok(())
+    }
+
     /// Sets the Sapling address to which any change will be sent.
     ///
     /// By default, change is sent to the Sapling address corresponding to the first note
@@ -407,6 +572,29 @@ impl<R: RngCore + CryptoRng> Builder<R> {
         self.change_address = Some((ovk, to));
     }
 
+    /// Sets the fee paid by this transaction, replacing the default fee.
+    ///
+    /// Returns an error if the fee is negative.
+    pub fn set_fee(&mut self, fee: Amount) -> Result<(), Error> {
+        if fee.is_negative() {
+            return Err(Error::InvalidAmount);
+        }
+
+        self.fee = fee;
+        Ok(())
+    }
+
+    /// Sets the height after which this transaction can no longer be mined, replacing
+    /// the default expiry height.
+    pub fn set_expiry_height(&mut self, expiry_height: u32) {
+        self.mtx.expiry_height = expiry_height;
+    }
+
+    /// Sets the policy for handling outputs below the dust threshold.
+    pub fn set_dust_policy(&mut self, dust_policy: DustPolicy) {
+        self.dust_policy = dust_policy;
+    }
+
     /// Builds a transaction from the configured spends and outputs.
     ///
     /// Upon success, returns a tuple containing the final transaction, and the
@@ -428,22 +616,43 @@ impl<R: RngCore + CryptoRng> Builder<R> {
         //
 
         // Valid change
-        let change = self.mtx.value_balance - self.fee + self.legacy.input_sum()
-            - self
+        let change = {
+            let input_sum = self.legacy.input_sum();
+            let output_sum = self
                 .mtx
                 .vout
                 .iter()
                 .map(|output| output.value)
-                .sum::<Amount>();
+                .sum::<Option<Amount>>();
+            (self.mtx.value_balance - self.fee)
+                .and_then(|change| change + input_sum?)
+                .and_then(|change| change - output_sum?)
+                .ok_or(Error::InvalidAmount)?
+        };
         if change.is_negative() {
             return Err(Error::ChangeIsNegative(change));
         }
 
+        // No dust outputs, other than memo-only Sapling outputs
+        for value in self
+            .outputs
+            .iter()
+            .filter(|output| output.note.value != 0)
+            .map(|output| Amount::from_u64(output.note.value).map_err(|_| Error::InvalidAmount))
+            .chain(self.mtx.vout.iter().map(|output| Ok(output.value)))
+        {
+            let value = value?;
+            if self.dust_policy.is_dust(value) {
+                return Err(Error::DustOutput(value));
+            }
+        }
+
         //
         // Change output
         //
 
-        if change.is_positive() {
+        // Change below the dust threshold is left to the fee.
+        if change.is_positive() && !self.dust_policy.is_dust(change) {
             // Send change to the specified change address. If no change address
             // was set, send change to the first Sapling address given as input.
             let change_address = if let Some(change_address) = self.change_address.take() {
@@ -654,7 +863,10 @@ impl<R: RngCore + CryptoRng> Builder<R> {
                 ));
 
                 let msg = secp256k1::Message::from_slice(&sighash).expect("32 bytes");
-                let sig = self.legacy.secp.sign(&msg, &info.sk);
+                let sig = match info.signer {
+                    TransparentInputSigner::SecretKey(ref sk) => self.legacy.secp.sign(&msg, sk),
+                    TransparentInputSigner::External(ref signer) => signer.sign(&msg),
+                };
 
                 // Signature has to have "SIGHASH_ALL" appended to it
                 let mut sig_bytes: Vec<u8> = sig.serialize_der()[..].to_vec();
@@ -680,10 +892,11 @@ mod tests {
 
     use crate::jubjub::fs::Fs;
 
-    use super::{Builder, Error};
+    use super::{Builder, DustPolicy, Error, OutputDescriptor};
     use crate::{
         legacy::TransparentAddress,
         merkle_tree::{CommitmentTree, IncrementalWitness},
+        note_encryption::Memo,
         prover::mock::MockTxProver,
         sapling::Node,
         transaction::components::Amount,
@@ -705,6 +918,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fails_on_negative_fee() {
+        let mut builder = Builder::new(0);
+        assert_eq!(
+            builder.set_fee(Amount::from_i64(-1).unwrap()),
+            Err(Error::InvalidAmount)
+        );
+    }
+
     #[cfg(feature = "transparent-inputs")]
     #[test]
     fn binding_sig_absent_if_no_shielded_spend_or_output() {
@@ -718,10 +940,11 @@ mod tests {
             rng: OsRng,
             mtx: TransactionData::new(),
             fee: Amount::zero(),
+            dust_policy: builder::DustPolicy::AllowDust,
             anchor: None,
             spends: vec![],
             outputs: vec![],
-            transparent_inputs: TransparentInputs::default(),
+            legacy: TransparentInputs::default(),
             change_address: None,
         };
 
@@ -749,6 +972,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn add_outputs_dispatches_by_pool() {
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let ovk = extfvk.fvk.ovk;
+        let to = extfvk.default_address().unwrap().1;
+
+        let mut builder = Builder::new(0);
+        builder
+            .add_outputs(
+                ovk,
+                vec![
+                    OutputDescriptor::Sapling(to.clone(), Amount::from_u64(5).unwrap(), None),
+                    OutputDescriptor::Transparent(
+                        TransparentAddress::PublicKey([0; 20]),
+                        Amount::from_u64(7).unwrap(),
+                    ),
+                ],
+            )
+            .unwrap();
+        assert_eq!(builder.outputs.len(), 1);
+        assert_eq!(builder.mtx.vout.len(), 1);
+        assert_eq!(builder.mtx.vout[0].value, Amount::from_u64(7).unwrap());
+
+        assert_eq!(
+            builder.add_output(
+                ovk,
+                OutputDescriptor::Sapling(to, Amount::from_i64(-1).unwrap(), None)
+            ),
+            Err(Error::InvalidAmount)
+        );
+    }
+
     #[test]
     fn fails_on_negative_change() {
         let mut rng = OsRng;
@@ -875,4 +1131,144 @@ mod tests {
             assert_eq!(builder.build(1, MockTxProver), Err(Error::BindingSig))
         }
     }
+
+    #[test]
+    fn fails_on_dust_output() {
+        let mut rng = OsRng;
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let ovk = extfvk.fvk.ovk;
+        let to = extfvk.default_address().unwrap().1;
+
+        let note = to
+            .create_note(50000, Fs::random(&mut rng), &JUBJUB)
+            .unwrap();
+        let cm = Node::new(note.cm(&JUBJUB).into_repr());
+        let mut tree = CommitmentTree::new();
+        tree.append(cm).unwrap();
+        let witness = IncrementalWitness::from_tree(&tree);
+
+        // Fail if an output is below the default dust threshold
+        // 0.00000999 z-ZEC out, 0.0001 t-ZEC fee, 0.0005 z-ZEC in
+        {
+            let mut builder = Builder::new(0);
+            builder
+                .add_sapling_spend(extsk.clone(), to.diversifier, note.clone(), witness.clone())
+                .unwrap();
+            builder
+                .add_sapling_output(
+                    ovk.clone(),
+                    to.clone(),
+                    Amount::from_u64(999).unwrap(),
+                    None,
+                )
+                .unwrap();
+            assert_eq!(
+                builder.build(1, MockTxProver),
+                Err(Error::DustOutput(Amount::from_u64(999).unwrap()))
+            );
+        }
+
+        // Dust outputs are permitted if the policy allows them
+        //
+        // (Still fails because we are using a MockTxProver which doesn't correctly
+        // compute bindingSig.)
+        {
+            let mut builder = Builder::new(0);
+            builder.set_dust_policy(DustPolicy::AllowDust);
+            builder
+                .add_sapling_spend(extsk, to.diversifier, note, witness)
+                .unwrap();
+            builder
+                .add_sapling_output(ovk, to, Amount::from_u64(999).unwrap(), None)
+                .unwrap();
+            assert_eq!(builder.build(1, MockTxProver), Err(Error::BindingSig))
+        }
+    }
+
+    #[test]
+    fn allows_memo_only_output() {
+        let mut rng = OsRng;
+
+        let extsk = ExtendedSpendingKey::master(&[]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let ovk = extfvk.fvk.ovk;
+        let to = extfvk.default_address().unwrap().1;
+
+        let note = to
+            .create_note(50000, Fs::random(&mut rng), &JUBJUB)
+            .unwrap();
+        let cm = Node::new(note.cm(&JUBJUB).into_repr());
+        let mut tree = CommitmentTree::new();
+        tree.append(cm).unwrap();
+        let witness = IncrementalWitness::from_tree(&tree);
+
+        // A zero-value output carrying a memo is not dust under the default policy
+        //
+        // (Still fails because we are using a MockTxProver which doesn't correctly
+        // compute bindingSig.)
+        let mut builder = Builder::new(0);
+        builder
+            .add_sapling_spend(extsk, to.diversifier, note, witness)
+            .unwrap();
+        builder
+            .add_sapling_output(ovk, to, Amount::zero(), Memo::from_str("Thanks"))
+            .unwrap();
+        assert_eq!(builder.build(1, MockTxProver), Err(Error::BindingSig))
+    }
+
+    #[cfg(feature = "transparent-inputs")]
+    #[test]
+    fn transparent_input_with_external_signer() {
+        use ripemd160::Ripemd160;
+        use secp256k1;
+        use sha2::{Digest, Sha256};
+
+        use super::{SecretKeySigner, TransparentSigner};
+        use crate::transaction::components::{OutPoint, TxOut};
+
+        let signer_for =
+            |sk: [u8; 32]| SecretKeySigner::new(secp256k1::SecretKey::from_slice(&sk).unwrap());
+
+        let signer = signer_for([1; 32]);
+        let pubkey = signer.public_key().serialize();
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&Ripemd160::digest(&Sha256::digest(&pubkey)));
+        let coin = TxOut {
+            value: Amount::from_u64(50000).unwrap(),
+            script_pubkey: TransparentAddress::PublicKey(hash).script(),
+        };
+
+        let mut builder = Builder::new(0);
+
+        // A coin locked to a different key is rejected
+        assert_eq!(
+            builder.add_transparent_input_with_signer(
+                signer_for([2; 32]),
+                OutPoint::new([0; 32], 0),
+                coin.clone()
+            ),
+            Err(Error::InvalidAddress)
+        );
+
+        builder
+            .add_transparent_input_with_signer(signer, OutPoint::new([0; 32], 0), coin)
+            .unwrap();
+        builder
+            .add_transparent_output(
+                &TransparentAddress::PublicKey([0; 20]),
+                Amount::from_u64(40000).unwrap(),
+            )
+            .unwrap();
+        builder.set_fee(Amount::from_u64(10000).unwrap()).unwrap();
+        builder.set_expiry_height(100);
+
+        let (tx, _) = builder.build(1, MockTxProver).unwrap();
+        assert_eq!(tx.expiry_height, 100);
+        assert!(tx.binding_sig.is_none());
+
+        // The P2PKH scriptSig ends with the signer's public key
+        assert!(tx.vin[0].script_sig.0.ends_with(&pubkey));
+    }
 }
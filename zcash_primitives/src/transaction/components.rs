@@ -9,7 +9,7 @@ use redjubjub::{PublicKey, Signature};
 use JUBJUB;
 
 pub mod amount;
-pub use self::amount::Amount;
+pub use self::amount::{Amount, Zatoshi};
 
 // π_A + π_B + π_C
 pub const GROTH_PROOF_SIZE: usize = (48 + 96 + 48);
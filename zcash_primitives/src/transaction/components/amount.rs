@@ -6,17 +6,16 @@ const MAX_MONEY: i64 = 21_000_000 * COIN;
 
 pub const DEFAULT_FEE: Amount = Amount(10000);
 
+/// The conventional threshold below which outputs are considered dust, and are unlikely
+/// to be relayed by nodes.
+pub const DEFAULT_DUST_THRESHOLD: Amount = Amount(1000);
+
 /// A type-safe representation of some quantity of Zcash.
 ///
 /// An Amount can only be constructed from an integer that is within the valid monetary
 /// range of `{-MAX_MONEY..MAX_MONEY}` (where `MAX_MONEY` = 21,000,000 × 10⁸ zatoshis).
-/// However, this range is not preserved as an invariant internally; it is possible to
-/// add two valid Amounts together to obtain an invalid Amount. It is the user's
-/// responsibility to handle the result of serializing potentially-invalid Amounts. In
-/// particular, a [`Transaction`] containing serialized invalid Amounts will be rejected
-/// by the network consensus rules.
-///
-/// [`Transaction`]: crate::transaction::Transaction
+/// This range is preserved as an invariant: adding or subtracting Amounts returns `None`
+/// if the result would fall outside it.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Amount(i64);
 
@@ -99,6 +98,22 @@ impl Amount {
     pub const fn is_negative(self) -> bool {
         self.0.is_negative()
     }
+
+    /// Adds two Amounts, returning `None` if the result would be outside the range
+    /// `{-MAX_MONEY..MAX_MONEY}`.
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(rhs.0)
+            .and_then(|sum| Amount::from_i64(sum).ok())
+    }
+
+    /// Subtracts `rhs` from `self`, returning `None` if the result would be outside the
+    /// range `{-MAX_MONEY..MAX_MONEY}`.
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0
+            .checked_sub(rhs.0)
+            .and_then(|diff| Amount::from_i64(diff).ok())
+    }
 }
 
 impl From<Amount> for i64 {
@@ -114,42 +129,77 @@ impl From<Amount> for u64 {
 }
 
 impl Add<Amount> for Amount {
-    type Output = Amount;
+    type Output = Option<Amount>;
 
-    fn add(self, rhs: Amount) -> Amount {
-        Amount::from_i64(self.0 + rhs.0).expect("addition should remain in range")
+    fn add(self, rhs: Amount) -> Option<Amount> {
+        self.checked_add(rhs)
     }
 }
 
 impl AddAssign<Amount> for Amount {
     fn add_assign(&mut self, rhs: Amount) {
-        *self = *self + rhs
+        *self = (*self + rhs).expect("addition should remain in range")
     }
 }
 
 impl Sub<Amount> for Amount {
-    type Output = Amount;
+    type Output = Option<Amount>;
 
-    fn sub(self, rhs: Amount) -> Amount {
-        Amount::from_i64(self.0 - rhs.0).expect("subtraction should remain in range")
+    fn sub(self, rhs: Amount) -> Option<Amount> {
+        self.checked_sub(rhs)
     }
 }
 
 impl SubAssign<Amount> for Amount {
     fn sub_assign(&mut self, rhs: Amount) {
-        *self = *self - rhs
+        *self = (*self - rhs).expect("subtraction should remain in range")
+    }
+}
+
+impl Sum<Amount> for Option<Amount> {
+    fn sum<I: Iterator<Item = Amount>>(mut iter: I) -> Option<Amount> {
+        iter.try_fold(Amount::zero(), Amount::checked_add)
+    }
+}
+
+/// A non-negative quantity of Zcash, such as the value of a note or an output.
+///
+/// Unlike [`Amount`], a Zatoshi value can never be negative, and it does not support
+/// arithmetic. This makes it harder to accidentally combine the value of something being
+/// spent with the value of something being received. Use [`Zatoshi::try_into_amount`]
+/// to obtain an [`Amount`] for computing balances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Zatoshi(u64);
+
+impl Zatoshi {
+    /// Returns a zero-valued Zatoshi.
+    pub const fn zero() -> Self {
+        Zatoshi(0)
+    }
+
+    /// Converts this value into an [`Amount`].
+    ///
+    /// Returns an error if the value is greater than `MAX_MONEY`.
+    pub fn try_into_amount(self) -> Result<Amount, ()> {
+        Amount::from_u64(self.0)
     }
 }
 
-impl Sum for Amount {
-    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
-        iter.fold(Amount::zero(), Add::add)
+impl From<u64> for Zatoshi {
+    fn from(value: u64) -> Zatoshi {
+        Zatoshi(value)
+    }
+}
+
+impl From<Zatoshi> for u64 {
+    fn from(value: Zatoshi) -> u64 {
+        value.0
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Amount, MAX_MONEY};
+    use super::{Amount, Zatoshi, MAX_MONEY};
 
     #[test]
     fn amount_in_range() {
@@ -203,10 +253,11 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn add_panics_on_overflow() {
+    fn add_overflow() {
         let v = Amount(MAX_MONEY);
-        let sum = v + Amount(1);
+        assert_eq!(v + Amount(1), None);
+        assert_eq!(v.checked_add(Amount(1)), None);
+        assert_eq!(v + Amount(-1), Some(Amount(MAX_MONEY - 1)));
     }
 
     #[test]
@@ -217,10 +268,23 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn sub_panics_on_underflow() {
+    fn sub_underflow() {
         let v = Amount(-MAX_MONEY);
-        let diff = v - Amount(1);
+        assert_eq!(v - Amount(1), None);
+        assert_eq!(v.checked_sub(Amount(1)), None);
+        assert_eq!(v - Amount(-1), Some(Amount(-MAX_MONEY + 1)));
+    }
+
+    #[test]
+    fn sum_overflow() {
+        let values = vec![Amount(MAX_MONEY), Amount(-1)];
+        assert_eq!(
+            values.iter().cloned().sum::<Option<Amount>>(),
+            Some(Amount(MAX_MONEY - 1))
+        );
+
+        let values = vec![Amount(MAX_MONEY), Amount(1), Amount(-1)];
+        assert_eq!(values.into_iter().sum::<Option<Amount>>(), None);
     }
 
     #[test]
@@ -229,4 +293,16 @@ mod tests {
         let mut a = Amount(-MAX_MONEY);
         a -= Amount(1);
     }
+
+    #[test]
+    fn zatoshi_into_amount() {
+        assert_eq!(Zatoshi::zero().try_into_amount(), Ok(Amount::zero()));
+        assert_eq!(
+            Zatoshi::from(MAX_MONEY as u64).try_into_amount(),
+            Ok(Amount(MAX_MONEY))
+        );
+        assert!(Zatoshi::from(MAX_MONEY as u64 + 1)
+            .try_into_amount()
+            .is_err());
+    }
 }
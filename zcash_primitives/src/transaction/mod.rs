@@ -21,8 +21,10 @@ use self::components::{Amount, JSDescription, OutputDescription, SpendDescriptio
 
 const OVERWINTER_VERSION_GROUP_ID: u32 = 0x03C48270;
 const OVERWINTER_TX_VERSION: u32 = 3;
-const SAPLING_VERSION_GROUP_ID: u32 = 0x892F2085;
-const SAPLING_TX_VERSION: u32 = 4;
+/// The version group ID of Sapling (v4) transactions.
+pub const SAPLING_VERSION_GROUP_ID: u32 = 0x892F2085;
+/// The version of Sapling transactions.
+pub const SAPLING_TX_VERSION: u32 = 4;
 
 #[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct TxId(pub [u8; 32]);
@@ -159,6 +161,36 @@ impl Transaction {
         self.txid
     }
 
+    /// Parses a transaction from its hex encoding, as returned by the
+    /// `getrawtransaction` RPC method.
+    ///
+    /// Returns an error if `tx_hex` is not valid hex, or does not contain exactly one
+    /// transaction.
+    pub fn from_hex(tx_hex: &str) -> io::Result<Self> {
+        let data = hex::decode(tx_hex.trim())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        let mut reader = &data[..];
+        let tx = Transaction::read(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Trailing data after transaction",
+            ));
+        }
+
+        Ok(tx)
+    }
+
+    /// Returns the hex encoding of this transaction, in the format accepted by the
+    /// `sendrawtransaction` RPC method.
+    pub fn to_hex(&self) -> String {
+        let mut data = vec![];
+        self.write(&mut data)
+            .expect("Transaction was serialized when it was created");
+        hex::encode(data)
+    }
+
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let header = reader.read_u32::<LittleEndian>()?;
         let overwintered = (header >> 31) == 1;
@@ -1,4 +1,5 @@
 use ff::Field;
+use hex;
 use pairing::bls12_381::Bls12;
 use rand_os::OsRng;
 
@@ -158,6 +159,15 @@ fn tx_read_write() {
     let mut encoded = Vec::with_capacity(data.len());
     tx.write(&mut encoded).unwrap();
     assert_eq!(&data[..], &encoded[..]);
+
+    // The hex encoding round-trips
+    let data_hex = hex::encode(&data[..]);
+    assert_eq!(tx.to_hex(), data_hex);
+    assert_eq!(Transaction::from_hex(&data_hex).unwrap(), tx);
+
+    // Trailing data and invalid hex are rejected
+    assert!(Transaction::from_hex(&format!("{}00", data_hex)).is_err());
+    assert!(Transaction::from_hex(&data_hex[1..]).is_err());
 }
 
 #[test]
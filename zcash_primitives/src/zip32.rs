@@ -292,6 +292,19 @@ impl ExtendedSpendingKey {
         xsk
     }
 
+    /// Returns the key for the given account at the standard ZIP 32 path
+    /// `m/32'/coin_type'/account'`, derived from the master key for `seed`.
+    pub fn from_zip32_path(seed: &[u8], coin_type: u32, account: u32) -> Self {
+        ExtendedSpendingKey::from_path(
+            &ExtendedSpendingKey::master(seed),
+            &[
+                ChildIndex::Hardened(32),
+                ChildIndex::Hardened(coin_type),
+                ChildIndex::Hardened(account),
+            ],
+        )
+    }
+
     pub fn derive_child(&self, i: ChildIndex) -> Self {
         let fvk = FullViewingKey::from_expanded_spending_key(&self.expsk, &JUBJUB);
         let tmp = match i {
@@ -384,6 +397,20 @@ impl ExtendedFullViewingKey {
         Ok(())
     }
 
+    /// Returns the child key corresponding to the path derived from the master key.
+    ///
+    /// Returns an error if the path contains a hardened index, as hardened children
+    /// can only be derived from an [`ExtendedSpendingKey`].
+    pub fn from_path(master: &ExtendedFullViewingKey, path: &[ChildIndex]) -> Result<Self, ()> {
+        let mut xfvk = master.clone();
+        for &i in path.iter() {
+            xfvk = xfvk.derive_child(i)?;
+        }
+        Ok(xfvk)
+    }
+
+    /// Derives the non-hardened child viewing key at index `i`, without access to the
+    /// spending key. Returns an error if `i` is hardened.
     pub fn derive_child(&self, i: ChildIndex) -> Result<Self, ()> {
         let tmp = match i {
             ChildIndex::Hardened(_) => return Err(()),
@@ -451,6 +478,8 @@ mod tests {
 
     use ff::{PrimeField, PrimeFieldRepr};
 
+    use crate::note_encryption::{try_sapling_note_decryption, Memo, SaplingNoteEncryption};
+
     #[test]
     fn derive_nonhardened_child() {
         let seed = [0; 32];
@@ -509,6 +538,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn xfvk_path() {
+        let seed = [0; 32];
+        let xsk_m = ExtendedSpendingKey::master(&seed);
+        let xfvk_m = ExtendedFullViewingKey::from(&xsk_m);
+
+        let path = [ChildIndex::NonHardened(5), ChildIndex::NonHardened(7)];
+        assert_eq!(
+            ExtendedFullViewingKey::from_path(&xfvk_m, &path).unwrap(),
+            ExtendedFullViewingKey::from(&ExtendedSpendingKey::from_path(&xsk_m, &path))
+        );
+
+        // Hardened indices anywhere in the path are rejected
+        assert!(ExtendedFullViewingKey::from_path(
+            &xfvk_m,
+            &[ChildIndex::NonHardened(5), ChildIndex::Hardened(7)]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn child_xfvk_only_decrypts_own_outputs() {
+        let seed = [0; 32];
+        let xfvk_m = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&seed));
+        let xfvk_1 = xfvk_m.derive_child(ChildIndex::NonHardened(1)).unwrap();
+        let xfvk_2 = xfvk_m.derive_child(ChildIndex::NonHardened(2)).unwrap();
+
+        // Encrypts a note to the default address of xfvk, returning the pieces needed
+        // for trial decryption.
+        let encrypt_to = |xfvk: &ExtendedFullViewingKey| {
+            let to = xfvk.default_address().unwrap().1;
+            let note = to.create_note(100, Fs::one(), &JUBJUB).unwrap();
+            let cmu = note.cm(&JUBJUB);
+            let ne = SaplingNoteEncryption::new_deterministic(
+                xfvk.fvk.ovk,
+                note,
+                to,
+                Memo::default(),
+                &[7; 32],
+            );
+            (ne.epk().clone(), cmu, ne.encrypt_note_plaintext())
+        };
+
+        let ivk_1 = xfvk_1.fvk.vk.ivk();
+        let ivk_2 = xfvk_2.fvk.vk.ivk();
+        let ivk_m = xfvk_m.fvk.vk.ivk();
+
+        let (epk, cmu, enc) = encrypt_to(&xfvk_1);
+        assert!(try_sapling_note_decryption(&ivk_1, &epk, &cmu, &enc).is_some());
+        assert!(try_sapling_note_decryption(&ivk_2, &epk, &cmu, &enc).is_none());
+        assert!(try_sapling_note_decryption(&ivk_m, &epk, &cmu, &enc).is_none());
+
+        let (epk, cmu, enc) = encrypt_to(&xfvk_2);
+        assert!(try_sapling_note_decryption(&ivk_1, &epk, &cmu, &enc).is_none());
+        assert!(try_sapling_note_decryption(&ivk_2, &epk, &cmu, &enc).is_some());
+    }
+
+    #[test]
+    fn zip32_path() {
+        let seed = [0; 32];
+        let xsk_m = ExtendedSpendingKey::master(&seed);
+
+        assert_eq!(
+            ExtendedSpendingKey::from_zip32_path(&seed, 133, 7),
+            ExtendedSpendingKey::from_path(
+                &xsk_m,
+                &[
+                    ChildIndex::Hardened(32),
+                    ChildIndex::Hardened(133),
+                    ChildIndex::Hardened(7)
+                ]
+            )
+        );
+    }
+
     #[test]
     fn diversifier() {
         let dk = DiversifierKey([0; 32]);
@@ -994,6 +1098,10 @@ mod tests {
         let m_1_2h = ExtendedSpendingKey::from_path(&m, &[i1, i2h]);
         let m_1_2hv = ExtendedFullViewingKey::from(&m_1_2h);
         let m_1_2hv_3 = m_1_2hv.derive_child(i3).unwrap();
+        assert_eq!(
+            ExtendedFullViewingKey::from_path(&m_1_2hv, &[i3]).unwrap(),
+            m_1_2hv_3
+        );
 
         let xfvks = [
             ExtendedFullViewingKey::from(&m),
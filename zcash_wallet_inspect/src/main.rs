@@ -0,0 +1,430 @@
+//! Inspects the databases of a `zcash_client_sqlite` wallet.
+//!
+//! The data and cache databases are only ever opened read-only, so this tool can be
+//! safely run against a wallet that is not behaving as expected, and fails instead of
+//! creating a database that does not exist. See the usage text for the available
+//! subcommands.
+
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::process;
+use zcash_client_sqlite::{
+    cache::get_scan_completeness,
+    chain::validate_combined_chain,
+    error::Error,
+    query::{
+        get_balance, get_chain_tip, get_orchard_balance, get_total_wallet_balance,
+        get_verified_balance, open_wallet_readonly,
+    },
+    scan::get_scan_cursor,
+};
+use zcash_primitives::transaction::{components::Amount, TxId};
+
+const USAGE: &str = "\
+Usage: zcash-wallet-inspect --data-db <path> [--cache-db <path>] [--account <n>]
+                            [--network mainnet|testnet] inspect <subcommand>
+
+Subcommands:
+    balance     Balances of the given account, or of every account
+    notes       Received notes, and the transactions that spent them
+    witnesses   Stored witnesses for each received note
+    blocks      Scanned and cached block ranges, and any gaps between them
+    chain       Checks that the cached blocks continue the scanned chain";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Network {
+    Mainnet,
+    Testnet,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+        }
+    }
+}
+
+/// The network that `zcash_client_sqlite` was built for. Its parameters (address
+/// encodings and activation heights) are selected by the `mainnet` feature.
+#[cfg(feature = "mainnet")]
+const BUILD_NETWORK: Network = Network::Mainnet;
+
+#[cfg(not(feature = "mainnet"))]
+const BUILD_NETWORK: Network = Network::Testnet;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Balance,
+    Notes,
+    Witnesses,
+    Blocks,
+    Chain,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    data_db: PathBuf,
+    cache_db: Option<PathBuf>,
+    account: Option<u32>,
+    network: Network,
+    command: Command,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut data_db = None;
+    let mut cache_db = None;
+    let mut account = None;
+    let mut network = BUILD_NETWORK;
+    let mut command = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| format!("{} requires a value", flag))
+        };
+        match arg.as_str() {
+            "--data-db" => data_db = Some(PathBuf::from(value(&arg)?)),
+            "--cache-db" => cache_db = Some(PathBuf::from(value(&arg)?)),
+            "--account" => {
+                let v = value(&arg)?;
+                account = Some(v.parse().map_err(|_| format!("Invalid account: {}", v))?);
+            }
+            "--network" => {
+                network = match value(&arg)?.as_str() {
+                    "mainnet" => Network::Mainnet,
+                    "testnet" => Network::Testnet,
+                    v => return Err(format!("Unknown network: {}", v)),
+                }
+            }
+            "inspect" => {
+                command = Some(match value("inspect")?.as_str() {
+                    "balance" => Command::Balance,
+                    "notes" => Command::Notes,
+                    "witnesses" => Command::Witnesses,
+                    "blocks" => Command::Blocks,
+                    "chain" => Command::Chain,
+                    v => return Err(format!("Unknown subcommand: {}", v)),
+                })
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    Ok(Options {
+        data_db: data_db.ok_or("--data-db is required")?,
+        cache_db,
+        account,
+        network,
+        command: command.ok_or("No subcommand given")?,
+    })
+}
+
+/// Formats an amount in ZEC, with all eight decimal places.
+fn format_zec(amount: Amount) -> String {
+    let zat = i64::from(amount);
+    format!(
+        "{}{}.{:08} ZEC",
+        if zat < 0 { "-" } else { "" },
+        zat.abs() / 100_000_000,
+        zat.abs() % 100_000_000
+    )
+}
+
+fn format_txid(txid: &[u8]) -> String {
+    if txid.len() != 32 {
+        return format!("invalid txid {:?}", txid);
+    }
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(txid);
+    TxId(bytes).to_string()
+}
+
+fn inspect_balance(opts: &Options) -> Result<(), Error> {
+    match opts.account {
+        Some(account) => {
+            println!("Account {}", account);
+            println!(
+                "  Total:    {}",
+                format_zec(get_balance(&opts.data_db, account)?)
+            );
+            match get_verified_balance(&opts.data_db, account) {
+                Ok(verified) => println!("  Verified: {}", format_zec(verified)),
+                Err(e) => println!("  Verified: unavailable ({})", e),
+            }
+            println!(
+                "  Orchard:  {}",
+                format_zec(get_orchard_balance(&opts.data_db, account)?)
+            );
+        }
+        None => {
+            let balances = get_total_wallet_balance(&opts.data_db)?;
+            for (account, balance) in balances.by_account {
+                println!("Account {}: {}", account, format_zec(balance));
+            }
+            println!("Total: {}", format_zec(balances.total));
+        }
+    }
+    Ok(())
+}
+
+fn inspect_notes(data: &Connection, account: Option<u32>) -> Result<(), Error> {
+    let mut stmt_notes = data.prepare(
+        "SELECT received_notes.id_note, received_notes.account, transactions.txid,
+            transactions.block, received_notes.output_index, received_notes.value,
+            received_notes.is_change, spent_tx.txid, spent_tx.block
+        FROM received_notes
+        INNER JOIN transactions ON transactions.id_tx = received_notes.tx
+        LEFT OUTER JOIN transactions AS spent_tx ON spent_tx.id_tx = received_notes.spent
+        WHERE ?1 IS NULL OR received_notes.account = ?1
+        ORDER BY received_notes.id_note",
+    )?;
+    let rows = stmt_notes.query_map(&[account], |row| {
+        let id_note: i64 = row.get(0)?;
+        let account: u32 = row.get(1)?;
+        let txid: Vec<u8> = row.get(2)?;
+        let height: Option<u32> = row.get(3)?;
+        let output_index: i64 = row.get(4)?;
+        let value: i64 = row.get(5)?;
+        let is_change: bool = row.get(6)?;
+        let spent_txid: Option<Vec<u8>> = row.get(7)?;
+        let spent_height: Option<u32> = row.get(8)?;
+        Ok((
+            id_note,
+            account,
+            txid,
+            height,
+            output_index,
+            value,
+            is_change,
+            spent_txid.map(|txid| (txid, spent_height)),
+        ))
+    })?;
+
+    let format_height = |height: Option<u32>| {
+        height.map_or_else(
+            || "unmined".to_owned(),
+            |height| format!("block {}", height),
+        )
+    };
+    for row in rows {
+        let (id_note, account, txid, height, output_index, value, is_change, spent) = row?;
+        println!(
+            "Note {} (account {}): {}{}",
+            id_note,
+            account,
+            Amount::from_i64(value).map_or_else(|_| format!("invalid value {}", value), format_zec),
+            if is_change { " change" } else { "" },
+        );
+        println!(
+            "  Received: {} output {} ({})",
+            format_txid(&txid),
+            output_index,
+            format_height(height)
+        );
+        if let Some((spent_txid, spent_height)) = spent {
+            println!(
+                "  Spent:    {} ({})",
+                format_txid(&spent_txid),
+                format_height(spent_height)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn inspect_witnesses(data: &Connection, opts: &Options) -> Result<(), Error> {
+    let tip = get_chain_tip(&opts.data_db)?.map(|(height, _)| height);
+
+    let mut stmt_witnesses = data.prepare(
+        "SELECT received_notes.id_note, received_notes.spent IS NOT NULL,
+            COUNT(sapling_witnesses.block), MIN(sapling_witnesses.block),
+            MAX(sapling_witnesses.block)
+        FROM received_notes
+        LEFT OUTER JOIN sapling_witnesses ON sapling_witnesses.note = received_notes.id_note
+        WHERE ?1 IS NULL OR received_notes.account = ?1
+        GROUP BY received_notes.id_note
+        ORDER BY received_notes.id_note",
+    )?;
+    let rows = stmt_witnesses.query_map(&[opts.account], |row| {
+        let id_note: i64 = row.get(0)?;
+        let spent: bool = row.get(1)?;
+        let count: u32 = row.get(2)?;
+        let oldest: Option<u32> = row.get(3)?;
+        let latest: Option<u32> = row.get(4)?;
+        Ok((id_note, spent, count, oldest, latest))
+    })?;
+
+    for row in rows {
+        let (id_note, spent, count, oldest, latest) = row?;
+        let status = match (latest, tip) {
+            (None, _) if spent => "spent",
+            (None, _) => "MISSING",
+            (Some(latest), Some(tip)) if latest < tip && !spent => "STALE",
+            _ => "ok",
+        };
+        match (oldest, latest) {
+            (Some(oldest), Some(latest)) => println!(
+                "Note {}: {} witnesses, blocks {} to {} [{}]",
+                id_note, count, oldest, latest, status
+            ),
+            _ => println!("Note {}: no witnesses [{}]", id_note, status),
+        }
+    }
+    if let Some(tip) = tip {
+        println!("Last scanned block: {}", tip);
+    }
+    Ok(())
+}
+
+fn inspect_blocks(data: &Connection, opts: &Options) -> Result<(), Error> {
+    let (count, lowest, highest): (u32, Option<u32>, Option<u32>) = data.query_row(
+        "SELECT COUNT(*), MIN(height), MAX(height) FROM blocks",
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    match (lowest, highest) {
+        (Some(lowest), Some(highest)) => println!(
+            "Scanned: {} blocks, heights {} to {}",
+            count, lowest, highest
+        ),
+        _ => println!("Scanned: none"),
+    }
+    if let Some(cursor) = get_scan_cursor(&opts.data_db)? {
+        println!("Scan cursor: {}", cursor.last_committed_height);
+    }
+
+    if let Some(db_cache) = &opts.cache_db {
+        let cache = Connection::open_with_flags(db_cache, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let (count, lowest, highest): (u32, Option<u32>, Option<u32>) = cache.query_row(
+            "SELECT COUNT(*), MIN(height), MAX(height) FROM compactblocks",
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        match (lowest, highest) {
+            (Some(lowest), Some(highest)) => {
+                println!(
+                    "Cached: {} blocks, heights {} to {}",
+                    count, lowest, highest
+                );
+                let completeness = get_scan_completeness(db_cache, &opts.data_db, highest)?;
+                for (from, to) in completeness.missing_from_cache {
+                    println!("Missing: heights {} to {}", from, to);
+                }
+            }
+            _ => println!("Cached: none"),
+        }
+    }
+    Ok(())
+}
+
+fn inspect_chain(opts: &Options) -> Result<(), Error> {
+    match get_chain_tip(&opts.data_db)? {
+        Some((height, hash)) => println!("Chain tip: {} ({})", height, hash),
+        None => println!("Chain tip: nothing scanned"),
+    }
+
+    match &opts.cache_db {
+        Some(db_cache) => match validate_combined_chain(db_cache, &opts.data_db) {
+            Ok(()) => println!("Cached blocks continue the scanned chain"),
+            Err(e) => println!("Invalid chain: {}", e),
+        },
+        None => println!("Pass --cache-db to check the cached blocks"),
+    }
+    Ok(())
+}
+
+fn run(opts: &Options) -> Result<(), Error> {
+    // Checks the network and schema version before anything else reads the database.
+    let data = open_wallet_readonly(&opts.data_db)?;
+
+    match opts.command {
+        Command::Balance => inspect_balance(opts),
+        Command::Notes => inspect_notes(&data, opts.account),
+        Command::Witnesses => inspect_witnesses(&data, opts),
+        Command::Blocks => inspect_blocks(&data, opts),
+        Command::Chain => inspect_chain(opts),
+    }
+}
+
+fn main() {
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if opts.network != BUILD_NETWORK {
+        eprintln!(
+            "This tool was built for {}; rebuild it {} to inspect {} wallets.",
+            BUILD_NETWORK,
+            match opts.network {
+                Network::Mainnet => "with --features mainnet",
+                Network::Testnet => "without the mainnet feature",
+            },
+            opts.network,
+        );
+        process::exit(2);
+    }
+
+    if let Err(e) = run(&opts) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use zcash_primitives::transaction::components::Amount;
+
+    use super::{format_zec, parse_args, Command, Options, BUILD_NETWORK};
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn parse_args_accepts_flags_in_any_order() {
+        assert_eq!(
+            parse_args(args(&[
+                "inspect",
+                "notes",
+                "--account",
+                "3",
+                "--data-db",
+                "data.db",
+            ])),
+            Ok(Options {
+                data_db: PathBuf::from("data.db"),
+                cache_db: None,
+                account: Some(3),
+                network: BUILD_NETWORK,
+                command: Command::Notes,
+            })
+        );
+
+        assert!(parse_args(args(&["inspect", "balance"])).is_err());
+        assert!(parse_args(args(&["--data-db", "data.db"])).is_err());
+        assert!(parse_args(args(&["--data-db", "data.db", "inspect", "keys"])).is_err());
+        assert!(parse_args(args(&["--data-db", "data.db", "--account"])).is_err());
+        assert!(parse_args(args(&["--network", "regtest", "inspect", "chain"])).is_err());
+    }
+
+    #[test]
+    fn format_zec_pads_zatoshis() {
+        assert_eq!(format_zec(Amount::zero()), "0.00000000 ZEC");
+        assert_eq!(
+            format_zec(Amount::from_u64(123_456_789).unwrap()),
+            "1.23456789 ZEC"
+        );
+        assert_eq!(format_zec(Amount::from_i64(-5).unwrap()), "-0.00000005 ZEC");
+    }
+}